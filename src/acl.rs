@@ -0,0 +1,177 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::record::Name;
+
+/// The loopback and private networks, of IPv4 (RFC 1918) and IPv6 (RFC 4193), link-local ones
+/// included
+pub const LOCAL_NETWORKS: &str =
+    "127.0.0.0/8,10.0.0.0/8,172.16.0.0/12,192.168.0.0/16,169.254.0.0/16,::1,fc00::/7,fe80::/10";
+
+/// An address prefix, e.g. 10.0.0.0/8 or 2001:db8::/32. A bare address matches only itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>()?, Some(prefix.parse::<u8>()?)),
+            None => (s.parse::<IpAddr>()?, None),
+        };
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            return Err(anyhow::anyhow!(
+                "Prefix length {} too long in {}",
+                prefix,
+                s
+            ));
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Cidr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        <String as serde::Deserialize>::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Cidr {
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        // Treat v4-mapped clients as their v4 address, for dual-stack sockets
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(*ip),
+            _ => *ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_eq(a: &[u8], b: &[u8], prefix: u8) -> bool {
+    let full = (prefix / 8) as usize;
+    if a[..full] != b[..full] {
+        return false;
+    }
+    let rem = prefix % 8;
+    if rem == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rem);
+    a[full] & mask == b[full] & mask
+}
+
+/// Allows clients to transfer, update or notify `zone`, written as ZONE=MATCH[,MATCH] where
+/// each MATCH is an address prefix or `key:NAME`, the TSIG key the request must be signed with.
+/// Both must hold when both are given.
+#[derive(Debug, Clone)]
+pub struct ZoneAcl {
+    pub zone: Vec<String>,
+    pub net: Option<Cidr>,
+    pub key: Option<Vec<String>>,
+}
+
+impl FromStr for ZoneAcl {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (zone, matches) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected ZONE=PREFIX or ZONE=key:NAME, got {}", s))?;
+        let mut acl = Self {
+            zone: crate::label::split_name(zone.trim_end_matches('.')),
+            net: None,
+            key: None,
+        };
+        for m in matches.split(',') {
+            match m.strip_prefix("key:") {
+                Some(_) if acl.key.is_some() => {
+                    return Err(anyhow::anyhow!("More than one key in {}", s))
+                }
+                Some(key) => acl.key = Some(crate::label::split_name(key.trim_end_matches('.'))),
+                None if acl.net.is_some() => {
+                    return Err(anyhow::anyhow!("More than one prefix in {}", s))
+                }
+                None => acl.net = Some(m.parse()?),
+            }
+        }
+        Ok(acl)
+    }
+}
+
+/// Compares names case-insensitively
+pub fn names_eq(a: &[String], b: &[String]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b.iter())
+            .all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+/// Is `name` equal to or below `zone`, comparing labels case-insensitively?
+pub fn within(name: &[String], zone: &[String]) -> bool {
+    name.len() >= zone.len() && names_eq(&name[name.len() - zone.len()..], zone)
+}
+
+/// Is `client`, which signed its request with `key` if any, allowed by one of `acls` for `zone`?
+pub fn allowed(acls: &[ZoneAcl], zone: &[String], client: &IpAddr, key: Option<&Name>) -> bool {
+    acls.iter().any(|acl| {
+        names_eq(&acl.zone, zone)
+            && acl.net.map(|net| net.contains(client)).unwrap_or(true)
+            && match (&acl.key, key) {
+                (Some(expected), Some(key)) => names_eq(expected, key.as_ref()),
+                (Some(_), None) => false,
+                (None, _) => true,
+            }
+    })
+}
+
+/// Who may query, with --allow-query for every name, and with the `allow-query` of the zones
+/// configured so for the names within them, see the config module. Everyone may unless told
+/// otherwise.
+#[derive(Default)]
+pub struct QueryAcl {
+    global: Vec<Cidr>,
+    zones: Vec<(Name, Vec<Cidr>)>,
+}
+
+impl QueryAcl {
+    pub fn new(global: Vec<Cidr>, zones: Vec<(Name, Vec<Cidr>)>) -> Self {
+        Self { global, zones }
+    }
+
+    /// Is `client` allowed to query `name`? The closest zone enclosing it with prefixes of its
+    /// own decides, with none given allowing nobody.
+    pub fn allows(&self, name: &[String], client: &IpAddr) -> bool {
+        let zone = self
+            .zones
+            .iter()
+            .filter(|(zone, _)| {
+                let zone: &[String] = zone.as_ref();
+                zone.len() <= name.len() && names_eq(zone, &name[name.len() - zone.len()..])
+            })
+            .max_by_key(|(zone, _)| zone.as_ref().len());
+        match zone {
+            Some((_, prefixes)) => prefixes.iter().any(|net| net.contains(client)),
+            None => self.global.is_empty() || self.global.iter().any(|net| net.contains(client)),
+        }
+    }
+}
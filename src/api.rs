@@ -0,0 +1,489 @@
+//! HTTP admin API changing records at runtime. Requests carry the token given with --api-token,
+//! of at least 16 characters, as `Authorization: Bearer <token>`, or as the password of HTTP
+//! basic authentication.
+//!
+//! - `PUT /zones/{zone}/records/{name}/{type}` replaces the RRset with the records of the JSON
+//!   body, in the zone file layout without the type, e.g. `[{"ttl": 60, "addr": "10.0.0.1"}]`
+//! - `DELETE /zones/{zone}/records/{name}/{type}` removes the RRset
+//! - `GET /zones/{zone}/versions` lists the versions of the zone kept with --history, as
+//!   `[{"id": 1, "serial": 2023010100, "time": 1672531200}]`, oldest first
+//! - `POST /zones/{zone}/rollback/{id}` restores that version of the zone, under a new serial
+//! - `POST /acme/present` and `POST /acme/cleanup`, with `{"fqdn": "_acme-challenge.<domain>.",
+//!   "value": "<digest>"}`, add and remove the TXT record of an ACME DNS-01 challenge (RFC 8555
+//!   Section 8.4) in the zone enclosing it. These are the requests of lego's httpreq provider,
+//!   given `HTTPREQ_ENDPOINT=http://<api>/acme`, `HTTPREQ_USERNAME` and `HTTPREQ_PASSWORD=<token>`.
+//!   Only `_acme-challenge` names can be changed this way, and other values of the RRset are kept
+//!   for concurrent challenges, e.g. of a wildcard and its domain.
+//!
+//! - `GET /stats` returns the statistics of each zone, see the stats module
+//!
+//! `{name}` is absolute, or `@` for the zone apex. See the edit module for how changes are kept,
+//! the health module for the endpoints answered without the token, and the grpc module for the
+//! same operations over gRPC.
+
+use std::fmt;
+use std::sync::Arc;
+
+use base64ct::{Base64, Encoding};
+use serde::Deserialize;
+
+use crate::history::Version;
+use crate::http::{Request, Response};
+use crate::parser::Type;
+use crate::record::{Name, Record, RecordInner};
+use crate::{edit, stats, Args, BaseStorage, SharedStorage};
+
+/// The admin operations, whichever API they are requested through. They block, on the updates
+/// of the storage and on writing zone files.
+pub struct Admin {
+    args: Arc<Args>,
+    storage: SharedStorage,
+    persist: bool,
+    stats: Arc<stats::Stats>,
+}
+
+pub struct Api {
+    admin: Arc<Admin>,
+    token: String,
+}
+
+/// A change to one RRset, empty records meaning deletion
+pub struct Change {
+    pub zone: Name,
+    pub name: Name,
+    pub ty: Type,
+    pub records: Vec<Record>,
+}
+
+enum Action {
+    Change(Change),
+    Versions(Name),
+    Rollback(Name, u64),
+    Acme(Challenge, bool),
+    Stats,
+}
+
+/// Body of the requests of lego's httpreq provider
+#[derive(Deserialize)]
+pub struct Challenge {
+    pub fqdn: String,
+    pub value: String,
+}
+
+/// Why an operation failed, with the HTTP status answered for it
+#[derive(Debug)]
+pub struct Error {
+    pub status: u16,
+    pub message: String,
+}
+
+impl Error {
+    fn new(status: u16, message: impl fmt::Display) -> Self {
+        Self {
+            status,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<edit::Error> for Error {
+    fn from(e: edit::Error) -> Self {
+        match e {
+            edit::Error::NoFile(_) => Error::new(409, e),
+            edit::Error::Invalid(_) => Error::new(422, e),
+            edit::Error::Io(_) => Error::new(500, e),
+        }
+    }
+}
+
+impl From<Error> for Response {
+    fn from(e: Error) -> Self {
+        Response::error(e.status, e.message)
+    }
+}
+
+/// Short, for resolvers not to keep challenges past their cleanup
+const ACME_TTL: u32 = 60;
+const ACME_LABEL: &str = "_acme-challenge";
+
+/// An absolute name, with or without the final dot
+pub fn parse_name(s: &str) -> anyhow::Result<Name> {
+    let s = s.trim_end_matches('.');
+    if s.is_empty() {
+        return Err(anyhow::anyhow!("empty name"));
+    }
+    Ok(Name::from(crate::label::split_name(s)))
+}
+
+/// Below which tokens are refused at startup, as too easily guessed
+const MIN_TOKEN_LEN: usize = 16;
+
+/// Equal length strings are compared in full, so the time taken does not tell how much of the
+/// token matched
+fn token_matches(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// A name of a request path, percent-encoded
+fn parse_segment(s: &str) -> anyhow::Result<Name> {
+    parse_name(&crate::http::percent_decode(s)?)
+}
+
+pub fn edit_error(e: edit::Error) -> Response {
+    Error::from(e).into()
+}
+
+/// `value` as the body of a response
+fn json(value: &impl serde::Serialize) -> Result<Response, Error> {
+    match serde_json::to_string_pretty(value) {
+        Ok(body) => Ok(Response::json(200, body + "\n")),
+        Err(e) => Err(Error::new(500, e)),
+    }
+}
+
+impl Api {
+    pub fn new(admin: Arc<Admin>, token: String) -> anyhow::Result<Self> {
+        if token.chars().count() < MIN_TOKEN_LEN {
+            return Err(anyhow::anyhow!(
+                "--api-token must be at least {} characters long",
+                MIN_TOKEN_LEN
+            ));
+        }
+        Ok(Self { admin, token })
+    }
+
+    /// The token, as bearer token or basic authentication password, the user being ignored
+    fn authorized(&self, request: &Request) -> bool {
+        let value = match request.header("authorization") {
+            Some(value) => value,
+            None => return false,
+        };
+        if let Some(token) = value.strip_prefix("Bearer ") {
+            return token_matches(token.trim(), &self.token);
+        }
+        value
+            .strip_prefix("Basic ")
+            .and_then(|credentials| Base64::decode_vec(credentials.trim()).ok())
+            .and_then(|credentials| String::from_utf8(credentials).ok())
+            .and_then(|credentials| {
+                let (_, password) = credentials.split_once(':')?;
+                Some(token_matches(password, &self.token))
+            })
+            .unwrap_or(false)
+    }
+
+    pub async fn handle(self: Arc<Self>, request: Request) -> Response {
+        if !self.authorized(&request) {
+            return Response::error(401, "missing or invalid token");
+        }
+
+        let action = match self.parse(&request) {
+            Ok(action) => action,
+            Err(response) => return response,
+        };
+        let admin = self.admin.clone();
+        let run = move || match action {
+            Action::Change(change) => admin.apply(change).map(|()| Response::empty(204)),
+            Action::Versions(zone) => {
+                let versions = admin.versions(&zone)?;
+                json(&versions.iter().map(Arc::as_ref).collect::<Vec<&Version>>())
+            }
+            Action::Rollback(zone, id) => admin.rollback(&zone, id).map(|()| Response::empty(204)),
+            Action::Acme(challenge, present) => admin
+                .acme(challenge, present)
+                .map(|()| Response::empty(204)),
+            Action::Stats => json(&admin.stats()),
+        };
+        match tokio::task::spawn_blocking(run).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => e.into(),
+            Err(e) => Response::error(500, e),
+        }
+    }
+
+    fn parse(&self, request: &Request) -> Result<Action, Response> {
+        let bad = |e: anyhow::Error| Response::error(400, e);
+        let segs: Vec<&str> = request.path.trim_start_matches('/').split('/').collect();
+        let (zone, name, ty) = match (request.method.as_str(), segs.as_slice()) {
+            (_, ["zones", zone, "records", name, ty]) => (*zone, *name, *ty),
+            ("GET", ["zones", zone, "versions"]) => {
+                return Ok(Action::Versions(parse_segment(zone).map_err(bad)?))
+            }
+            ("POST", ["zones", zone, "rollback", id]) => {
+                let id = id
+                    .parse()
+                    .map_err(|e| bad(anyhow::anyhow!("invalid version {}: {}", id, e)))?;
+                return Ok(Action::Rollback(parse_segment(zone).map_err(bad)?, id));
+            }
+            ("POST", ["acme", action @ ("present" | "cleanup")]) => {
+                let challenge: Challenge = serde_json::from_slice(&request.body).map_err(|e| {
+                    bad(anyhow::anyhow!(
+                        "expected {{\"fqdn\": ..., \"value\": ...}}: {}",
+                        e
+                    ))
+                })?;
+                return Ok(Action::Acme(challenge, *action == "present"));
+            }
+            ("GET", ["stats"]) => return Ok(Action::Stats),
+            (_, ["stats"]) => return Err(Response::error(405, "expected GET")),
+            (_, ["acme", "present" | "cleanup"]) => {
+                return Err(Response::error(405, "expected POST"))
+            }
+            (_, ["zones", _, "versions"]) => return Err(Response::error(405, "expected GET")),
+            (_, ["zones", _, "rollback", _]) => return Err(Response::error(405, "expected POST")),
+            _ => return Err(Response::error(404, "no such endpoint")),
+        };
+        let zone = parse_segment(zone).map_err(bad)?;
+        let name = match name {
+            "@" => zone.clone(),
+            name => parse_segment(name).map_err(bad)?,
+        };
+        let ty: Type = ty.parse().map_err(bad)?;
+
+        let records = match request.method.as_str() {
+            "PUT" => parse_records(ty, &request.body).map_err(bad)?,
+            "DELETE" => Vec::new(),
+            _ => return Err(Response::error(405, "expected PUT or DELETE")),
+        };
+        Ok(Action::Change(Change {
+            zone,
+            name,
+            ty,
+            records,
+        }))
+    }
+}
+
+fn parse_records(ty: Type, body: &[u8]) -> anyhow::Result<Vec<Record>> {
+    let items: Vec<serde_json::Map<String, serde_json::Value>> = serde_json::from_slice(body)
+        .map_err(|e| anyhow::anyhow!("expected a JSON array of records: {}", e))?;
+    if items.is_empty() {
+        return Err(anyhow::anyhow!(
+            "no records given, use DELETE to remove an RRset"
+        ));
+    }
+    let mut records = Vec::new();
+    for mut item in items {
+        match item.get("type").and_then(|v| v.as_str()) {
+            Some(given) if given.parse::<Type>().ok() != Some(ty) => {
+                return Err(anyhow::anyhow!(
+                    "record of type {} in a {:?} RRset",
+                    given,
+                    ty
+                ))
+            }
+            _ => (),
+        }
+        item.insert("type".into(), format!("{:?}", ty).into());
+        records.push(serde_json::from_value(item.into())?);
+    }
+    Ok(records)
+}
+
+impl Admin {
+    pub fn new(
+        args: Arc<Args>,
+        storage: SharedStorage,
+        persist: bool,
+        stats: Arc<stats::Stats>,
+    ) -> Self {
+        Self {
+            args,
+            storage,
+            persist,
+            stats,
+        }
+    }
+
+    fn is_zone(&self, zone: &Name) -> bool {
+        self.storage
+            .snapshot()
+            .base
+            .get(zone.as_ref())
+            .map(|records| records.iter().any(|r| r.inner.ty() == Type::SOA))
+            .unwrap_or(false)
+    }
+
+    fn edit(&self, zone: &Name, changes: Vec<edit::Change>) -> Result<(), Error> {
+        edit::apply(&self.args.zones, &self.storage, zone, changes, self.persist)?;
+        Ok(())
+    }
+
+    pub fn apply(&self, change: Change) -> Result<(), Error> {
+        if !crate::acl::within(change.name.as_ref(), change.zone.as_ref()) {
+            return Err(Error::new(
+                400,
+                format!("{} is not within {}", change.name, change.zone),
+            ));
+        }
+        let _updating = self.storage.lock_updates();
+        if !self.is_zone(&change.zone) {
+            return Err(Error::new(404, format!("no zone {}", change.zone)));
+        }
+        let existing = self
+            .storage
+            .snapshot()
+            .base
+            .get(change.name.as_ref())
+            .cloned()
+            .unwrap_or_default();
+        edit::check(
+            &change.zone,
+            &change.name,
+            change.ty,
+            &change.records,
+            &existing,
+        )
+        .map_err(|message| Error::new(422, message))?;
+
+        log::info!(
+            "API: {} {} {:?} ({} record(s))",
+            if change.records.is_empty() {
+                "delete"
+            } else {
+                "set"
+            },
+            change.name,
+            change.ty,
+            change.records.len()
+        );
+        let changes = vec![(change.name, change.ty, change.records)];
+        self.edit(&change.zone, changes)
+    }
+
+    /// The versions of `zone` kept, oldest first
+    pub fn versions(&self, zone: &Name) -> Result<Vec<Arc<Version>>, Error> {
+        let history = self
+            .storage
+            .history()
+            .ok_or_else(|| Error::new(404, "no versions are kept, see --history"))?;
+        let versions = history.versions(zone.as_ref());
+        if versions.is_empty() {
+            return Err(Error::new(404, format!("no versions of {}", zone)));
+        }
+        Ok(versions)
+    }
+
+    pub fn stats(&self) -> Vec<stats::Snapshot> {
+        self.stats.snapshot()
+    }
+
+    /// Sets every RRset of `zone` as it was in version `id`, but the SOA, whose serial is bumped
+    /// by the edit
+    pub fn rollback(&self, zone: &Name, id: u64) -> Result<(), Error> {
+        let _updating = self.storage.lock_updates();
+        if !self.is_zone(zone) {
+            return Err(Error::new(404, format!("no zone {}", zone)));
+        }
+        let version = self.storage.history().and_then(|history| {
+            history
+                .versions(zone.as_ref())
+                .into_iter()
+                .find(|v| v.id == id)
+        });
+        let version =
+            version.ok_or_else(|| Error::new(404, format!("no version {} of {}", id, zone)))?;
+        let current = crate::history::zone_records(&self.storage.snapshot().base, zone.as_ref());
+        let mut rrsets: Vec<(&Name, Type)> = Vec::new();
+        for (name, records) in current.iter().chain(version.records.iter()) {
+            for record in records {
+                let rrset = (name, record.inner.ty());
+                let is_soa = name == zone && rrset.1 == Type::SOA;
+                if !is_soa && !rrsets.contains(&rrset) {
+                    rrsets.push(rrset);
+                }
+            }
+        }
+        let rrset = |base: &BaseStorage, name: &Name, ty: Type| -> Vec<Record> {
+            base.get(name.as_ref())
+                .into_iter()
+                .flatten()
+                .filter(|r| r.inner.ty() == ty)
+                .cloned()
+                .collect()
+        };
+        let changes: Vec<edit::Change> = rrsets
+            .into_iter()
+            .filter_map(|(name, ty)| {
+                let restored = rrset(&version.records, name, ty);
+                (rrset(&current, name, ty) != restored).then(|| (name.clone(), ty, restored))
+            })
+            .collect();
+
+        log::info!(
+            "API: roll {} back to version {} ({} RRset(s) changed)",
+            zone,
+            id,
+            changes.len()
+        );
+        if changes.is_empty() {
+            return Ok(());
+        }
+        self.edit(zone, changes)
+    }
+
+    /// Adds (`present`) or removes the TXT record of `challenge`
+    pub fn acme(&self, challenge: Challenge, present: bool) -> Result<(), Error> {
+        let fqdn = challenge.fqdn.trim_end_matches('.');
+        let name = Name::from(crate::label::split_name(fqdn));
+        match name.as_ref().first() {
+            Some(label) if label.eq_ignore_ascii_case(ACME_LABEL) => (),
+            _ => {
+                return Err(Error::new(
+                    422,
+                    format!("{} is not an {} name", challenge.fqdn, ACME_LABEL),
+                ))
+            }
+        }
+
+        let _updating = self.storage.lock_updates();
+        let snapshot = self.storage.snapshot();
+        let zone = match crate::journal::zone_of(&snapshot.base, name.as_ref()) {
+            Some(zone) => Name::from(zone.to_vec()),
+            None => return Err(Error::new(404, format!("no zone encloses {}", name))),
+        };
+        let mut records: Vec<Record> = snapshot
+            .base
+            .get(name.as_ref())
+            .into_iter()
+            .flatten()
+            .filter(|r| r.inner.ty() == Type::TXT)
+            .cloned()
+            .collect();
+        let inner = RecordInner::TXT {
+            content: challenge.value,
+        };
+        let before = records.len();
+        if present {
+            if !records.iter().any(|r| r.inner == inner) {
+                records.push(Record::new(inner, ACME_TTL));
+            }
+        } else {
+            records.retain(|r| r.inner != inner);
+        }
+        if records.len() == before {
+            return Ok(());
+        }
+        let existing = snapshot.base.get(name.as_ref()).map(Vec::as_slice);
+        edit::check(&zone, &name, Type::TXT, &records, existing.unwrap_or(&[]))
+            .map_err(|message| Error::new(422, message))?;
+
+        log::info!(
+            "API: ACME challenge {} at {}",
+            if present { "presented" } else { "cleaned up" },
+            name
+        );
+        self.edit(&zone, vec![(name, Type::TXT, records)])
+    }
+}
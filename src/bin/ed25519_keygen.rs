@@ -1,25 +1,173 @@
-use std::{path::PathBuf, os::unix::prelude::OpenOptionsExt, io::Write};
+use std::{io::Write, path::PathBuf, str::FromStr};
 
+use base64ct::{Base64, Encoding};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
 use rand::rngs::OsRng;
+use rsa::PublicKeyParts;
+use sha2::{Digest, Sha256};
 use structopt::StructOpt;
 
 use ed25519::pkcs8::EncodePrivateKey;
 
+#[path = "../keyfile.rs"]
+mod keyfile;
+
+const DIGEST_SHA256: u8 = 2;
+const RSA_BITS: usize = 2048;
+
+#[derive(Clone, Copy)]
+enum Algorithm {
+    /// RSA/SHA-256, RFC 5702
+    RsaSha256 = 8,
+    /// ECDSA P-256 with SHA-256, RFC 6605
+    EcdsaP256Sha256 = 13,
+    /// Ed25519, RFC 8080
+    Ed25519 = 15,
+}
+
+impl FromStr for Algorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rsasha256" | "8" => Ok(Self::RsaSha256),
+            "ecdsap256sha256" | "13" => Ok(Self::EcdsaP256Sha256),
+            "ed25519" | "15" => Ok(Self::Ed25519),
+            _ => Err(anyhow::anyhow!(
+                "Unknown algorithm {}, expected one of rsasha256, ecdsap256sha256, ed25519",
+                s
+            )),
+        }
+    }
+}
+
 #[derive(StructOpt)]
 struct Args {
     #[structopt(short, long)]
     output: PathBuf,
+
+    /// rsasha256 (8), ecdsap256sha256 (13) or ed25519 (15). RSA keys are of 2048 bits.
+    #[structopt(short, long, default_value = "ed25519")]
+    algorithm: Algorithm,
+
+    /// Zone the key is for. Its DNSKEY record, and the DS record to give the parent zone, are
+    /// then printed.
+    #[structopt(short, long)]
+    zone: Option<String>,
+
+    /// Make a zone signing key, without the SEP flag nor DS record, rather than a key signing key
+    #[structopt(long)]
+    zsk: bool,
+
+    /// Encrypt the key with a passphrase, from --passphrase-file or DNS_KEY_PASSPHRASE, or else
+    /// asked for
+    #[structopt(long)]
+    encrypt: bool,
+
+    /// File whose first line is the passphrase of --encrypt
+    #[structopt(long)]
+    passphrase_file: Option<PathBuf>,
+
+    /// Passphrase of --encrypt, better given by the environment than on the command line
+    #[structopt(long, env = "DNS_KEY_PASSPHRASE", hide_env_values = true)]
+    passphrase: Option<String>,
+}
+
+/// Key tag, RFC 4034 Appendix B
+fn key_tag(rdata: &[u8]) -> u16 {
+    let mut acc: u32 = 0;
+    for (i, byte) in rdata.iter().enumerate() {
+        acc += if i % 2 == 0 {
+            (*byte as u32) << 8
+        } else {
+            *byte as u32
+        };
+    }
+    acc += (acc >> 16) & 0xffff;
+    acc as u16
+}
+
+/// `zone` in wire format, lowercased as DS digests take it (RFC 4034 Section 5.1.4)
+fn wire(zone: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    for label in zone
+        .trim_end_matches('.')
+        .split('.')
+        .filter(|label| !label.is_empty())
+    {
+        out.push(label.len() as u8);
+        out.extend(label.to_ascii_lowercase().as_bytes());
+    }
+    out.push(0);
+    out
 }
 
 #[paw::main]
 fn main(args: Args) -> anyhow::Result<()> {
     env_logger::init();
-    let mut file = std::fs::File::options().write(true).create(true).truncate(true).mode(0o600).open(&args.output)?;
+    let passphrase = match args.encrypt {
+        false => None,
+        true => Some(
+            match keyfile::passphrase(args.passphrase.as_deref(), args.passphrase_file.as_deref())?
+            {
+                Some(passphrase) => passphrase,
+                None => {
+                    let passphrase = keyfile::prompt("Passphrase: ")?;
+                    if keyfile::prompt("Passphrase again: ")? != passphrase {
+                        return Err(anyhow::anyhow!("Passphrases do not match"));
+                    }
+                    passphrase
+                }
+            },
+        ),
+    };
+    if passphrase.as_deref() == Some("") {
+        return Err(anyhow::anyhow!("Empty passphrase"));
+    }
+    let mut file = keyfile::open(
+        std::fs::File::options()
+            .write(true)
+            .create(true)
+            .truncate(true),
+        &args.output,
+    )?;
 
-    let mut osrng = OsRng;
-    let kp = ed25519_dalek::Keypair::generate(&mut osrng);
-    let enc_kp = ed25519::pkcs8::KeypairBytes::from_bytes(&kp.to_bytes());
-    match enc_kp.to_pkcs8_pem(pem_rfc7468::LineEnding::LF) {
+    // The PKCS#8 DER of the key, and its public key as DNSKEY records carry it
+    let (der, public) = match args.algorithm {
+        Algorithm::RsaSha256 => {
+            let key = rsa::RsaPrivateKey::new(&mut rand_core::OsRng, RSA_BITS)?;
+            // RFC 3110 Section 2: exponent length, exponent, then modulus
+            let exponent = key.e().to_bytes_be();
+            let mut public = match u8::try_from(exponent.len()) {
+                Ok(len) => vec![len],
+                Err(_) => [&[0][..], &(exponent.len() as u16).to_be_bytes()].concat(),
+            };
+            public.extend(exponent);
+            public.extend(key.n().to_bytes_be());
+            (key.to_pkcs8_der(), public)
+        }
+        Algorithm::EcdsaP256Sha256 => {
+            let key = p256::SecretKey::random(&mut rand_core::OsRng);
+            // RFC 6605 Section 4: the point's coordinates, without the SEC1 prefix
+            let point = key.public_key().to_encoded_point(false);
+            (key.to_pkcs8_der(), point.as_bytes()[1..].to_vec())
+        }
+        Algorithm::Ed25519 => {
+            let kp = ed25519_dalek::Keypair::generate(&mut OsRng);
+            let enc_kp = ed25519::pkcs8::KeypairBytes::from_bytes(&kp.to_bytes());
+            (enc_kp.to_pkcs8_der(), kp.public.as_bytes().to_vec())
+        }
+    };
+    let pem = der
+        .map_err(anyhow::Error::from)
+        .and_then(|der| match &passphrase {
+            Some(passphrase) => keyfile::encrypt(der.as_ref(), passphrase),
+            None => {
+                pem_rfc7468::encode_string("PRIVATE KEY", pem_rfc7468::LineEnding::LF, der.as_ref())
+                    .map_err(|e| anyhow::anyhow!("{}", e))
+            }
+        });
+    match pem {
         Err(e) => {
             log::error!("Error: {}", e);
             return Err(anyhow::anyhow!("Failed to generate keypair."));
@@ -27,5 +175,32 @@ fn main(args: Args) -> anyhow::Result<()> {
         Ok(s) => file.write_all(s.as_bytes())?,
     }
 
+    if let Some(zone) = &args.zone {
+        let owner = format!("{}.", zone.trim_end_matches('.'));
+        let flags: u16 = if args.zsk { 256 } else { 257 };
+        let mut rdata = flags.to_be_bytes().to_vec();
+        rdata.extend([3, args.algorithm as u8]);
+        rdata.extend(&public);
+        let tag = key_tag(&rdata);
+        println!(
+            "{} IN DNSKEY {} 3 {} {} ; key tag {}",
+            owner,
+            flags,
+            args.algorithm as u8,
+            Base64::encode_string(&public),
+            tag
+        );
+        if !args.zsk {
+            let digest: String = Sha256::digest(&[wire(zone), rdata].concat())
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect();
+            println!(
+                "{} IN DS {} {} {} {}",
+                owner, tag, args.algorithm as u8, DIGEST_SHA256, digest
+            );
+        }
+    }
+
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,161 @@
+//! Blocking of names, such as those of ad and tracking servers, read from the lists given with
+//! --blocklist: files, or plain HTTP URLs re-read every --blocklist-refresh seconds. Lists are
+//! in hosts format, `0.0.0.0 ads.example.com`, or hold one name per line, `#` starting comments.
+//!
+//! A name listed blocks the names below it as well. Blocked names are answered according to
+//! --block-answer, NXDOMAIN by default, with an Extended DNS Error telling they are blocked
+//! (RFC 8914). Only names resolved upstream are blocked: the zones served and local overrides
+//! take precedence.
+
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::RwLock;
+
+use crate::parser::Type;
+use crate::record::{Name, Record, RecordInner};
+
+/// TTL of the answers to blocked names
+const BLOCKED_TTL: u32 = 60;
+
+/// Names given for the local host in hosts files, not to be blocked
+const LOCAL_NAMES: [&str; 6] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+];
+
+/// How blocked names are answered, with --block-answer
+#[derive(Debug, Clone, Copy)]
+pub enum BlockAnswer {
+    NxDomain,
+    /// 0.0.0.0 and ::
+    Null,
+    /// This address, for the queries of its type
+    Sinkhole(IpAddr),
+}
+
+impl std::str::FromStr for BlockAnswer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nxdomain" => Ok(Self::NxDomain),
+            "null" => Ok(Self::Null),
+            _ => s
+                .parse()
+                .map(Self::Sinkhole)
+                .map_err(|_| format!("Expected nxdomain, null or an IP address, got {}", s)),
+        }
+    }
+}
+
+impl BlockAnswer {
+    /// Record answering a query of type `ty` for a blocked name, if any
+    pub fn record(&self, ty: Type) -> Option<Record> {
+        let inner = match (self, ty) {
+            (Self::NxDomain, _) => return None,
+            (Self::Null, Type::A) => RecordInner::A {
+                addr: Ipv4Addr::UNSPECIFIED.octets(),
+            },
+            (Self::Null, Type::AAAA) => RecordInner::AAAA {
+                addr: Ipv6Addr::UNSPECIFIED.octets(),
+            },
+            (Self::Sinkhole(IpAddr::V4(addr)), Type::A) => RecordInner::A {
+                addr: addr.octets(),
+            },
+            (Self::Sinkhole(IpAddr::V6(addr)), Type::AAAA) => RecordInner::AAAA {
+                addr: addr.octets(),
+            },
+            _ => return None,
+        };
+        Some(Record::new(inner, BLOCKED_TTL))
+    }
+}
+
+/// Names of a list, lowercased
+fn parse(content: &str) -> HashSet<Name> {
+    let mut names = HashSet::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let listed = match fields.as_slice() {
+            [_] => &fields[..],
+            [ip, names @ ..] if ip.parse::<IpAddr>().is_ok() => names,
+            _ => continue,
+        };
+        for name in listed {
+            let name = name.trim_end_matches('.').to_ascii_lowercase();
+            if name.is_empty() || LOCAL_NAMES.contains(&name.as_str()) {
+                continue;
+            }
+            names.insert(Name::from(crate::label::split_name(&name)));
+        }
+    }
+    names
+}
+
+/// Reads the list at `source`, a path or a plain HTTP URL
+async fn read(source: &str) -> anyhow::Result<HashSet<Name>> {
+    let content = match source.strip_prefix("http://") {
+        Some(url) => {
+            let (addr, path) = match url.find('/') {
+                Some(i) => url.split_at(i),
+                None => (url, "/"),
+            };
+            let addr = match addr.contains(':') {
+                true => addr.to_owned(),
+                false => format!("{}:80", addr),
+            };
+            let reply = crate::http::get(&addr, path, &[]).await?;
+            if reply.status != 200 {
+                return Err(reply.error());
+            }
+            String::from_utf8_lossy(&reply.body).into_owned()
+        }
+        None => tokio::fs::read_to_string(source).await?,
+    };
+    Ok(parse(&content))
+}
+
+pub struct Blocklist {
+    sources: Vec<String>,
+    /// Names of each source, as last read
+    names: RwLock<Vec<HashSet<Name>>>,
+}
+
+impl Blocklist {
+    pub fn new(sources: Vec<String>) -> Self {
+        let names = sources.iter().map(|_| HashSet::new()).collect();
+        Self {
+            sources,
+            names: RwLock::new(names),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Re-reads every list. Those which cannot be read keep the names read last.
+    pub async fn refresh(&self) {
+        for (i, source) in self.sources.iter().enumerate() {
+            match read(source).await {
+                Ok(names) => {
+                    log::info!("Blocklist {}: {} name(s)", source, names.len());
+                    self.names.write().unwrap()[i] = names;
+                }
+                Err(e) => log::error!("Failed to read blocklist {}: {}", source, e),
+            }
+        }
+    }
+
+    /// Whether `name`, or a name above it, is listed
+    pub fn is_blocked(&self, name: &[String]) -> bool {
+        let name: Vec<String> = name.iter().map(|l| l.to_ascii_lowercase()).collect();
+        let lists = self.names.read().unwrap();
+        (0..name.len()).any(|i| lists.iter().any(|names| names.contains(&name[i..])))
+    }
+}
@@ -0,0 +1,556 @@
+//! Cache of the answers of upstream resolvers, with --forward, and of those resolved with
+//! --recursive, keyed by name, type and class, and view: the client group whose own upstream
+//! resolvers gave them, if any, see the clients module, followed by the subnet of the client for
+//! the answers given for it with --ecs, see the ecs module. Answers expire with the lowest TTL of
+//! their records, and are served with TTLs counting down. Once --cache-size answers are held, the
+//! least recently used one makes room for the next.
+//!
+//! Negative answers are cached as well, for the TTL of the SOA record they carry or its minimum
+//! field if lower, RFC 2308 Section 5, and at most 3 hours. Those without a SOA record are not.
+//! A name error holds for every type of the name, a NODATA answer for its type only.
+//!
+//! Answers validated with --trust-anchors are cached without their DNSSEC records, with whether
+//! they are authentic so that AD is set when served, see the dnssec module. Bogus ones are not.
+//!
+//! Answers are kept for --serve-stale once expired, to be served when upstreams cannot be reached,
+//! with a TTL of 30 seconds and an Extended DNS Error telling they are stale (RFC 8767).
+//!
+//! Popular answers, served from the cache at least 10 times, are refreshed in the background
+//! when they are served within the last tenth of their TTL, so that their clients do not wait for
+//! upstreams once they expire.
+//!
+//! With --cache-file, the cache is saved every 5 minutes and when the server is stopped, and
+//! restored on startup, so that a restart does not send every query upstream at once. Each answer
+//! is saved as a DNS message, its question giving its key, along with its view, when it was
+//! cached and when it expires.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::message::{MessageWriter, Rcode, Section};
+use crate::parser::{Class, OpCode, ReqHeaderStatus, Type};
+use crate::record::{Name, Record, RecordInner, TtlBounds};
+use crate::resolver::Answer;
+
+/// Longest time negative answers are cached, RFC 2308 Section 5
+const MAX_NEGATIVE_TTL: u32 = 3 * 3600;
+
+/// TTL of stale answers, RFC 8767 Section 4
+const STALE_TTL: u32 = 30;
+
+/// Hits from which an answer is refreshed before it expires
+const PREFETCH_HITS: u32 = 10;
+
+/// Start of the files of --cache-file, with the version of their format
+const FILE_MAGIC: &[u8] = b"DNSCACHE2";
+
+/// View, name, type and class. No type for name errors, which hold for all of them.
+type Key = (String, Vec<String>, Option<Type>, u16);
+
+struct Entry {
+    answer: Answer,
+    stored: Instant,
+    expires: Instant,
+    hits: u32,
+    /// Whether it is being refreshed before it expires
+    prefetching: bool,
+    /// Tick of its last use, its key in `Entries::used`
+    used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<Key, Entry>,
+    /// Keys from the least recently used
+    used: BTreeMap<u64, Key>,
+    tick: u64,
+}
+
+impl Entries {
+    fn touch(&mut self, key: &Key) {
+        self.tick += 1;
+        if let Some(entry) = self.by_key.get_mut(key) {
+            self.used.remove(&entry.used);
+            entry.used = self.tick;
+            self.used.insert(self.tick, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.by_key.remove(key) {
+            self.used.remove(&entry.used);
+        }
+    }
+
+    /// Removes the least recently used entries until there is room for another
+    fn make_room(&mut self, capacity: usize) {
+        while self.by_key.len() >= capacity {
+            let oldest = match self.used.iter().next() {
+                Some((_, key)) => key.clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+        }
+    }
+}
+
+pub struct Cache {
+    capacity: usize,
+    /// How long answers are kept once expired, to be served should upstreams fail
+    stale: Duration,
+    entries: Mutex<Entries>,
+}
+
+fn key(view: &str, name: &[String], ty: Option<Type>, class: u16) -> Key {
+    let name = name.iter().map(|l| l.to_ascii_lowercase()).collect();
+    (view.to_owned(), name, ty, class)
+}
+
+/// TTL of the negative answers carrying `record`, if a SOA record
+fn negative_ttl(record: &crate::record::Record) -> Option<u32> {
+    match record.inner {
+        RecordInner::SOA { minimum, .. } => Some(record.ttl.min(minimum).min(MAX_NEGATIVE_TTL)),
+        _ => None,
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// `answer` as a response to the question of `key`, name errors asking for any type
+fn encode(key: &Key, answer: &Answer) -> std::io::Result<Vec<u8>> {
+    let status = ReqHeaderStatus {
+        qr: false,
+        opcode: OpCode::Query,
+        rd: false,
+        // For AD to be kept, see answer_of
+        ad: true,
+        cd: false,
+    };
+    let class = Class::from(key.3);
+    let bounds = TtlBounds {
+        min: 0,
+        max: u32::MAX,
+    };
+    let mut msg = MessageWriter::new(0, &status, usize::MAX);
+    msg.set_rcode(answer.rcode);
+    msg.set_ad(answer.secure);
+    msg.push_question(&key.1, key.2.unwrap_or(Type::ANY), class)?;
+    for (name, record) in answer.answers.iter() {
+        msg.push(Section::Answer, name.as_ref(), record, class, &bounds)?;
+    }
+    for (name, record) in answer.authorities.iter() {
+        msg.push(Section::Authority, name.as_ref(), record, class, &bounds)?;
+    }
+    // Name servers of the zone, for RPZ, see answer_of
+    for ns in answer.name_servers.iter() {
+        let record = Record::new(RecordInner::NS { ns: ns.clone() }, 0);
+        msg.push(Section::Additional, &key.1, &record, class, &bounds)?;
+    }
+    Ok(msg.finish())
+}
+
+/// The key and answer of a message written by `encode`, for `view`
+fn decode(view: &str, msg: &[u8]) -> anyhow::Result<(Key, Answer)> {
+    let (_, resp) = crate::parser::parse_response(msg)
+        .map_err(|e| anyhow::anyhow!("malformed answer: {}", e))?;
+    let (name, ty, class) = resp
+        .questions
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("answer without question"))?;
+    let answer = crate::resolver::answer_of(msg)?;
+    let ty = match answer.rcode {
+        Rcode::Name if answer.answers.is_empty() => None,
+        _ => Some(Type::try_from(*ty).map_err(|_| anyhow::anyhow!("unknown type {}", ty))?),
+    };
+    let name = name.to_record_name();
+    Ok((key(view, name.as_ref(), ty, (*class).into()), answer))
+}
+
+/// How long `answer` may be cached, if at all
+fn lifetime(answer: &Answer) -> Option<u32> {
+    if answer.rcode != Rcode::OK && answer.rcode != Rcode::Name {
+        return None;
+    }
+    let answers = answer.answers.iter().map(|(_, r)| r.ttl);
+    let ttl = if answer.rcode == Rcode::Name || answer.answers.is_empty() {
+        let soa = answer
+            .authorities
+            .iter()
+            .find_map(|(_, r)| negative_ttl(r))?;
+        answers.chain([soa]).min()?
+    } else {
+        answers
+            .chain(answer.authorities.iter().map(|(_, r)| r.ttl))
+            .min()?
+    };
+    (ttl > 0).then_some(ttl)
+}
+
+impl Cache {
+    pub fn new(capacity: usize, stale: Duration) -> Self {
+        Self {
+            capacity,
+            stale,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// The answer cached for `name` in `view`, with the TTLs left. Records owned by the name take
+    /// its case.
+    pub fn get(&self, view: &str, name: &[String], ty: Type, class: u16) -> Option<Answer> {
+        self.lookup(view, name, ty, class, false)
+    }
+
+    /// The answer cached for `name` once expired, if for less than --serve-stale, with the TTL
+    /// of stale answers
+    pub fn get_stale(&self, view: &str, name: &[String], ty: Type, class: u16) -> Option<Answer> {
+        self.lookup(view, name, ty, class, true)
+    }
+
+    fn lookup(
+        &self,
+        view: &str,
+        name: &[String],
+        ty: Type,
+        class: u16,
+        stale: bool,
+    ) -> Option<Answer> {
+        let mut entries = self.entries.lock().unwrap();
+        let key = [
+            key(view, name, Some(ty), class),
+            key(view, name, None, class),
+        ]
+        .into_iter()
+        .find(|key| entries.by_key.contains_key(key))?;
+        let now = Instant::now();
+        let entry = &entries.by_key[&key];
+        if entry.expires + self.stale <= now {
+            entries.remove(&key);
+            return None;
+        }
+        if (entry.expires <= now) != stale {
+            return None;
+        }
+        let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+        let mut answer = entry.answer.clone();
+        entries.touch(&key);
+        if let Some(entry) = entries.by_key.get_mut(&key) {
+            entry.hits = entry.hits.saturating_add(1);
+        }
+
+        let records = answer
+            .answers
+            .iter_mut()
+            .chain(answer.authorities.iter_mut());
+        for (owner, record) in records {
+            record.ttl = match stale {
+                true => STALE_TTL,
+                false => record.ttl.saturating_sub(elapsed),
+            };
+            if crate::acl::names_eq(owner.as_ref(), name) {
+                *owner = Name::from(name.to_vec());
+            }
+        }
+        Some(answer)
+    }
+
+    /// Whether the answer cached for `name` is to be refreshed now, being popular and about to
+    /// expire. Only true once per answer cached.
+    pub fn prefetch(&self, view: &str, name: &[String], ty: Type, class: u16) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = match entries.by_key.get_mut(&key(view, name, Some(ty), class)) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let now = Instant::now();
+        let left = entry.expires.saturating_duration_since(now);
+        let due = left > Duration::ZERO && left * 10 < entry.expires - entry.stored;
+        if !due || entry.hits < PREFETCH_HITS || entry.prefetching {
+            return false;
+        }
+        entry.prefetching = true;
+        true
+    }
+
+    /// Caches `answer` for `name` in `view`, if it can be
+    pub fn insert(&self, view: &str, name: &[String], ty: Type, class: u16, answer: &Answer) {
+        let ttl = match lifetime(answer) {
+            Some(ttl) if self.capacity > 0 => ttl,
+            _ => return,
+        };
+        let mut answer = answer.clone();
+        if answer.answers.is_empty() || answer.rcode == Rcode::Name {
+            // The SOA record is served with the TTL of the negative answer
+            for (_, record) in answer.authorities.iter_mut() {
+                if let Some(negative) = negative_ttl(record) {
+                    record.ttl = negative;
+                }
+            }
+        }
+        let ty = match answer.rcode {
+            Rcode::Name if answer.answers.is_empty() => None,
+            _ => Some(ty),
+        };
+        let key = key(view, name, ty, class);
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(&key);
+        entries.make_room(self.capacity);
+
+        let now = Instant::now();
+        let entry = Entry {
+            answer,
+            stored: now,
+            expires: now + Duration::from_secs(ttl.into()),
+            hits: 0,
+            prefetching: false,
+            used: 0,
+        };
+        entries.by_key.insert(key.clone(), entry);
+        entries.touch(&key);
+    }
+
+    /// Writes the answers cached, and those kept stale, to `path`. Returns how many.
+    pub fn save(&self, path: &Path) -> anyhow::Result<usize> {
+        let mut out = FILE_MAGIC.to_vec();
+        let count = {
+            let entries = self.entries.lock().unwrap();
+            let (now, unix) = (Instant::now(), unix_now());
+            // From the least recently used, so that they are restored in the same order
+            for key in entries.used.values() {
+                let entry = &entries.by_key[key];
+                let msg = encode(key, &entry.answer)?;
+                let stored = unix - now.duration_since(entry.stored).as_secs();
+                let expires = match entry.expires.checked_duration_since(now) {
+                    Some(left) => unix + left.as_secs(),
+                    None => unix - now.duration_since(entry.expires).as_secs(),
+                };
+                out.write_all(&stored.to_be_bytes())?;
+                out.write_all(&expires.to_be_bytes())?;
+                out.write_all(&entry.hits.to_be_bytes())?;
+                for field in [key.0.as_bytes(), &msg] {
+                    out.write_all(&(field.len() as u32).to_be_bytes())?;
+                    out.write_all(field)?;
+                }
+            }
+            entries.by_key.len()
+        };
+        // Never leaves a partly written file behind
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, &out)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(count)
+    }
+
+    /// Restores the answers saved to `path` by `save`, as long as they are not older than
+    /// --serve-stale once expired. Returns how many.
+    pub fn load(&self, path: &Path) -> anyhow::Result<usize> {
+        let data = std::fs::read(path)?;
+        let mut rest = data
+            .strip_prefix(FILE_MAGIC)
+            .ok_or_else(|| anyhow::anyhow!("not a cache file"))?;
+        let (now, unix) = (Instant::now(), unix_now());
+        let mut entries = self.entries.lock().unwrap();
+        let mut count = 0;
+        while !rest.is_empty() {
+            let truncated = || anyhow::anyhow!("truncated cache file");
+            let header = rest.get(..20).ok_or_else(truncated)?;
+            let stored = u64::from_be_bytes(header[..8].try_into()?);
+            let expires = u64::from_be_bytes(header[8..16].try_into()?);
+            let hits = u32::from_be_bytes(header[16..20].try_into()?);
+            rest = &rest[20..];
+            let mut fields = [&[][..]; 2];
+            for field in fields.iter_mut() {
+                let len = rest.get(..4).ok_or_else(truncated)?;
+                let len = u32::from_be_bytes(len.try_into()?) as usize;
+                *field = rest.get(4..4 + len).ok_or_else(truncated)?;
+                rest = &rest[4 + len..];
+            }
+            let [view, msg] = fields;
+            let view = std::str::from_utf8(view)?;
+
+            let (key, mut answer) = decode(view, msg)?;
+            // Expired for longer than answers are kept, or too long ago to be told apart
+            let expires = match expires.checked_sub(unix) {
+                Some(left) => now + Duration::from_secs(left),
+                None => match now.checked_sub(Duration::from_secs(unix - expires)) {
+                    Some(expires) if expires + self.stale > now => expires,
+                    _ => continue,
+                },
+            };
+            // Their TTLs count down from now on
+            let elapsed = unix.saturating_sub(stored) as u32;
+            let records = answer
+                .answers
+                .iter_mut()
+                .chain(answer.authorities.iter_mut());
+            for (_, record) in records {
+                record.ttl = record.ttl.saturating_sub(elapsed);
+            }
+
+            if self.capacity == 0 {
+                break;
+            }
+            entries.remove(&key);
+            entries.make_room(self.capacity);
+            let entry = Entry {
+                answer,
+                stored: now,
+                expires,
+                hits,
+                prefetching: false,
+                used: 0,
+            };
+            entries.by_key.insert(key.clone(), entry);
+            entries.touch(&key);
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IN: u16 = 1;
+
+    fn labels(s: &str) -> Vec<String> {
+        crate::label::split_name(s)
+    }
+
+    fn a(ttl: u32) -> (Name, Record) {
+        let record = Record::new(
+            RecordInner::A {
+                addr: [192, 0, 2, 1],
+            },
+            ttl,
+        );
+        (Name::from(labels("www.example.com")), record)
+    }
+
+    fn soa(ttl: u32, minimum: u32) -> (Name, Record) {
+        let name = |s: &str| Name::from(labels(s));
+        let soa = RecordInner::SOA {
+            serial: 1,
+            mname: name("ns.example.com"),
+            rname: name("admin.example.com"),
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum,
+        };
+        (name("example.com"), Record::new(soa, ttl))
+    }
+
+    fn answer(
+        rcode: Rcode,
+        answers: Vec<(Name, Record)>,
+        authorities: Vec<(Name, Record)>,
+    ) -> Answer {
+        Answer {
+            rcode,
+            answers,
+            authorities,
+            name_servers: Vec::new(),
+            secure: false,
+        }
+    }
+
+    fn ttls(answer: &Answer) -> Vec<u32> {
+        let records = answer.answers.iter().chain(answer.authorities.iter());
+        records.map(|(_, record)| record.ttl).collect()
+    }
+
+    /// Makes the answers cached `secs` older
+    fn age(cache: &Cache, secs: u64) {
+        let mut entries = cache.entries.lock().unwrap();
+        for entry in entries.by_key.values_mut() {
+            entry.stored -= Duration::from_secs(secs);
+            entry.expires -= Duration::from_secs(secs);
+        }
+    }
+
+    #[test]
+    fn ttl() {
+        let cache = Cache::new(10, Duration::from_secs(60));
+        let www = labels("www.example.com");
+        let cached = answer(Rcode::OK, vec![a(300), a(60)], vec![]);
+        cache.insert("", &www, Type::A, IN, &cached);
+        assert_eq!(ttls(&cache.get("", &www, Type::A, IN).unwrap()), [300, 60]);
+        // Other views and types are cached apart, names whatever their case
+        assert!(cache.get("group", &www, Type::A, IN).is_none());
+        assert!(cache.get("", &www, Type::AAAA, IN).is_none());
+        let upper = labels("WWW.example.com");
+        let cached = cache.get("", &upper, Type::A, IN).unwrap();
+        assert_eq!(cached.answers[0].0, Name::from(upper));
+
+        age(&cache, 40);
+        assert_eq!(ttls(&cache.get("", &www, Type::A, IN).unwrap()), [260, 20]);
+        // Expired with the lowest TTL, and then only served stale for a while
+        age(&cache, 20);
+        assert!(cache.get("", &www, Type::A, IN).is_none());
+        let stale = cache.get_stale("", &www, Type::A, IN).unwrap();
+        assert_eq!(ttls(&stale), [STALE_TTL, STALE_TTL]);
+        age(&cache, 60);
+        assert!(cache.get_stale("", &www, Type::A, IN).is_none());
+
+        // Not at all with a TTL of 0
+        cache.insert(
+            "",
+            &www,
+            Type::A,
+            IN,
+            &answer(Rcode::OK, vec![a(0)], vec![]),
+        );
+        assert!(cache.get("", &www, Type::A, IN).is_none());
+    }
+
+    #[test]
+    fn negative() {
+        let cache = Cache::new(10, Duration::ZERO);
+        let (nx, www) = (labels("nx.example.com"), labels("www.example.com"));
+
+        // A name error holds for every type, for the SOA minimum when lower than its TTL
+        let name_error = answer(Rcode::Name, vec![], vec![soa(3600, 60)]);
+        cache.insert("", &nx, Type::A, IN, &name_error);
+        let cached = cache.get("", &nx, Type::AAAA, IN).unwrap();
+        assert_eq!(cached.rcode, Rcode::Name);
+        assert_eq!(ttls(&cached), [60]);
+        age(&cache, 60);
+        assert!(cache.get("", &nx, Type::A, IN).is_none());
+
+        // NODATA only for its type, for the SOA TTL when lower than its minimum
+        let no_data = answer(Rcode::OK, vec![], vec![soa(300, 3600)]);
+        cache.insert("", &www, Type::AAAA, IN, &no_data);
+        assert_eq!(ttls(&cache.get("", &www, Type::AAAA, IN).unwrap()), [300]);
+        assert!(cache.get("", &www, Type::A, IN).is_none());
+
+        // For 3 hours at most
+        let long = answer(Rcode::Name, vec![], vec![soa(86400, 86400)]);
+        cache.insert("", &nx, Type::A, IN, &long);
+        let cached = cache.get("", &nx, Type::A, IN).unwrap();
+        assert_eq!(ttls(&cached), [MAX_NEGATIVE_TTL]);
+
+        // Not without a SOA record, nor failures
+        let other = labels("other.example.com");
+        cache.insert(
+            "",
+            &other,
+            Type::A,
+            IN,
+            &answer(Rcode::Name, vec![], vec![]),
+        );
+        let failure = answer(Rcode::Internal, vec![], vec![soa(300, 300)]);
+        cache.insert("", &other, Type::AAAA, IN, &failure);
+        assert!(cache.get("", &other, Type::A, IN).is_none());
+        assert!(cache.get("", &other, Type::AAAA, IN).is_none());
+    }
+}
@@ -0,0 +1,139 @@
+//! Catalog zones (RFC 9432): zones listing other zones, so that secondaries learn which zones to
+//! serve from the catalog alone. Each member zone is a PTR record at `<id>.zones.<catalog>`, and
+//! the catalog holds `version TXT "2"`. Member properties (`group`, `coo`) are not supported and
+//! ignored when consuming a catalog.
+//!
+//! A consumed catalog is transferred like any secondary zone, its members then being provisioned
+//! as secondaries of the same primaries, see the secondary module. A produced catalog lists every
+//! other zone we serve, and is generated anew whenever the served data changes.
+
+use sha2::{Digest, Sha256};
+
+use crate::acl::names_eq;
+use crate::parser::Type;
+use crate::record::{Name, Record, RecordInner};
+use crate::BaseStorage;
+
+const VERSION: &str = "2";
+
+fn is_below(name: &[String], apex: &[String]) -> bool {
+    name.len() >= apex.len() && names_eq(&name[name.len() - apex.len()..], apex)
+}
+
+fn child(parent: &[String], labels: &[&str]) -> Name {
+    let mut name: Vec<String> = labels.iter().map(|l| l.to_string()).collect();
+    name.extend(parent.iter().cloned());
+    Name::from(name)
+}
+
+/// Member zones listed by the catalog `catalog` of `base`
+pub fn members(base: &BaseStorage, catalog: &[String]) -> anyhow::Result<Vec<Name>> {
+    let version = base
+        .get(child(catalog, &["version"]).as_ref())
+        .into_iter()
+        .flatten()
+        .find_map(|r| match &r.inner {
+            RecordInner::TXT { content } => Some(content.as_str()),
+            _ => None,
+        });
+    if version != Some(VERSION) {
+        return Err(anyhow::anyhow!(
+            "unsupported catalog zone version {:?}, expected {}",
+            version,
+            VERSION
+        ));
+    }
+
+    let zones = child(catalog, &["zones"]);
+    let mut members: Vec<Name> = Vec::new();
+    for (name, records) in base.iter() {
+        if name.as_ref().len() != zones.as_ref().len() + 1
+            || !is_below(name.as_ref(), zones.as_ref())
+        {
+            continue;
+        }
+        let ptrs: Vec<&Name> = records
+            .iter()
+            .filter_map(|r| match &r.inner {
+                RecordInner::PTR { ptr } => Some(ptr),
+                _ => None,
+            })
+            .collect();
+        // RFC 9432 Section 4.1: exactly one PTR per member
+        match ptrs.as_slice() {
+            [member]
+                if !members
+                    .iter()
+                    .any(|m| names_eq(m.as_ref(), member.as_ref())) =>
+            {
+                members.push((*member).clone())
+            }
+            [member] => log::warn!("{}: {} listed more than once, ignored", name, member),
+            _ => log::warn!("{}: expected a single PTR record, ignored", name),
+        }
+    }
+    Ok(members)
+}
+
+/// Label of `member` under `zones`, stable as long as it is listed
+fn member_id(member: &Name) -> String {
+    let hash = Sha256::digest(member.to_absolute().to_ascii_lowercase().as_bytes());
+    hash[..8].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Replaces each of `catalogs` in `base` with a catalog listing every other zone of `base`.
+/// `previous` is the data being replaced: a catalog keeps its serial unless its members changed.
+pub fn produce(base: &mut BaseStorage, catalogs: &[Name], previous: &BaseStorage) {
+    if catalogs.is_empty() {
+        return;
+    }
+    base.retain(|name, _| !catalogs.iter().any(|c| is_below(name.as_ref(), c.as_ref())));
+    let mut apexes: Vec<Name> = base
+        .iter()
+        .filter(|(_, records)| records.iter().any(|r| r.inner.ty() == Type::SOA))
+        .map(|(name, _)| name.clone())
+        .collect();
+    apexes.sort_by_key(|name| name.to_absolute().to_ascii_lowercase());
+
+    for catalog in catalogs {
+        let record = |inner| Record::new(inner, 0);
+        let invalid = Name::from(vec!["invalid".to_owned()]);
+        let mut zone: BaseStorage = BaseStorage::new();
+        zone.insert(
+            catalog.clone(),
+            vec![record(RecordInner::NS {
+                ns: invalid.clone(),
+            })],
+        );
+        zone.insert(
+            child(catalog.as_ref(), &["version"]),
+            vec![record(RecordInner::TXT {
+                content: VERSION.to_owned(),
+            })],
+        );
+        for member in apexes.iter() {
+            zone.insert(
+                child(catalog.as_ref(), &[&member_id(member), "zones"]),
+                vec![record(RecordInner::PTR {
+                    ptr: member.clone(),
+                })],
+            );
+        }
+
+        let serial = crate::serial::generated_serial(0);
+        zone.get_mut(catalog.as_ref()).unwrap().insert(
+            0,
+            record(RecordInner::SOA {
+                serial,
+                mname: invalid.clone(),
+                rname: invalid,
+                refresh: 300,
+                retry: 60,
+                expire: 2419200,
+                minimum: 0,
+            }),
+        );
+        base.extend(zone);
+        crate::serial::follow(base, catalog.as_ref(), previous);
+    }
+}
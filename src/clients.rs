@@ -0,0 +1,125 @@
+//! Client groups, read from the YAML file given with --client-groups and keyed by group name, so
+//! that the names outside of the zones served are resolved differently for some clients:
+//!
+//! ```yaml
+//! kids:
+//!   clients: [192.168.1.64/26, "2001:db8:1::/48"]
+//!   forward: [1.1.1.3, 1.0.0.3]
+//!   blocklists: [/etc/dns/kids.txt]
+//! servers:
+//!   clients: [10.0.0.0/24]
+//!   blocklists: []
+//! ```
+//!
+//! A client belongs to the group with the longest prefix matching its address, if any. Groups
+//! with `forward` have their queries forwarded there instead of the --forward resolvers, or of
+//! --recursive, and answers cached apart from those of other clients. Groups with `blocklists`
+//! block the names of those lists instead of those of --blocklist, none if empty. Zones of type
+//! forward apply to every client. Groups with `forward` may ask them as their `policy` tells,
+//! see the forward module.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::acl::Cidr;
+use crate::blocklist::Blocklist;
+use crate::forward::{parse_upstream, Policy, PolicyConfig, Strategy, Upstreams};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GroupConfig {
+    clients: Vec<String>,
+    forward: Option<Vec<String>>,
+    blocklists: Option<Vec<String>>,
+    policy: Option<PolicyConfig>,
+}
+
+pub struct Group {
+    pub name: String,
+    clients: Vec<Cidr>,
+    pub forward: Option<Arc<Upstreams>>,
+    pub blocklist: Option<Arc<Blocklist>>,
+}
+
+impl Group {
+    /// Partition of the cache holding the answers for the group, see the cache module
+    pub fn view(&self) -> &str {
+        match self.forward {
+            Some(_) => &self.name,
+            None => "",
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Groups(Vec<Group>);
+
+impl Groups {
+    pub fn read(path: &Path, strategy: Strategy, policy: Policy) -> anyhow::Result<Self> {
+        let err = |e: &dyn std::fmt::Display| anyhow::anyhow!("{}: {}", path.display(), e);
+        let content = std::fs::read_to_string(path).map_err(|e| err(&e))?;
+        let config: HashMap<String, GroupConfig> =
+            serde_yaml::from_str(&content).map_err(|e| err(&e))?;
+        let mut groups = Vec::new();
+        for (name, config) in config {
+            let err = |e: &dyn std::fmt::Display| err(&format!("group {}: {}", name, e));
+            let clients = config
+                .clients
+                .iter()
+                .map(|prefix| prefix.parse::<Cidr>())
+                .collect::<anyhow::Result<Vec<_>>>()
+                .map_err(|e| err(&e))?;
+            let forward = match config.forward {
+                Some(addrs) => {
+                    let servers = addrs
+                        .iter()
+                        .map(|addr| parse_upstream(addr).map_err(|e| err(&e)))
+                        .collect::<anyhow::Result<Vec<_>>>()?;
+                    let policy = policy.with(config.policy.as_ref());
+                    Some(Arc::new(Upstreams::new(servers, strategy, policy)))
+                }
+                None => None,
+            };
+            let blocklist = config
+                .blocklists
+                .map(|lists| Arc::new(Blocklist::new(lists)));
+            groups.push(Group {
+                name,
+                clients,
+                forward,
+                blocklist,
+            });
+        }
+        Ok(Self(groups))
+    }
+
+    /// The group of the client at `ip`, if any
+    pub fn find(&self, ip: &IpAddr) -> Option<&Group> {
+        self.0
+            .iter()
+            .flat_map(|group| group.clients.iter().map(move |prefix| (group, prefix)))
+            .filter(|(_, prefix)| prefix.contains(ip))
+            .max_by_key(|(_, prefix)| prefix.prefix_len())
+            .map(|(group, _)| group)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Group> {
+        self.0.iter()
+    }
+}
+
+/// The blocklists of the groups of the file at `path`
+pub fn blocklists(path: &Path) -> anyhow::Result<Vec<String>> {
+    let err = |e: &dyn std::fmt::Display| anyhow::anyhow!("{}: {}", path.display(), e);
+    let content = std::fs::read_to_string(path).map_err(|e| err(&e))?;
+    let config: HashMap<String, GroupConfig> =
+        serde_yaml::from_str(&content).map_err(|e| err(&e))?;
+    Ok(config
+        .into_values()
+        .flat_map(|config| config.blocklists.unwrap_or_default())
+        .collect())
+}
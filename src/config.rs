@@ -0,0 +1,192 @@
+//! Per-zone configuration, read from the YAML file given with --zone-config and keyed by zone
+//! name:
+//!
+//! ```yaml
+//! example.com:
+//!   type: secondary
+//!   primaries: [192.0.2.1, "[2001:db8::1]:5353"]
+//!   key: xfr-key
+//! ```
+//!
+//! Zones are of type `primary` unless configured otherwise, served from the zone data. With
+//! `reverse: true`, PTR records are generated from their addresses in the matching reverse zones
+//! we serve. With `keys`, files of Ed25519 keys, or `key-store`, a directory of keys scheduled to
+//! roll over (see the keystore module), their answers are signed, see the sign module, and with
+//! `nsec3`, their nonexistence proven by NSEC3 records, given a hexadecimal `salt`, `iterations`
+//! and `opt-out`, none of them by default, or with `compact-denial: true`, by NSEC records made
+//! for each negative answer. With `imported-keys`, the zone is signed by other providers too, see
+//! the multi_signer module. Secondary zones are transferred from their primaries instead, see the
+//! secondary module. Their transfers are signed with `key`, one of the --tsig-key keys, if given.
+//!
+//! Zones of type `catalog` are secondary zones as well, catalogs of other zones (RFC 9432) which
+//! are then transferred from the same primaries. A zone of type `catalog-producer` is generated
+//! instead, as the catalog of every other zone served. See the catalog module.
+//!
+//! Zones of type `forward` are not served: queries for names within them are forwarded to their
+//! `forwarders` instead of the --forward resolvers, or resolved with --recursive, asked as their
+//! `policy` tells if given, see the forward module.
+//!
+//! Zones of any type but `catalog-producer` may be queried only by the clients of the prefixes
+//! given by `allow-query`, overriding --allow-query for the names within them, and everyone
+//! else is answered REFUSED:
+//!
+//! ```yaml
+//! corp.example.com:
+//!   type: primary
+//!   allow-query: [10.0.0.0/8, "2001:db8::/32"]
+//! ```
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::acl::Cidr;
+use crate::forward::{parse_upstream, PolicyConfig, Upstream};
+use crate::record::Name;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ZoneConfig {
+    Primary(PrimaryConfig),
+    Secondary(SecondaryConfig),
+    /// A catalog zone transferred as a secondary, its members too
+    Catalog(SecondaryConfig),
+    /// A catalog zone listing every other zone
+    #[serde(rename = "catalog-producer")]
+    CatalogProducer,
+    Forward(ForwardConfig),
+}
+
+impl ZoneConfig {
+    /// The prefixes of the clients allowed to query the names of the zone, if restricted
+    pub fn allow_query(&self) -> Option<&[Cidr]> {
+        match self {
+            Self::Primary(config) => config.allow_query.as_deref(),
+            Self::Secondary(config) | Self::Catalog(config) => config.allow_query.as_deref(),
+            Self::Forward(config) => config.allow_query.as_deref(),
+            Self::CatalogProducer => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct PrimaryConfig {
+    /// Generate PTR records from the A and AAAA records of the zone, see the reverse module
+    #[serde(default)]
+    pub reverse: bool,
+    /// Sign the zone with these keys, the key signing key first, see the sign module
+    #[serde(default)]
+    pub keys: Vec<PathBuf>,
+    /// Sign the zone with the keys of this store instead, see the keystore module
+    #[serde(rename = "key-store")]
+    pub key_store: Option<PathBuf>,
+    /// Deny existence with NSEC3 records of these parameters rather than NSEC ones
+    pub nsec3: Option<Nsec3Config>,
+    /// Deny existence with compact answers rather than a chain, see the sign module
+    #[serde(default, rename = "compact-denial")]
+    pub compact_denial: bool,
+    /// Publish the keys of the other signers of the zone of this file, see the multi_signer
+    /// module
+    #[serde(rename = "imported-keys")]
+    pub imported_keys: Option<PathBuf>,
+    /// Only answer the clients of these prefixes for the names of the zone
+    #[serde(rename = "allow-query")]
+    pub allow_query: Option<Vec<Cidr>>,
+}
+
+/// Parameters of NSEC3 records, RFC 5155 Section 3.1, by default those of RFC 9276: no salt nor
+/// additional iterations
+#[derive(Deserialize, Clone, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Nsec3Config {
+    /// In hexadecimal
+    #[serde(default, deserialize_with = "crate::record::de_salt")]
+    pub salt: Vec<u8>,
+    #[serde(default)]
+    pub iterations: u16,
+    /// Leave the delegations without DS records out of the chain, RFC 5155 Section 6
+    #[serde(default)]
+    pub opt_out: bool,
+}
+
+impl Nsec3Config {
+    /// Beyond which some validators treat answers as insecure, RFC 9276 Section 3.2
+    const MAX_ITERATIONS: u16 = 100;
+
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.iterations > Self::MAX_ITERATIONS {
+            return Err(anyhow::anyhow!(
+                "{} NSEC3 iterations, above {} validators may not accept",
+                self.iterations,
+                Self::MAX_ITERATIONS
+            ));
+        }
+        if self.iterations > 0 || !self.salt.is_empty() {
+            log::warn!("NSEC3 salts and iterations only add cost, RFC 9276 recommends none");
+        }
+        Ok(())
+    }
+
+    /// The hash of `name`, owner of its NSEC3 record
+    pub fn hash(&self, name: &[String]) -> Vec<u8> {
+        crate::dnssec::nsec3_hash(name, &self.salt, self.iterations)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SecondaryConfig {
+    #[serde(deserialize_with = "de_addrs")]
+    pub primaries: Vec<SocketAddr>,
+    pub key: Option<Name>,
+    #[serde(rename = "allow-query")]
+    pub allow_query: Option<Vec<Cidr>>,
+}
+
+#[derive(Deserialize)]
+pub struct ForwardConfig {
+    #[serde(deserialize_with = "de_upstreams")]
+    pub forwarders: Vec<Upstream>,
+    pub policy: Option<PolicyConfig>,
+    #[serde(rename = "allow-query")]
+    pub allow_query: Option<Vec<Cidr>>,
+}
+
+/// Addresses, with port 53 unless given
+fn de_addrs<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<SocketAddr>, D::Error> {
+    let addrs = Vec::<String>::deserialize(deserializer)?;
+    if addrs.is_empty() {
+        return Err(serde::de::Error::custom("no addresses given"));
+    }
+    addrs
+        .iter()
+        .map(|addr| {
+            addr.parse::<SocketAddr>()
+                .or_else(|_| addr.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+                .map_err(|_| serde::de::Error::custom(format!("invalid address {}", addr)))
+        })
+        .collect()
+}
+
+/// Upstream resolvers, as forward::parse_upstream parses them
+fn de_upstreams<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Upstream>, D::Error> {
+    let upstreams = Vec::<String>::deserialize(deserializer)?;
+    if upstreams.is_empty() {
+        return Err(serde::de::Error::custom("no addresses given"));
+    }
+    upstreams
+        .iter()
+        .map(|upstream| parse_upstream(upstream).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+pub fn read(path: &Path) -> anyhow::Result<HashMap<Name, ZoneConfig>> {
+    let content =
+        std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+    serde_yaml::from_str(&content).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))
+}
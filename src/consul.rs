@@ -0,0 +1,134 @@
+//! Records stored in the Consul KV store, one key per RRset under a common prefix:
+//! `<prefix><owner name>/<type>`, holding one record per line as "TTL RDATA", RDATA being in
+//! master file syntax:
+//!
+//! ```text
+//! consul kv put dns/www.example.com/A "60 10.0.0.1"
+//! ```
+//!
+//! Changes are followed with blocking queries, so they are served as soon as Consul commits
+//! them. Only plain HTTP is supported.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64ct::{Base64, Encoding};
+use serde::Deserialize;
+
+use crate::store::Store;
+use crate::BaseStorage;
+
+/// How long Consul may hold a blocking query before answering with unchanged data
+const BLOCKING_WAIT: &str = "5m";
+
+/// Between two reads when Consul gives no index to block on
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize)]
+struct Entry {
+    #[serde(rename = "Key")]
+    key: String,
+    /// Base64, null for keys without a value
+    #[serde(rename = "Value")]
+    value: Option<String>,
+}
+
+/// The records of KV store `entries`, keys and values, laid out as in Consul under `prefix`
+pub fn records(
+    prefix: &str,
+    entries: &[(String, Vec<u8>)],
+    source: &str,
+) -> anyhow::Result<BaseStorage> {
+    let mut rows = Vec::new();
+    for (key, value) in entries {
+        let rest = key.strip_prefix(prefix).unwrap_or(key);
+        let (name, ty) = match rest.rsplit_once('/') {
+            Some((name, ty)) if !ty.is_empty() => (name, ty),
+            // Keys which are not RRsets
+            _ => continue,
+        };
+        let value = std::str::from_utf8(value)
+            .map_err(|_| anyhow::anyhow!("{}: value is not UTF-8", key))?;
+        let records =
+            crate::zonefile::split_rrset(value).map_err(|e| anyhow::anyhow!("{}: {}", key, e))?;
+        for (ttl, content) in records {
+            rows.push((name, ty, ttl, content));
+        }
+    }
+    crate::zonefile::parse_rows(rows, source)
+}
+
+pub struct ConsulSource {
+    /// host:port of the HTTP API
+    pub addr: String,
+    pub prefix: String,
+    /// ACL token, sent as X-Consul-Token
+    pub token: Option<String>,
+}
+
+impl ConsulSource {
+    fn name(&self) -> String {
+        format!("consul://{}/{}", self.addr, self.prefix)
+    }
+
+    /// Reads every record, blocking until the data changes past `index` when it is not 0.
+    /// Returns the records along with the index to wait on next.
+    async fn fetch(&self, index: u64) -> anyhow::Result<(BaseStorage, u64)> {
+        let mut path = format!("/v1/kv/{}?recurse=true", self.prefix);
+        if index > 0 {
+            path.push_str(&format!("&index={}&wait={}", index, BLOCKING_WAIT));
+        }
+        let headers: Vec<(&str, &str)> = self
+            .token
+            .iter()
+            .map(|token| ("X-Consul-Token", token.as_str()))
+            .collect();
+        let response = crate::http::get(&self.addr, &path, &headers).await?;
+        let entries: Vec<Entry> = match response.status {
+            200 => serde_json::from_slice(&response.body)?,
+            // Nothing under the prefix yet
+            404 => Vec::new(),
+            _ => return Err(response.error()),
+        };
+
+        let mut values = Vec::new();
+        for entry in entries {
+            // Folders have no value
+            if let Some(value) = &entry.value {
+                let value = Base64::decode_vec(value)
+                    .map_err(|_| anyhow::anyhow!("{}: invalid base64 value", entry.key))?;
+                values.push((entry.key, value));
+            }
+        }
+        let base = records(&self.prefix, &values, &self.name())?;
+        let index = response
+            .header("x-consul-index")
+            .and_then(|index| index.parse().ok());
+        Ok((base, index.unwrap_or(0)))
+    }
+
+    pub async fn read(&self) -> anyhow::Result<BaseStorage> {
+        Ok(self.fetch(0).await?.0)
+    }
+
+    /// Follows changes with blocking queries. The last records read are served while Consul is
+    /// unreachable, or while the data is invalid.
+    pub async fn watch(self, store: Arc<Store>) {
+        let (source, store, index) = (&self, &store, &AtomicU64::new(0));
+        crate::retry::forever(&self.name(), || async move {
+            let (base, next) = source.fetch(index.load(Ordering::Relaxed)).await?;
+            store.set_source("consul", base);
+            // Consul documents resetting the index whenever it goes backwards
+            let previous = index.load(Ordering::Relaxed);
+            let next = if next < previous { 0 } else { next };
+            index.store(next, Ordering::Relaxed);
+            if next == 0 {
+                // Nothing to block on, fall back to polling
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+            Ok(())
+        })
+        .await
+    }
+}
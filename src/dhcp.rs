@@ -0,0 +1,176 @@
+//! Records for the hosts leased an address by a DHCP server, read from its lease files: an A or
+//! AAAA record at `<hostname>.<domain>`, --dhcp-domain, per active lease carrying a hostname.
+//! The domain should be within one of the zones served, or its names are local overrides, see
+//! the zone module.
+//! PTR records are generated for them in the reverse zones served, as for zones configured with
+//! `reverse: true`, see the reverse module.
+//!
+//! Two formats are read, told apart by their first line:
+//!
+//! - dnsmasq's `dhcp-leasefile`: `<expiry> <MAC or IAID> <address> <hostname> <client id>` per
+//!   lease, `*` standing for no hostname
+//! - Kea's memfile CSV (`kea-leases4.csv`, `kea-leases6.csv`), with its header line. Rows are
+//!   appended as leases change, the last row of an address being its current lease.
+//!
+//! Hostnames are reduced to their first label, lowercased, and leases whose hostname is not a
+//! valid label are skipped. The files are read again every --watch-interval, so that expired
+//! leases are removed.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::record::{Name, Record, RecordInner};
+use crate::store::Store;
+use crate::BaseStorage;
+
+const TTL: u32 = 60;
+
+struct Lease {
+    address: IpAddr,
+    hostname: String,
+    /// In seconds since epoch, 0 for never
+    expiry: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn parse_dnsmasq(content: &str) -> Vec<Lease> {
+    let mut leases = Vec::new();
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // The DUID of the server comes before the DHCPv6 leases
+        if fields.len() < 4 || fields[0] == "duid" {
+            continue;
+        }
+        if let (Ok(expiry), Ok(address)) = (fields[0].parse(), fields[2].parse()) {
+            leases.push(Lease {
+                address,
+                hostname: fields[3].to_owned(),
+                expiry,
+            });
+        }
+    }
+    leases
+}
+
+fn parse_kea(content: &str) -> anyhow::Result<Vec<Lease>> {
+    let mut lines = content.lines();
+    let header: Vec<&str> = lines.next().unwrap_or_default().split(',').collect();
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|c| *c == name)
+            .ok_or_else(|| anyhow::anyhow!("no {} column", name))
+    };
+    let (address, hostname, expire, lifetime, state) = (
+        column("address")?,
+        column("hostname")?,
+        column("expire")?,
+        column("valid_lifetime")?,
+        column("state")?,
+    );
+
+    let mut leases: HashMap<IpAddr, Lease> = HashMap::new();
+    for line in lines {
+        let fields: Vec<&str> = line.split(',').collect();
+        let field = |i: usize| fields.get(i).copied().unwrap_or_default();
+        let address = match field(address).parse() {
+            Ok(address) => address,
+            Err(_) => continue,
+        };
+        // Released leases are written with a lifetime of 0, and only state 0 is in use
+        if field(lifetime) == "0" || field(state) != "0" {
+            leases.remove(&address);
+            continue;
+        }
+        leases.insert(
+            address,
+            Lease {
+                address,
+                hostname: field(hostname).to_owned(),
+                expiry: field(expire).parse().unwrap_or(1),
+            },
+        );
+    }
+    Ok(leases.into_values().collect())
+}
+
+/// First label of `hostname`, if a valid one
+fn host_label(hostname: &str) -> Option<String> {
+    let label = hostname.split('.').next()?.to_ascii_lowercase();
+    let valid = !label.is_empty()
+        && label.len() <= 63
+        && label
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !label.starts_with('-')
+        && !label.ends_with('-');
+    valid.then_some(label)
+}
+
+pub struct DhcpSource {
+    pub paths: Vec<PathBuf>,
+    pub domain: Name,
+}
+
+impl DhcpSource {
+    pub async fn read(&self) -> anyhow::Result<BaseStorage> {
+        let now = now();
+        let mut base = BaseStorage::new();
+        for path in self.paths.iter() {
+            let content = tokio::fs::read_to_string(path)
+                .await
+                .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+            let leases = if content.starts_with("address,") {
+                parse_kea(&content).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?
+            } else {
+                parse_dnsmasq(&content)
+            };
+
+            for lease in leases {
+                if lease.expiry != 0 && lease.expiry < now {
+                    continue;
+                }
+                let label = match host_label(&lease.hostname) {
+                    Some(label) => label,
+                    None => continue,
+                };
+                let inner = match lease.address {
+                    IpAddr::V4(addr) => RecordInner::A {
+                        addr: addr.octets(),
+                    },
+                    IpAddr::V6(addr) => RecordInner::AAAA {
+                        addr: addr.octets(),
+                    },
+                };
+                let mut name = vec![label];
+                name.extend(self.domain.as_ref().iter().cloned());
+                let record = Record::new(inner, TTL);
+                let records = base.entry(Name::from(name)).or_default();
+                if !records.contains(&record) {
+                    records.push(record);
+                }
+            }
+        }
+        Ok(base)
+    }
+
+    /// Re-reads the lease files every `interval`, picking up new leases as well as expired ones
+    pub async fn watch(self, store: Arc<Store>, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            match self.read().await {
+                Ok(base) => store.set_source("dhcp", base),
+                Err(e) => log::error!("Failed to reload DHCP leases: {}", e),
+            }
+        }
+    }
+}
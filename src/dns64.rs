@@ -0,0 +1,120 @@
+//! DNS64 (RFC 6147), with --dns64-prefix: AAAA queries for names resolved upstream which have no
+//! AAAA record are answered with AAAA records synthesized from their A records, so that clients of
+//! IPv6-only networks reach IPv4-only hosts through a NAT64 gateway. The IPv4 address is embedded
+//! in the NAT64 prefix as RFC 6052 Section 2.2 lays out, e.g. 192.0.2.1 as 64:ff9b::c000:201.
+//!
+//! CNAME records of the A answer are kept. Synthesized records take the lower of the TTL of the
+//! A record and that of the negative AAAA answer, RFC 6147 Section 5.1.7. Names of the zones
+//! served are not synthesized for, and names without A records are answered as they are.
+
+use std::net::Ipv6Addr;
+
+use crate::message::Rcode;
+use crate::parser::Type;
+use crate::record::{Record, RecordInner};
+use crate::resolver::Answer;
+
+/// NAT64 prefix, of one of the lengths of RFC 6052 Section 2.2
+#[derive(Debug, Clone, Copy)]
+pub struct Prefix {
+    addr: [u8; 16],
+    len: usize,
+}
+
+impl std::str::FromStr for Prefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Expected an IPv6 prefix like 64:ff9b::/96, got {}", s);
+        let (addr, len) = s.split_once('/').ok_or_else(invalid)?;
+        let addr: Ipv6Addr = addr.parse().map_err(|_| invalid())?;
+        let len: usize = len.parse().map_err(|_| invalid())?;
+        if ![32, 40, 48, 56, 64, 96].contains(&len) {
+            return Err(format!(
+                "NAT64 prefixes are 32, 40, 48, 56, 64 or 96 bits long, got {}",
+                len
+            ));
+        }
+        let mut prefix = Self { addr: [0; 16], len };
+        prefix.addr[..len / 8].copy_from_slice(&addr.octets()[..len / 8]);
+        Ok(prefix)
+    }
+}
+
+impl Prefix {
+    /// `v4` embedded in the prefix, skipping bits 64 to 71 which must be zero
+    pub fn embed(&self, v4: [u8; 4]) -> [u8; 16] {
+        let mut addr = self.addr;
+        let mut i = self.len / 8;
+        for byte in v4 {
+            if i == 8 {
+                i += 1;
+            }
+            addr[i] = byte;
+            i += 1;
+        }
+        addr
+    }
+}
+
+/// Whether `answer`, to a AAAA query, is one to synthesize AAAA records for: without error and
+/// without AAAA record
+pub fn applies(answer: &Answer) -> bool {
+    answer.rcode == Rcode::OK
+        && !answer
+            .answers
+            .iter()
+            .any(|(_, r)| r.inner.ty() == Type::AAAA)
+}
+
+/// The answer to a AAAA query from `aaaa`, its negative answer, and `a`, the answer to the A
+/// query for the same name, if it holds A records
+pub fn synthesize(prefix: &Prefix, aaaa: Answer, a: Answer) -> Option<Answer> {
+    if a.rcode != Rcode::OK || !a.answers.iter().any(|(_, r)| r.inner.ty() == Type::A) {
+        return None;
+    }
+    let negative_ttl = aaaa.authorities.iter().map(|(_, r)| r.ttl).min();
+    let answers = a
+        .answers
+        .into_iter()
+        .map(|(owner, record)| match record.inner {
+            RecordInner::A { addr } => {
+                let inner = RecordInner::AAAA {
+                    addr: prefix.embed(addr),
+                };
+                let ttl = negative_ttl.map_or(record.ttl, |ttl| ttl.min(record.ttl));
+                (owner, Record::new(inner, ttl))
+            }
+            _ => (owner, record),
+        })
+        .collect();
+    Some(Answer {
+        rcode: Rcode::OK,
+        answers,
+        authorities: Vec::new(),
+        name_servers: a.name_servers,
+        // Synthesized, RFC 6147 Section 5.5
+        secure: false,
+    })
+}
+
+/// `query` asking for `ty` instead, if its question can be found
+pub fn with_type(query: &[u8], ty: Type) -> Option<Vec<u8>> {
+    // Labels of the question, uncompressed
+    let mut i = 12;
+    loop {
+        let len = *query.get(i)? as usize;
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        i += 1 + len;
+        if len == 0 {
+            break;
+        }
+    }
+    let mut query = query.to_vec();
+    query
+        .get_mut(i..i + 2)?
+        .copy_from_slice(&(ty as u16).to_be_bytes());
+    Some(query)
+}
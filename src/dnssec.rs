@@ -0,0 +1,1902 @@
+//! DNSSEC validation of the answers of upstream resolvers (RFC 4035 Section 5), with
+//! --trust-anchors: a file of DS or DNSKEY records in master file syntax, one per line, for the
+//! zones whose names, and those below them, are to be validated:
+//!
+//! ```text
+//! example.com. 3600 IN DS 60485 15 2 D4B7D520E7BB5F0F67674A0CCEB1E3E0614B93C4F9E99B8383F6A1E4469DA50A
+//! ```
+//!
+//! With --root-trust-anchor, or instead, the DS records of the root key signing keys published by
+//! IANA are trusted too, so that every name is validated. The keys of trust anchors may be
+//! tracked through their rollovers, see the managed_keys module.
+//!
+//! Queries forwarded upstream then carry DO and CD, and the keys of each zone are chased from the
+//! trust anchor down, asking the same upstream resolvers for the DS and DNSKEY RRsets. Answers
+//! whose RRsets are all validated, and the nonexistence of the name or type proven by NSEC or
+//! NSEC3 records for negative ones, are authentic, and carry AD for the clients setting DO or AD.
+//! Those failing validation are answered SERVFAIL with an Extended DNS Error (RFC 8914), unless
+//! the client set CD, which gets them as they are. So are names outside of the trust anchors, or
+//! below an insecure delegation, without AD.
+//!
+//! Only RSA/SHA-256 (algorithm 8, RFC 5702), ECDSA P-256 (algorithm 13, RFC 6605) and Ed25519
+//! (algorithm 15, RFC 8080) signatures are verified, and DS digests of SHA-256 and SHA-384: zones
+//! signed with other algorithms only are insecure, RFC 4035 Section 5.2. So are
+//! names denied with NSEC3 records of more than 150 iterations, RFC 9276 Section 3.2.
+//!
+//! DNSSEC records are left out of the responses to clients not setting DO, and of cached answers.
+//! Names resolved with --recursive are not validated.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use base64ct::{Base64, Encoding};
+use sha2::{Digest, Sha256, Sha384};
+
+use crate::forward::Upstreams;
+use crate::label::{escape, split_name, unescape};
+use crate::parser::{find_opt, parse_name, parse_raw_response, RawRR, Type};
+
+const DNAME: u16 = 39;
+const DS: u16 = 43;
+const RRSIG: u16 = 46;
+const NSEC: u16 = 47;
+const DNSKEY: u16 = 48;
+const NSEC3: u16 = 50;
+
+/// RSA/SHA-256, RFC 5702
+const RSASHA256: u8 = 8;
+/// ECDSA P-256 with SHA-256, RFC 6605
+const ECDSAP256SHA256: u8 = 13;
+/// Ed25519, RFC 8080
+const ED25519: u8 = 15;
+/// Digest types of DS records, RFC 4509 and RFC 6605
+const DIGEST_SHA256: u8 = 2;
+const DIGEST_SHA384: u8 = 4;
+/// Hash algorithm of NSEC3 records, RFC 5155 Section 11
+const NSEC3_SHA1: u8 = 1;
+
+/// Flags of DNSKEY records, RFC 4034 Section 2.1.1 and RFC 5011 Section 7
+const ZONE_KEY: u16 = 0x0100;
+pub const REVOKED: u16 = 0x0080;
+pub const SEP: u16 = 0x0001;
+
+/// The root key signing keys, KSK-2017 and KSK-2024, as published by IANA in root-anchors.xml
+const ROOT_ANCHORS: &str = "\
+. IN DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D
+. IN DS 38696 8 2 683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16
+";
+
+/// NSEC3 iterations past which names are deemed insecure, RFC 9276 Section 3.2
+const MAX_ITERATIONS: u16 = 150;
+/// CNAME records followed from the name queried
+const MAX_CNAMES: usize = 8;
+/// How long the keys of a zone are kept at most, in seconds
+const MAX_KEYS_TTL: u32 = 3600;
+/// How long a zone is deemed insecure, or its keys bogus, before they are chased again
+const INSECURE_TTL: Duration = Duration::from_secs(300);
+const BOGUS_TTL: Duration = Duration::from_secs(60);
+
+/// UDP payload size advertised in the queries for keys
+const PAYLOAD_SIZE: u16 = 1232;
+
+/// Outcome of the validation of an answer
+#[derive(Debug, Clone)]
+pub enum Security {
+    Secure,
+    Insecure,
+    /// Why it failed validation
+    Bogus(String),
+}
+
+/// A RR, in canonical form: owner and names of the RDATA lowercased and uncompressed
+struct Rr {
+    owner: Vec<String>,
+    ty: u16,
+    class: u16,
+    ttl: u32,
+    rdata: Vec<u8>,
+}
+
+/// The fields of RDATA, for the types whose names may be compressed and are lowercased in their
+/// canonical form, RFC 4034 Section 6.2
+enum Field {
+    Name,
+    Fixed(usize),
+}
+
+fn layout(ty: u16) -> &'static [Field] {
+    use Field::*;
+    match ty {
+        // NS, MD, MF, CNAME, MB, MG, MR, PTR and DNAME
+        2..=5 | 7..=9 | 12 | DNAME => &[Name],
+        6 => &[Name, Name],
+        // MINFO and RP
+        14 | 17 => &[Name, Name],
+        // MX, AFSDB, RT and KX
+        15 | 18 | 21 | 36 => &[Fixed(2), Name],
+        // PX
+        26 => &[Fixed(2), Name, Name],
+        33 => &[Fixed(6), Name],
+        _ => &[],
+    }
+}
+
+/// The RDATA of `rr`, a RR of `msg`, with its names uncompressed, and lowercased if `lower`
+pub fn rdata(msg: &[u8], rr: &RawRR<'_>, lower: bool) -> Option<Vec<u8>> {
+    let end = rr.rdata_offset + rr.rdata.len();
+    let mut i = rr.rdata_offset;
+    let mut out = Vec::with_capacity(rr.rdata.len());
+    for field in layout(rr.ty) {
+        match field {
+            Field::Fixed(len) => {
+                out.extend_from_slice(msg.get(i..i + len)?);
+                i += len;
+            }
+            Field::Name => {
+                let (rest, name) = parse_name(msg)(msg.get(i..)?).ok()?;
+                for label in name.labels {
+                    out.push(label.len() as u8);
+                    match lower {
+                        true => out.extend(label.to_ascii_lowercase()),
+                        false => out.extend_from_slice(label),
+                    }
+                }
+                out.push(0);
+                i = msg.len() - rest.len();
+            }
+        }
+    }
+    out.extend_from_slice(msg.get(i..end)?);
+    Some(out)
+}
+
+fn lowercase(labels: &[&[u8]]) -> Vec<String> {
+    labels
+        .iter()
+        .map(|label| escape(&label.to_ascii_lowercase()))
+        .collect()
+}
+
+/// The uncompressed name at `i` of `data`, lowercased, and the offset past it
+fn read_name(data: &[u8], mut i: usize) -> Option<(Vec<String>, usize)> {
+    let mut labels = Vec::new();
+    loop {
+        let len = *data.get(i)? as usize;
+        i += 1;
+        if len == 0 {
+            return Some((labels, i));
+        }
+        if len > 63 {
+            return None;
+        }
+        labels.push(escape(&data.get(i..i + len)?.to_ascii_lowercase()));
+        i += len;
+    }
+}
+
+fn wire(name: &[String]) -> Vec<u8> {
+    let mut out = Vec::new();
+    crate::record::serialize_name(name, &mut out).unwrap();
+    out
+}
+
+pub fn display(name: &[String]) -> String {
+    format!("{}.", name.join("."))
+}
+
+fn type_name(ty: u16) -> String {
+    match ty {
+        DNAME => "DNAME".to_string(),
+        DS => "DS".to_string(),
+        RRSIG => "RRSIG".to_string(),
+        NSEC => "NSEC".to_string(),
+        DNSKEY => "DNSKEY".to_string(),
+        NSEC3 => "NSEC3".to_string(),
+        _ => match Type::try_from(ty) {
+            Ok(ty) => format!("{:?}", ty),
+            Err(_) => format!("TYPE{}", ty),
+        },
+    }
+}
+
+/// Canonical order of lowercased names, RFC 4034 Section 6.1
+fn canonical(a: &[String], b: &[String]) -> Ordering {
+    let labels = |name: &[String]| {
+        name.iter()
+            .rev()
+            .map(|label| unescape(label))
+            .collect::<Vec<_>>()
+    };
+    labels(a).cmp(&labels(b))
+}
+
+/// Labels of `owner` counted by the signatures of its RRsets, a leading wildcard left out
+fn label_count(owner: &[String]) -> usize {
+    owner.len() - owner.first().is_some_and(|label| label == "*") as usize
+}
+
+fn wildcard(name: &[String]) -> Vec<String> {
+    std::iter::once("*".to_string())
+        .chain(name.iter().cloned())
+        .collect()
+}
+
+/// Seconds since the epoch, modulo 2^32 as RRSIG times, RFC 4034 Section 3.1.5
+pub fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32
+}
+
+#[derive(Clone)]
+struct Rrsig {
+    type_covered: u16,
+    algorithm: u8,
+    labels: u8,
+    original_ttl: u32,
+    expiration: u32,
+    inception: u32,
+    key_tag: u16,
+    signer: Vec<String>,
+    /// The RDATA up to the signature, the signer lowercased, as signed
+    signed: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl Rrsig {
+    fn parse(rdata: &[u8]) -> Option<Self> {
+        let u32_at = |i: usize| u32::from_be_bytes(rdata[i..i + 4].try_into().unwrap());
+        if rdata.len() < 18 {
+            return None;
+        }
+        let (signer, end) = read_name(rdata, 18)?;
+        let mut signed = rdata[..18].to_vec();
+        signed.extend(wire(&signer));
+        Some(Self {
+            type_covered: u16::from_be_bytes([rdata[0], rdata[1]]),
+            algorithm: rdata[2],
+            labels: rdata[3],
+            original_ttl: u32_at(4),
+            expiration: u32_at(8),
+            inception: u32_at(12),
+            key_tag: u16::from_be_bytes([rdata[16], rdata[17]]),
+            signer,
+            signed,
+            signature: rdata[end..].to_vec(),
+        })
+    }
+}
+
+#[derive(Clone)]
+struct Dnskey {
+    flags: u16,
+    algorithm: u8,
+    key: Vec<u8>,
+    tag: u16,
+    rdata: Vec<u8>,
+}
+
+impl Dnskey {
+    fn parse(rdata: &[u8]) -> Option<Self> {
+        // Protocol 3, RFC 4034 Section 2.1.2
+        if rdata.len() < 4 || rdata[2] != 3 {
+            return None;
+        }
+        Some(Self {
+            flags: u16::from_be_bytes([rdata[0], rdata[1]]),
+            algorithm: rdata[3],
+            key: rdata[4..].to_vec(),
+            tag: key_tag(rdata),
+            rdata: rdata.to_vec(),
+        })
+    }
+
+    /// Whether it may sign the RRsets of its zone
+    fn signs(&self) -> bool {
+        self.flags & ZONE_KEY != 0 && self.flags & REVOKED == 0
+    }
+}
+
+/// RFC 4034 Appendix B
+pub fn key_tag(rdata: &[u8]) -> u16 {
+    let mut acc: u32 = 0;
+    for (i, byte) in rdata.iter().enumerate() {
+        acc += match i % 2 {
+            0 => (*byte as u32) << 8,
+            _ => *byte as u32,
+        };
+    }
+    acc += (acc >> 16) & 0xffff;
+    acc as u16
+}
+
+#[derive(Clone)]
+struct Ds {
+    key_tag: u16,
+    algorithm: u8,
+    digest_type: u8,
+    digest: Vec<u8>,
+}
+
+impl Ds {
+    fn parse(rdata: &[u8]) -> Option<Self> {
+        if rdata.len() < 4 {
+            return None;
+        }
+        Some(Self {
+            key_tag: u16::from_be_bytes([rdata[0], rdata[1]]),
+            algorithm: rdata[2],
+            digest_type: rdata[3],
+            digest: rdata[4..].to_vec(),
+        })
+    }
+
+    fn supported(&self) -> bool {
+        supported(self.algorithm) && matches!(self.digest_type, DIGEST_SHA256 | DIGEST_SHA384)
+    }
+
+    /// Whether it is the digest of `key`, of the zone `owner`, RFC 4034 Section 5.1.4
+    fn matches(&self, owner: &[String], key: &Dnskey) -> bool {
+        if self.key_tag != key.tag || self.algorithm != key.algorithm {
+            return false;
+        }
+        let mut data = wire(owner);
+        data.extend_from_slice(&key.rdata);
+        match self.digest_type {
+            DIGEST_SHA256 => Sha256::digest(&data)[..] == self.digest[..],
+            DIGEST_SHA384 => Sha384::digest(&data)[..] == self.digest[..],
+            _ => false,
+        }
+    }
+}
+
+/// A trust anchor, or the DS records of a zone, which its keys must match
+#[derive(Clone)]
+enum Anchor {
+    Ds(Ds),
+    Key(Dnskey),
+}
+
+impl Anchor {
+    fn supported(&self) -> bool {
+        match self {
+            Self::Ds(ds) => ds.supported(),
+            Self::Key(key) => supported(key.algorithm),
+        }
+    }
+
+    fn trusts(&self, owner: &[String], key: &Dnskey) -> bool {
+        match self {
+            Self::Ds(ds) => ds.matches(owner, key),
+            Self::Key(anchor) => anchor.rdata == key.rdata,
+        }
+    }
+}
+
+pub fn hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Whether signatures of `algorithm` are verified
+fn supported(algorithm: u8) -> bool {
+    matches!(algorithm, RSASHA256 | ECDSAP256SHA256 | ED25519)
+}
+
+/// Trust anchors by their owner names, of `content`, read from `source`
+fn parse_anchors(
+    content: &str,
+    source: &str,
+    anchors: &mut HashMap<Vec<String>, Vec<Anchor>>,
+) -> anyhow::Result<()> {
+    for (n, line) in content.lines().enumerate() {
+        let line = line.split(';').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let owner = words.next().unwrap().trim_end_matches('.');
+        let owner: Vec<String> = match owner {
+            "" => vec![],
+            _ => split_name(&owner.to_ascii_lowercase()),
+        };
+        // TTL and class are optional
+        let words: Vec<&str> = words
+            .skip_while(|word| word.parse::<u32>().is_ok() || word.eq_ignore_ascii_case("IN"))
+            .collect();
+        fn number<T: std::str::FromStr>(words: &[&str], i: usize) -> Option<T> {
+            words.get(i)?.parse().ok()
+        }
+        let anchor = match words
+            .first()
+            .map(|word| word.to_ascii_uppercase())
+            .as_deref()
+        {
+            Some("DS") => (|| {
+                Some(Anchor::Ds(Ds {
+                    key_tag: number(&words, 1)?,
+                    algorithm: number(&words, 2)?,
+                    digest_type: number(&words, 3)?,
+                    digest: hex(&words.get(4..)?.concat())?,
+                }))
+            })(),
+            Some("DNSKEY") => (|| {
+                let mut rdata = number::<u16>(&words, 1)?.to_be_bytes().to_vec();
+                rdata.extend_from_slice(&[number(&words, 2)?, number(&words, 3)?]);
+                rdata.extend(Base64::decode_vec(&words.get(4..)?.concat()).ok()?);
+                Dnskey::parse(&rdata).map(Anchor::Key)
+            })(),
+            _ => None,
+        };
+        let anchor =
+            anchor.with_context(|| format!("{}:{}: not a DS or DNSKEY record", source, n + 1))?;
+        anchors.entry(owner).or_default().push(anchor);
+    }
+    Ok(())
+}
+
+struct Nsec {
+    owner: Vec<String>,
+    next: Vec<String>,
+    types: Vec<u8>,
+}
+
+impl Nsec {
+    fn parse(owner: &[String], rdata: &[u8]) -> Option<Self> {
+        let (next, end) = read_name(rdata, 0)?;
+        Some(Self {
+            owner: owner.to_vec(),
+            next,
+            types: rdata[end..].to_vec(),
+        })
+    }
+
+    /// Whether `name` is between the owner and the next name, so does not exist
+    fn covers(&self, name: &[String]) -> bool {
+        canonical(&self.owner, name) == Ordering::Less
+            && (canonical(name, &self.next) == Ordering::Less
+                // The last of the zone, whose next name is the apex
+                || canonical(&self.next, &self.owner) != Ordering::Greater)
+    }
+
+    /// The closest encloser of `name`, which it covers
+    fn closest_encloser(&self, name: &[String]) -> Vec<String> {
+        let common = |other: &[String]| {
+            name.iter()
+                .rev()
+                .zip(other.iter().rev())
+                .take_while(|(a, b)| a == b)
+                .count()
+        };
+        let len = common(&self.owner).max(common(&self.next));
+        name[name.len() - len..].to_vec()
+    }
+}
+
+struct Nsec3 {
+    zone: Vec<String>,
+    hash: Vec<u8>,
+    opt_out: bool,
+    iterations: u16,
+    salt: Vec<u8>,
+    next: Vec<u8>,
+    types: Vec<u8>,
+}
+
+impl Nsec3 {
+    fn parse(owner: &[String], rdata: &[u8]) -> Option<Self> {
+        if rdata.len() < 5 || rdata[0] != NSEC3_SHA1 {
+            return None;
+        }
+        let salt_end = 5 + rdata[4] as usize;
+        let salt = rdata.get(5..salt_end)?.to_vec();
+        let next_end = salt_end + 1 + *rdata.get(salt_end)? as usize;
+        Some(Self {
+            zone: owner.get(1..)?.to_vec(),
+            hash: base32hex(owner.first()?)?,
+            opt_out: rdata[1] & 1 != 0,
+            iterations: u16::from_be_bytes([rdata[2], rdata[3]]),
+            salt,
+            next: rdata.get(salt_end + 1..next_end)?.to_vec(),
+            types: rdata[next_end..].to_vec(),
+        })
+    }
+
+    fn hash_of(&self, name: &[String]) -> Option<Vec<u8>> {
+        if !name.ends_with(&self.zone) {
+            return None;
+        }
+        Some(nsec3_hash(name, &self.salt, self.iterations))
+    }
+
+    fn matches(&self, name: &[String]) -> bool {
+        self.hash_of(name).is_some_and(|hash| hash == self.hash)
+    }
+
+    fn covers(&self, name: &[String]) -> bool {
+        self.hash_of(name)
+            .is_some_and(|hash| match self.hash < self.next {
+                true => self.hash < hash && hash < self.next,
+                // The last of the zone
+                false => self.hash < hash || hash < self.next,
+            })
+    }
+}
+
+/// The hash of `name` in lowercase, owner of its NSEC3 record, RFC 5155 Section 5
+pub fn nsec3_hash(name: &[String], salt: &[u8], iterations: u16) -> Vec<u8> {
+    let name: Vec<String> = name
+        .iter()
+        .map(|label| label.to_ascii_lowercase())
+        .collect();
+    let mut hash = sha1(&[wire(&name), salt.to_vec()].concat());
+    for _ in 0..iterations {
+        hash = sha1(&[&hash[..], salt].concat());
+    }
+    hash.to_vec()
+}
+
+/// Base32 with the extended hex alphabet, RFC 4648 Section 7, of the owner names of NSEC3 records
+pub fn base32hex(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut bits, mut count) = (0u32, 0);
+    for c in s.bytes().map(|c| c.to_ascii_lowercase()) {
+        let value = match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'v' => c - b'a' + 10,
+            _ => return None,
+        };
+        bits = (bits << 5) | value as u32;
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    Some(out)
+}
+
+/// The label of the owner of the NSEC3 record of `hash`, in lowercase without padding
+pub fn to_base32hex(hash: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuv";
+    let mut out = String::new();
+    let (mut bits, mut count) = (0u32, 0);
+    for byte in hash {
+        bits = (bits << 8) | *byte as u32;
+        count += 8;
+        while count >= 5 {
+            count -= 5;
+            out.push(ALPHABET[(bits >> count) as usize & 31] as char);
+        }
+        bits &= (1 << count) - 1;
+    }
+    if count > 0 {
+        out.push(ALPHABET[(bits << (5 - count)) as usize & 31] as char);
+    }
+    out
+}
+
+/// SHA-1, which NSEC3 hashes names with (RFC 5155 Section 5)
+fn sha1(data: &[u8]) -> [u8; 20] {
+    <sha1::Sha1 as sha1::Digest>::digest(data).into()
+}
+
+/// Whether the type bitmap of a NSEC or NSEC3 record lists `ty`, RFC 4034 Section 4.1.2
+fn has_type(bitmap: &[u8], ty: u16) -> bool {
+    let (window, bit) = ((ty >> 8) as u8, (ty & 0xff) as usize);
+    let mut i = 0;
+    while let (Some(&block), Some(&len)) = (bitmap.get(i), bitmap.get(i + 1)) {
+        let bits = bitmap.get(i + 2..i + 2 + len as usize).unwrap_or(&[]);
+        if block == window {
+            return bits
+                .get(bit / 8)
+                .is_some_and(|byte| byte & (0x80 >> (bit % 8)) != 0);
+        }
+        i += 2 + len as usize;
+    }
+    false
+}
+
+/// Proofs of nonexistence of a response
+#[derive(Default)]
+struct Denial {
+    nsecs: Vec<Nsec>,
+    nsec3s: Vec<Nsec3>,
+}
+
+impl Denial {
+    fn is_empty(&self) -> bool {
+        self.nsecs.is_empty() && self.nsec3s.is_empty()
+    }
+
+    /// The closest encloser of `name` proven by NSEC3 records, RFC 5155 Section 8.3, and whether
+    /// the record covering the next closer name has opt-out
+    fn closest_encloser(&self, name: &[String]) -> Option<(Vec<String>, bool)> {
+        for i in 1..=name.len() {
+            let encloser = &name[i..];
+            let Some(record) = self.nsec3s.iter().find(|record| record.matches(encloser)) else {
+                continue;
+            };
+            // Not below a delegation
+            if has_type(&record.types, Type::NS as u16)
+                && !has_type(&record.types, Type::SOA as u16)
+            {
+                return None;
+            }
+            let next_closer = &name[i - 1..];
+            return self
+                .nsec3s
+                .iter()
+                .find(|record| record.covers(next_closer))
+                .map(|record| (encloser.to_vec(), record.opt_out));
+        }
+        None
+    }
+
+    /// Checks that `name` does not exist, with `nxdomain`, or has no `ty` records
+    fn check(&self, name: &[String], ty: u16, nxdomain: bool) -> Result<(), Security> {
+        let lacks = |types: &[u8]| !has_type(types, ty) && !has_type(types, Type::CNAME as u16);
+        let bogus = |what: &str| {
+            Err(Security::Bogus(format!(
+                "no proof that {} {}",
+                display(name),
+                what
+            )))
+        };
+        if !self.nsecs.is_empty() {
+            if !nxdomain {
+                if let Some(nsec) = self.nsecs.iter().find(|nsec| nsec.owner == name) {
+                    return match lacks(&nsec.types) {
+                        true => Ok(()),
+                        false => bogus(&format!("has no {} records", type_name(ty))),
+                    };
+                }
+            }
+            let Some(cover) = self.nsecs.iter().find(|nsec| nsec.covers(name)) else {
+                return bogus("does not exist");
+            };
+            // Empty non-terminal
+            if !nxdomain && cover.next.len() > name.len() && cover.next.ends_with(name) {
+                return Ok(());
+            }
+            let wildcard = wildcard(&cover.closest_encloser(name));
+            let denied = match nxdomain {
+                true => self.nsecs.iter().any(|nsec| nsec.covers(&wildcard)),
+                false => self
+                    .nsecs
+                    .iter()
+                    .any(|nsec| nsec.owner == wildcard && lacks(&nsec.types)),
+            };
+            return match denied {
+                true => Ok(()),
+                false => bogus("is not matched by a wildcard"),
+            };
+        }
+        if self
+            .nsec3s
+            .iter()
+            .any(|record| record.iterations > MAX_ITERATIONS)
+        {
+            return Err(Security::Insecure);
+        }
+        if !nxdomain {
+            if let Some(record) = self.nsec3s.iter().find(|record| record.matches(name)) {
+                return match lacks(&record.types) {
+                    true => Ok(()),
+                    false => bogus(&format!("has no {} records", type_name(ty))),
+                };
+            }
+        }
+        let Some((encloser, opt_out)) = self.closest_encloser(name) else {
+            return bogus("does not exist");
+        };
+        // Maybe an unsigned delegation, RFC 5155 Section 9.2
+        if opt_out {
+            return Err(Security::Insecure);
+        }
+        let wildcard = wildcard(&encloser);
+        let denied = match nxdomain {
+            true => self.nsec3s.iter().any(|record| record.covers(&wildcard)),
+            false => self
+                .nsec3s
+                .iter()
+                .any(|record| record.matches(&wildcard) && lacks(&record.types)),
+        };
+        match denied {
+            true => Ok(()),
+            false => bogus("is not matched by a wildcard"),
+        }
+    }
+
+    /// Checks that `owner`, of a RRset signed for `labels` labels, was expanded from a wildcard
+    /// as the name queried did not exist, RFC 4035 Section 5.3.4
+    fn check_expansion(&self, owner: &[String], labels: usize) -> Result<(), Security> {
+        let next_closer = &owner[owner.len() - labels - 1..];
+        match self.nsecs.iter().any(|nsec| nsec.covers(owner))
+            || self.nsec3s.iter().any(|record| record.covers(next_closer))
+        {
+            true => Ok(()),
+            false => Err(Security::Bogus(format!(
+                "no proof that {} does not exist",
+                display(owner)
+            ))),
+        }
+    }
+
+    /// Whether `name` is an insecure delegation, Ok(true), or not a delegation at all, Ok(false)
+    fn check_ds(&self, name: &[String]) -> Result<bool, Security> {
+        let bogus = || {
+            Err(Security::Bogus(format!(
+                "no proof that {} has no DS records",
+                display(name)
+            )))
+        };
+        let delegation = |types: &[u8]| {
+            if has_type(types, DS) || has_type(types, Type::SOA as u16) {
+                return bogus();
+            }
+            Ok(has_type(types, Type::NS as u16))
+        };
+        if !self.nsecs.is_empty() {
+            if let Some(nsec) = self.nsecs.iter().find(|nsec| nsec.owner == name) {
+                return delegation(&nsec.types);
+            }
+            return match self.nsecs.iter().any(|nsec| nsec.covers(name)) {
+                true => Ok(false),
+                false => bogus(),
+            };
+        }
+        if self
+            .nsec3s
+            .iter()
+            .any(|record| record.iterations > MAX_ITERATIONS)
+        {
+            return Err(Security::Insecure);
+        }
+        if let Some(record) = self.nsec3s.iter().find(|record| record.matches(name)) {
+            return delegation(&record.types);
+        }
+        match self.closest_encloser(name) {
+            Some((_, opt_out)) => Ok(opt_out),
+            None => bogus(),
+        }
+    }
+}
+
+/// A RRset of a response and its signatures
+struct RRset<'a> {
+    owner: &'a [String],
+    ty: u16,
+    rrs: Vec<&'a Rr>,
+    sigs: Vec<Rrsig>,
+}
+
+/// The RRsets of `rrs`, in order of appearance
+fn rrsets(rrs: &[Rr]) -> Vec<RRset<'_>> {
+    let mut sets: Vec<RRset> = Vec::new();
+    for rr in rrs.iter().filter(|rr| rr.ty != RRSIG) {
+        match sets
+            .iter_mut()
+            .find(|set| set.owner == rr.owner && set.ty == rr.ty)
+        {
+            Some(set) => set.rrs.push(rr),
+            None => sets.push(RRset {
+                owner: &rr.owner,
+                ty: rr.ty,
+                rrs: vec![rr],
+                sigs: vec![],
+            }),
+        }
+    }
+    for rr in rrs.iter().filter(|rr| rr.ty == RRSIG) {
+        let Some(sig) = Rrsig::parse(&rr.rdata) else {
+            continue;
+        };
+        if let Some(set) = sets
+            .iter_mut()
+            .find(|set| set.owner == rr.owner && set.ty == sig.type_covered)
+        {
+            set.sigs.push(sig);
+        }
+    }
+    sets
+}
+
+/// Checks that `sig` is a valid signature of `rrset` by one of `keys`, RFC 4035 Section 5.3
+fn verify(rrset: &RRset<'_>, sig: &Rrsig, keys: &[Dnskey]) -> Result<(), String> {
+    let what = || format!("{} {}", display(rrset.owner), type_name(rrset.ty));
+    let now = now();
+    if (now.wrapping_sub(sig.inception) as i32) < 0 {
+        return Err(format!("signature of {} not yet valid", what()));
+    }
+    if (sig.expiration.wrapping_sub(now) as i32) < 0 {
+        return Err(format!("signature of {} expired", what()));
+    }
+    let labels = sig.labels as usize;
+    if labels > label_count(rrset.owner) || !rrset.owner.ends_with(&sig.signer) {
+        return Err(format!("signature of {} not of its zone", what()));
+    }
+    // Signed as the wildcard it was expanded from
+    let owner = match labels < label_count(rrset.owner) {
+        true => wire(&wildcard(&rrset.owner[rrset.owner.len() - labels..])),
+        false => wire(rrset.owner),
+    };
+    let mut rdatas: Vec<&[u8]> = rrset.rrs.iter().map(|rr| &rr.rdata[..]).collect();
+    rdatas.sort();
+    rdatas.dedup();
+    let mut data = sig.signed.clone();
+    for rdata in rdatas {
+        data.extend_from_slice(&owner);
+        data.extend_from_slice(&rrset.ty.to_be_bytes());
+        data.extend_from_slice(&rrset.rrs[0].class.to_be_bytes());
+        data.extend_from_slice(&sig.original_ttl.to_be_bytes());
+        data.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        data.extend_from_slice(rdata);
+    }
+    let valid = keys
+        .iter()
+        .filter(|key| key.signs() && key.tag == sig.key_tag && key.algorithm == sig.algorithm)
+        .any(|key| match key.algorithm {
+            RSASHA256 => {
+                use rsa::PublicKey;
+
+                let padding = rsa::PaddingScheme::new_pkcs1v15_sign(Some(rsa::Hash::SHA2_256));
+                rsa_key(&key.key).is_some_and(|key| {
+                    key.verify(padding, &Sha256::digest(&data), &sig.signature)
+                        .is_ok()
+                })
+            }
+            ECDSAP256SHA256 => {
+                use p256::ecdsa::signature::Verifier;
+
+                let sec1 = [&[0x04][..], &key.key].concat();
+                let key = p256::ecdsa::VerifyingKey::from_sec1_bytes(&sec1);
+                let signature = p256::ecdsa::Signature::try_from(&sig.signature[..]);
+                match (key, signature) {
+                    (Ok(key), Ok(signature)) => key.verify(&data, &signature).is_ok(),
+                    _ => false,
+                }
+            }
+            ED25519 => {
+                let key = ed25519_dalek::PublicKey::from_bytes(&key.key);
+                let signature = ed25519_dalek::Signature::try_from(&sig.signature[..]);
+                match (key, signature) {
+                    (Ok(key), Ok(signature)) => key.verify_strict(&data, &signature).is_ok(),
+                    _ => false,
+                }
+            }
+            _ => false,
+        });
+    match valid {
+        true => Ok(()),
+        false => Err(format!("bad signature of {}", what())),
+    }
+}
+
+/// The RSA public key of the DNSKEY record `key`, its exponent and modulus, RFC 3110 Section 2
+fn rsa_key(key: &[u8]) -> Option<rsa::RsaPublicKey> {
+    let (len, rest) = match key.first()? {
+        0 => (
+            u16::from_be_bytes(key.get(1..3)?.try_into().unwrap()) as usize,
+            &key[3..],
+        ),
+        len => (*len as usize, &key[1..]),
+    };
+    if len == 0 || rest.len() <= len {
+        return None;
+    }
+    let exponent = rsa::BigUint::from_bytes_be(&rest[..len]);
+    let modulus = rsa::BigUint::from_bytes_be(&rest[len..]);
+    rsa::RsaPublicKey::new(modulus, exponent).ok()
+}
+
+/// A response, its RRs in canonical form
+struct Message {
+    rcode: u8,
+    qname: Vec<String>,
+    qtype: u16,
+    answers: Vec<Rr>,
+    authorities: Vec<Rr>,
+}
+
+/// Parses `msg`, the response to `query`, whose question it is taken to answer if it has none
+fn parse(msg: &[u8], query: &[u8]) -> Option<Message> {
+    let (_, resp) = parse_raw_response(msg).ok()?;
+    let (_, asked) = parse_raw_response(query).ok()?;
+    let (qname, qtype) = resp.question.as_ref().or(asked.question.as_ref())?;
+    let rrs = |rrs: &[RawRR<'_>]| -> Option<Vec<Rr>> {
+        rrs.iter()
+            .map(|rr| {
+                Some(Rr {
+                    owner: lowercase(&rr.name.labels),
+                    ty: rr.ty,
+                    class: rr.class,
+                    ttl: rr.ttl,
+                    rdata: rdata(msg, rr, true)?,
+                })
+            })
+            .collect()
+    };
+    Some(Message {
+        rcode: (resp.flags & 0xf) as u8,
+        qname: lowercase(&qname.labels),
+        qtype: *qtype,
+        answers: rrs(&resp.answers)?,
+        authorities: rrs(&resp.authorities)?,
+    })
+}
+
+/// A query for the `ty` records of `name`, with DO and CD
+fn query(name: &[String], ty: u16) -> Vec<u8> {
+    let mut query = rand::random::<u16>().to_be_bytes().to_vec();
+    // RD and CD, one question and the OPT RR
+    query.extend_from_slice(&[0x01, 0x10, 0, 1, 0, 0, 0, 0, 0, 1]);
+    query.extend(wire(name));
+    query.extend_from_slice(&ty.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+    query.push(0); // Root
+    query.extend_from_slice(&(Type::OPT as u16).to_be_bytes());
+    query.extend_from_slice(&PAYLOAD_SIZE.to_be_bytes());
+    query.extend_from_slice(&[0, 0, 0x80, 0, 0, 0]); // DO, and no options
+    query
+}
+
+/// `query` with DO and CD, so that upstream resolvers send the DNSSEC records of the answer
+/// whether they deem it valid or not
+pub fn with_do(query: &[u8]) -> Option<Vec<u8>> {
+    let mut out = query.to_vec();
+    match find_opt(query)? {
+        Some((_, rdata, _)) => out[rdata - 4] |= 0x80,
+        None => {
+            let arcount = u16::from_be_bytes([out[10], out[11]]).checked_add(1)?;
+            out[10..12].copy_from_slice(&arcount.to_be_bytes());
+            out.push(0); // Root
+            out.extend_from_slice(&(Type::OPT as u16).to_be_bytes());
+            out.extend_from_slice(&PAYLOAD_SIZE.to_be_bytes());
+            out.extend_from_slice(&[0, 0, 0x80, 0, 0, 0]);
+        }
+    }
+    out[3] |= 0x10;
+    Some(out)
+}
+
+/// The DO bit of `query`, None if it has no OPT RR
+pub fn dnssec_ok(query: &[u8]) -> Option<bool> {
+    let (_, rdata, _) = find_opt(query)??;
+    Some(query[rdata - 4] & 0x80 != 0)
+}
+
+/// `response` without its RRSIG, NSEC and NSEC3 records, for the clients which did not set DO,
+/// and without its OPT RR unless `keep_opt`. Names are written uncompressed.
+pub fn strip(response: &[u8], keep_opt: bool) -> Option<Vec<u8>> {
+    let (_, resp) = parse_raw_response(response).ok()?;
+    let mut out = response[..resp.question_end].to_vec();
+    let mut counts = [0u16; 3];
+    for (count, rrs) in counts
+        .iter_mut()
+        .zip([&resp.answers, &resp.authorities, &resp.additionals])
+    {
+        for rr in rrs {
+            match rr.ty {
+                RRSIG | NSEC | NSEC3 => continue,
+                ty if ty == Type::OPT as u16 && !keep_opt => continue,
+                _ => (),
+            }
+            let rdata = rdata(response, rr, false)?;
+            for label in &rr.name.labels {
+                out.push(label.len() as u8);
+                out.extend_from_slice(label);
+            }
+            out.push(0);
+            out.extend_from_slice(&rr.ty.to_be_bytes());
+            out.extend_from_slice(&rr.class.to_be_bytes());
+            let ttl = match rr.ty == Type::OPT as u16 {
+                true => rr.ttl & !0x8000,
+                false => rr.ttl,
+            };
+            out.extend_from_slice(&ttl.to_be_bytes());
+            out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            out.extend(rdata);
+            *count += 1;
+        }
+    }
+    for (i, count) in counts.iter().enumerate() {
+        out[6 + 2 * i..8 + 2 * i].copy_from_slice(&count.to_be_bytes());
+    }
+    Some(out)
+}
+
+/// Validated keys of a zone
+#[derive(Clone)]
+enum Keys {
+    Secure(Vec<Dnskey>),
+    Insecure,
+    Bogus(String),
+}
+
+type KeysFuture<'a> = Pin<Box<dyn Future<Output = Keys> + Send + 'a>>;
+type SecurityFuture<'a> = Pin<Box<dyn Future<Output = Security> + Send + 'a>>;
+
+/// A key of the DNSKEY RRset of the zone of a trust anchor, see the managed_keys module
+pub struct AnchorKey {
+    pub rdata: Vec<u8>,
+    /// Whether it signs the RRset, revoked or not
+    pub signs: bool,
+    /// Whether the trust anchor trusts it
+    pub trusted: bool,
+}
+
+pub struct Validator {
+    anchors: RwLock<HashMap<Vec<String>, Vec<Anchor>>>,
+    /// Keys of the zones chased, and until when they are kept
+    keys: Mutex<HashMap<Vec<String>, (Instant, Keys)>>,
+}
+
+impl Validator {
+    /// A validator trusting the anchors of the file at `path`, if any, and the root ones if `root`
+    pub fn new(path: Option<&Path>, root: bool) -> anyhow::Result<Self> {
+        let mut anchors = HashMap::new();
+        if let Some(path) = path {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            parse_anchors(&content, &path.display().to_string(), &mut anchors)?;
+        }
+        if root {
+            parse_anchors(ROOT_ANCHORS, "root anchors", &mut anchors)?;
+        }
+        Ok(Self {
+            anchors: RwLock::new(anchors),
+            keys: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The zones of the trust anchors
+    pub fn anchor_zones(&self) -> Vec<Vec<String>> {
+        self.anchors.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Trusts `keys`, the RDATA of DNSKEY records, as the anchor of `zone` in place of its current
+    /// one
+    pub fn set_anchor(&self, zone: &[String], keys: &[Vec<u8>]) {
+        let anchor = keys
+            .iter()
+            .filter_map(|rdata| Dnskey::parse(rdata))
+            .map(Anchor::Key)
+            .collect();
+        self.anchors.write().unwrap().insert(zone.to_vec(), anchor);
+        self.keys.lock().unwrap().clear();
+    }
+
+    /// The closest trust anchor `name` is at or below
+    fn anchor_of<'a>(&self, name: &'a [String]) -> Option<&'a [String]> {
+        let anchors = self.anchors.read().unwrap();
+        (0..=name.len())
+            .map(|i| &name[i..])
+            .find(|zone| anchors.contains_key(*zone))
+    }
+
+    /// The DNSKEY RRset of `zone`, that of a trust anchor, asked to `up`, with which of its keys
+    /// sign it and are trusted by the anchor
+    pub async fn anchor_keys(
+        &self,
+        up: &Upstreams,
+        zone: &[String],
+    ) -> Result<Vec<AnchorKey>, String> {
+        let anchor = self.anchors.read().unwrap().get(zone).cloned();
+        let anchor = anchor.ok_or_else(|| format!("no trust anchor for {}", display(zone)))?;
+        let msg = self.fetch(up, zone, DNSKEY).await?;
+        let sets = rrsets(&msg.answers);
+        let set = sets
+            .iter()
+            .find(|set| set.owner == zone && set.ty == DNSKEY)
+            .ok_or_else(|| format!("no DNSKEY records for {}", display(zone)))?;
+        let keys = set.rrs.iter().filter_map(|rr| Dnskey::parse(&rr.rdata));
+        Ok(keys
+            .map(|key| {
+                // Revoked keys sign the RRset too, to prove they were revoked by their owner
+                let unrevoked = Dnskey {
+                    flags: key.flags & !REVOKED,
+                    ..key.clone()
+                };
+                let signs = set
+                    .sigs
+                    .iter()
+                    .filter(|sig| sig.signer == zone)
+                    .any(|sig| verify(set, sig, std::slice::from_ref(&unrevoked)).is_ok());
+                AnchorKey {
+                    trusted: anchor.iter().any(|trust| trust.trusts(zone, &key)),
+                    rdata: key.rdata,
+                    signs,
+                }
+            })
+            .collect())
+    }
+
+    async fn fetch(&self, up: &Upstreams, name: &[String], ty: u16) -> Result<Message, String> {
+        let what = || format!("{} {}", display(name), type_name(ty));
+        let query = query(name, ty);
+        let response = up
+            .forward(&query, true)
+            .await
+            .map_err(|e| format!("{}: {}", what(), e))?;
+        let msg =
+            parse(&response, &query).ok_or_else(|| format!("{}: malformed response", what()))?;
+        match msg.rcode {
+            0 | 3 => Ok(msg),
+            rcode => Err(format!("{}: rcode {}", what(), rcode)),
+        }
+    }
+
+    /// Validates the signed `rrset`, chasing the keys of its signer from `anchor`. Returns the
+    /// labels it was signed for, fewer than those of its owner for wildcard expansions.
+    async fn rrset(
+        &self,
+        up: &Upstreams,
+        anchor: &[String],
+        rrset: &RRset<'_>,
+    ) -> Result<usize, Security> {
+        let mut last = Security::Bogus(format!(
+            "no usable signature of {} {}",
+            display(rrset.owner),
+            type_name(rrset.ty)
+        ));
+        for sig in &rrset.sigs {
+            if !sig.signer.ends_with(anchor) {
+                continue;
+            }
+            match self.keys(up, anchor, &sig.signer).await {
+                Keys::Secure(keys) => match verify(rrset, sig, &keys) {
+                    Ok(()) => return Ok(sig.labels as usize),
+                    Err(e) => last = Security::Bogus(e),
+                },
+                Keys::Insecure => return Err(Security::Insecure),
+                Keys::Bogus(e) => last = Security::Bogus(e),
+            }
+        }
+        Err(last)
+    }
+
+    /// The validated NSEC and NSEC3 records of the authority section of `msg`, after checking
+    /// its other RRsets
+    async fn denial(&self, up: &Upstreams, msg: &Message) -> Result<Denial, Security> {
+        let mut denial = Denial::default();
+        for set in rrsets(&msg.authorities) {
+            // Those of insecure zones, or left out by the upstream resolver
+            if set.sigs.is_empty() {
+                continue;
+            }
+            let anchor = self.anchor_of(set.owner).ok_or(Security::Insecure)?;
+            self.rrset(up, anchor, &set).await?;
+            for rr in &set.rrs {
+                match set.ty {
+                    NSEC => denial.nsecs.extend(Nsec::parse(set.owner, &rr.rdata)),
+                    NSEC3 => denial.nsec3s.extend(Nsec3::parse(set.owner, &rr.rdata)),
+                    _ => (),
+                }
+            }
+        }
+        Ok(denial)
+    }
+
+    fn keys<'a>(
+        &'a self,
+        up: &'a Upstreams,
+        anchor: &'a [String],
+        zone: &'a [String],
+    ) -> KeysFuture<'a> {
+        Box::pin(async move {
+            if let Some((until, keys)) = self.keys.lock().unwrap().get(zone) {
+                if *until > Instant::now() {
+                    return keys.clone();
+                }
+            }
+            let (keys, ttl) = self.chase(up, anchor, zone).await;
+            if let Keys::Bogus(e) = &keys {
+                log::warn!("Keys of {}: {}", display(zone), e);
+            }
+            let mut cache = self.keys.lock().unwrap();
+            cache.retain(|_, (until, _)| *until > Instant::now());
+            cache.insert(zone.to_vec(), (Instant::now() + ttl, keys.clone()));
+            keys
+        })
+    }
+
+    /// The keys of `zone`, validated from `anchor` down, and how long to keep them
+    async fn chase(&self, up: &Upstreams, anchor: &[String], zone: &[String]) -> (Keys, Duration) {
+        let bogus = |e: String| (Keys::Bogus(e), BOGUS_TTL);
+        let insecure = (Keys::Insecure, INSECURE_TTL);
+        let trusted = if zone == anchor {
+            self.anchors.read().unwrap()[anchor].clone()
+        } else {
+            let msg = match self.fetch(up, zone, DS).await {
+                Ok(msg) => msg,
+                Err(e) => return bogus(e),
+            };
+            let sets = rrsets(&msg.answers);
+            let Some(set) = sets.iter().find(|set| set.owner == zone && set.ty == DS) else {
+                let denial = match self.denial(up, &msg).await {
+                    Ok(denial) => denial,
+                    Err(Security::Bogus(e)) => return bogus(e),
+                    Err(_) => return insecure,
+                };
+                return match denial.check_ds(zone) {
+                    Ok(true) | Err(Security::Insecure) => insecure,
+                    Ok(false) => bogus(format!("{} is not a zone", display(zone))),
+                    Err(Security::Bogus(e)) => bogus(e),
+                    Err(_) => insecure,
+                };
+            };
+            // Signed by the parent zone
+            let set = RRset {
+                owner: set.owner,
+                ty: DS,
+                rrs: set.rrs.clone(),
+                sigs: set
+                    .sigs
+                    .iter()
+                    .filter(|sig| sig.signer.len() < zone.len())
+                    .cloned()
+                    .collect(),
+            };
+            match self.rrset(up, anchor, &set).await {
+                Ok(_) => (),
+                Err(Security::Insecure) => return insecure,
+                Err(Security::Bogus(e)) => return bogus(e),
+                Err(Security::Secure) => unreachable!(),
+            }
+            set.rrs
+                .iter()
+                .filter_map(|rr| Ds::parse(&rr.rdata))
+                .map(Anchor::Ds)
+                .collect()
+        };
+        if !trusted.iter().any(Anchor::supported) {
+            return insecure;
+        }
+
+        let msg = match self.fetch(up, zone, DNSKEY).await {
+            Ok(msg) => msg,
+            Err(e) => return bogus(e),
+        };
+        let sets = rrsets(&msg.answers);
+        let Some(set) = sets
+            .iter()
+            .find(|set| set.owner == zone && set.ty == DNSKEY)
+        else {
+            return bogus(format!("no DNSKEY records for {}", display(zone)));
+        };
+        let keys: Vec<Dnskey> = set
+            .rrs
+            .iter()
+            .filter_map(|rr| Dnskey::parse(&rr.rdata))
+            .collect();
+        let entry: Vec<Dnskey> = keys
+            .iter()
+            .filter(|key| trusted.iter().any(|trust| trust.trusts(zone, key)))
+            .cloned()
+            .collect();
+        for sig in set.sigs.iter().filter(|sig| sig.signer == zone) {
+            if verify(set, sig, &entry).is_ok() {
+                let ttl = set
+                    .rrs
+                    .iter()
+                    .map(|rr| rr.ttl)
+                    .chain([sig.original_ttl, MAX_KEYS_TTL])
+                    .min()
+                    .unwrap();
+                return (Keys::Secure(keys), Duration::from_secs(ttl as u64));
+            }
+        }
+        bogus(format!(
+            "no DNSKEY of {} matching its DS records signs them",
+            display(zone)
+        ))
+    }
+
+    /// Whether the RRsets of `name` are to be signed, walking the delegations from `anchor` down
+    fn name<'a>(
+        &'a self,
+        up: &'a Upstreams,
+        anchor: &'a [String],
+        name: &'a [String],
+    ) -> SecurityFuture<'a> {
+        Box::pin(async move {
+            match self.keys(up, anchor, anchor).await {
+                Keys::Secure(_) => (),
+                Keys::Insecure => return Security::Insecure,
+                Keys::Bogus(e) => return Security::Bogus(e),
+            }
+            for i in (0..name.len() - anchor.len()).rev() {
+                let child = &name[i..];
+                let msg = match self.fetch(up, child, DS).await {
+                    Ok(msg) => msg,
+                    Err(e) => return Security::Bogus(e),
+                };
+                if msg
+                    .answers
+                    .iter()
+                    .any(|rr| rr.owner == child && rr.ty == DS)
+                {
+                    match self.keys(up, anchor, child).await {
+                        Keys::Secure(_) => continue,
+                        Keys::Insecure => return Security::Insecure,
+                        Keys::Bogus(e) => return Security::Bogus(e),
+                    }
+                }
+                let denial = match self.denial(up, &msg).await {
+                    Ok(denial) => denial,
+                    Err(security) => return security,
+                };
+                match denial.check_ds(child) {
+                    Ok(true) => return Security::Insecure,
+                    Ok(false) => (),
+                    Err(security) => return security,
+                }
+            }
+            Security::Secure
+        })
+    }
+
+    /// Validates `response`, the answer of `up` to `query`, with DO and CD
+    pub async fn validate(&self, up: &Upstreams, query: &[u8], response: &[u8]) -> Security {
+        let Some(msg) = parse(response, query) else {
+            return Security::Bogus("malformed response".to_string());
+        };
+        if !matches!(msg.rcode, 0 | 3) {
+            return Security::Insecure;
+        }
+        let mut secure = true;
+        let answers = rrsets(&msg.answers);
+        // Validated DNAME records, whose synthesized CNAME records are not signed
+        let mut dnames: Vec<&[String]> = Vec::new();
+        // Owners of wildcard expansions, and the labels they were signed for
+        let mut expansions = Vec::new();
+        for set in &answers {
+            let Some(anchor) = self.anchor_of(set.owner) else {
+                secure = false;
+                continue;
+            };
+            let synthesized =
+                |dname: &&[String]| set.owner.len() > dname.len() && set.owner.ends_with(dname);
+            if set.ty == Type::CNAME as u16 && set.sigs.is_empty() && dnames.iter().any(synthesized)
+            {
+                continue;
+            }
+            let result = match set.sigs.is_empty() {
+                true => match self.name(up, anchor, set.owner).await {
+                    Security::Secure => Err(Security::Bogus(format!(
+                        "no signature of {} {}",
+                        display(set.owner),
+                        type_name(set.ty)
+                    ))),
+                    security => Err(security),
+                },
+                false => self.rrset(up, anchor, set).await,
+            };
+            match result {
+                Ok(labels) => {
+                    if set.ty == DNAME {
+                        dnames.push(set.owner);
+                    }
+                    if labels < label_count(set.owner) {
+                        expansions.push((set.owner, labels));
+                    }
+                }
+                Err(Security::Bogus(e)) => return Security::Bogus(e),
+                Err(_) => secure = false,
+            }
+        }
+
+        // The name answered, at the end of the CNAME chain
+        let mut name = msg.qname.clone();
+        for _ in 0..MAX_CNAMES {
+            let cname = answers
+                .iter()
+                .find(|set| set.owner == name && set.ty == Type::CNAME as u16);
+            match cname.and_then(|set| read_name(&set.rrs[0].rdata, 0)) {
+                Some((target, _)) if msg.qtype != Type::CNAME as u16 => name = target,
+                _ => break,
+            }
+        }
+        let answered = answers
+            .iter()
+            .any(|set| set.owner == name && (set.ty == msg.qtype || msg.qtype == Type::ANY as u16));
+        if answered && expansions.is_empty() {
+            return match secure {
+                true => Security::Secure,
+                false => Security::Insecure,
+            };
+        }
+
+        let Some(anchor) = self.anchor_of(&name) else {
+            return Security::Insecure;
+        };
+        let denial = match self.denial(up, &msg).await {
+            Ok(denial) => denial,
+            Err(security) => return security,
+        };
+        for (owner, labels) in expansions {
+            if let Err(security) = denial.check_expansion(owner, labels) {
+                return security;
+            }
+        }
+        if !answered {
+            if denial.is_empty() {
+                return match self.name(up, anchor, &name).await {
+                    Security::Secure => {
+                        Security::Bogus(format!("no proof that {} does not exist", display(&name)))
+                    }
+                    security => security,
+                };
+            }
+            match denial.check(&name, msg.qtype, msg.rcode == 3) {
+                Ok(()) => (),
+                Err(Security::Insecure) => secure = false,
+                Err(security) => return security,
+            }
+        }
+        match secure {
+            true => Security::Secure,
+            false => Security::Insecure,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{ExpandedSecretKey, PublicKey, SecretKey};
+
+    use super::*;
+
+    fn name(s: &str) -> Vec<String> {
+        split_name(s)
+    }
+
+    fn bitmap(types: &[Type]) -> Vec<u8> {
+        crate::record::type_bitmap(types)
+    }
+
+    #[test]
+    fn sha1_vectors() {
+        let hex =
+            |data: &[u8]| -> String { sha1(data).iter().map(|b| format!("{:02x}", b)).collect() };
+        assert_eq!(hex(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        // Over a block, and across the length padding
+        let long = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        assert_eq!(hex(long), "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+        assert_eq!(
+            hex(&[b'a'; 1000]),
+            "291e9a6c66994949b57ba5e650361e98fc36b1ba"
+        );
+    }
+
+    #[test]
+    fn nsec3_hashes() {
+        // RFC 5155 Appendix A
+        let salt = hex("aabbccdd").unwrap();
+        for (owner, hash) in [
+            ("example", "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom"),
+            ("a.example", "35mthgpgcu1qg68fab165klnsnk3dpvl"),
+            ("w.example", "k8udemvp1j2f7eg6jebps17vp3n8i58h"),
+            ("*.w.example", "r53bq7cc2uvmubfu5ocmm6pers9tk9en"),
+            ("A.EXAMPLE", "35mthgpgcu1qg68fab165klnsnk3dpvl"),
+        ] {
+            let hashed = nsec3_hash(&name(owner), &salt, 12);
+            assert_eq!(to_base32hex(&hashed), hash, "{}", owner);
+            assert_eq!(base32hex(hash).unwrap(), hashed);
+            assert_eq!(base32hex(&hash.to_ascii_uppercase()).unwrap(), hashed);
+        }
+        assert_eq!(base32hex("w"), None);
+    }
+
+    #[test]
+    fn type_bitmaps() {
+        let types = bitmap(&[Type::A, Type::MX, Type::RRSIG, Type::NSEC, Type::NXNAME]);
+        for ty in [Type::A, Type::MX, Type::RRSIG, Type::NSEC, Type::NXNAME] {
+            assert!(has_type(&types, ty as u16), "{:?}", ty);
+        }
+        for ty in [Type::AAAA, Type::NS, Type::TXT, Type::CNAME] {
+            assert!(!has_type(&types, ty as u16), "{:?}", ty);
+        }
+        // Truncated
+        assert!(!has_type(&types[..3], Type::MX as u16));
+        assert!(!has_type(&[], Type::A as u16));
+    }
+
+    #[test]
+    fn canonical_order() {
+        // RFC 4034 Section 6.1
+        let order = [
+            "example",
+            "a.example",
+            "yljkjljk.a.example",
+            "z.a.example",
+            "zabc.a.example",
+            "z.example",
+            "\\001.z.example",
+            "*.z.example",
+            "\\200.z.example",
+        ];
+        for pair in order.windows(2) {
+            let (a, b) = (name(&pair[0].to_ascii_lowercase()), name(pair[1]));
+            assert_eq!(
+                canonical(&a, &b),
+                Ordering::Less,
+                "{} < {}",
+                pair[0],
+                pair[1]
+            );
+            assert_eq!(canonical(&b, &a), Ordering::Greater);
+        }
+    }
+
+    fn nsec(owner: &str, next: &str, types: &[Type]) -> Nsec {
+        Nsec {
+            owner: name(owner),
+            next: name(next),
+            types: bitmap(types),
+        }
+    }
+
+    /// The NSEC chain of a zone with example.com, a.example.com, b.a.example.com (so that
+    /// a.example.com is an empty non-terminal), *.w.example.com and z.example.com
+    fn nsec_zone() -> Denial {
+        Denial {
+            nsecs: vec![
+                nsec(
+                    "example.com",
+                    "b.a.example.com",
+                    &[Type::SOA, Type::NS, Type::NSEC],
+                ),
+                nsec("b.a.example.com", "*.w.example.com", &[Type::A, Type::NSEC]),
+                nsec("*.w.example.com", "z.example.com", &[Type::TXT, Type::NSEC]),
+                nsec("z.example.com", "example.com", &[Type::A, Type::NSEC]),
+            ],
+            nsec3s: vec![],
+        }
+    }
+
+    fn is_bogus(result: Result<(), Security>) -> bool {
+        matches!(result, Err(Security::Bogus(_)))
+    }
+
+    #[test]
+    fn nsec_denial() {
+        let zone = nsec_zone();
+        let (a, aaaa) = (Type::A as u16, Type::AAAA as u16);
+        // Names covered, and no wildcard at their closest encloser
+        assert!(zone.check(&name("c.example.com"), a, true).is_ok());
+        assert!(zone.check(&name("zz.example.com"), a, true).is_ok());
+        // Existing names
+        assert!(is_bogus(zone.check(&name("z.example.com"), a, true)));
+        assert!(is_bogus(zone.check(&name("z.example.com"), a, false)));
+        // No data
+        assert!(zone.check(&name("z.example.com"), aaaa, false).is_ok());
+        assert!(zone.check(&name("a.example.com"), a, false).is_ok());
+        // Covered by a wildcard, which lacks the type
+        assert!(zone.check(&name("x.w.example.com"), a, false).is_ok());
+        assert!(is_bogus(zone.check(
+            &name("x.w.example.com"),
+            Type::TXT as u16,
+            false
+        )));
+        assert!(is_bogus(zone.check(&name("x.w.example.com"), a, true)));
+        // Outside of the chain given
+        let partial = Denial {
+            nsecs: vec![nsec("z.example.com", "example.com", &[Type::A])],
+            nsec3s: vec![],
+        };
+        assert!(is_bogus(partial.check(&name("c.example.com"), a, true)));
+    }
+
+    #[test]
+    fn nsec_closest_encloser() {
+        let record = nsec("b.a.example.com", "*.w.example.com", &[]);
+        assert_eq!(
+            record.closest_encloser(&name("c.a.example.com")),
+            name("a.example.com")
+        );
+        assert_eq!(
+            record.closest_encloser(&name("c.example.com")),
+            name("example.com")
+        );
+    }
+
+    /// The NSEC3 chain of `names`, in zone example, with the RFC 5155 salt and `iterations`, each
+    /// having `types`, or those given with it
+    fn nsec3_zone(names: &[(&str, &[Type])], iterations: u16, opt_out: bool) -> Denial {
+        let salt = hex("aabbccdd").unwrap();
+        let mut hashes: Vec<(Vec<u8>, &[Type])> = names
+            .iter()
+            .map(|(owner, types)| (nsec3_hash(&name(owner), &salt, iterations), *types))
+            .collect();
+        hashes.sort_by(|a, b| a.0.cmp(&b.0));
+        let nsec3s = (0..hashes.len())
+            .map(|i| Nsec3 {
+                zone: name("example"),
+                hash: hashes[i].0.clone(),
+                opt_out,
+                iterations,
+                salt: salt.clone(),
+                next: hashes[(i + 1) % hashes.len()].0.clone(),
+                types: bitmap(hashes[i].1),
+            })
+            .collect();
+        Denial {
+            nsecs: vec![],
+            nsec3s,
+        }
+    }
+
+    const APEX: &[Type] = &[Type::SOA, Type::NS, Type::NSEC3PARAM];
+
+    #[test]
+    fn nsec3_denial() {
+        let zone = nsec3_zone(&[("example", APEX), ("a.example", &[Type::A])], 12, false);
+        let (a, aaaa) = (Type::A as u16, Type::AAAA as u16);
+        assert!(zone.check(&name("b.example"), a, true).is_ok());
+        assert!(zone.check(&name("c.b.example"), a, true).is_ok());
+        assert!(is_bogus(zone.check(&name("a.example"), a, true)));
+        assert!(zone.check(&name("a.example"), aaaa, false).is_ok());
+        assert!(is_bogus(zone.check(&name("a.example"), a, false)));
+        // Outside of the zone of the chain
+        assert!(is_bogus(zone.check(&name("b.other"), a, true)));
+    }
+
+    #[test]
+    fn nsec3_wildcard() {
+        let wildcard: &[Type] = &[Type::TXT];
+        let zone = nsec3_zone(&[("example", APEX), ("*.example", wildcard)], 0, false);
+        // Matched by the wildcard, which lacks A records
+        assert!(zone
+            .check(&name("b.example"), Type::A as u16, false)
+            .is_ok());
+        assert!(is_bogus(zone.check(
+            &name("b.example"),
+            Type::TXT as u16,
+            false
+        )));
+        assert!(is_bogus(zone.check(
+            &name("b.example"),
+            Type::A as u16,
+            true
+        )));
+    }
+
+    #[test]
+    fn nsec3_closest_encloser() {
+        let zone = nsec3_zone(&[("example", APEX), ("a.example", &[Type::A])], 1, false);
+        let (encloser, opt_out) = zone.closest_encloser(&name("x.y.a.example")).unwrap();
+        assert_eq!(encloser, name("a.example"));
+        assert!(!opt_out);
+        // Below a delegation
+        let zone = nsec3_zone(&[("example", APEX), ("d.example", &[Type::NS])], 1, false);
+        assert_eq!(zone.closest_encloser(&name("x.d.example")), None);
+    }
+
+    #[test]
+    fn nsec3_insecure() {
+        let zone = nsec3_zone(&[("example", APEX)], MAX_ITERATIONS + 1, false);
+        assert!(matches!(
+            zone.check(&name("b.example"), 1, true),
+            Err(Security::Insecure)
+        ));
+        assert!(matches!(
+            zone.check_ds(&name("b.example")),
+            Err(Security::Insecure)
+        ));
+        let zone = nsec3_zone(&[("example", APEX)], MAX_ITERATIONS, false);
+        assert!(zone.check(&name("b.example"), 1, true).is_ok());
+        // Maybe an unsigned delegation
+        let zone = nsec3_zone(&[("example", APEX)], 0, true);
+        assert!(matches!(
+            zone.check(&name("b.example"), 1, true),
+            Err(Security::Insecure)
+        ));
+        assert_eq!(zone.check_ds(&name("b.example")).ok(), Some(true));
+    }
+
+    #[test]
+    fn ds_denial() {
+        let delegation: &[Type] = &[Type::NS];
+        let zone = nsec3_zone(&[("example", APEX), ("d.example", delegation)], 0, false);
+        assert_eq!(zone.check_ds(&name("d.example")).ok(), Some(true));
+        assert!(matches!(
+            zone.check_ds(&name("example")),
+            Err(Security::Bogus(_))
+        ));
+        let secure = nsec3_zone(
+            &[("example", APEX), ("d.example", &[Type::NS, Type::DS])],
+            0,
+            false,
+        );
+        assert!(matches!(
+            secure.check_ds(&name("d.example")),
+            Err(Security::Bogus(_))
+        ));
+
+        let zone = nsec_zone();
+        assert_eq!(zone.check_ds(&name("z.example.com")).ok(), Some(false));
+        assert_eq!(zone.check_ds(&name("c.example.com")).ok(), Some(false));
+    }
+
+    /// DNSKEY RDATA of an Ed25519 zone key, with the public key of `secret`
+    fn dnskey(secret: &SecretKey, flags: u16) -> Dnskey {
+        let public = PublicKey::from(secret);
+        let mut rdata = flags.to_be_bytes().to_vec();
+        rdata.extend_from_slice(&[3, ED25519]);
+        rdata.extend_from_slice(public.as_bytes());
+        Dnskey::parse(&rdata).unwrap()
+    }
+
+    fn rr(owner: &str, addr: [u8; 4]) -> Rr {
+        Rr {
+            owner: name(owner),
+            ty: Type::A as u16,
+            class: 1,
+            ttl: 300,
+            rdata: addr.to_vec(),
+        }
+    }
+
+    /// A signature of `rrs`, as signed by `secret` for `key` of example.com, valid from `inception`
+    /// to `expiration`, for owners of `labels` labels
+    fn rrsig(secret: &SecretKey, key: &Dnskey, rrs: &[Rr], labels: u8, times: (u32, u32)) -> Rrsig {
+        let mut rdata = (Type::A as u16).to_be_bytes().to_vec();
+        rdata.extend_from_slice(&[ED25519, labels]);
+        rdata.extend_from_slice(&300u32.to_be_bytes());
+        rdata.extend_from_slice(&times.1.to_be_bytes());
+        rdata.extend_from_slice(&times.0.to_be_bytes());
+        rdata.extend_from_slice(&key.tag.to_be_bytes());
+        rdata.extend(wire(&name("example.com")));
+        let owner = match labels as usize {
+            n if n < rrs[0].owner.len() => wildcard(&rrs[0].owner[rrs[0].owner.len() - n..]),
+            _ => rrs[0].owner.clone(),
+        };
+        let mut data = rdata.clone();
+        let mut rdatas: Vec<&Vec<u8>> = rrs.iter().map(|rr| &rr.rdata).collect();
+        rdatas.sort();
+        for rdata in rdatas {
+            data.extend(wire(&owner));
+            data.extend_from_slice(&(Type::A as u16).to_be_bytes());
+            data.extend_from_slice(&1u16.to_be_bytes());
+            data.extend_from_slice(&300u32.to_be_bytes());
+            data.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            data.extend_from_slice(rdata);
+        }
+        let public = PublicKey::from(secret);
+        let signature = ExpandedSecretKey::from(secret).sign(&data, &public);
+        rdata.extend_from_slice(&signature.to_bytes());
+        Rrsig::parse(&rdata).unwrap()
+    }
+
+    #[test]
+    fn signatures() {
+        let secret = SecretKey::from_bytes(&[7; 32]).unwrap();
+        let keys = [dnskey(&secret, ZONE_KEY)];
+        let key = &keys[0];
+        let valid = (now() - 3600, now() + 3600);
+        let rrs = [
+            rr("www.example.com", [192, 0, 2, 2]),
+            rr("www.example.com", [192, 0, 2, 1]),
+        ];
+        let set = &rrsets(&rrs)[0];
+        let check = |sig: &Rrsig, keys: &[Dnskey]| verify(set, sig, keys);
+
+        let sig = rrsig(&secret, key, &rrs, 3, valid);
+        assert!(check(&sig, &keys).is_ok());
+        // Whatever the order of the records
+        let reversed = [
+            rr("www.example.com", [192, 0, 2, 1]),
+            rr("www.example.com", [192, 0, 2, 2]),
+        ];
+        assert!(verify(&rrsets(&reversed)[0], &sig, &keys).is_ok());
+
+        let mut bad = sig.clone();
+        bad.signature[0] ^= 1;
+        assert!(check(&bad, &keys).unwrap_err().contains("bad signature"));
+        let tampered = [
+            rr("www.example.com", [192, 0, 2, 2]),
+            rr("www.example.com", [192, 0, 2, 3]),
+        ];
+        assert!(verify(&rrsets(&tampered)[0], &sig, &keys).is_err());
+
+        let expired = rrsig(&secret, key, &rrs, 3, (now() - 7200, now() - 3600));
+        assert!(check(&expired, &keys).unwrap_err().contains("expired"));
+        let early = rrsig(&secret, key, &rrs, 3, (now() + 3600, now() + 7200));
+        assert!(check(&early, &keys).unwrap_err().contains("not yet valid"));
+        let labels = rrsig(&secret, key, &rrs, 4, valid);
+        assert!(check(&labels, &keys)
+            .unwrap_err()
+            .contains("not of its zone"));
+
+        // Another key, a revoked one, and one which is no zone key
+        let other = SecretKey::from_bytes(&[8; 32]).unwrap();
+        assert!(check(&sig, &[dnskey(&other, ZONE_KEY)]).is_err());
+        assert!(check(&sig, &[dnskey(&secret, ZONE_KEY | REVOKED)]).is_err());
+        assert!(check(&sig, &[dnskey(&secret, 0)]).is_err());
+    }
+
+    #[test]
+    fn wildcard_signatures() {
+        let secret = SecretKey::from_bytes(&[7; 32]).unwrap();
+        let key = dnskey(&secret, ZONE_KEY);
+        let valid = (now() - 3600, now() + 3600);
+        // Expanded from *.example.com
+        let rrs = [rr("www.example.com", [192, 0, 2, 1])];
+        let sig = rrsig(&secret, &key, &rrs, 2, valid);
+        assert!(verify(&rrsets(&rrs)[0], &sig, &[key]).is_ok());
+        let zone = nsec_zone();
+        assert!(zone.check_expansion(&name("www.example.com"), 2).is_ok());
+        assert!(zone.check_expansion(&name("z.example.com"), 2).is_err());
+    }
+
+    #[test]
+    fn key_tags_and_ds() {
+        let mut rdata = vec![1, 1, 3, ED25519];
+        rdata.extend(0..32);
+        let key = Dnskey::parse(&rdata).unwrap();
+        assert_eq!(key.tag, 62736);
+        assert!(key.signs());
+
+        let owner = name("example.com");
+        let ds = |digest_type, digest: &str| Ds {
+            key_tag: 62736,
+            algorithm: ED25519,
+            digest_type,
+            digest: hex(digest).unwrap(),
+        };
+        let sha256 = ds(
+            DIGEST_SHA256,
+            "0307DFD8A74F833F0D0E1962AE873BF91E49753BA1C2469930BF6703CD47C346",
+        );
+        assert!(sha256.matches(&owner, &key));
+        assert!(!sha256.matches(&name("example.net"), &key));
+        let sha384 = ds(
+            DIGEST_SHA384,
+            "8866B6F3FE3A68B70C23CA4F26FEFD0342A0E0E89F2E2D147F4DF10E9843644E420081C4B02B3B65B905005A3F239E82",
+        );
+        assert!(sha384.matches(&owner, &key));
+        let mut wrong = sha256.clone();
+        wrong.digest[0] ^= 1;
+        assert!(!wrong.matches(&owner, &key));
+        // SHA-1 digests are not supported
+        assert!(!ds(1, "00").supported());
+        // Protocol other than 3
+        assert!(Dnskey::parse(&[1, 1, 2, ED25519]).is_none());
+    }
+
+    #[test]
+    fn anchors() {
+        let mut anchors = HashMap::new();
+        parse_anchors(ROOT_ANCHORS, "root", &mut anchors).unwrap();
+        let root = &anchors[&Vec::<String>::new()];
+        assert_eq!(root.len(), 2);
+        assert!(root.iter().all(Anchor::supported));
+
+        let content = "; comment\nExample.COM. 3600 IN DS 62736 15 2 0307DFD8A74F833F0D0E1962AE873BF91E49753BA1C2469930BF6703CD47C346\n\nexample.net. DNSKEY 257 3 15 AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=\n";
+        parse_anchors(content, "anchors", &mut anchors).unwrap();
+        let mut rdata = vec![1, 1, 3, ED25519];
+        rdata.extend(0..32);
+        let key = Dnskey::parse(&rdata).unwrap();
+        assert!(anchors[&name("example.com")][0].trusts(&name("example.com"), &key));
+        assert!(anchors[&name("example.net")][0].trusts(&name("example.net"), &key));
+
+        let err = parse_anchors("example.com. A 192.0.2.1\n", "anchors", &mut anchors);
+        assert!(err.unwrap_err().to_string().contains("anchors:1"));
+    }
+}
@@ -0,0 +1,295 @@
+//! dnstap output, with --dnstap: a frame for each query received and each response sent, as
+//! protobuf `dnstap.Dnstap` messages, written in the bidirectional Frame Streams protocol to the
+//! Unix socket given, where e.g. `fstrm_capture -t protobuf:dnstap.Dnstap -u <socket> -w <file>`
+//! listens, the file being read by `dnstap-read`, or a collector such as the fluentd plugins.
+//!
+//! Messages are of type AUTH_QUERY and AUTH_RESPONSE, or CLIENT_QUERY and CLIENT_RESPONSE when
+//! names outside of the zones served are resolved, with --forward, --recursive or
+//! --client-groups. Each carries the address and port of the client, the transport, the time of
+//! the query and the DNS message itself; responses, one per message of zone transfers, also the
+//! time they were sent. The Dnstap messages carry --dnstap-identity, if given, and the version of
+//! this server.
+//!
+//! Frames are written by a task of their own. Those it cannot keep up with are dropped, as are
+//! those made while the socket is unavailable, connections being retried every few seconds, and
+//! counted by `dns_dnstap_dropped_total`, see the metrics module. When the server shuts down, the
+//! frames queued are written and the stream is stopped.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::sync::mpsc;
+
+/// Frames waiting to be written, beyond which they are dropped
+const QUEUE: usize = 10_000;
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// How long shutting down waits for the frames queued to be written
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+/// Frame Streams control frames
+const ACCEPT: u32 = 1;
+const START: u32 = 2;
+const STOP: u32 = 3;
+const READY: u32 = 4;
+const FINISH: u32 = 5;
+const FIELD_CONTENT_TYPE: u32 = 1;
+
+/// `Message.Type`, the response of each being the query plus one
+const AUTH_QUERY: u64 = 1;
+const CLIENT_QUERY: u64 = 5;
+
+tokio::task_local! {
+    static QUERY: Query;
+}
+
+#[derive(Clone)]
+pub struct Dnstap(Arc<Inner>);
+
+struct Inner {
+    identity: Option<String>,
+    /// The type of query messages
+    ty: u64,
+    /// Frames to write, an empty one stopping the writer
+    frames: mpsc::Sender<Vec<u8>>,
+    dropped: AtomicU64,
+    writer: Mutex<Option<tokio::task::JoinHandle<()>>>,
+}
+
+/// A query tapped, for its responses
+pub struct Query {
+    tap: Dnstap,
+    time: SystemTime,
+    remote: SocketAddr,
+    tcp: bool,
+}
+
+impl Dnstap {
+    /// Sends frames to the socket at `path`, of the queries of clients resolving names outside of
+    /// the zones served if `resolving`
+    #[cfg(unix)]
+    pub fn new(path: PathBuf, identity: Option<String>, resolving: bool) -> anyhow::Result<Self> {
+        let (frames, queue) = mpsc::channel(QUEUE);
+        let tap = Self(Arc::new(Inner {
+            identity,
+            ty: if resolving { CLIENT_QUERY } else { AUTH_QUERY },
+            frames,
+            dropped: AtomicU64::new(0),
+            writer: Mutex::new(None),
+        }));
+        let writer = tokio::spawn(run(path, queue, tap.clone()));
+        *tap.0.writer.lock().unwrap() = Some(writer);
+        Ok(tap)
+    }
+
+    #[cfg(not(unix))]
+    pub fn new(_: PathBuf, _: Option<String>, _: bool) -> anyhow::Result<Self> {
+        Err(anyhow::anyhow!("--dnstap is only supported on Unix"))
+    }
+
+    /// Sends a frame for the query in `buf`, returning it to be given to `scope`
+    pub fn query(&self, buf: &[u8], remote: SocketAddr, tcp: bool) -> Query {
+        let query = Query {
+            tap: self.clone(),
+            time: SystemTime::now(),
+            remote,
+            tcp,
+        };
+        self.send(query.message(self.0.ty, Some(buf), None));
+        query
+    }
+
+    /// Frames dropped so far
+    pub fn dropped(&self) -> u64 {
+        self.0.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Writes the frames queued and stops the stream, for the server to shut down
+    pub async fn close(&self) {
+        let writer = self.0.writer.lock().unwrap().take();
+        let closed = async {
+            if let (Some(writer), Ok(())) = (writer, self.0.frames.send(Vec::new()).await) {
+                let _ = writer.await;
+            }
+        };
+        if tokio::time::timeout(CLOSE_TIMEOUT, closed).await.is_err() {
+            log::warn!("Timed out writing the dnstap frames queued");
+        }
+    }
+
+    fn send(&self, message: Vec<u8>) {
+        let mut frame = Vec::with_capacity(message.len() + 64);
+        if let Some(identity) = &self.0.identity {
+            bytes(&mut frame, 1, identity.as_bytes());
+        }
+        let version = concat!("impl-cat-dns ", env!("CARGO_PKG_VERSION"));
+        bytes(&mut frame, 2, version.as_bytes());
+        bytes(&mut frame, 14, &message);
+        // Type MESSAGE
+        uint(&mut frame, 15, 1);
+        if self.0.frames.try_send(frame).is_err() {
+            self.0.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Query {
+    /// A `dnstap.Message` of type `ty`
+    fn message(&self, ty: u64, query: Option<&[u8]>, response: Option<&[u8]>) -> Vec<u8> {
+        let mut out = Vec::new();
+        uint(&mut out, 1, ty);
+        let (family, address) = match self.remote.ip() {
+            std::net::IpAddr::V4(ip) => (1, ip.octets().to_vec()),
+            std::net::IpAddr::V6(ip) => (2, ip.octets().to_vec()),
+        };
+        uint(&mut out, 2, family);
+        uint(&mut out, 3, if self.tcp { 2 } else { 1 });
+        bytes(&mut out, 4, &address);
+        uint(&mut out, 6, self.remote.port().into());
+        time(&mut out, 8, self.time);
+        if let Some(query) = query {
+            bytes(&mut out, 10, query);
+        }
+        if let Some(response) = response {
+            time(&mut out, 12, SystemTime::now());
+            bytes(&mut out, 14, response);
+        }
+        out
+    }
+}
+
+/// Runs `request` for `query`, if tapped, so that its responses are too
+pub async fn scope<F: Future>(query: Option<Query>, request: F) -> F::Output {
+    match query {
+        Some(query) => QUERY.scope(query, request).await,
+        None => request.await,
+    }
+}
+
+/// Sends a frame for `msg`, sent in response to the query being tapped, if any
+pub fn sent(msg: &[u8]) {
+    let _ = QUERY.try_with(|query| {
+        let message = query.message(query.tap.0.ty + 1, None, Some(msg));
+        query.tap.send(message);
+    });
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn uint(out: &mut Vec<u8>, field: u64, value: u64) {
+    varint(out, field << 3);
+    varint(out, value);
+}
+
+fn bytes(out: &mut Vec<u8>, field: u64, value: &[u8]) {
+    varint(out, field << 3 | 2);
+    varint(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+/// Seconds then nanoseconds, as fixed32, of `at` in fields `field` and the next
+fn time(out: &mut Vec<u8>, field: u64, at: SystemTime) {
+    let since = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    uint(out, field, since.as_secs());
+    varint(out, (field + 1) << 3 | 5);
+    out.extend_from_slice(&since.subsec_nanos().to_le_bytes());
+}
+
+/// Writes the frames of `queue` to the socket at `path`, reconnecting as needed
+#[cfg(unix)]
+async fn run(path: PathBuf, mut queue: mpsc::Receiver<Vec<u8>>, tap: Dnstap) {
+    loop {
+        match write(&path, &mut queue).await {
+            Ok(()) => return,
+            Err(e) => log::warn!("dnstap socket {}: {:#}", path.display(), e),
+        }
+        let retry = tokio::time::sleep(RETRY_INTERVAL);
+        tokio::pin!(retry);
+        loop {
+            tokio::select! {
+                _ = &mut retry => break,
+                frame = queue.recv() => match frame {
+                    Some(frame) if !frame.is_empty() => {
+                        tap.0.dropped.fetch_add(1, Ordering::Relaxed)
+                    }
+                    _ => return,
+                },
+            };
+        }
+    }
+}
+
+/// Connects to the socket at `path` and writes the frames of `queue`, until it is closed or
+/// stopped
+#[cfg(unix)]
+async fn write(path: &std::path::Path, queue: &mut mpsc::Receiver<Vec<u8>>) -> anyhow::Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut stream = tokio::net::UnixStream::connect(path).await?;
+    stream.write_all(&control(READY)).await?;
+    let mut header = [0; 8];
+    stream.read_exact(&mut header).await?;
+    let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    if header[..4] != [0; 4] || !(4..=512).contains(&len) {
+        return Err(anyhow::anyhow!("malformed Frame Streams control frame"));
+    }
+    let mut frame = vec![0; len as usize];
+    stream.read_exact(&mut frame).await?;
+    if frame[..4] != ACCEPT.to_be_bytes() {
+        return Err(anyhow::anyhow!("Frame Streams handshake not accepted"));
+    }
+    stream.write_all(&control(START)).await?;
+    log::info!("Sending dnstap frames to {}", path.display());
+
+    let mut stream = tokio::io::BufWriter::new(stream);
+    while let Some(mut frame) = queue.recv().await {
+        while !frame.is_empty() {
+            stream
+                .write_all(&(frame.len() as u32).to_be_bytes())
+                .await?;
+            stream.write_all(&frame).await?;
+            match queue.try_recv() {
+                Ok(next) => frame = next,
+                Err(_) => break,
+            }
+        }
+        if frame.is_empty() {
+            // Neither STOP nor FINISH carry any field
+            stream.write_all(&[0, 0, 0, 0, 0, 0, 0, 4]).await?;
+            stream.write_all(&STOP.to_be_bytes()).await?;
+            stream.flush().await?;
+            let mut finish = [0; 12];
+            stream.read_exact(&mut finish).await?;
+            if finish[8..] != FINISH.to_be_bytes() {
+                return Err(anyhow::anyhow!("Frame Streams stop not acknowledged"));
+            }
+            return Ok(());
+        }
+        stream.flush().await?;
+    }
+    Ok(())
+}
+
+/// A control frame of type `ty`, with our content type
+#[cfg(unix)]
+fn control(ty: u32) -> Vec<u8> {
+    let mut payload = ty.to_be_bytes().to_vec();
+    payload.extend_from_slice(&FIELD_CONTENT_TYPE.to_be_bytes());
+    payload.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+    payload.extend_from_slice(CONTENT_TYPE);
+    let mut frame = vec![0; 4];
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    frame
+}
@@ -0,0 +1,216 @@
+//! Records for the running containers of a Docker engine, from their labels:
+//!
+//! ```text
+//! docker run -l dns.name=app.example.com -l dns.srv=_http._tcp:8080 -l dns.ttl=60 app
+//! ```
+//!
+//! - `dns.name`: comma separated names given an A or AAAA record per address of the container,
+//!   on each network it is connected to. Containers without addresses of their own, such as those
+//!   using the host network, get none.
+//! - `dns.srv`: comma separated `<service>:<port>`, each giving an SRV record at
+//!   `<service>.<name>` targeting `<name>`, for each name
+//! - `dns.ttl`: TTL of these records, 60 by default
+//!
+//! Records of containers sharing a name are served together. Containers are listed anew whenever
+//! one starts or stops, or is connected to or disconnected from a network, as reported by the
+//! events API, so that records are removed with their containers. Only the Docker API on a Unix
+//! socket or plain HTTP is supported.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use crate::record::{Name, Record, RecordInner};
+use crate::store::Store;
+use crate::BaseStorage;
+
+const DEFAULT_TTL: u32 = 60;
+
+/// After which the engine ends the stream of events, the containers being listed anew, so that a
+/// connection gone silent is told from an engine without events
+const EVENTS_TIMEOUT: u64 = 300;
+
+/// URL encoded `{"type":["container","network"],"event":["start","die","connect","disconnect"]}`
+const EVENT_FILTERS: &str = "%7B%22type%22%3A%5B%22container%22%2C%22network%22%5D%2C%22event%22\
+    %3A%5B%22start%22%2C%22die%22%2C%22connect%22%2C%22disconnect%22%5D%7D";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Container {
+    id: String,
+    #[serde(default)]
+    labels: Option<std::collections::HashMap<String, String>>,
+    network_settings: Option<NetworkSettings>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct NetworkSettings {
+    #[serde(default)]
+    networks: Option<std::collections::HashMap<String, Network>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Network {
+    #[serde(default, rename = "IPAddress")]
+    ip_address: String,
+    #[serde(default, rename = "GlobalIPv6Address")]
+    global_ipv6_address: String,
+}
+
+fn parse_name(s: &str) -> anyhow::Result<Name> {
+    let s = s.trim().trim_end_matches('.');
+    if s.is_empty() {
+        return Err(anyhow::anyhow!("empty name"));
+    }
+    Ok(Name::from(crate::label::split_name(s)))
+}
+
+impl Container {
+    fn label(&self, key: &str) -> Option<&str> {
+        self.labels.as_ref()?.get(key).map(String::as_str)
+    }
+
+    /// Records of the container, empty without a `dns.name` label
+    fn records(&self) -> anyhow::Result<Vec<(Name, Record)>> {
+        let names = match self.label("dns.name") {
+            Some(names) => names
+                .split(',')
+                .map(parse_name)
+                .collect::<anyhow::Result<Vec<Name>>>()?,
+            None => return Ok(Vec::new()),
+        };
+        let ttl = match self.label("dns.ttl") {
+            Some(ttl) => ttl
+                .trim()
+                .parse()
+                .map_err(|e| anyhow::anyhow!("invalid dns.ttl {}: {}", ttl, e))?,
+            None => DEFAULT_TTL,
+        };
+
+        let mut addresses = Vec::new();
+        let networks = self
+            .network_settings
+            .iter()
+            .flat_map(|settings| settings.networks.iter().flatten());
+        for (_, network) in networks {
+            if let Ok(addr) = network.ip_address.parse::<Ipv4Addr>() {
+                addresses.push(RecordInner::A {
+                    addr: addr.octets(),
+                });
+            }
+            if let Ok(addr) = network.global_ipv6_address.parse::<Ipv6Addr>() {
+                addresses.push(RecordInner::AAAA {
+                    addr: addr.octets(),
+                });
+            }
+        }
+
+        let mut services = Vec::new();
+        for service in self.label("dns.srv").into_iter().flat_map(|s| s.split(',')) {
+            let (service, port) = service
+                .trim()
+                .rsplit_once(':')
+                .ok_or_else(|| anyhow::anyhow!("expected <service>:<port>, got {}", service))?;
+            let port: u16 = port
+                .parse()
+                .map_err(|e| anyhow::anyhow!("invalid port {}: {}", port, e))?;
+            services.push((crate::label::split_name(service), port));
+        }
+
+        let mut records = Vec::new();
+        for name in names {
+            for inner in addresses.iter() {
+                records.push((name.clone(), Record::new(inner.clone(), ttl)));
+            }
+            for (service, port) in services.iter() {
+                let mut owner = service.clone();
+                owner.extend(name.as_ref().iter().cloned());
+                let inner = RecordInner::SRV {
+                    priority: 0,
+                    weight: 0,
+                    port: *port,
+                    target: name.clone(),
+                };
+                records.push((Name::from(owner), Record::new(inner, ttl)));
+            }
+        }
+        Ok(records)
+    }
+}
+
+pub struct DockerSource {
+    /// Path of the Unix socket, or host:port, of the API
+    pub addr: String,
+}
+
+impl DockerSource {
+    fn name(&self) -> String {
+        format!("docker://{}", self.addr)
+    }
+
+    /// Records of the running containers. Containers whose labels are invalid are skipped.
+    pub async fn read(&self) -> anyhow::Result<BaseStorage> {
+        let response = crate::http::get(&self.addr, "/containers/json", &[]).await?;
+        if response.status != 200 {
+            return Err(response.error());
+        }
+        let containers: Vec<Container> = serde_json::from_slice(&response.body)?;
+
+        let mut base = BaseStorage::new();
+        for container in containers {
+            let records = match container.records() {
+                Ok(records) => records,
+                Err(e) => {
+                    let id = &container.id[..container.id.len().min(12)];
+                    log::warn!("{}: skipping container {}: {}", self.name(), id, e);
+                    continue;
+                }
+            };
+            for (name, record) in records {
+                let records = base.entry(name).or_default();
+                if !records.contains(&record) {
+                    records.push(record);
+                }
+            }
+        }
+        Ok(base)
+    }
+
+    /// Waits for the first container event from `since`, in seconds since epoch, at most
+    /// EVENTS_TIMEOUT
+    async fn wait(&self, since: &str) -> anyhow::Result<()> {
+        let until = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            + EVENTS_TIMEOUT;
+        let path = format!(
+            "/events?since={}&until={}&filters={}",
+            since, until, EVENT_FILTERS
+        );
+        let response = crate::http::get_first_chunk(&self.addr, &path, &[]).await?;
+        if response.status != 200 {
+            return Err(response.error());
+        }
+        Ok(())
+    }
+
+    /// Lists the containers anew on every event. Events from the time the containers were last
+    /// listed are waited for, so that none is missed in between.
+    pub async fn watch(self, store: Arc<Store>) {
+        let (source, store) = (&self, &store);
+        crate::retry::forever(&self.name(), || async move {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let since = format!("{}.{:09}", now.as_secs(), now.subsec_nanos());
+            store.set_source("docker", source.read().await?);
+            source.wait(&since).await
+        })
+        .await
+    }
+}
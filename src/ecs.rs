@@ -0,0 +1,171 @@
+//! EDNS Client Subnet (RFC 7871), with --ecs: queries forwarded upstream carry the subnet of the
+//! client, its address cut to --ecs-ipv4-prefix or --ecs-ipv6-prefix bits, so that upstream
+//! resolvers can answer with the servers closest to it without learning the address itself. A
+//! subnet given by the client is replaced with that of its own address, unless of prefix length
+//! 0, which asks for no subnet to be sent. Names resolved with --recursive are not concerned.
+//!
+//! Answers for a scope narrower than the whole address space are cached for the subnet of the
+//! client only, those of scope 0 or without subnet for every client, see the cache module.
+//! Answers are not relayed as is then, not to pass on the subnet option to clients which did not
+//! send one.
+
+use std::net::IpAddr;
+
+use crate::parser::{find_opt, options};
+
+/// OPTION-CODE of the client subnet, RFC 7871 Section 6
+const ECS_OPTION: u16 = 8;
+
+/// UDP payload size advertised in the OPT RRs added to queries
+const PAYLOAD_SIZE: u16 = 1232;
+
+/// Prefix lengths of the subnets sent, with --ecs
+#[derive(Debug, Clone, Copy)]
+pub struct Lengths {
+    pub v4: u8,
+    pub v6: u8,
+}
+
+/// The subnet of a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subnet {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl std::fmt::Display for Subnet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+/// `octets` with the bits past `len` cleared
+fn masked<const N: usize>(mut octets: [u8; N], len: u8) -> [u8; N] {
+    for (i, octet) in octets.iter_mut().enumerate() {
+        let kept = (len as usize).saturating_sub(i * 8).min(8);
+        *octet &= !(0xffu16 >> kept) as u8;
+    }
+    octets
+}
+
+impl Subnet {
+    pub fn of(ip: IpAddr, lengths: Lengths) -> Self {
+        // v4-mapped clients, of dual-stack sockets, as their v4 address
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            _ => ip,
+        };
+        match ip {
+            IpAddr::V4(v4) => Self {
+                addr: masked(v4.octets(), lengths.v4).into(),
+                len: lengths.v4,
+            },
+            IpAddr::V6(v6) => Self {
+                addr: masked(v6.octets(), lengths.v6).into(),
+                len: lengths.v6,
+            },
+        }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.len
+    }
+
+    /// The option carrying the subnet, RFC 7871 Section 6
+    fn option(&self) -> Vec<u8> {
+        self.scoped(0)
+    }
+
+    /// The option carrying the subnet in a response valid for the `scope` first bits of it, RFC
+    /// 7871 Section 7.2.1
+    pub fn scoped(&self, scope: u8) -> Vec<u8> {
+        let (family, octets): (u16, Vec<u8>) = match self.addr {
+            IpAddr::V4(v4) => (1, v4.octets().to_vec()),
+            IpAddr::V6(v6) => (2, v6.octets().to_vec()),
+        };
+        let address = &octets[..(self.len as usize).div_ceil(8)];
+        let mut option = Vec::new();
+        option.extend_from_slice(&ECS_OPTION.to_be_bytes());
+        option.extend_from_slice(&(4 + address.len() as u16).to_be_bytes());
+        option.extend_from_slice(&family.to_be_bytes());
+        option.extend_from_slice(&[self.len, scope]);
+        option.extend_from_slice(address);
+        option
+    }
+}
+
+/// `query` carrying `subnet`, in place of any subnet given by the client. None if the client
+/// asked for no subnet to be sent, or `query` cannot be parsed.
+pub fn with_subnet(query: &[u8], subnet: &Subnet) -> Option<Vec<u8>> {
+    let mut out;
+    match find_opt(query)? {
+        Some((_, rdata, end)) => {
+            let mut kept = Vec::new();
+            for (code, data) in options(&query[rdata..end]) {
+                match code {
+                    ECS_OPTION if data.get(2) == Some(&0) => return None,
+                    ECS_OPTION => (),
+                    _ => {
+                        kept.extend_from_slice(&code.to_be_bytes());
+                        kept.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                        kept.extend_from_slice(data);
+                    }
+                }
+            }
+            kept.extend(subnet.option());
+            out = query[..rdata - 2].to_vec();
+            out.extend_from_slice(&(kept.len() as u16).to_be_bytes());
+            out.extend(kept);
+            out.extend_from_slice(&query[end..]);
+        }
+        None => {
+            let option = subnet.option();
+            out = query.to_vec();
+            let arcount = u16::from_be_bytes([out[10], out[11]]).checked_add(1)?;
+            out[10..12].copy_from_slice(&arcount.to_be_bytes());
+            out.push(0); // Root
+            out.extend_from_slice(&(crate::parser::Type::OPT as u16).to_be_bytes());
+            out.extend_from_slice(&PAYLOAD_SIZE.to_be_bytes());
+            out.extend_from_slice(&[0, 0, 0, 0]); // Extended RCODE, version and flags
+            out.extend_from_slice(&(option.len() as u16).to_be_bytes());
+            out.extend(option);
+        }
+    }
+    Some(out)
+}
+
+/// The subnet given by the client of `query`, if any and well-formed
+pub fn subnet(query: &[u8]) -> Option<Subnet> {
+    let (_, rdata, end) = find_opt(query)??;
+    let (_, data) = options(&query[rdata..end]).find(|(code, _)| *code == ECS_OPTION)?;
+    let (family, len, address) = (data.get(..2)?, *data.get(2)?, data.get(4..)?);
+    if address.len() != (len as usize).div_ceil(8) {
+        return None;
+    }
+    let addr: IpAddr = match family {
+        [0, 1] if len <= 32 => {
+            let mut octets = [0; 4];
+            octets[..address.len()].copy_from_slice(address);
+            masked(octets, len).into()
+        }
+        [0, 2] if len <= 128 => {
+            let mut octets = [0; 16];
+            octets[..address.len()].copy_from_slice(address);
+            masked(octets, len).into()
+        }
+        _ => return None,
+    };
+    Some(Subnet { addr, len })
+}
+
+/// The SCOPE PREFIX-LENGTH of the subnet option of `response`, if any
+pub fn scope(response: &[u8]) -> Option<u8> {
+    let (_, rdata, end) = find_opt(response)??;
+    options(&response[rdata..end])
+        .find(|(code, _)| *code == ECS_OPTION)
+        .and_then(|(_, data)| data.get(3).copied())
+}
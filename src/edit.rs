@@ -0,0 +1,200 @@
+//! Runtime changes to RRsets, from the admin API and dynamic updates. They are kept in memory on
+//! top of the other layers or, with --persist, written back to the zone file holding the zone,
+//! which is then reloaded. Either way, the SOA serial of the zone is incremented unless the
+//! changes set the SOA themselves, so that secondaries pick them up.
+
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::parser::Type;
+use crate::record::{Name, Record, RecordInner};
+use crate::store::Store;
+use crate::{load, ZoneArgs};
+
+/// Replaces the RRset of a name and type, empty records meaning deletion
+pub type Change = (Name, Type, Vec<Record>);
+
+/// Checks that setting the RRset of `name` and `ty` in `zone` to `rrset`, next to the `existing`
+/// records of the name, keeps the zone sound: the apex keeps its single SOA and some NS, there is
+/// no SOA elsewhere and a CNAME stays alone at its name, but for its DNSSEC records
+pub fn check(
+    zone: &Name,
+    name: &Name,
+    ty: Type,
+    rrset: &[Record],
+    existing: &[Record],
+) -> Result<(), String> {
+    let at_apex = crate::acl::names_eq(name.as_ref(), zone.as_ref());
+    let dnssec = |ty: Type| ty == Type::RRSIG || ty == Type::NSEC;
+    let others = || {
+        existing
+            .iter()
+            .map(|r| r.inner.ty())
+            .filter(move |other| *other != ty && !dnssec(*other))
+    };
+    match ty {
+        Type::SOA if !at_apex => Err(format!("{} is not the apex of {}", name, zone)),
+        Type::SOA if rrset.len() != 1 => Err(format!("{} must have exactly one SOA", zone)),
+        Type::NS if at_apex && rrset.is_empty() => {
+            Err(format!("{} must keep at least one NS", zone))
+        }
+        Type::CNAME if rrset.len() > 1 => Err(format!("{} can only have one CNAME", name)),
+        Type::CNAME if !rrset.is_empty() && others().next().is_some() => {
+            Err(format!("{} has other data than a CNAME", name))
+        }
+        _ if !rrset.is_empty() && !dnssec(ty) && others().any(|t| t == Type::CNAME) => {
+            Err(format!("{} has a CNAME", name))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub enum Error {
+    /// The zone is not read from a zone file, so changes to it cannot be persisted
+    NoFile(Name),
+    /// The zone data does not load anymore with the changes, the zone file was restored
+    Invalid(anyhow::Error),
+    Io(anyhow::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoFile(zone) => {
+                write!(f, "{} is not read from a zone file, cannot persist", zone)
+            }
+            Error::Invalid(e) => write!(f, "{}", e),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Every file zones may be read from
+fn zone_files(args: &ZoneArgs) -> anyhow::Result<Vec<PathBuf>> {
+    let mut paths = args.base_files();
+    if let Some(dir) = &args.zones_dir {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let hidden = entry.file_name().to_string_lossy().starts_with('.');
+            if entry.file_type()?.is_file() && !hidden {
+                paths.push(entry.path());
+            }
+        }
+    }
+    Ok(paths)
+}
+
+/// Applies `changes` to `zone`, atomically. Callers hold `Store::lock_updates` from the moment
+/// they read the data the changes are based on.
+pub fn apply(
+    args: &ZoneArgs,
+    storage: &Store,
+    zone: &Name,
+    mut changes: Vec<Change>,
+    persist: bool,
+) -> Result<(), Error> {
+    let sets_soa = changes
+        .iter()
+        .any(|(name, ty, _)| name == zone && *ty == Type::SOA);
+    let soa = storage
+        .snapshot()
+        .base
+        .get(zone.as_ref())
+        .and_then(|records| records.iter().find(|r| r.inner.ty() == Type::SOA))
+        .cloned();
+    if let (false, Some(mut soa)) = (sets_soa, soa) {
+        if let RecordInner::SOA { serial, .. } = &mut soa.inner {
+            *serial = serial.wrapping_add(1);
+        }
+        changes.push((zone.clone(), Type::SOA, vec![soa]));
+    }
+
+    if !persist {
+        storage.edit(changes);
+        return Ok(());
+    }
+
+    for path in zone_files(args).map_err(Error::Io)? {
+        let mut base = load::read(&path, args.format).map_err(Error::Io)?;
+        let holds_zone = base
+            .get(zone.as_ref())
+            .map(|records| records.iter().any(|r| r.inner.ty() == Type::SOA))
+            .unwrap_or(false);
+        if !holds_zone {
+            continue;
+        }
+
+        for (name, ty, records) in changes {
+            let rrsets = base.entry(name.clone()).or_default();
+            // Keep the RRset where it was, so that rewritten files read the same
+            let pos = rrsets
+                .iter()
+                .position(|r| r.inner.ty() == ty)
+                .unwrap_or(rrsets.len());
+            rrsets.retain(|r| r.inner.ty() != ty);
+            rrsets.splice(pos.min(rrsets.len())..pos.min(rrsets.len()), records);
+            if rrsets.is_empty() {
+                base.remove(name.as_ref());
+            }
+        }
+
+        let original = std::fs::read(&path).map_err(|e| Error::Io(e.into()))?;
+        load::write(&path, args.format, &base).map_err(Error::Io)?;
+        if let Err(e) = crate::reload(args, storage) {
+            std::fs::write(&path, original).map_err(|e| Error::Io(e.into()))?;
+            return Err(Error::Invalid(e));
+        }
+        return Ok(());
+    }
+    Err(Error::NoFile(zone.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> Name {
+        Name::from(crate::label::split_name(s))
+    }
+
+    fn ns(to: &str) -> Record {
+        Record::new(RecordInner::NS { ns: name(to) }, 300)
+    }
+
+    fn cname(to: &str) -> Record {
+        Record::new(RecordInner::CNAME { to: name(to) }, 300)
+    }
+
+    fn a() -> Record {
+        Record::new(
+            RecordInner::A {
+                addr: [192, 0, 2, 1],
+            },
+            300,
+        )
+    }
+
+    #[test]
+    fn apex_keeps_soa_and_ns() {
+        let zone = name("example.com");
+        let apex = [ns("ns1.example.com"), ns("ns2.example.com")];
+        assert!(check(&zone, &zone, Type::SOA, &[], &apex).is_err());
+        assert!(check(&zone, &zone, Type::NS, &[], &apex).is_err());
+        assert!(check(&zone, &name("EXAMPLE.com"), Type::NS, &[], &apex).is_err());
+        assert!(check(&zone, &zone, Type::NS, &apex[..1], &apex).is_ok());
+        let below = name("sub.example.com");
+        assert!(check(&zone, &below, Type::NS, &[], &apex).is_ok());
+    }
+
+    #[test]
+    fn cname_is_exclusive() {
+        let zone = name("example.com");
+        let www = name("www.example.com");
+        assert!(check(&zone, &www, Type::CNAME, &[cname("a.")], &[a()]).is_err());
+        assert!(check(&zone, &www, Type::A, &[a()], &[cname("a.")]).is_err());
+        let two = [cname("a."), cname("b.")];
+        assert!(check(&zone, &www, Type::CNAME, &two, &[]).is_err());
+        assert!(check(&zone, &www, Type::CNAME, &[cname("b.")], &[cname("a.")]).is_ok());
+        assert!(check(&zone, &www, Type::A, &[], &[cname("a."), a()]).is_ok());
+    }
+}
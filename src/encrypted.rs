@@ -0,0 +1,293 @@
+//! Encrypted transports to upstream resolvers, given to --forward, zones of type `forward` and
+//! client groups as `tls://host[:port]` for DNS over TLS (RFC 7858), port 853 by default, or as
+//! `https://host[:port][/path]` for DNS over HTTPS (RFC 8484), POSTed to /dns-query by default:
+//!
+//! ```text
+//! --forward tls://9.9.9.9 --forward https://dns.google/dns-query
+//! ```
+//!
+//! Their certificates must be valid for the host given, a name or an IP address, and chain to
+//! the certificate authorities of the system, or of the file SSL_CERT_FILE names. Connections
+//! are kept open to be reused: those over TLS are each asked one query at a time, and kept idle
+//! for a while after, while queries over HTTPS are multiplexed over a single HTTP/2 connection.
+//! Names given are resolved with the resolver of the system when connecting.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+
+/// How long a connection over TLS is kept idle, as servers close theirs after a while
+const MAX_IDLE: Duration = Duration::from_secs(10);
+
+/// Idle connections over TLS kept per upstream resolver
+const MAX_IDLE_CONNECTIONS: usize = 8;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Configuration of TLS clients checking certificates against the certificate authorities of the
+/// system, those of `server` for the errors
+pub fn client_config(server: &str) -> anyhow::Result<rustls::ClientConfig> {
+    let mut roots = rustls::RootCertStore::empty();
+    let native = rustls_native_certs::load_native_certs();
+    roots.add_parsable_certificates(native.certs);
+    if roots.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No certificate authority of the system to check {} against",
+            server
+        ));
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    Ok(rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+/// An upstream resolver over TLS or HTTPS, and its connections
+pub struct Client {
+    /// As given
+    url: String,
+    host: String,
+    port: u16,
+    /// Of the requests over HTTPS, None over TLS
+    path: Option<String>,
+    name: ServerName<'static>,
+    connector: TlsConnector,
+    /// Connections over TLS, with when they were last used
+    idle: Mutex<Vec<(Instant, TlsStream<TcpStream>)>>,
+    /// The connection over HTTPS, once established
+    http: tokio::sync::Mutex<Option<h2::client::SendRequest<Bytes>>>,
+}
+
+impl std::fmt::Debug for Client {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.url)
+    }
+}
+
+impl Client {
+    /// Parses `url`, tls://host[:port] or https://host[:port][/path], None for other schemes
+    pub fn parse(url: &str) -> Option<anyhow::Result<Self>> {
+        let (rest, default_port, https) = match url.split_once("://") {
+            Some(("tls", rest)) => (rest, 853, false),
+            Some(("https", rest)) => (rest, 443, true),
+            _ => return None,
+        };
+        Some(Self::new(url, rest, default_port, https))
+    }
+
+    fn new(url: &str, rest: &str, default_port: u16, https: bool) -> anyhow::Result<Self> {
+        let (authority, path) = match rest.find('/') {
+            Some(i) if https => (&rest[..i], Some(rest[i..].to_owned())),
+            Some(_) => return Err(anyhow::anyhow!("Unexpected path in {}", url)),
+            None => (rest, https.then(|| "/dns-query".to_owned())),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse()?),
+            _ => (authority, default_port),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(anyhow::anyhow!("No host in {}", url));
+        }
+
+        let mut config = client_config(url)?;
+        if https {
+            config.alpn_protocols = vec![b"h2".to_vec()];
+        }
+
+        Ok(Self {
+            url: url.to_owned(),
+            host: host.to_owned(),
+            port,
+            path,
+            name: ServerName::try_from(host.to_owned())?,
+            connector: TlsConnector::from(Arc::new(config)),
+            idle: Mutex::new(Vec::new()),
+            http: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    async fn connect(&self) -> anyhow::Result<TlsStream<TcpStream>> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        Ok(self.connector.connect(self.name.clone(), stream).await?)
+    }
+
+    /// The response to `query`, checked to answer it as `answers` tells
+    pub async fn exchange(
+        &self,
+        query: &[u8],
+        answers: fn(&[u8], &[u8]) -> bool,
+    ) -> anyhow::Result<Vec<u8>> {
+        let response = match &self.path {
+            Some(path) => self.over_https(path, query).await?,
+            None => self.over_tls(query).await?,
+        };
+        if !answers(query, &response) {
+            return Err(anyhow::anyhow!("response does not match the query"));
+        }
+        Ok(response)
+    }
+
+    async fn over_tls(&self, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let idle = {
+            let mut idle = self.idle.lock().unwrap();
+            idle.retain(|(since, _)| since.elapsed() < MAX_IDLE);
+            idle.pop()
+        };
+        // The server may have closed it meanwhile, a new one is opened then
+        if let Some((_, stream)) = idle {
+            if let Ok(response) = self.ask_over_tls(stream, query).await {
+                return Ok(response);
+            }
+        }
+        let stream = self.connect().await?;
+        self.ask_over_tls(stream, query).await
+    }
+
+    /// Asks `query` over `stream`, which is kept to be reused once answered
+    async fn ask_over_tls(
+        &self,
+        mut stream: TlsStream<TcpStream>,
+        query: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut framed = Vec::with_capacity(query.len() + 2);
+        framed.extend_from_slice(&(query.len() as u16).to_be_bytes());
+        framed.extend_from_slice(query);
+        stream.write_all(&framed).await?;
+
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).await?;
+        let mut response = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut response).await?;
+
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push((Instant::now(), stream));
+        }
+        Ok(response)
+    }
+
+    async fn over_https(&self, path: &str, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let (sender, reused) = {
+            let mut http = self.http.lock().await;
+            match &*http {
+                Some(sender) => (sender.clone(), true),
+                None => {
+                    let sender = self.connect_http().await?;
+                    *http = Some(sender.clone());
+                    (sender, false)
+                }
+            }
+        };
+        match self.post(sender, path, query).await {
+            Ok(response) => Ok(response),
+            // The server may have closed the connection meanwhile, a new one is opened then
+            Err(_) if reused => {
+                let sender = self.connect_http().await?;
+                *self.http.lock().await = Some(sender.clone());
+                self.post(sender, path, query).await
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn connect_http(&self) -> anyhow::Result<h2::client::SendRequest<Bytes>> {
+        let stream = self.connect().await?;
+        if stream.get_ref().1.alpn_protocol() != Some(b"h2") {
+            return Err(anyhow::anyhow!("{} does not speak HTTP/2", self.url));
+        }
+        let (sender, connection) = h2::client::handshake(stream).await?;
+        let url = self.url.clone();
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                log::debug!("Connection to {} closed: {}", url, e);
+            }
+        });
+        Ok(sender)
+    }
+
+    async fn post(
+        &self,
+        sender: h2::client::SendRequest<Bytes>,
+        path: &str,
+        query: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        let authority = match self.port {
+            443 => self.host.clone(),
+            port => format!("{}:{}", self.host, port),
+        };
+        let uri = http::Uri::builder()
+            .scheme("https")
+            .authority(authority.as_str())
+            .path_and_query(path)
+            .build()?;
+        let request = http::Request::post(uri)
+            .header("accept", DNS_MESSAGE)
+            .header("content-type", DNS_MESSAGE)
+            .header("content-length", query.len())
+            .body(())?;
+        let mut sender = sender.ready().await?;
+        let (response, mut body) = sender.send_request(request, false)?;
+        body.send_data(Bytes::copy_from_slice(query), true)?;
+
+        let response = response.await?;
+        if response.status() != http::StatusCode::OK {
+            return Err(anyhow::anyhow!("HTTP {}", response.status()));
+        }
+        let mut body = response.into_body();
+        let mut message = Vec::new();
+        while let Some(data) = body.data().await {
+            let data = data?;
+            if message.len() + data.len() > u16::MAX as usize {
+                return Err(anyhow::anyhow!("response over {} bytes", u16::MAX));
+            }
+            message.extend_from_slice(&data);
+            body.flow_control().release_capacity(data.len())?;
+        }
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(url: &str) -> anyhow::Result<Client> {
+        Client::parse(url).unwrap()
+    }
+
+    #[test]
+    fn urls() {
+        let client = parse("tls://9.9.9.9").unwrap();
+        assert_eq!((client.host.as_str(), client.port), ("9.9.9.9", 853));
+        assert_eq!(client.path, None);
+        let client = parse("tls://[2620:fe::fe]:8853").unwrap();
+        assert_eq!((client.host.as_str(), client.port), ("2620:fe::fe", 8853));
+
+        let client = parse("https://dns.google/dns-query").unwrap();
+        assert_eq!((client.host.as_str(), client.port), ("dns.google", 443));
+        assert_eq!(client.path.as_deref(), Some("/dns-query"));
+        let client = parse("https://doh.example:8443").unwrap();
+        assert_eq!((client.host.as_str(), client.port), ("doh.example", 8443));
+        assert_eq!(client.path.as_deref(), Some("/dns-query"));
+        assert_eq!(client.url(), "https://doh.example:8443");
+
+        assert!(Client::parse("9.9.9.9").is_none());
+        assert!(Client::parse("quic://9.9.9.9").is_none());
+        assert!(parse("tls://9.9.9.9/dns-query").is_err());
+        assert!(parse("tls://").is_err());
+        assert!(parse("tls://9.9.9.9:dot").is_err());
+    }
+}
@@ -0,0 +1,195 @@
+//! Records stored in etcd, with keys laid out as in the consul module, one key per RRset under a
+//! common prefix: `<prefix><owner name>/<type>`, holding one record per line as "TTL RDATA":
+//!
+//! ```text
+//! etcdctl put dns/www.example.com/A "60 10.0.0.1"
+//! ```
+//!
+//! etcd is reached through the JSON gateway of its v3 API, served next to gRPC on its client
+//! port, over plain HTTP and without authentication. The keys under the prefix are read, then
+//! watched from the revision read, so that changes are served as soon as etcd commits them.
+
+use std::sync::Arc;
+
+use base64ct::{Base64, Encoding};
+use serde::Deserialize;
+
+use crate::store::Store;
+use crate::BaseStorage;
+
+/// Of a watch response not yet ended by a newline
+const MAX_MESSAGE: usize = 16 << 20;
+
+/// Revisions and other int64 fields are strings in JSON
+#[derive(Deserialize)]
+struct Header {
+    revision: String,
+}
+
+#[derive(Deserialize)]
+struct RangeResponse {
+    header: Header,
+    #[serde(default)]
+    kvs: Vec<KeyValue>,
+}
+
+/// Base64 key and value
+#[derive(Deserialize)]
+struct KeyValue {
+    key: String,
+    #[serde(default)]
+    value: String,
+}
+
+/// One message of the stream of a watch, a response or an error
+#[derive(Deserialize)]
+struct WatchMessage {
+    result: Option<WatchResponse>,
+    error: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct WatchResponse {
+    /// Such as when the revision watched from was compacted
+    #[serde(default)]
+    canceled: bool,
+    #[serde(default)]
+    events: Vec<serde_json::Value>,
+}
+
+pub struct EtcdSource {
+    /// host:port of the client API
+    pub addr: String,
+    pub prefix: String,
+}
+
+impl EtcdSource {
+    fn name(&self) -> String {
+        format!("etcd://{}/{}", self.addr, self.prefix)
+    }
+
+    /// The range of the keys under the prefix, as the base64 key and range_end of requests
+    fn range(&self) -> (String, String) {
+        let mut end = self.prefix.as_bytes().to_vec();
+        // The first key past those starting with the prefix, whose last byte is never 0xff in
+        // UTF-8, or from "\0" on for an empty prefix
+        match end.last_mut() {
+            Some(last) => *last += 1,
+            None => end.push(0),
+        }
+        let key = match self.prefix.as_bytes() {
+            b"" => &[0][..],
+            prefix => prefix,
+        };
+        (Base64::encode_string(key), Base64::encode_string(&end))
+    }
+
+    /// Reads every record, along with the revision read
+    async fn fetch(&self) -> anyhow::Result<(BaseStorage, i64)> {
+        let (key, range_end) = self.range();
+        let request = serde_json::json!({ "key": key, "range_end": range_end });
+        let body = serde_json::to_vec(&request)?;
+        let response = crate::http::post(&self.addr, "/v3/kv/range", &[], &body).await?;
+        if response.status != 200 {
+            return Err(response.error());
+        }
+        let range: RangeResponse = serde_json::from_slice(&response.body)?;
+
+        let mut entries = Vec::new();
+        for kv in range.kvs {
+            let decode = |data: &str| {
+                Base64::decode_vec(data).map_err(|_| anyhow::anyhow!("Invalid base64 from etcd"))
+            };
+            let key = String::from_utf8(decode(&kv.key)?)?;
+            entries.push((key, decode(&kv.value)?));
+        }
+        let base = crate::consul::records(&self.prefix, &entries, &self.name())?;
+        Ok((base, range.header.revision.parse()?))
+    }
+
+    pub async fn read(&self) -> anyhow::Result<BaseStorage> {
+        Ok(self.fetch().await?.0)
+    }
+
+    /// Waits until a key under the prefix changes past `revision`, or the watch is canceled
+    async fn wait(&self, revision: i64) -> anyhow::Result<()> {
+        let (key, range_end) = self.range();
+        let request = serde_json::json!({
+            "create_request": {
+                "key": key,
+                "range_end": range_end,
+                "start_revision": (revision + 1).to_string(),
+                // For a connection gone silent to be told from a quiet prefix
+                "progress_notify": true,
+            }
+        });
+        let body = serde_json::to_vec(&request)?;
+        let (response, chunks) =
+            crate::http::post_stream(&self.addr, "/v3/watch", &[], &body).await?;
+        let mut chunks = match chunks {
+            Some(chunks) if response.status == 200 => chunks,
+            _ => return Err(response.error()),
+        };
+
+        // Messages are delimited by newlines, whichever chunks they arrive in
+        let mut pending = Vec::new();
+        loop {
+            let chunk = chunks.next().await?;
+            pending.extend(chunk.ok_or_else(|| anyhow::anyhow!("Watch ended"))?);
+            while let Some(end) = pending.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = pending.drain(..=end).collect();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                let message: WatchMessage = serde_json::from_slice(&line)?;
+                if let Some(error) = message.error {
+                    return Err(anyhow::anyhow!("Watch failed: {}", error));
+                }
+                match message.result {
+                    Some(result) if result.canceled || !result.events.is_empty() => return Ok(()),
+                    _ => (),
+                }
+            }
+            if pending.len() > MAX_MESSAGE {
+                return Err(anyhow::anyhow!("Watch response over {} bytes", MAX_MESSAGE));
+            }
+        }
+    }
+
+    /// Reads the records anew whenever the watch reports an event, rather than applying the
+    /// events. The last records read are served while etcd is unreachable.
+    pub async fn watch(self, store: Arc<Store>) {
+        let (source, store) = (&self, &store);
+        crate::retry::forever(&self.name(), || async move {
+            let (base, revision) = source.fetch().await?;
+            store.set_source("etcd", base);
+            source.wait(revision).await
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(prefix: &str) -> (Vec<u8>, Vec<u8>) {
+        let source = EtcdSource {
+            addr: "localhost:2379".to_owned(),
+            prefix: prefix.to_owned(),
+        };
+        let (key, end) = source.range();
+        (
+            Base64::decode_vec(&key).unwrap(),
+            Base64::decode_vec(&end).unwrap(),
+        )
+    }
+
+    #[test]
+    fn prefix_range() {
+        assert_eq!(range("dns/"), (b"dns/".to_vec(), b"dns0".to_vec()));
+        assert_eq!(range("\u{e9}"), (vec![0xc3, 0xa9], vec![0xc3, 0xaa]));
+        // Every key
+        assert_eq!(range(""), (vec![0], vec![0]));
+    }
+}
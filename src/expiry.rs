@@ -0,0 +1,119 @@
+//! Monitoring of the expiration of the signatures served, so that signed zones do not go bogus
+//! unnoticed. Every hour, and once at startup, the zones signed online are signed again ahead of
+//! the queries for them, see the sign module, which renews the signatures about to expire, and
+//! the earliest expiration of the signatures of each signed zone, signed online or offline, is
+//! noted for the `dns_signature_expiry_seconds` metric, see the metrics module.
+//!
+//! Zones signed offline cannot be signed again by the server: a warning is logged once their
+//! signatures expire within three days, and an error once they expired.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::record::{Name, RecordInner, TtlBounds};
+use crate::sign::Signer;
+use crate::{RecordStorage, SharedStorage};
+
+const INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Zones signed offline are warned about once their signatures expire within this many seconds
+const WARN_BEFORE: u32 = 3 * 86400;
+
+/// The earliest expiration of the signatures of each signed zone, in seconds since the epoch
+#[derive(Default)]
+pub struct Expiries(Mutex<HashMap<Name, u32>>);
+
+impl Expiries {
+    /// Seconds until the earliest expiration of the signatures of each zone, negative once past
+    pub fn remaining(&self) -> Vec<(Name, i64)> {
+        let now = crate::dnssec::now();
+        let expiries = self.0.lock().unwrap();
+        expiries
+            .iter()
+            .map(|(zone, expiration)| (zone.clone(), expiration.wrapping_sub(now) as i32 as i64))
+            .collect()
+    }
+}
+
+/// Signs the zones of `signers` again every hour, and notes the earliest expiration of the
+/// signatures of the signed zones of `storage` in `expiries`
+pub async fn monitor(
+    storage: SharedStorage,
+    signers: HashMap<Name, Arc<Signer>>,
+    ttl_bounds: TtlBounds,
+    expiries: Arc<Expiries>,
+) {
+    loop {
+        let (storage, signers, expiries) = (storage.clone(), signers.clone(), expiries.clone());
+        let pass = tokio::task::spawn_blocking(move || {
+            check(&storage.snapshot(), &signers, &ttl_bounds, &expiries)
+        });
+        if let Err(e) = pass.await {
+            log::error!("Checking the expiration of signatures failed: {}", e);
+        }
+        tokio::time::sleep(INTERVAL).await;
+    }
+}
+
+fn check(
+    storage: &RecordStorage,
+    signers: &HashMap<Name, Arc<Signer>>,
+    ttl_bounds: &TtlBounds,
+    expiries: &Expiries,
+) {
+    let now = crate::dnssec::now();
+    let mut earliest = HashMap::new();
+    for (zone, signer) in signers.iter() {
+        let signed = signer.presign(storage, ttl_bounds);
+        if signed > 0 {
+            log::info!("Signed {} RRset(s) of {}", signed, zone);
+        }
+        if let Some(expiration) = signer.earliest_expiration() {
+            earliest.insert(zone.clone(), expiration);
+        }
+    }
+    for (zone, expiration) in offline(storage, signers) {
+        let remaining = expiration.wrapping_sub(now) as i32;
+        if remaining <= 0 {
+            log::error!(
+                "Signatures of {} expired, the zone must be signed again",
+                zone
+            );
+        } else if remaining < WARN_BEFORE as i32 {
+            log::warn!(
+                "Signatures of {} expire in {} hour(s), the zone must be signed again",
+                zone,
+                remaining / 3600
+            );
+        }
+        earliest.insert(zone, expiration);
+    }
+    *expiries.0.lock().unwrap() = earliest;
+}
+
+/// The earliest expiration of the RRSIG records of each zone of `storage` signed offline, those
+/// not signed by one of `signers`
+fn offline(storage: &RecordStorage, signers: &HashMap<Name, Arc<Signer>>) -> HashMap<Name, u32> {
+    let now = crate::dnssec::now();
+    let mut earliest: HashMap<Name, u32> = HashMap::new();
+    for (name, records) in storage.base.iter() {
+        let Some(zone) = storage.zones.find(name.as_ref()) else {
+            continue;
+        };
+        if signers.contains_key(&zone.origin) {
+            continue;
+        }
+        let expirations = records.iter().filter_map(|record| match record.inner {
+            RecordInner::RRSIG { expiration, .. } => Some(expiration),
+            _ => None,
+        });
+        for expiration in expirations {
+            let current = earliest.entry(zone.origin.clone()).or_insert(expiration);
+            if (expiration.wrapping_sub(now) as i32) < (current.wrapping_sub(now) as i32) {
+                *current = expiration;
+            }
+        }
+    }
+    earliest
+}
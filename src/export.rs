@@ -0,0 +1,102 @@
+//! `export` subcommand: prints one zone, as loaded from the zone files and databases given on the
+//! command line, in a canonical form. Names are sorted, and so are the records of each name, SOA
+//! first, so that exports of the same data are identical and diff cleanly.
+
+use crate::load::Format;
+use crate::parser::Type;
+use crate::record::{Name, Record};
+use crate::{load_base, store, Args, BaseStorage};
+
+pub async fn run(args: &Args, zone: &Name, format: Format) -> anyhow::Result<()> {
+    if format == Format::Auto {
+        return Err(anyhow::anyhow!("--format auto cannot be used for exports"));
+    }
+    print(format, load_zone(args, zone).await?)
+}
+
+/// The records of `zone`, as loaded from the zone data and databases of `args`
+pub async fn load_zone(args: &Args, zone: &Name) -> anyhow::Result<BaseStorage> {
+    let storage = store::Store::new(load_base(&args.zones, None, &mut Vec::new())?, None);
+    if let Some(path) = &args.sqlite {
+        let source = crate::sqlite::SqliteSource { path: path.clone() };
+        storage.set_source("sqlite", source.read().await?);
+    }
+    if let Some(conn) = &args.postgres {
+        let source = crate::postgres::PostgresSource {
+            conn: conn.clone(),
+            channel: args.postgres_channel.clone(),
+        };
+        storage.set_source("postgres", source.read().await?);
+    }
+    if let Some(conn) = &args.redis {
+        let source = crate::redis::RedisSource {
+            conn: conn.clone(),
+            prefix: args.redis_prefix.clone(),
+        };
+        storage.set_source("redis", source.read().await?);
+    }
+    if let Some(addr) = &args.consul {
+        let source = crate::consul::ConsulSource {
+            addr: addr.clone(),
+            prefix: args.consul_prefix.clone(),
+            token: args.consul_token.clone(),
+        };
+        storage.set_source("consul", source.read().await?);
+    }
+    if let Some(addr) = &args.etcd {
+        let source = crate::etcd::EtcdSource {
+            addr: addr.clone(),
+            prefix: args.etcd_prefix.clone(),
+        };
+        storage.set_source("etcd", source.read().await?);
+    }
+    if let Some(api) = &args.kubernetes {
+        let namespace = args.kubernetes_namespace.clone();
+        let source = crate::kubernetes::KubernetesSource::new(api, namespace)?;
+        storage.set_source("kubernetes", source.read().await?);
+    }
+    if let Some(addr) = &args.docker {
+        let source = crate::docker::DockerSource { addr: addr.clone() };
+        storage.set_source("docker", source.read().await?);
+    }
+    if let (false, Some(domain)) = (args.dhcp_leases.is_empty(), &args.dhcp_domain) {
+        let source = crate::dhcp::DhcpSource {
+            paths: args.dhcp_leases.clone(),
+            domain: domain.clone(),
+        };
+        storage.set_source("dhcp", source.read().await?);
+    }
+
+    let snapshot = storage.snapshot();
+    let records = snapshot
+        .base
+        .keys()
+        .find(|name| crate::acl::names_eq(name.as_ref(), zone.as_ref()))
+        .and_then(|apex| crate::xfr::zone_records(&snapshot, apex.as_ref()))
+        .ok_or_else(|| anyhow::anyhow!("no zone {} in the zone data", zone))?;
+    let mut base = BaseStorage::new();
+    // The SOA closing a transfer is left out
+    for (name, record) in records.iter().take(records.len() - 1) {
+        base.entry(Name::from(name.to_vec()))
+            .or_default()
+            .push((*record).clone());
+    }
+    Ok(base)
+}
+
+/// Prints `base` in `format`, each name with its records sorted
+pub fn print(format: Format, mut base: BaseStorage) -> anyhow::Result<()> {
+    for records in base.values_mut() {
+        records.sort_by_cached_key(|r: &Record| {
+            (
+                r.inner.ty() != Type::SOA,
+                r.inner.ty() as u16,
+                r.inner.rdata_text(),
+                r.ttl,
+            )
+        });
+    }
+
+    print!("{}", crate::load::to_string(format, &base)?);
+    Ok(())
+}
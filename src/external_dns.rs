@@ -0,0 +1,291 @@
+//! Provider for Kubernetes external-dns through its webhook API (`--provider=webhook`), so that
+//! the hostnames of Services and Ingresses are published in the zones served here. It is served
+//! on the address given with --external-dns, without authentication as external-dns sends none:
+//! bind it to localhost and run external-dns next to this server, or keep it on a private network.
+//!
+//! - `GET /` negotiates, the zones served being the domain filter
+//! - `GET /records` lists the A, AAAA, CNAME, TXT, NS, PTR and SRV RRsets of every zone served
+//! - `POST /records` applies the changes planned by external-dns, see the edit module for how
+//!   they are kept
+//! - `POST /adjustendpoints` drops the endpoints of other types or outside of the zones served
+//!
+//! Each endpoint is a whole RRset: set identifiers and provider specific properties are ignored.
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::http::{Request, Response};
+use crate::journal::zone_of;
+use crate::parser::Type;
+use crate::record::{Name, Record, RecordInner};
+use crate::{edit, Args, SharedStorage};
+
+const MEDIA_TYPE: &str = "application/external.dns.webhook+json;version=1";
+
+/// Of endpoints without one
+const DEFAULT_TTL: u32 = 300;
+
+const TYPES: [Type; 7] = [
+    Type::A,
+    Type::AAAA,
+    Type::CNAME,
+    Type::TXT,
+    Type::NS,
+    Type::PTR,
+    Type::SRV,
+];
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Endpoint {
+    dns_name: String,
+    #[serde(default)]
+    targets: Vec<String>,
+    record_type: String,
+    #[serde(default, rename = "recordTTL", skip_serializing_if = "Option::is_none")]
+    record_ttl: Option<u32>,
+    /// Labels, set identifier and the like, handed back as they were by /adjustendpoints
+    #[serde(flatten)]
+    other: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Older versions of external-dns send the field names capitalized, and null for no endpoints
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Changes {
+    #[serde(default, alias = "Create")]
+    create: Option<Vec<Endpoint>>,
+    #[serde(default, alias = "UpdateOld")]
+    update_old: Option<Vec<Endpoint>>,
+    #[serde(default, alias = "UpdateNew")]
+    update_new: Option<Vec<Endpoint>>,
+    #[serde(default, alias = "Delete")]
+    delete: Option<Vec<Endpoint>>,
+}
+
+#[derive(Serialize)]
+struct DomainFilter {
+    include: Vec<Name>,
+}
+
+fn json<T: Serialize>(value: &T) -> Response {
+    match serde_json::to_string_pretty(value) {
+        Ok(body) => Response {
+            content_type: Some(MEDIA_TYPE),
+            ..Response::json(200, body + "\n")
+        },
+        Err(e) => Response::error(500, e),
+    }
+}
+
+/// Target of `inner`, names being given without the final dot as external-dns does
+fn target(inner: &RecordInner) -> String {
+    match inner {
+        RecordInner::NS { ns: name }
+        | RecordInner::CNAME { to: name }
+        | RecordInner::PTR { ptr: name } => name.to_string(),
+        inner => inner.rdata_text(),
+    }
+}
+
+/// Target in master file syntax: external-dns quotes TXT targets, but not always
+fn rdata(ty: Type, target: &str) -> String {
+    match ty {
+        Type::TXT if !target.starts_with('"') => RecordInner::TXT {
+            content: target.to_owned(),
+        }
+        .rdata_text(),
+        Type::NS | Type::CNAME | Type::PTR | Type::SRV if !target.ends_with('.') => {
+            format!("{}.", target)
+        }
+        _ => target.to_owned(),
+    }
+}
+
+impl Endpoint {
+    fn name(&self) -> Name {
+        Name::from(crate::label::split_name(
+            self.dns_name.trim_end_matches('.'),
+        ))
+    }
+
+    fn ty(&self) -> anyhow::Result<Type> {
+        let ty: Type = self.record_type.parse()?;
+        if !TYPES.contains(&ty) {
+            return Err(anyhow::anyhow!("unsupported record type {:?}", ty));
+        }
+        Ok(ty)
+    }
+
+    fn records(&self) -> anyhow::Result<Vec<Record>> {
+        let ttl = self
+            .record_ttl
+            .filter(|ttl| *ttl > 0)
+            .unwrap_or(DEFAULT_TTL);
+        let ty = self.ty()?;
+        let rdata: Vec<String> = self.targets.iter().map(|t| rdata(ty, t)).collect();
+        let rows = rdata.iter().map(|rdata| {
+            (
+                self.dns_name.as_str(),
+                self.record_type.as_str(),
+                ttl,
+                rdata.as_str(),
+            )
+        });
+        let base = crate::zonefile::parse_rows(rows, "external-dns")?;
+        Ok(base.into_values().flatten().collect())
+    }
+}
+
+pub struct Provider {
+    args: Arc<Args>,
+    storage: SharedStorage,
+    persist: bool,
+}
+
+impl Provider {
+    pub fn new(args: Arc<Args>, storage: SharedStorage, persist: bool) -> Self {
+        Self {
+            args,
+            storage,
+            persist,
+        }
+    }
+
+    pub async fn handle(self: Arc<Self>, request: Request) -> Response {
+        let provider = self.clone();
+        let run = move || match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/") => provider.negotiate(),
+            ("GET", "/records") => provider.records(),
+            ("POST", "/records") => provider.apply(&request.body),
+            ("POST", "/adjustendpoints") => provider.adjust(&request.body),
+            (_, "/") | (_, "/records") => Response::error(405, "expected GET or POST"),
+            (_, "/adjustendpoints") => Response::error(405, "expected POST"),
+            _ => Response::error(404, "no such endpoint"),
+        };
+        match tokio::task::spawn_blocking(run).await {
+            Ok(response) => response,
+            Err(e) => Response::error(500, e),
+        }
+    }
+
+    fn negotiate(&self) -> Response {
+        let include = self
+            .storage
+            .snapshot()
+            .zones
+            .iter()
+            .map(|zone| zone.origin.clone())
+            .collect();
+        json(&DomainFilter { include })
+    }
+
+    fn records(&self) -> Response {
+        let snapshot = self.storage.snapshot();
+        let mut endpoints = Vec::new();
+        for (name, records) in snapshot.base.iter() {
+            if zone_of(&snapshot.base, name.as_ref()).is_none() {
+                continue;
+            }
+            for ty in TYPES {
+                let rrset: Vec<&Record> = records.iter().filter(|r| r.inner.ty() == ty).collect();
+                if let Some(first) = rrset.first() {
+                    endpoints.push(Endpoint {
+                        dns_name: name.to_string(),
+                        targets: rrset.iter().map(|r| target(&r.inner)).collect(),
+                        record_type: format!("{:?}", ty),
+                        record_ttl: Some(first.ttl),
+                        other: Default::default(),
+                    });
+                }
+            }
+        }
+        json(&endpoints)
+    }
+
+    /// Deletions first, then the RRsets created or updated, grouped by zone
+    fn apply(&self, body: &[u8]) -> Response {
+        let changes: Changes = match serde_json::from_slice(body) {
+            Ok(changes) => changes,
+            Err(e) => return Response::error(400, format!("expected changes: {}", e)),
+        };
+        let removed = changes
+            .delete
+            .into_iter()
+            .chain(changes.update_old)
+            .flatten();
+        let set = changes
+            .create
+            .into_iter()
+            .chain(changes.update_new)
+            .flatten();
+
+        let mut rrsets: Vec<edit::Change> = Vec::new();
+        for endpoint in removed {
+            let (name, ty) = match endpoint.ty() {
+                Ok(ty) => (endpoint.name(), ty),
+                Err(e) => return Response::error(422, format!("{}: {}", endpoint.dns_name, e)),
+            };
+            if !rrsets.iter().any(|(n, t, _)| *n == name && *t == ty) {
+                rrsets.push((name, ty, Vec::new()));
+            }
+        }
+        for endpoint in set {
+            let (name, ty, records) =
+                match endpoint.ty().and_then(|ty| Ok((ty, endpoint.records()?))) {
+                    Ok((ty, records)) => (endpoint.name(), ty, records),
+                    Err(e) => return Response::error(422, format!("{}: {}", endpoint.dns_name, e)),
+                };
+            match rrsets.iter_mut().find(|(n, t, _)| *n == name && *t == ty) {
+                Some((_, _, existing)) => existing.extend(records),
+                None => rrsets.push((name, ty, records)),
+            }
+        }
+
+        let _updating = self.storage.lock_updates();
+        let snapshot = self.storage.snapshot();
+        let mut zones: Vec<(Name, Vec<edit::Change>)> = Vec::new();
+        for (name, ty, records) in rrsets {
+            let zone = match zone_of(&snapshot.base, name.as_ref()) {
+                Some(zone) => Name::from(zone.to_vec()),
+                None => return Response::error(422, format!("no zone encloses {}", name)),
+            };
+            let change = (name, ty, records);
+            match zones.iter_mut().find(|(z, _)| *z == zone) {
+                Some((_, changes)) => changes.push(change),
+                None => zones.push((zone, vec![change])),
+            }
+        }
+        for (zone, changes) in zones {
+            log::info!(
+                "external-dns: {} RRset(s) changed in {}",
+                changes.len(),
+                zone
+            );
+            let applied = edit::apply(
+                &self.args.zones,
+                &self.storage,
+                &zone,
+                changes,
+                self.persist,
+            );
+            if let Err(e) = applied {
+                return crate::api::edit_error(e);
+            }
+        }
+        Response::empty(204)
+    }
+
+    fn adjust(&self, body: &[u8]) -> Response {
+        let mut endpoints: Vec<Endpoint> = match serde_json::from_slice(body) {
+            Ok(endpoints) => endpoints,
+            Err(e) => return Response::error(400, format!("expected endpoints: {}", e)),
+        };
+        let snapshot = self.storage.snapshot();
+        endpoints.retain(|endpoint| {
+            endpoint.ty().is_ok() && zone_of(&snapshot.base, endpoint.name().as_ref()).is_some()
+        });
+        json(&endpoints)
+    }
+}
@@ -0,0 +1,240 @@
+//! Failover of A and AAAA records whose endpoint is down, with a `check` on records, e.g.
+//!
+//! ```yaml
+//! www:
+//!   - {type: A, ttl: 30, addr: 192.0.2.1, check: "http:80/healthz"}
+//!   - {type: A, ttl: 30, addr: 192.0.2.2, check: "tcp:443"}
+//! ```
+//!
+//! Every --check-interval seconds, the address of each record with a check is probed, the check
+//! failing after --check-timeout seconds:
+//!
+//! - `tcp:<port>` opens a TCP connection to the port
+//! - `http:<port>[/<path>]` sends a GET request for the path, `/` by default, to the port, the
+//!   check passing if the response status is 2xx or 3xx
+//!
+//! An address fails after 3 checks failed in a row, and is back after 2 passed in a row, as
+//! logged. Until checked, addresses are taken as up. Records whose address failed are withheld
+//! from answers, unless every record of the RRset did, in which case the RRset is served whole,
+//! as withholding it would not help. This comes before geo constraints and weights, see the geo
+//! and weight modules. The state of each check is given by `dns_check_up`, labelled with the
+//! address, `target`, and the check, `check`, see the metrics module.
+//!
+//! Checks are not part of master files, nor of zone transfers, and zones signed offline cannot
+//! have any.
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::record::{Record, RecordInner};
+use crate::SharedStorage;
+
+/// Failed checks in a row after which an address fails
+const FALL: u32 = 3;
+/// Passed checks in a row after which a failed address is back
+const RISE: u32 = 2;
+
+/// How the endpoint of a record is checked
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Check {
+    Tcp(u16),
+    /// Port and path
+    Http(u16, String),
+}
+
+impl TryFrom<String> for Check {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let error = || format!("Expected tcp:<port> or http:<port>[/<path>], got {}", s);
+        let (kind, target) = s.split_once(':').ok_or_else(error)?;
+        match kind {
+            "tcp" => Ok(Self::Tcp(target.parse().map_err(|_| error())?)),
+            "http" => {
+                let (port, path) = match target.find('/') {
+                    Some(i) => (&target[..i], &target[i..]),
+                    None => (target, "/"),
+                };
+                let port = port.parse().map_err(|_| error())?;
+                Ok(Self::Http(port, path.to_owned()))
+            }
+            _ => Err(error()),
+        }
+    }
+}
+
+impl From<Check> for String {
+    fn from(check: Check) -> Self {
+        check.to_string()
+    }
+}
+
+impl std::fmt::Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(port) => write!(f, "tcp:{}", port),
+            Self::Http(port, path) => write!(f, "http:{}{}", port, path),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct State {
+    up: bool,
+    /// Checks in a row with the other outcome
+    streak: u32,
+}
+
+/// The state of the endpoints checked
+pub struct Failover {
+    interval: Duration,
+    timeout: Duration,
+    states: RwLock<HashMap<(IpAddr, Check), State>>,
+}
+
+impl Failover {
+    pub fn new(interval: u64, timeout: u64) -> anyhow::Result<Self> {
+        if interval == 0 || timeout == 0 {
+            return Err(anyhow::anyhow!(
+                "--check-interval and --check-timeout must be positive"
+            ));
+        }
+        Ok(Self {
+            interval: Duration::from_secs(interval),
+            timeout: Duration::from_secs(timeout),
+            states: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// The records of `records` to serve, without those whose endpoint is down, unless all are
+    pub fn available<'a>(&self, records: Vec<&'a Record>) -> Vec<&'a Record> {
+        if records.iter().all(|record| record.check.is_none()) {
+            return records;
+        }
+        let states = self.states.read().unwrap();
+        let down = |record: &Record| match (target(record), &record.check) {
+            (Some(ip), Some(check)) => states
+                .get(&(ip, check.clone()))
+                .is_some_and(|state| !state.up),
+            _ => false,
+        };
+        let up: Vec<&Record> = records
+            .iter()
+            .copied()
+            .filter(|record| !down(record))
+            .collect();
+        match up.is_empty() {
+            true => records,
+            false => up,
+        }
+    }
+
+    /// Each endpoint checked, and whether it is up
+    pub fn states(&self) -> Vec<(IpAddr, Check, bool)> {
+        let states = self.states.read().unwrap();
+        let mut states: Vec<(IpAddr, Check, bool)> = states
+            .iter()
+            .map(|((ip, check), state)| (*ip, check.clone(), state.up))
+            .collect();
+        states.sort_by_key(|(ip, check, _)| (*ip, check.to_string()));
+        states
+    }
+
+    fn record(&self, ip: IpAddr, check: Check, passed: bool) {
+        let mut states = self.states.write().unwrap();
+        let state = states.entry((ip, check.clone())).or_insert(State {
+            up: true,
+            streak: 0,
+        });
+        if passed == state.up {
+            state.streak = 0;
+            return;
+        }
+        state.streak += 1;
+        if state.streak < if state.up { FALL } else { RISE } {
+            return;
+        }
+        *state = State {
+            up: passed,
+            streak: 0,
+        };
+        match passed {
+            true => log::info!("{} of {} passed, serving its records again", check, ip),
+            false => log::warn!("{} of {} failed, withholding its records", check, ip),
+        }
+    }
+}
+
+/// The address of an A or AAAA record
+fn target(record: &Record) -> Option<IpAddr> {
+    match record.inner {
+        RecordInner::A { addr } => Some(addr.into()),
+        RecordInner::AAAA { addr } => Some(addr.into()),
+        _ => None,
+    }
+}
+
+/// Checks the endpoints of the records of `storage` every interval
+pub async fn monitor(storage: SharedStorage, failover: Arc<Failover>) {
+    let mut interval = tokio::time::interval(failover.interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let targets: HashSet<(IpAddr, Check)> = storage
+            .snapshot()
+            .base
+            .values()
+            .flatten()
+            .filter_map(|record| Some((target(record)?, record.check.clone()?)))
+            .collect();
+        failover
+            .states
+            .write()
+            .unwrap()
+            .retain(|target, _| targets.contains(target));
+
+        let mut checks = Vec::new();
+        for (ip, check) in targets {
+            let failover = failover.clone();
+            checks.push(tokio::spawn(async move {
+                let probe = probe(ip, &check);
+                let passed = match tokio::time::timeout(failover.timeout, probe).await {
+                    Ok(Ok(())) => true,
+                    Ok(Err(e)) => {
+                        log::debug!("{} of {} failed: {:#}", check, ip, e);
+                        false
+                    }
+                    Err(_) => {
+                        log::debug!("{} of {} timed out", check, ip);
+                        false
+                    }
+                };
+                failover.record(ip, check, passed);
+            }));
+        }
+        for check in checks {
+            let _ = check.await;
+        }
+    }
+}
+
+async fn probe(ip: IpAddr, check: &Check) -> anyhow::Result<()> {
+    match check {
+        Check::Tcp(port) => {
+            tokio::net::TcpStream::connect(SocketAddr::new(ip, *port)).await?;
+        }
+        Check::Http(port, path) => {
+            let addr = SocketAddr::new(ip, *port).to_string();
+            let reply = crate::http::get(&addr, path, &[]).await?;
+            if !(200..400).contains(&reply.status) {
+                return Err(reply.error());
+            }
+        }
+    }
+    Ok(())
+}
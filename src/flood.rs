@@ -0,0 +1,232 @@
+//! Mitigation of random-subdomain attacks, also known as water torture, with --nxdomain-flood:
+//! floods of queries for names made up under one zone, each answered NXDOMAIN, exhausting the
+//! upstream or authoritative servers of the zone, or this one.
+//!
+//! The names answered NXDOMAIN are counted under their zone, that of the SOA record of the
+//! response, or the parent of the name without one. A zone is under attack once more distinct
+//! names than --nxdomain-flood per second are, over 10 seconds. Queries for names under it are
+//! then limited to --nxdomain-flood-rate per second, those beyond dropped unanswered, unless the
+//! name is known to exist, in the zone data, or answered from the cache. Names dropped still count
+//! as nonexistent, and the zone is no longer under attack once no burst was for
+//! --nxdomain-flood-hold seconds.
+//!
+//! Attacks are logged as they are detected, and for alerting, see the metrics module:
+//!
+//! - `dns_nxdomain_flood_active`, labelled with the zone, 1 for each zone under attack
+//! - `dns_nxdomain_flood_detected_total`, attacks detected
+//! - `dns_nxdomain_flood_dropped_total`, queries dropped
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::record::Name;
+
+/// Over which the distinct names of each zone are counted
+const WINDOW: Duration = Duration::from_secs(10);
+/// Beyond which zones neither under attack nor counted lately are forgotten, and others ignored
+const MAX_ZONES: usize = 10_000;
+
+tokio::task_local! {
+    /// With the name asked for, until answered
+    static FLOOD: (Arc<Flood>, RefCell<Option<Vec<String>>>);
+}
+
+pub struct Flood {
+    /// Distinct names per window
+    threshold: usize,
+    /// Queries per second under a zone under attack
+    rate: f64,
+    hold: Duration,
+    zones: Mutex<HashMap<Name, Zone>>,
+    detected: AtomicU64,
+    dropped: AtomicU64,
+}
+
+#[derive(Default)]
+struct Zone {
+    /// Start of the current window
+    since: Option<Instant>,
+    /// Distinct nonexistent names within it
+    names: HashSet<Vec<String>>,
+    /// When the attack ends, unless another burst comes
+    until: Option<Instant>,
+    tokens: f64,
+    at: Option<Instant>,
+}
+
+impl Flood {
+    pub fn new(threshold: u32, rate: Option<u32>, hold: Duration) -> anyhow::Result<Self> {
+        let rate = rate.unwrap_or(threshold);
+        if threshold == 0 || rate == 0 {
+            return Err(anyhow::anyhow!(
+                "--nxdomain-flood and --nxdomain-flood-rate must be positive"
+            ));
+        }
+        Ok(Self {
+            threshold: threshold as usize * WINDOW.as_secs() as usize,
+            rate: rate as f64,
+            hold,
+            zones: Mutex::new(HashMap::new()),
+            detected: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Whether a query for `segs`, a name not known to exist, may be answered, taking a token
+    /// from the zone under attack it is under, if any
+    pub fn allow(&self, segs: &[String]) -> bool {
+        let segs: Vec<String> = segs.iter().map(|l| l.to_ascii_lowercase()).collect();
+        let now = Instant::now();
+        let mut zones = self.zones.lock().unwrap();
+        let origin = (0..segs.len()).map(|i| &segs[i..]).find(|origin| {
+            let zone = zones.get(*origin);
+            zone.and_then(|zone| zone.until)
+                .is_some_and(|until| until > now)
+        });
+        let origin = match origin {
+            Some(origin) => Name::from(origin.to_vec()),
+            None => return true,
+        };
+        let zone = zones.get_mut(&origin).expect("zone under attack");
+        let elapsed = zone
+            .at
+            .map_or(Duration::ZERO, |at| now.saturating_duration_since(at));
+        zone.tokens = (zone.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+        zone.at = Some(now);
+        if zone.tokens >= 1.0 {
+            zone.tokens -= 1.0;
+            return true;
+        }
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        self.count(&mut zones, origin, segs, now);
+        false
+    }
+
+    /// The zones under attack
+    pub fn attacked(&self) -> Vec<Name> {
+        let now = Instant::now();
+        let zones = self.zones.lock().unwrap();
+        let mut attacked: Vec<Name> = zones
+            .iter()
+            .filter(|(_, zone)| zone.until.is_some_and(|until| until > now))
+            .map(|(origin, _)| origin.clone())
+            .collect();
+        attacked.sort_by_key(|origin| origin.to_string());
+        attacked
+    }
+
+    /// Attacks detected so far
+    pub fn detected(&self) -> u64 {
+        self.detected.load(Ordering::Relaxed)
+    }
+
+    /// Queries dropped so far
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Counts `name`, answered NXDOMAIN, under the zone of `origin`
+    fn nxdomain(&self, origin: Name, name: Vec<String>) {
+        let mut zones = self.zones.lock().unwrap();
+        self.count(&mut zones, origin, name, Instant::now());
+    }
+
+    fn count(
+        &self,
+        zones: &mut HashMap<Name, Zone>,
+        origin: Name,
+        name: Vec<String>,
+        now: Instant,
+    ) {
+        if zones.len() >= MAX_ZONES && !zones.contains_key(&origin) {
+            zones.retain(|_, zone| {
+                zone.until.is_some_and(|until| until > now)
+                    || zone.since.is_some_and(|since| now < since + WINDOW)
+            });
+            if zones.len() >= MAX_ZONES {
+                return;
+            }
+        }
+        let zone = zones.entry(origin.clone()).or_default();
+        if zone.until.is_some_and(|until| until <= now) {
+            zone.until = None;
+        }
+        if zone.since.is_none_or(|since| now >= since + WINDOW) {
+            zone.since = Some(now);
+            zone.names.clear();
+        }
+        zone.names.insert(name);
+        if zone.names.len() <= self.threshold {
+            return;
+        }
+        if zone.until.is_none() {
+            log::warn!(
+                "Random-subdomain attack on {}: over {} nonexistent names in {:?}, limiting queries to {}/s",
+                origin,
+                self.threshold,
+                WINDOW,
+                self.rate
+            );
+            self.detected.fetch_add(1, Ordering::Relaxed);
+            zone.tokens = self.rate;
+            zone.at = Some(now);
+        }
+        zone.until = Some(now + self.hold);
+        zone.since = None;
+        zone.names.clear();
+    }
+}
+
+/// Runs `request`, for its NXDOMAIN responses to be counted by `flood`, if any
+pub async fn scope<F: Future>(flood: Option<Arc<Flood>>, request: F) -> F::Output {
+    match flood {
+        Some(flood) => FLOOD.scope((flood, RefCell::new(None)), request).await,
+        None => request.await,
+    }
+}
+
+/// Sets `segs` as the name asked for by the request, responses not repeating the question
+pub fn query(segs: &[String]) {
+    let _ = FLOOD.try_with(|(_, name)| {
+        *name.borrow_mut() = Some(segs.iter().map(|l| l.to_ascii_lowercase()).collect());
+    });
+}
+
+/// Counts the name asked for, if `msg`, sent in response to the request, is NXDOMAIN
+pub fn sent(msg: &[u8]) {
+    if msg.get(3).is_none_or(|b| b & 0x0f != 3) {
+        return;
+    }
+    let _ = FLOOD.try_with(|(flood, name)| {
+        let name = match name.borrow_mut().take() {
+            Some(name) => name,
+            None => return,
+        };
+        let soa = match crate::parser::parse_response(msg) {
+            Ok((_, resp)) => resp
+                .authorities
+                .iter()
+                .find(|rr| rr.ty == crate::parser::Type::SOA)
+                .map(|soa| soa.name.to_record_name()),
+            Err(_) => return,
+        };
+        let origin: Vec<String> = match soa {
+            Some(soa) => soa
+                .as_ref()
+                .iter()
+                .map(|l| l.to_ascii_lowercase())
+                .collect(),
+            None if name.len() > 1 => name[1..].to_vec(),
+            None => return,
+        };
+        // The SOA of another zone cannot stand for the zone of the name
+        if origin.is_empty() || !name.ends_with(&origin) || name == origin {
+            return;
+        }
+        flood.nxdomain(Name::from(origin), name);
+    });
+}
@@ -0,0 +1,677 @@
+//! Forwarding of the queries for names outside of the zones served to upstream resolvers, given
+//! with --forward, so that this server can be the only one its clients are configured with. The
+//! query is passed on as it was received and the answer relayed as is, with RA set, then cached
+//! as long as its records are of types we know, see the cache module. With --ecs the query
+//! carries the subnet of the client instead, see the ecs module, and with --trust-anchors the
+//! answer is validated first, see the dnssec module. Upstream resolvers are
+//! asked over UDP, then over TCP if the answer is truncated and the client came over TCP, or
+//! always or never with --forward-tcp-fallback. Those given as tls:// or https:// URLs are asked
+//! over TLS or HTTPS instead, see the encrypted module.
+//!
+//! With `--forward system`, the name servers of /etc/resolv.conf are asked, as it changes, see the
+//! resolv_conf module.
+//!
+//! Upstream resolvers are asked one after the other until one answers, starting with the first
+//! given, in turn, or with the fastest lately, depending on --forward-strategy. Those failing 3
+//! times in a row are marked down and asked last, until they answer a probe again. Each is waited
+//! for --forward-timeout milliseconds, and asked again up to --forward-retries times before the
+//! next one, after --forward-backoff milliseconds, doubling each time.
+//!
+//! Every upstream resolver is probed every 5 seconds, asked for the root NS RRset, so that its
+//! round trip time is known and one failing is marked down before clients wait for it. A probe
+//! answered SERVFAIL counts as a failure. Round trip times, queries and failures are exposed
+//! with --metrics, see the metrics module.
+//!
+//! Zones of type `forward` in --zone-config have upstream resolvers of their own, asked the same
+//! way for the names within them, those of the closest such zone. They take precedence over
+//! --forward and --recursive, and over the zones served if below them. Client groups may have
+//! upstream resolvers of their own instead of --forward, see the clients module. Both may set
+//! their own policy for asking them, overriding the flags above:
+//!
+//! ```yaml
+//! policy:
+//!   timeout: 500
+//!   retries: 2
+//!   backoff: 50
+//!   tcp-fallback: always
+//! ```
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+use crate::encrypted::Client;
+use crate::record::Name;
+
+/// Failures in a row after which an upstream resolver is down, and only asked after the others
+const MAX_FAILURES: u32 = 3;
+
+/// How often upstream resolvers are probed
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// An upstream resolver
+#[derive(Debug, Clone)]
+pub enum Upstream {
+    /// Asked over UDP, then over TCP as the policy tells
+    Plain(SocketAddr),
+    /// Asked over TLS or HTTPS, see the encrypted module
+    Encrypted(Arc<Client>),
+}
+
+impl PartialEq for Upstream {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Plain(a), Self::Plain(b)) => a == b,
+            (Self::Encrypted(a), Self::Encrypted(b)) => a.url() == b.url(),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Upstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Plain(addr) => addr.fmt(f),
+            Self::Encrypted(client) => f.write_str(client.url()),
+        }
+    }
+}
+
+/// Parses `ip:port`, an IP address alone for port 53, or a tls:// or https:// URL, see the
+/// encrypted module
+pub fn parse_upstream(s: &str) -> Result<Upstream, String> {
+    if let Some(client) = Client::parse(s) {
+        let client = client.map_err(|e| format!("Invalid upstream resolver {}: {}", s, e))?;
+        return Ok(Upstream::Encrypted(Arc::new(client)));
+    }
+    s.parse::<SocketAddr>()
+        .or_else(|_| s.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .map(Upstream::Plain)
+        .map_err(|_| {
+            format!(
+                "Expected IP, IP:PORT or a tls:// or https:// URL, got {}",
+                s
+            )
+        })
+}
+
+/// An upstream resolver given with --forward
+#[derive(Debug, Clone)]
+pub enum Target {
+    Upstream(Upstream),
+    /// The name servers of /etc/resolv.conf, see the resolv_conf module
+    System,
+}
+
+/// Parses `system`, or an upstream resolver as `parse_upstream` does
+pub fn parse_target(s: &str) -> Result<Target, String> {
+    match s {
+        "system" => Ok(Target::System),
+        _ => parse_upstream(s).map(Target::Upstream),
+    }
+}
+
+/// The upstream resolvers of `targets`, `system` standing for Target::System
+pub fn expand(targets: &[Target], system: &[SocketAddr]) -> Vec<Upstream> {
+    let mut servers = Vec::new();
+    for target in targets {
+        match target {
+            Target::Upstream(upstream) => servers.push(upstream.clone()),
+            Target::System => servers.extend(system.iter().copied().map(Upstream::Plain)),
+        }
+    }
+    servers
+}
+
+/// The only question of `msg`, as its lowercased QNAME in wire format, QTYPE and QCLASS
+fn question(msg: &[u8]) -> Option<(Vec<u8>, &[u8])> {
+    if msg.get(4..6)? != [0, 1] {
+        return None;
+    }
+    let mut end = 12;
+    loop {
+        let len = *msg.get(end)? as usize;
+        // Compression pointers have no place in the first name of a message
+        if len > 63 {
+            return None;
+        }
+        end += 1 + len;
+        if len == 0 {
+            break;
+        }
+    }
+    let name = msg.get(12..end)?.to_ascii_lowercase();
+    Some((name, msg.get(end..end + 4)?))
+}
+
+/// Whether `response` answers `query`: same ID, QR set and, if repeated, the same question, the
+/// case of the name aside, as the resolver module checks
+fn answers(query: &[u8], response: &[u8]) -> bool {
+    let repeated = response.get(4..6) != Some(&[0, 0]);
+    response.len() >= 12
+        && response[..2] == query[..2]
+        && response[2] & 0x80 != 0
+        && (!repeated || question(response).is_some_and(|q| Some(q) == question(query)))
+}
+
+async fn over_udp(upstream: SocketAddr, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let local: SocketAddr = match upstream {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(upstream).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0; u16::MAX as usize];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        // Anything else is stray, or spoofed
+        if answers(query, &buf[..len]) {
+            buf.truncate(len);
+            return Ok(buf);
+        }
+    }
+}
+
+async fn over_tcp(upstream: SocketAddr, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(upstream).await?;
+    let mut framed = Vec::with_capacity(query.len() + 2);
+    framed.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    framed.extend_from_slice(query);
+    stream.write_all(&framed).await?;
+
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).await?;
+    let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut buf).await?;
+    if !answers(query, &buf) {
+        return Err(anyhow::anyhow!("response does not match the query"));
+    }
+    Ok(buf)
+}
+
+/// When a truncated answer over UDP is asked again over TCP, with --forward-tcp-fallback
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TcpFallback {
+    Never,
+    /// For the clients which came over TCP, the others being answered truncated as well
+    TcpClients,
+    Always,
+}
+
+impl std::str::FromStr for TcpFallback {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Self::Never),
+            "tcp-clients" => Ok(Self::TcpClients),
+            "always" => Ok(Self::Always),
+            _ => Err(format!("Expected never, tcp-clients or always, got {}", s)),
+        }
+    }
+}
+
+/// How upstream resolvers are asked
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    /// How long one is waited for before asking again
+    pub timeout: Duration,
+    /// Times one is asked again before trying the next one
+    pub retries: u32,
+    /// Wait before asking again the first time, doubled each time
+    pub backoff: Duration,
+    pub tcp_fallback: TcpFallback,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(2),
+            retries: 0,
+            backoff: Duration::from_millis(100),
+            tcp_fallback: TcpFallback::TcpClients,
+        }
+    }
+}
+
+/// A policy overriding the flags, in --zone-config and --client-groups, in milliseconds
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PolicyConfig {
+    timeout: Option<u64>,
+    retries: Option<u32>,
+    backoff: Option<u64>,
+    tcp_fallback: Option<TcpFallback>,
+}
+
+impl Policy {
+    /// This policy, overridden by `config` if given
+    pub fn with(self, config: Option<&PolicyConfig>) -> Self {
+        let config = match config {
+            Some(config) => config,
+            None => return self,
+        };
+        Self {
+            timeout: config.timeout.map_or(self.timeout, Duration::from_millis),
+            retries: config.retries.unwrap_or(self.retries),
+            backoff: config.backoff.map_or(self.backoff, Duration::from_millis),
+            tcp_fallback: config.tcp_fallback.unwrap_or(self.tcp_fallback),
+        }
+    }
+}
+
+async fn exchange(
+    upstream: &Upstream,
+    query: &[u8],
+    tcp: bool,
+    fallback: TcpFallback,
+) -> anyhow::Result<Vec<u8>> {
+    // An ID of our own for each attempt, that of the query being chosen by the client, so that
+    // spoofed responses have to guess it as well as the port
+    let mut ours = query.to_vec();
+    ours[..2].copy_from_slice(&rand::random::<u16>().to_be_bytes());
+    let mut response = match upstream {
+        Upstream::Plain(addr) => over_plain(*addr, &ours, tcp, fallback).await?,
+        Upstream::Encrypted(client) => client.exchange(&ours, answers).await?,
+    };
+    response[..2].copy_from_slice(&query[..2]);
+    Ok(response)
+}
+
+async fn over_plain(
+    upstream: SocketAddr,
+    query: &[u8],
+    tcp: bool,
+    fallback: TcpFallback,
+) -> anyhow::Result<Vec<u8>> {
+    let response = over_udp(upstream, query).await?;
+    let truncated = response[2] & 0x02 != 0;
+    let fallback = match fallback {
+        TcpFallback::Never => false,
+        TcpFallback::TcpClients => tcp,
+        TcpFallback::Always => true,
+    };
+    if truncated && fallback {
+        return over_tcp(upstream, query).await;
+    }
+    Ok(response)
+}
+
+/// The answer of `upstream` to `query`, asking again on failure as `policy` tells
+async fn ask(
+    upstream: &Upstream,
+    query: &[u8],
+    tcp: bool,
+    policy: &Policy,
+) -> anyhow::Result<Vec<u8>> {
+    let mut backoff = policy.backoff;
+    let mut retries = policy.retries;
+    loop {
+        let exchange = exchange(upstream, query, tcp, policy.tcp_fallback);
+        let e = match tokio::time::timeout(policy.timeout, exchange).await {
+            Ok(Ok(response)) => return Ok(response),
+            Ok(Err(e)) => anyhow::anyhow!("{}: {}", upstream, e),
+            Err(_) => anyhow::anyhow!("{}: timed out", upstream),
+        };
+        if retries == 0 {
+            return Err(e);
+        }
+        log::debug!("Asking again in {:?}, {}", backoff, e);
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+        retries -= 1;
+    }
+}
+
+/// The answer of the first server of `servers` to answer `query`, with RA set. `tcp` tells
+/// whether the client came over TCP, and so can take an answer of any size.
+pub async fn forward(servers: &[SocketAddr], query: &[u8], tcp: bool) -> anyhow::Result<Vec<u8>> {
+    let mut last = anyhow::anyhow!("no server to ask");
+    for server in servers {
+        match ask(&Upstream::Plain(*server), query, tcp, &Policy::default()).await {
+            Ok(mut response) => {
+                response[3] |= 0x80;
+                return Ok(response);
+            }
+            Err(e) => last = e,
+        }
+        log::debug!("Forwarding failed: {}", last);
+    }
+    Err(last)
+}
+
+/// Which upstream resolver is asked first, with --forward-strategy
+#[derive(Debug, Clone, Copy)]
+pub enum Strategy {
+    /// In the order given
+    Failover,
+    /// Each in turn
+    RoundRobin,
+    /// The one answering the fastest lately
+    Fastest,
+}
+
+impl std::str::FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "failover" => Ok(Self::Failover),
+            "round-robin" => Ok(Self::RoundRobin),
+            "fastest" => Ok(Self::Fastest),
+            _ => Err(format!(
+                "Expected failover, round-robin or fastest, got {}",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Health {
+    /// Failures in a row
+    failures: u32,
+    down: bool,
+    /// Smoothed round trip time, RFC 6298 style
+    srtt: Option<Duration>,
+    /// Queries sent, probes included, and those which failed
+    sent: u64,
+    lost: u64,
+}
+
+/// Health of an upstream resolver, for --metrics
+pub struct Stats {
+    pub server: Upstream,
+    pub up: bool,
+    pub srtt: Option<Duration>,
+    pub sent: u64,
+    pub lost: u64,
+}
+
+/// The upstream resolvers of --forward, with their health
+pub struct Upstreams {
+    /// Replaced as /etc/resolv.conf changes with --forward system, see the resolv_conf module
+    servers: Mutex<Vec<(Upstream, Health)>>,
+    strategy: Strategy,
+    policy: Policy,
+    next: AtomicUsize,
+}
+
+impl Upstreams {
+    pub fn new(servers: Vec<Upstream>, strategy: Strategy, policy: Policy) -> Self {
+        let servers = servers
+            .into_iter()
+            .map(|server| (server, Health::default()))
+            .collect();
+        Self {
+            servers: Mutex::new(servers),
+            strategy,
+            policy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.servers.lock().unwrap().is_empty()
+    }
+
+    /// Replaces the upstream resolvers, keeping the health of those kept
+    pub fn set_servers(&self, servers: Vec<Upstream>) {
+        let mut current = self.servers.lock().unwrap();
+        let mut kept: HashMap<String, Health> = current
+            .drain(..)
+            .map(|(server, health)| (server.to_string(), health))
+            .collect();
+        *current = servers
+            .into_iter()
+            .map(|server| {
+                let health = kept.remove(&server.to_string()).unwrap_or_default();
+                (server, health)
+            })
+            .collect();
+    }
+
+    /// The servers in the order to ask them, those down last
+    fn order(&self) -> Vec<Upstream> {
+        let servers = self.servers.lock().unwrap();
+        let mut order: Vec<&(Upstream, Health)> = servers.iter().collect();
+        match self.strategy {
+            Strategy::Failover => (),
+            Strategy::RoundRobin => {
+                let first = self.next.fetch_add(1, Ordering::Relaxed) % order.len().max(1);
+                order.rotate_left(first);
+            }
+            Strategy::Fastest => {
+                // Servers not measured yet come first, to get measured
+                order.sort_by_key(|(_, health)| health.srtt.unwrap_or_default());
+            }
+        }
+        order.sort_by_key(|(_, health)| health.down);
+        order
+            .into_iter()
+            .map(|(server, _)| server.clone())
+            .collect()
+    }
+
+    fn record(&self, server: &Upstream, rtt: Option<Duration>) {
+        let mut servers = self.servers.lock().unwrap();
+        // It may have been removed meanwhile
+        let health = match servers.iter_mut().find(|(s, _)| s == server) {
+            Some((_, health)) => health,
+            None => return,
+        };
+        health.sent += 1;
+        match rtt {
+            Some(rtt) => {
+                if health.down {
+                    log::info!("Upstream resolver {} is back up", server);
+                }
+                health.failures = 0;
+                health.down = false;
+                health.srtt = Some(match health.srtt {
+                    Some(srtt) => (srtt * 7 + rtt) / 8,
+                    None => rtt,
+                });
+            }
+            None => {
+                health.lost += 1;
+                health.failures += 1;
+                if health.failures == MAX_FAILURES {
+                    log::warn!("Upstream resolver {} is down", server);
+                    health.down = true;
+                }
+            }
+        }
+    }
+
+    /// The answer of the first upstream resolver to answer `query`, with RA set. `tcp` tells
+    /// whether the client came over TCP, and so can take an answer of any size.
+    pub async fn forward(&self, query: &[u8], tcp: bool) -> anyhow::Result<Vec<u8>> {
+        let mut last = anyhow::anyhow!("no upstream resolver");
+        for server in self.order() {
+            let start = Instant::now();
+            match ask(&server, query, tcp, &self.policy).await {
+                Ok(mut response) => {
+                    self.record(&server, Some(start.elapsed()));
+                    response[3] |= 0x80;
+                    return Ok(response);
+                }
+                Err(e) => last = e,
+            }
+            self.record(&server, None);
+            log::debug!("Forwarding failed: {}", last);
+        }
+        Err(last)
+    }
+
+    pub fn stats(&self) -> Vec<Stats> {
+        let servers = self.servers.lock().unwrap();
+        servers
+            .iter()
+            .map(|(server, health)| Stats {
+                server: server.clone(),
+                up: !health.down,
+                srtt: health.srtt,
+                sent: health.sent,
+                lost: health.lost,
+            })
+            .collect()
+    }
+
+    /// Asks every upstream resolver for the root NS RRset every few seconds, recording how long
+    /// it takes to answer or that it failed to
+    pub async fn probe(self: Arc<Self>) {
+        let mut query = vec![0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(&(crate::parser::Type::NS as u16).to_be_bytes());
+        query.extend_from_slice(&1u16.to_be_bytes()); // IN
+
+        // A probe failing counts as one failure, however many times it would be asked
+        let policy = Policy {
+            retries: 0,
+            ..self.policy
+        };
+        loop {
+            tokio::time::sleep(PROBE_INTERVAL).await;
+            let servers: Vec<Upstream> = self
+                .servers
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(s, _)| s.clone())
+                .collect();
+            for server in servers {
+                let id: u16 = rand::random();
+                query[..2].copy_from_slice(&id.to_be_bytes());
+                let start = Instant::now();
+                let rtt = match ask(&server, &query, false, &policy).await {
+                    Ok(response) if response[3] & 0x0f != 2 => Some(start.elapsed()),
+                    _ => None,
+                };
+                self.record(&server, rtt);
+            }
+        }
+    }
+}
+
+/// Upstream resolvers of --forward, and those of the zones of type forward
+pub struct Forwarders {
+    default: Arc<Upstreams>,
+    /// By lowercased zone name
+    by_zone: HashMap<Name, Arc<Upstreams>>,
+}
+
+impl Forwarders {
+    pub fn new(default: Upstreams, by_zone: HashMap<Name, Upstreams>) -> Self {
+        let by_zone = by_zone
+            .into_iter()
+            .map(|(zone, upstreams)| {
+                let zone: Vec<String> = zone
+                    .as_ref()
+                    .iter()
+                    .map(|l| l.to_ascii_lowercase())
+                    .collect();
+                (Name::from(zone), Arc::new(upstreams))
+            })
+            .collect();
+        Self {
+            default: Arc::new(default),
+            by_zone,
+        }
+    }
+
+    /// The upstream resolvers of --forward
+    pub fn default_upstreams(&self) -> &Arc<Upstreams> {
+        &self.default
+    }
+
+    /// Whether there are no upstream resolvers at all
+    pub fn is_empty(&self) -> bool {
+        self.default.is_empty() && self.by_zone.is_empty()
+    }
+
+    /// The upstream resolvers for `name`, if any, given the origin of the zone served it belongs
+    /// to: those of the closest zone of type forward below that origin, or outside of the zones
+    /// served `default`, those of the client group, or else --forward
+    pub fn find(
+        &self,
+        name: &[String],
+        served: Option<&[String]>,
+        default: Option<&Arc<Upstreams>>,
+    ) -> Option<Arc<Upstreams>> {
+        let name: Vec<String> = name.iter().map(|l| l.to_ascii_lowercase()).collect();
+        let below = served.map_or(0, |origin| origin.len() + 1);
+        let closest =
+            (0..(name.len() + 1).saturating_sub(below)).find_map(|i| self.by_zone.get(&name[i..]));
+        let default = default.unwrap_or(&self.default);
+        match closest {
+            Some(upstreams) => Some(upstreams.clone()),
+            None if served.is_none() && !default.is_empty() => Some(default.clone()),
+            None => None,
+        }
+    }
+
+    /// The upstream resolvers of --forward, then those of each zone of type forward
+    pub fn iter(&self) -> impl Iterator<Item = (Option<&Name>, &Arc<Upstreams>)> {
+        let by_zone = self
+            .by_zone
+            .iter()
+            .map(|(zone, upstreams)| (Some(zone), upstreams));
+        std::iter::once((None, &self.default)).chain(by_zone)
+    }
+
+    /// Probes the upstream resolvers, see `Upstreams::probe`
+    pub fn probe(&self) {
+        let all = std::iter::once(&self.default).chain(self.by_zone.values());
+        // Those of --forward system may have none yet
+        for upstreams in all {
+            tokio::spawn(upstreams.clone().probe());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u16, flags: u8, name: &[u8], ty: u16) -> Vec<u8> {
+        let mut msg = id.to_be_bytes().to_vec();
+        msg.extend_from_slice(&[flags, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        msg.extend_from_slice(name);
+        msg.extend_from_slice(&ty.to_be_bytes());
+        msg.extend_from_slice(&1u16.to_be_bytes());
+        msg
+    }
+
+    #[test]
+    fn responses_match_queries() {
+        let name = b"\x03www\x07example\x03com\x00";
+        let query = message(0x1234, 0x01, name, 1);
+        assert!(answers(&query, &message(0x1234, 0x81, name, 1)));
+        // The case of the name may differ, RFC 4343
+        let upper = b"\x03WWW\x07Example\x03com\x00";
+        assert!(answers(&query, &message(0x1234, 0x81, upper, 1)));
+
+        // Another ID, a query, another name or type
+        assert!(!answers(&query, &message(0x1235, 0x81, name, 1)));
+        assert!(!answers(&query, &message(0x1234, 0x01, name, 1)));
+        let other = b"\x03www\x07example\x03net\x00";
+        assert!(!answers(&query, &message(0x1234, 0x81, other, 1)));
+        assert!(!answers(&query, &message(0x1234, 0x81, name, 28)));
+        // A pointer instead of the name, or a question cut short
+        assert!(!answers(&query, &message(0x1234, 0x81, b"\xc0\x0c", 1)));
+        assert!(!answers(&query, &message(0x1234, 0x81, name, 1)[..20]));
+        // Questions need not be repeated, as by this server
+        let mut bare = message(0x1234, 0x81, name, 1);
+        bare[5] = 0;
+        bare.truncate(12);
+        assert!(answers(&query, &bare));
+    }
+}
@@ -0,0 +1,140 @@
+//! Bulk record generation, the equivalent of BIND's $GENERATE
+
+use std::str::FromStr;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::label::split_name;
+use crate::record::Name;
+
+/// START-STOP[/STEP], bounds included
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Range {
+    start: u32,
+    stop: u32,
+    step: u32,
+}
+
+impl FromStr for Range {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow::anyhow!("Invalid range {}, expected START-STOP[/STEP]", s);
+        let (range, step) = match s.split_once('/') {
+            Some((range, step)) => (range, step.parse().map_err(|_| invalid())?),
+            None => (s, 1),
+        };
+        let (start, stop) = range.split_once('-').ok_or_else(invalid)?;
+        let (start, stop): (u32, u32) = (
+            start.trim().parse().map_err(|_| invalid())?,
+            stop.trim().parse().map_err(|_| invalid())?,
+        );
+        if start > stop || step == 0 {
+            return Err(invalid());
+        }
+        Ok(Self { start, stop, step })
+    }
+}
+
+impl TryFrom<String> for Range {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl Range {
+    pub fn iter(&self) -> impl Iterator<Item = u32> {
+        (self.start..=self.stop).step_by(self.step as usize)
+    }
+}
+
+/// Replaces `$` with the iterator value. `${offset[,width[,base]]}` adds `offset`, pads to `width`
+/// and prints in base d, o, x or X. `$$` and `\$` are a literal `$`.
+pub fn substitute(template: &str, i: u32) -> anyhow::Result<String> {
+    let mut ret = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'$') => {
+                ret.push('$');
+                chars.next();
+            }
+            '$' if chars.peek() == Some(&'$') => {
+                ret.push('$');
+                chars.next();
+            }
+            '$' if chars.peek() == Some(&'{') => {
+                chars.next();
+                let spec: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                let mut parts = spec.split(',');
+                let offset: i64 = parts.next().unwrap_or("0").trim().parse()?;
+                let width: usize = match parts.next() {
+                    Some(w) => w.trim().parse()?,
+                    None => 0,
+                };
+                let base = parts.next().unwrap_or("d").trim();
+                let val = i as i64 + offset;
+                if val < 0 {
+                    return Err(anyhow::anyhow!("${{{}}} yields a negative value", spec));
+                }
+                ret.push_str(&match base {
+                    "d" => format!("{:0width$}", val, width = width),
+                    "o" => format!("{:0width$o}", val, width = width),
+                    "x" => format!("{:0width$x}", val, width = width),
+                    "X" => format!("{:0width$X}", val, width = width),
+                    _ => return Err(anyhow::anyhow!("Unknown base {} in ${{{}}}", base, spec)),
+                });
+            }
+            '$' => ret.push_str(&i.to_string()),
+            c => ret.push(c),
+        }
+    }
+    Ok(ret)
+}
+
+fn substitute_value(value: &serde_yaml::Value, i: u32) -> anyhow::Result<serde_yaml::Value> {
+    use serde_yaml::Value;
+    Ok(match value {
+        Value::String(s) => Value::String(substitute(s, i)?),
+        Value::Sequence(seq) => Value::Sequence(
+            seq.iter()
+                .map(|v| substitute_value(v, i))
+                .collect::<anyhow::Result<_>>()?,
+        ),
+        Value::Mapping(map) => {
+            let mut ret = serde_yaml::Mapping::new();
+            for (k, v) in map.iter() {
+                ret.insert(k.clone(), substitute_value(v, i)?);
+            }
+            Value::Mapping(ret)
+        }
+        v => v.clone(),
+    })
+}
+
+/// A `$generate` entry: `record` is instantiated for each value of `range`, with `$` replaced in
+/// the owner name and in every string of the record
+#[derive(Debug, Deserialize)]
+pub struct Generator {
+    range: Range,
+    name: String,
+    record: serde_yaml::Value,
+}
+
+impl Generator {
+    pub fn expand<T: DeserializeOwned>(&self) -> anyhow::Result<Vec<(Name, T)>> {
+        self.range
+            .iter()
+            .map(|i| {
+                let name = substitute(&self.name, i)?;
+                let record: T = serde_yaml::from_value(substitute_value(&self.record, i)?)
+                    .map_err(|e| anyhow::anyhow!("$generate {} at {}: {}", self.name, i, e))?;
+                Ok((Name::from(split_name(&name)), record))
+            })
+            .collect()
+    }
+}
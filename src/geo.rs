@@ -0,0 +1,126 @@
+//! Answers depending on the location of the client, with `geo` constraints on records, e.g.
+//!
+//! ```yaml
+//! www:
+//!   - {type: A, ttl: 300, addr: 192.0.2.1, geo: eu}
+//!   - {type: A, ttl: 300, addr: 198.51.100.1}
+//! ```
+//!
+//! for clients in Europe to get the first record, and everyone else the second. Constraints are
+//! continent codes, such as `eu`, `na` or `as`, or ISO 3166 country codes, such as `de` or `us`,
+//! one or a list of them. Of the records of an RRset, those whose constraints match the client are
+//! served, or, if none does, those without constraints, or, if every record has some, all of them.
+//!
+//! The location of clients is looked up in the MaxMind GeoLite2 or GeoIP2 Country or City
+//! database given with --geoip, read at startup. Without it, as for addresses it does not know,
+//! such as private ones, no constraint matches. Queries from resolvers carrying the subnet of
+//! their client, RFC 7871, are answered for that subnet rather than the address of the resolver.
+//! The response carries the subnet back with the scope the answer is valid for, for the resolver
+//! to cache it accordingly: the prefix length given when constraints or locations, see the
+//! proximity module, were involved, 0 otherwise.
+//!
+//! Constraints are not part of master files, nor of zone transfers. Zones signed offline cannot
+//! have any, see `validate::served_in_part`: `sign` refuses them and they fail the load-time
+//! checks next to the signatures.
+
+use std::net::IpAddr;
+use std::path::Path;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::record::Record;
+
+pub struct GeoIp {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+/// The part of the records of the database we use, those of Country and City databases alike
+#[derive(Deserialize)]
+struct Location<'a> {
+    #[serde(borrow)]
+    continent: Option<Code<'a>>,
+    #[serde(borrow)]
+    country: Option<Country<'a>>,
+}
+
+#[derive(Deserialize)]
+struct Code<'a> {
+    code: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct Country<'a> {
+    iso_code: Option<&'a str>,
+}
+
+impl GeoIp {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let reader = maxminddb::Reader::open_readfile(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        log::info!(
+            "Locating clients with the {} database {}",
+            reader.metadata.database_type,
+            path.display()
+        );
+        Ok(Self { reader })
+    }
+
+    /// The continent and country of `ip`, lowercase, as far as known
+    pub fn regions(&self, ip: IpAddr) -> Vec<String> {
+        let location: Location = match self.reader.lookup(ip) {
+            Ok(location) => location,
+            Err(e) => {
+                log::debug!("No location for {}: {}", ip, e);
+                return Vec::new();
+            }
+        };
+        let continent = location.continent.and_then(|continent| continent.code);
+        let country = location.country.and_then(|country| country.iso_code);
+        continent
+            .into_iter()
+            .chain(country)
+            .map(|code| code.to_ascii_lowercase())
+            .collect()
+    }
+}
+
+/// The records of `records` to serve to a client in `regions`
+pub fn select<'a>(records: Vec<&'a Record>, regions: &[String]) -> Vec<&'a Record> {
+    if records.iter().all(|record| record.geo.is_empty()) {
+        return records;
+    }
+    let matching = |record: &&Record| {
+        record.geo.iter().any(|geo| {
+            regions
+                .iter()
+                .any(|region| geo.eq_ignore_ascii_case(region))
+        })
+    };
+    let selected: Vec<&Record> = records.iter().copied().filter(matching).collect();
+    if !selected.is_empty() {
+        return selected;
+    }
+    let unconstrained: Vec<&Record> = records
+        .iter()
+        .copied()
+        .filter(|record| record.geo.is_empty())
+        .collect();
+    match unconstrained.is_empty() {
+        true => records,
+        false => unconstrained,
+    }
+}
+
+/// Constraints, given as one or a list
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Geo {
+        One(String),
+        List(Vec<String>),
+    }
+    Ok(match Geo::deserialize(deserializer)? {
+        Geo::One(geo) => vec![geo],
+        Geo::List(geo) => geo,
+    })
+}
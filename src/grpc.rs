@@ -0,0 +1,430 @@
+//! gRPC admin API, for infrastructure preferring typed RPC to the HTTP admin API: the service
+//! `dns.admin.v1.Admin` of proto/admin.proto offers the operations of the api module, records
+//! being given as their TTL and RDATA in master file syntax rather than in the zone file layout,
+//! and streams the changes to the zones served, whatever they come from: zone files, databases,
+//! dynamic updates or either API.
+//!
+//! It is served on --grpc over TLS only, with the certificate chain and key of --grpc-cert and
+//! --grpc-key, to the clients presenting a certificate issued by a certificate authority of
+//! --grpc-client-ca, all equally trusted. E.g. with grpcurl:
+//!
+//! ```text
+//! grpcurl -cacert ca.pem -cert client.pem -key client.key -import-path proto -proto admin.proto \
+//!     -d '{"zone": "example.com"}' dns.example.com:50051 dns.admin.v1.Admin/WatchChanges
+//! ```
+//!
+//! Failures have the status codes matching the HTTP statuses of the admin API: INVALID_ARGUMENT
+//! for 400 and 422, NOT_FOUND for 404, FAILED_PRECONDITION for 409 and INTERNAL otherwise. A
+//! watch falling behind the changes ends with DATA_LOSS, for the client to read the zones anew,
+//! e.g. with a zone transfer, before watching again.
+
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use tonic::{Code, Request, Response, Status};
+
+use crate::api::{self, Admin};
+use crate::parser::Type;
+use crate::record::{Name, Record};
+use crate::store::ZoneChange;
+use crate::{Args, SharedStorage};
+
+mod proto {
+    tonic::include_proto!("dns.admin.v1");
+}
+
+use proto::admin_server::AdminServer;
+
+/// Changes sent to a watch but not yet received by its client
+const WATCH_BUFFERED: usize = 64;
+
+struct Service {
+    admin: Arc<Admin>,
+    storage: SharedStorage,
+}
+
+fn status(e: api::Error) -> Status {
+    let code = match e.status {
+        400 | 422 => Code::InvalidArgument,
+        404 => Code::NotFound,
+        409 => Code::FailedPrecondition,
+        _ => Code::Internal,
+    };
+    Status::new(code, e.message)
+}
+
+fn invalid(e: anyhow::Error) -> Status {
+    Status::invalid_argument(e.to_string())
+}
+
+/// The change to the RRset of `name` and `ty` in `zone`, `name` being "@" for the apex
+fn parse_change(
+    zone: &str,
+    name: &str,
+    ty: &str,
+    records: &[proto::Record],
+) -> anyhow::Result<api::Change> {
+    let zone = api::parse_name(zone)?;
+    let name = match name {
+        "@" => zone.clone(),
+        name => api::parse_name(name)?,
+    };
+    let ty: Type = ty.parse()?;
+
+    // One line each, not to be read as several records
+    if let Some(record) = records.iter().find(|r| r.rdata.contains(['\n', '\r'])) {
+        return Err(anyhow::anyhow!("invalid RDATA {:?}", record.rdata));
+    }
+    let (owner, mnemonic) = (name.to_absolute(), format!("{:?}", ty));
+    let rows = records
+        .iter()
+        .map(|r| (owner.as_str(), mnemonic.as_str(), r.ttl, r.rdata.as_str()));
+    let parsed: Vec<Record> = crate::zonefile::parse_rows(rows, "gRPC request")?
+        .into_values()
+        .flatten()
+        .collect();
+    if parsed.len() != records.len() {
+        return Err(anyhow::anyhow!("expected one record per RDATA"));
+    }
+    Ok(api::Change {
+        zone,
+        name,
+        ty,
+        records: parsed,
+    })
+}
+
+fn zone_change(change: &ZoneChange) -> proto::ZoneChange {
+    let records = |records: &[(Name, Record)]| {
+        records
+            .iter()
+            .map(|(name, record)| proto::ResourceRecord {
+                name: name.to_absolute(),
+                r#type: format!("{:?}", record.inner.ty()),
+                ttl: record.ttl,
+                rdata: record.inner.rdata_text(),
+            })
+            .collect()
+    };
+    proto::ZoneChange {
+        zone: change.zone.to_absolute(),
+        serial: change.serial,
+        removed: records(&change.removed),
+        added: records(&change.added),
+    }
+}
+
+impl Service {
+    /// Runs `operation` off the runtime, as admin operations block
+    async fn run<T: Send + 'static>(
+        &self,
+        operation: impl FnOnce(&Admin) -> Result<T, api::Error> + Send + 'static,
+    ) -> Result<Response<T>, Status> {
+        let admin = self.admin.clone();
+        match tokio::task::spawn_blocking(move || operation(&admin)).await {
+            Ok(result) => result.map(Response::new).map_err(status),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl proto::admin_server::Admin for Service {
+    async fn set_records(
+        &self,
+        request: Request<proto::SetRecordsRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let request = request.into_inner();
+        if request.records.is_empty() {
+            return Err(Status::invalid_argument(
+                "no records given, use DeleteRecords to remove an RRset",
+            ));
+        }
+        let change = parse_change(
+            &request.zone,
+            &request.name,
+            &request.r#type,
+            &request.records,
+        )
+        .map_err(invalid)?;
+        self.run(move |admin| admin.apply(change).map(|()| proto::Empty {}))
+            .await
+    }
+
+    async fn delete_records(
+        &self,
+        request: Request<proto::DeleteRecordsRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let request = request.into_inner();
+        let change =
+            parse_change(&request.zone, &request.name, &request.r#type, &[]).map_err(invalid)?;
+        self.run(move |admin| admin.apply(change).map(|()| proto::Empty {}))
+            .await
+    }
+
+    async fn list_versions(
+        &self,
+        request: Request<proto::ListVersionsRequest>,
+    ) -> Result<Response<proto::ListVersionsResponse>, Status> {
+        let zone = api::parse_name(&request.get_ref().zone).map_err(invalid)?;
+        self.run(move |admin| {
+            let versions = admin.versions(&zone)?;
+            let versions = versions.iter().map(|version| proto::Version {
+                id: version.id,
+                serial: version.serial,
+                time: version.time,
+            });
+            Ok(proto::ListVersionsResponse {
+                versions: versions.collect(),
+            })
+        })
+        .await
+    }
+
+    async fn rollback(
+        &self,
+        request: Request<proto::RollbackRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let zone = api::parse_name(&request.get_ref().zone).map_err(invalid)?;
+        let id = request.get_ref().id;
+        self.run(move |admin| admin.rollback(&zone, id).map(|()| proto::Empty {}))
+            .await
+    }
+
+    async fn present_challenge(
+        &self,
+        request: Request<proto::Challenge>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let proto::Challenge { fqdn, value } = request.into_inner();
+        let challenge = api::Challenge { fqdn, value };
+        self.run(move |admin| admin.acme(challenge, true).map(|()| proto::Empty {}))
+            .await
+    }
+
+    async fn cleanup_challenge(
+        &self,
+        request: Request<proto::Challenge>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let proto::Challenge { fqdn, value } = request.into_inner();
+        let challenge = api::Challenge { fqdn, value };
+        self.run(move |admin| admin.acme(challenge, false).map(|()| proto::Empty {}))
+            .await
+    }
+
+    async fn get_stats(
+        &self,
+        _: Request<proto::GetStatsRequest>,
+    ) -> Result<Response<proto::GetStatsResponse>, Status> {
+        let zones = self.admin.stats().into_iter().map(|zone| proto::ZoneStats {
+            zone: zone.zone,
+            queries: zone.queries,
+            answers: zone.answers,
+            nxdomain: zone.nxdomain,
+        });
+        Ok(Response::new(proto::GetStatsResponse {
+            zones: zones.collect(),
+        }))
+    }
+
+    type WatchChangesStream = ReceiverStream<Result<proto::ZoneChange, Status>>;
+
+    async fn watch_changes(
+        &self,
+        request: Request<proto::WatchChangesRequest>,
+    ) -> Result<Response<Self::WatchChangesStream>, Status> {
+        let zone = match request.get_ref().zone.as_str() {
+            "" => None,
+            zone => Some(api::parse_name(zone).map_err(invalid)?),
+        };
+        let mut changes = self.storage.subscribe();
+        let (tx, rx) = mpsc::channel(WATCH_BUFFERED);
+        tokio::spawn(async move {
+            loop {
+                let change = tokio::select! {
+                    change = changes.recv() => change,
+                    () = tx.closed() => return,
+                };
+                let message = match change {
+                    Ok(change) => match &zone {
+                        Some(zone)
+                            if !crate::acl::names_eq(zone.as_ref(), change.zone.as_ref()) =>
+                        {
+                            continue
+                        }
+                        _ => Ok(zone_change(&change)),
+                    },
+                    Err(broadcast::error::RecvError::Lagged(missed)) => Err(Status::data_loss(
+                        format!("{} change(s) missed, watch fell behind", missed),
+                    )),
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                let last = message.is_err();
+                if tx.send(message).await.is_err() || last {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// The TLS configuration of --grpc-cert, --grpc-key and --grpc-client-ca
+pub fn tls(args: &Args) -> anyhow::Result<ServerTlsConfig> {
+    let path = |path: &Option<std::path::PathBuf>, flag: &str| {
+        path.clone()
+            .ok_or_else(|| anyhow::anyhow!("--grpc requires --{}", flag))
+    };
+    let read = |path: &Path| {
+        std::fs::read(path).map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))
+    };
+    let cert = read(&path(&args.grpc_cert, "grpc-cert")?)?;
+    let key = read(&path(&args.grpc_key, "grpc-key")?)?;
+    let client_ca = read(&path(&args.grpc_client_ca, "grpc-client-ca")?)?;
+    Ok(ServerTlsConfig::new()
+        .identity(Identity::from_pem(cert, key))
+        .client_ca_root(Certificate::from_pem(client_ca)))
+}
+
+/// Serves the admin operations of `admin` on `listener`, with the changes of `storage`
+pub fn serve(
+    listener: TcpListener,
+    tls: ServerTlsConfig,
+    admin: Arc<Admin>,
+    storage: SharedStorage,
+) -> anyhow::Result<impl Future<Output = ()>> {
+    let mut server = Server::builder().tls_config(tls)?;
+    let incoming =
+        TcpIncoming::from_listener(listener, true, None).map_err(|e| anyhow::anyhow!(e))?;
+    let router = server.add_service(AdminServer::new(Service { admin, storage }));
+    Ok(async move {
+        if let Err(e) = router.serve_with_incoming(incoming).await {
+            log::error!("gRPC admin API failed: {}", e);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::admin_client::AdminClient;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use structopt::StructOpt;
+    use tonic::transport::{Channel, ClientTlsConfig};
+
+    struct Authority(rcgen::Certificate, KeyPair);
+
+    impl Authority {
+        fn new() -> Self {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            Self(params.self_signed(&key).unwrap(), key)
+        }
+
+        /// A certificate for `name` and its key
+        fn issue(&self, name: &str) -> Identity {
+            let key = KeyPair::generate().unwrap();
+            let params = CertificateParams::new(vec![name.to_owned()]).unwrap();
+            let cert = params.signed_by(&key, &self.0, &self.1).unwrap();
+            Identity::from_pem(cert.pem(), key.serialize_pem())
+        }
+    }
+
+    async fn client(
+        addr: std::net::SocketAddr,
+        ca: &Authority,
+        identity: Option<Identity>,
+    ) -> anyhow::Result<AdminClient<Channel>> {
+        let mut tls = ClientTlsConfig::new()
+            .ca_certificate(Certificate::from_pem(ca.0.pem()))
+            .domain_name("localhost");
+        if let Some(identity) = identity {
+            tls = tls.identity(identity);
+        }
+        let channel = Channel::from_shared(format!("https://{}", addr))?
+            .tls_config(tls)?
+            .connect()
+            .await?;
+        Ok(AdminClient::new(channel))
+    }
+
+    /// Whether the server refuses the client, during the handshake or, with TLS 1.3, right after
+    async fn refused(client: anyhow::Result<AdminClient<Channel>>) -> bool {
+        match client {
+            Ok(mut client) => client.get_stats(proto::GetStatsRequest {}).await.is_err(),
+            Err(_) => true,
+        }
+    }
+
+    #[tokio::test]
+    async fn mutual_tls() {
+        let ca = Authority::new();
+        let tls = ServerTlsConfig::new()
+            .identity(ca.issue("localhost"))
+            .client_ca_root(Certificate::from_pem(ca.0.pem()));
+        let zone =
+            "example.com. 3600 IN SOA ns.example.com. admin.example.com. 1 3600 600 86400 60";
+        let base = crate::zonefile::parse_standalone(zone, "test.zone").unwrap();
+        let storage = Arc::new(crate::store::Store::new(base, None));
+        let args = Arc::new(Args::from_iter(["impl-cat-dns"]));
+        let admin = Arc::new(Admin::new(args, storage.clone(), false, Arc::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, tls, admin, storage).unwrap());
+
+        assert!(refused(client(addr, &ca, None).await).await);
+        let other = Authority::new().issue("client");
+        assert!(refused(client(addr, &ca, Some(other)).await).await);
+
+        let mut client = client(addr, &ca, Some(ca.issue("client"))).await.unwrap();
+        let watch = proto::WatchChangesRequest {
+            zone: "example.com".to_owned(),
+        };
+        let mut changes = client.watch_changes(watch).await.unwrap().into_inner();
+        let set = |rdata: &str| proto::SetRecordsRequest {
+            zone: "example.com".to_owned(),
+            name: "www.example.com.".to_owned(),
+            r#type: "A".to_owned(),
+            records: vec![proto::Record {
+                ttl: 60,
+                rdata: rdata.to_owned(),
+            }],
+        };
+        client.set_records(set("10.0.0.1")).await.unwrap();
+        let change = changes.message().await.unwrap().unwrap();
+        assert_eq!(change.zone, "example.com.");
+        assert_eq!(change.serial, Some(2));
+        assert_eq!(change.removed, Vec::new());
+        assert_eq!(
+            change.added,
+            vec![proto::ResourceRecord {
+                name: "www.example.com.".to_owned(),
+                r#type: "A".to_owned(),
+                ttl: 60,
+                rdata: "10.0.0.1".to_owned(),
+            }]
+        );
+
+        let injected = set("10.0.0.2\nmail.example.com. 60 IN A 10.0.0.3");
+        let e = client.set_records(injected).await.unwrap_err();
+        assert_eq!(e.code(), Code::InvalidArgument);
+        let delete = proto::DeleteRecordsRequest {
+            zone: "example.org".to_owned(),
+            name: "@".to_owned(),
+            r#type: "A".to_owned(),
+        };
+        let e = client.delete_records(delete).await.unwrap_err();
+        assert_eq!(e.code(), Code::NotFound);
+        let versions = proto::ListVersionsRequest {
+            zone: "example.com".to_owned(),
+        };
+        let e = client.list_versions(versions).await.unwrap_err();
+        assert_eq!(e.code(), Code::NotFound);
+    }
+}
@@ -0,0 +1,47 @@
+//! Health endpoints of the admin API, see the api module, answered without its token for
+//! orchestrators and load balancers to probe:
+//!
+//! - `GET /healthz` succeeds as long as the process runs
+//! - `GET /readyz` succeeds once traffic may be sent to the server, failing with 503 while a
+//!   secondary zone is not transferred yet or expired, see the secondary module, or while every
+//!   upstream resolver of --forward, or of a zone of type forward, is down, see the forward module.
+//!   The body tells which check failed, e.g.
+//!   `{"status": "not ready", "checks": {"sockets": true, "zones": false, "upstreams": true}, "unserved": ["example.com"]}`
+//!
+//! The DNS sockets are bound, and the zone data loaded, before the admin API listens.
+
+use crate::http::{Request, Response};
+use crate::Options;
+
+/// The response to `request` if for a health endpoint
+pub fn handle(opts: &Options, request: &Request) -> Option<Response> {
+    let path = request.path.as_str();
+    if !matches!(path, "/healthz" | "/readyz") {
+        return None;
+    }
+    if request.method != "GET" {
+        return Some(Response::error(405, "expected GET"));
+    }
+    if path == "/healthz" {
+        return Some(Response::json(200, "{\"status\": \"ok\"}\n".to_owned()));
+    }
+
+    let unserved = opts.secondaries.unserved();
+    let upstreams = opts.forward.iter().all(|(_, upstreams)| {
+        let stats = upstreams.stats();
+        stats.is_empty() || stats.iter().any(|server| server.up)
+    });
+    let ready = unserved.is_empty() && upstreams;
+    let unserved: Vec<String> = unserved
+        .iter()
+        .map(|zone| serde_json::Value::from(zone.to_string()).to_string())
+        .collect();
+    let body = format!(
+        "{{\"status\": \"{}\", \"checks\": {{\"sockets\": true, \"zones\": {}, \"upstreams\": {}}}, \"unserved\": [{}]}}\n",
+        if ready { "ready" } else { "not ready" },
+        unserved.is_empty(),
+        upstreams,
+        unserved.join(", ")
+    );
+    Some(Response::json(if ready { 200 } else { 503 }, body))
+}
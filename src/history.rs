@@ -0,0 +1,160 @@
+//! Past versions of each zone, so that a bad change can be rolled back through the admin API.
+//! Whenever the served data of a zone changes, whatever the change came from, the new version is
+//! kept, up to --history of them per zone. With --history-dir, each version is also written there
+//! as a master file, `<zone>/<id>.zone`, and read back on startup.
+
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::load::Format;
+use crate::parser::Type;
+use crate::record::Name;
+use crate::BaseStorage;
+
+#[derive(Serialize)]
+pub struct Version {
+    /// Increasing within the zone
+    pub id: u64,
+    pub serial: Option<u32>,
+    /// When this version started being served, in seconds since epoch
+    pub time: u64,
+    #[serde(skip)]
+    pub records: BaseStorage,
+}
+
+pub struct History {
+    limit: usize,
+    dir: Option<PathBuf>,
+    zones: Mutex<HashMap<Name, VecDeque<Arc<Version>>>>,
+}
+
+/// Records of `zone` in `base`, glue included, without those of the zones below it
+pub fn zone_records(base: &BaseStorage, zone: &[String]) -> BaseStorage {
+    base.iter()
+        .filter(|(name, _)| crate::journal::zone_of(base, name.as_ref()) == Some(zone))
+        .map(|(name, records)| (name.clone(), records.clone()))
+        .collect()
+}
+
+fn soa_serial(zone: &Name, records: &BaseStorage) -> Option<u32> {
+    records
+        .get(zone.as_ref())?
+        .iter()
+        .find(|r| r.inner.ty() == Type::SOA)
+        .and_then(crate::journal::serial)
+}
+
+impl History {
+    /// Keeps `limit` versions of each zone, reading those saved in `dir` if given
+    pub fn new(limit: usize, dir: Option<PathBuf>) -> anyhow::Result<Self> {
+        let mut zones: HashMap<Name, VecDeque<Arc<Version>>> = HashMap::new();
+        if let Some(dir) = &dir {
+            std::fs::create_dir_all(dir)?;
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_dir() {
+                    continue;
+                }
+                let zone = Name::from(crate::label::split_name(
+                    &entry.file_name().to_string_lossy(),
+                ));
+                let mut versions = Vec::new();
+                for file in std::fs::read_dir(entry.path())? {
+                    let path = file?.path();
+                    let id = match path.file_stem().and_then(|s| s.to_str()).map(str::parse) {
+                        Some(Ok(id)) => id,
+                        _ => continue,
+                    };
+                    let records = crate::load::read(&path, Format::Zone)?;
+                    let time = std::fs::metadata(&path)?
+                        .modified()?
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0);
+                    versions.push(Arc::new(Version {
+                        id,
+                        serial: soa_serial(&zone, &records),
+                        time,
+                        records,
+                    }));
+                }
+                versions.sort_by_key(|v| v.id);
+                let skip = versions.len().saturating_sub(limit);
+                zones.insert(zone, versions.into_iter().skip(skip).collect());
+            }
+        }
+        Ok(Self {
+            limit,
+            dir,
+            zones: Mutex::new(zones),
+        })
+    }
+
+    fn path(dir: &Path, zone: &Name, id: u64) -> PathBuf {
+        dir.join(zone.to_string()).join(format!("{}.zone", id))
+    }
+
+    /// Keeps the new version of every zone that differs between `old` and `new`
+    pub fn record(&self, old: &BaseStorage, new: &BaseStorage) {
+        let mut changed: Vec<&[String]> = Vec::new();
+        for name in old.keys().chain(new.keys()) {
+            if old.get(name.as_ref()) == new.get(name.as_ref()) {
+                continue;
+            }
+            if let Some(zone) = crate::journal::zone_of(new, name.as_ref()) {
+                if !changed.contains(&zone) {
+                    changed.push(zone);
+                }
+            }
+        }
+
+        let mut zones = self.zones.lock().unwrap();
+        for zone in changed {
+            let zone = Name::from(zone.to_vec());
+            let records = zone_records(new, zone.as_ref());
+            let versions = zones.entry(zone.clone()).or_default();
+            if versions.back().map(|v| &v.records) == Some(&records) {
+                continue;
+            }
+            let version = Version {
+                id: versions.back().map(|v| v.id + 1).unwrap_or(1),
+                serial: soa_serial(&zone, &records),
+                time: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+                records,
+            };
+            if let Some(dir) = &self.dir {
+                let path = Self::path(dir, &zone, version.id);
+                let written = std::fs::create_dir_all(path.parent().unwrap())
+                    .map_err(anyhow::Error::from)
+                    .and_then(|()| crate::load::write(&path, Format::Zone, &version.records));
+                if let Err(e) = written {
+                    log::error!("Could not save version {} of {}: {}", version.id, zone, e);
+                }
+            }
+            versions.push_back(Arc::new(version));
+            while versions.len() > self.limit {
+                let dropped = versions.pop_front().unwrap();
+                if let Some(dir) = &self.dir {
+                    let _ = std::fs::remove_file(Self::path(dir, &zone, dropped.id));
+                }
+            }
+        }
+    }
+
+    /// Versions of `zone`, oldest first
+    pub fn versions(&self, zone: &[String]) -> Vec<Arc<Version>> {
+        self.zones
+            .lock()
+            .unwrap()
+            .get(zone)
+            .map(|versions| versions.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
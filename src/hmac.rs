@@ -0,0 +1,25 @@
+//! HMAC (RFC 2104) over the sha2 digests, as used by TSIG and SCRAM authentication
+
+use sha2::digest::generic_array::typenum::Unsigned;
+use sha2::digest::{BlockInput, Output};
+use sha2::Digest;
+
+/// MAC of the concatenation of `data`
+pub fn hmac<D: Digest + BlockInput>(key: &[u8], data: &[&[u8]]) -> Output<D> {
+    let mut block = vec![0u8; <D as BlockInput>::BlockSize::USIZE];
+    if key.len() > block.len() {
+        let digest = D::digest(key);
+        block[..digest.len()].copy_from_slice(&digest);
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = D::new();
+    inner.update(block.iter().map(|b| b ^ 0x36).collect::<Vec<_>>());
+    for part in data {
+        inner.update(part);
+    }
+    let mut outer = D::new();
+    outer.update(block.iter().map(|b| b ^ 0x5c).collect::<Vec<_>>());
+    outer.update(inner.finalize());
+    outer.finalize()
+}
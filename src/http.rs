@@ -0,0 +1,486 @@
+//! Just enough of an HTTP/1.1 server for the admin API and the external-dns webhook: one request
+//! per connection, bodies delimited by Content-Length. Also just enough of a client for the
+//! sources reading plain HTTP APIs, and the signers of remote keys: GET and POST requests, one
+//! per connection, over TCP or Unix sockets.
+
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{TcpListener, TcpStream};
+
+const MAX_BODY: usize = 1 << 20;
+const MAX_HEADERS: usize = 100;
+/// Of the request, status and header lines, and of the size lines of chunks
+const MAX_LINE: usize = 8 << 10;
+/// Of the bodies of responses to the client, blocklists among them
+const MAX_REPLY: usize = 64 << 20;
+
+/// How long a client may take to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long connecting to a server may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a server may keep us waiting for more of its response: longer than Consul blocking
+/// queries and Docker event streams last, 5 minutes, and than the 10 minutes between the progress
+/// notifications of etcd watches
+const IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+pub struct Request {
+    pub method: String,
+    /// Without the query string
+    pub path: String,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    /// JSON unless `content_type` tells otherwise, if any
+    pub body: Option<String>,
+    /// Of the body, application/json unless given
+    pub content_type: Option<&'static str>,
+}
+
+impl Response {
+    pub fn empty(status: u16) -> Self {
+        Self {
+            status,
+            body: None,
+            content_type: None,
+        }
+    }
+
+    pub fn json(status: u16, body: String) -> Self {
+        Self {
+            status,
+            body: Some(body),
+            content_type: None,
+        }
+    }
+
+    pub fn error(status: u16, message: impl std::fmt::Display) -> Self {
+        Self::json(
+            status,
+            format!(
+                "{{\"error\": {}}}\n",
+                serde_json::Value::from(message.to_string())
+            ),
+        )
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        422 => "Unprocessable Entity",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+pub fn percent_decode(s: &str) -> anyhow::Result<String> {
+    let mut out = Vec::new();
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+            let hex = std::str::from_utf8(&hex)?;
+            out.push(u8::from_str_radix(hex, 16)?);
+        } else {
+            out.push(b);
+        }
+    }
+    Ok(String::from_utf8(out)?)
+}
+
+/// Reads a line into `line`, failing if longer than MAX_LINE
+async fn read_line<R: AsyncBufRead + Unpin>(
+    stream: &mut R,
+    line: &mut String,
+) -> std::io::Result<()> {
+    let read = (&mut *stream).take(MAX_LINE as u64).read_line(line).await?;
+    if read == MAX_LINE && !line.ends_with('\n') {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "line too long",
+        ));
+    }
+    Ok(())
+}
+
+async fn read_request(stream: &mut BufReader<TcpStream>) -> Result<Request, Response> {
+    let bad = |e: &dyn std::fmt::Display| Response::error(400, e);
+    let mut line = String::new();
+    read_line(stream, &mut line).await.map_err(|e| bad(&e))?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_owned(), target.to_owned()),
+        _ => return Err(bad(&"invalid request line")),
+    };
+    let path = target.split('?').next().unwrap().to_owned();
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        read_line(stream, &mut line).await.map_err(|e| bad(&e))?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(bad(&"too many headers"));
+        }
+        let (key, value) = header
+            .split_once(':')
+            .ok_or_else(|| bad(&"invalid header"))?;
+        headers.push((key.trim().to_owned(), value.trim().to_owned()));
+    }
+
+    let mut request = Request {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+    };
+    if let Some(length) = request.header("content-length") {
+        let length: usize = length.parse().map_err(|e| bad(&e))?;
+        if length > MAX_BODY {
+            return Err(Response::error(413, "request body too large"));
+        }
+        request.body.resize(length, 0);
+        stream
+            .read_exact(&mut request.body)
+            .await
+            .map_err(|e| bad(&e))?;
+    } else if request.header("transfer-encoding").is_some() {
+        return Err(bad(&"only Content-Length delimited bodies are supported"));
+    }
+    Ok(request)
+}
+
+async fn write_response(stream: &mut TcpStream, response: Response) -> std::io::Result<()> {
+    let content_type = response.content_type.unwrap_or("application/json");
+    let body = response.body.unwrap_or_default();
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        response.status,
+        reason(response.status),
+        body.len()
+    );
+    if !body.is_empty() {
+        head.push_str(&format!("Content-Type: {}\r\n", content_type));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await
+}
+
+/// Response to a GET or POST request
+pub struct Reply {
+    pub status: u16,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Error giving the status and body, for unexpected statuses
+    pub fn error(&self) -> anyhow::Error {
+        anyhow::anyhow!(
+            "HTTP {}: {}",
+            self.status,
+            String::from_utf8_lossy(&self.body).trim()
+        )
+    }
+}
+
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+type Stream = BufReader<Box<dyn Connection>>;
+
+/// `io`, failing with a TimedOut error past `timeout`
+async fn within<T>(
+    timeout: Duration,
+    io: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    match tokio::time::timeout(timeout, io).await {
+        Ok(result) => result,
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("HTTP server silent for {} seconds", timeout.as_secs()),
+        )
+        .into()),
+    }
+}
+
+/// Connects to `addr`, returning the connection and the host to send requests for
+async fn connect(addr: &str) -> anyhow::Result<(Box<dyn Connection>, &str)> {
+    if addr.starts_with('/') {
+        #[cfg(unix)]
+        return Ok((Box::new(UnixStream::connect(addr).await?), "localhost"));
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!(
+            "Unix sockets are not supported on this platform"
+        ));
+    }
+    Ok((Box::new(TcpStream::connect(addr).await?), addr))
+}
+
+/// Sends a GET request to `addr`, host:port or the path of a Unix socket, reading the response
+/// up to its body
+async fn send_get(
+    addr: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+) -> anyhow::Result<(Stream, Reply)> {
+    send("GET", addr, path, headers, None).await
+}
+
+/// Sends a request of `method` with `body`, if any, to `addr`, reading the response up to its
+/// body
+async fn send(
+    method: &str,
+    addr: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: Option<&[u8]>,
+) -> anyhow::Result<(Stream, Reply)> {
+    let (connection, host) = within(CONNECT_TIMEOUT, connect(addr)).await?;
+    let mut stream = BufReader::new(connection);
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+        method, path, host
+    );
+    for (key, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    if let Some(body) = body {
+        request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    request.push_str("\r\n");
+    let mut request = request.into_bytes();
+    request.extend_from_slice(body.unwrap_or_default());
+    let reply = within(IDLE_TIMEOUT, async {
+        stream.get_mut().write_all(&request).await?;
+        read_reply(&mut stream).await
+    })
+    .await?;
+    Ok((stream, reply))
+}
+
+/// Reads the status line and headers of a response
+async fn read_reply(stream: &mut Stream) -> anyhow::Result<Reply> {
+    let mut line = String::new();
+    read_line(stream, &mut line).await?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("Invalid HTTP status line {:?}", line))?;
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        read_line(stream, &mut line).await?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(anyhow::anyhow!("Too many headers in the HTTP response"));
+        }
+        if let Some((key, value)) = header.split_once(':') {
+            headers.push((key.trim().to_owned(), value.trim().to_owned()));
+        }
+    }
+    Ok(Reply {
+        status,
+        headers,
+        body: Vec::new(),
+    })
+}
+
+/// Reads the next chunk of a chunked body, of at most `budget` bytes, empty at its end
+async fn read_chunk(stream: &mut Stream, budget: usize) -> anyhow::Result<Vec<u8>> {
+    within(IDLE_TIMEOUT, async {
+        let mut line = String::new();
+        read_line(stream, &mut line).await?;
+        let size = usize::from_str_radix(line.trim().split(';').next().unwrap(), 16)?;
+        if size > budget {
+            return Err(too_large());
+        }
+        let mut chunk = vec![0; size];
+        stream.read_exact(&mut chunk).await?;
+        let mut end = [0; 2];
+        stream.read_exact(&mut end).await?;
+        if &end != b"\r\n" {
+            return Err(anyhow::anyhow!("Invalid end of HTTP chunk"));
+        }
+        Ok(chunk)
+    })
+    .await
+}
+
+fn too_large() -> anyhow::Error {
+    anyhow::anyhow!("HTTP response body over {} bytes", MAX_REPLY)
+}
+
+fn is_chunked(reply: &Reply) -> bool {
+    reply
+        .header("transfer-encoding")
+        .map(|value| value.eq_ignore_ascii_case("chunked"))
+        .unwrap_or(false)
+}
+
+async fn read_body(mut stream: Stream, mut reply: Reply) -> anyhow::Result<Reply> {
+    if is_chunked(&reply) {
+        loop {
+            let chunk = read_chunk(&mut stream, MAX_REPLY - reply.body.len()).await?;
+            if chunk.is_empty() {
+                break;
+            }
+            reply.body.extend_from_slice(&chunk);
+        }
+    } else if let Some(length) = reply.header("content-length") {
+        let length: usize = length.parse()?;
+        if length > MAX_REPLY {
+            return Err(too_large());
+        }
+        reply.body.resize(length, 0);
+        within(IDLE_TIMEOUT, async {
+            stream.read_exact(&mut reply.body).await?;
+            Ok(())
+        })
+        .await?;
+    } else {
+        let mut body = stream.take(MAX_REPLY as u64 + 1);
+        within(IDLE_TIMEOUT, async {
+            body.read_to_end(&mut reply.body).await?;
+            Ok(())
+        })
+        .await?;
+        if reply.body.len() > MAX_REPLY {
+            return Err(too_large());
+        }
+    }
+    Ok(reply)
+}
+
+/// Sends a GET request to `addr` with `headers`, reading the whole response
+pub async fn get(addr: &str, path: &str, headers: &[(&str, &str)]) -> anyhow::Result<Reply> {
+    let (stream, reply) = send_get(addr, path, headers).await?;
+    read_body(stream, reply).await
+}
+
+/// Sends a POST request of `body` to `addr` with `headers`, reading the whole response
+pub async fn post(
+    addr: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> anyhow::Result<Reply> {
+    let (stream, reply) = send("POST", addr, path, headers, Some(body)).await?;
+    read_body(stream, reply).await
+}
+
+/// The chunks of a streamed response body
+pub struct Chunks(Stream);
+
+impl Chunks {
+    /// The next chunk, of at most MAX_REPLY bytes, None at the end of the body
+    pub async fn next(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        let chunk = read_chunk(&mut self.0, MAX_REPLY).await?;
+        Ok(Some(chunk).filter(|chunk| !chunk.is_empty()))
+    }
+}
+
+/// Sends a POST request of `body` for a streamed response, returning the reply up to its body
+/// along with the chunks of the body, or the whole reply when its body is not chunked
+pub async fn post_stream(
+    addr: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> anyhow::Result<(Reply, Option<Chunks>)> {
+    let (stream, reply) = send("POST", addr, path, headers, Some(body)).await?;
+    if !is_chunked(&reply) {
+        return Ok((read_body(stream, reply).await?, None));
+    }
+    Ok((reply, Some(Chunks(stream))))
+}
+
+/// Sends a GET request for a streamed response, returning once its first chunk is received or
+/// it ends, that chunk being the body of the reply
+pub async fn get_first_chunk(
+    addr: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+) -> anyhow::Result<Reply> {
+    let (mut stream, reply) = send_get(addr, path, headers).await?;
+    if !is_chunked(&reply) {
+        return read_body(stream, reply).await;
+    }
+    let body = read_chunk(&mut stream, MAX_REPLY).await?;
+    Ok(Reply { body, ..reply })
+}
+
+/// Accepts connections forever, answering each request with `handler`
+pub async fn serve<F, Fut>(listener: TcpListener, handler: F)
+where
+    F: Fn(Request) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Response> + Send,
+{
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                log::error!("Failed to accept an API connection: {}", e);
+                continue;
+            }
+        };
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let mut stream = BufReader::new(stream);
+            let response = match tokio::time::timeout(READ_TIMEOUT, read_request(&mut stream)).await
+            {
+                Ok(Ok(request)) => {
+                    log::debug!("API {} {} from {}", request.method, request.path, remote);
+                    handler(request).await
+                }
+                Ok(Err(response)) => response,
+                Err(_) => return,
+            };
+            if let Err(e) = write_response(stream.get_mut(), response).await {
+                log::debug!("Failed to answer API request from {}: {}", remote, e);
+            }
+        });
+    }
+}
@@ -0,0 +1,89 @@
+//! Coalescing of identical lookups in flight: those started while one is pending wait for its
+//! outcome rather than running again, so that a burst of queries for a name missing from the
+//! cache makes a single one upstream.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::OnceCell;
+
+pub struct Inflight<K, V> {
+    pending: Mutex<HashMap<K, Arc<OnceCell<V>>>>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Inflight<K, V> {
+    pub fn new() -> Self {
+        Self {
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Runs `lookup`, unless one for `key` is pending, in which case its outcome is waited for
+    /// instead. Should the caller running the lookup go away, one of those waiting runs theirs.
+    pub async fn run<F: Future<Output = V>>(&self, key: K, lookup: F) -> V {
+        let cell = self
+            .pending
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let outcome = cell.get_or_init(|| lookup).await.clone();
+
+        // Lookups from now on start anew
+        let mut pending = self.pending.lock().unwrap();
+        if pending.get(&key).is_some_and(|c| Arc::ptr_eq(c, &cell)) {
+            pending.remove(&key);
+        }
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn coalesced() {
+        let inflight = Inflight::new();
+        let runs = AtomicUsize::new(0);
+        let lookup = |outcome: u32| {
+            let runs = &runs;
+            async move {
+                runs.fetch_add(1, Ordering::Relaxed);
+                // Pending until those started meanwhile joined it
+                tokio::task::yield_now().await;
+                outcome
+            }
+        };
+
+        let outcomes = tokio::join!(
+            inflight.run("example.com", lookup(1)),
+            inflight.run("example.com", lookup(2)),
+            inflight.run("example.net", lookup(3)),
+        );
+        assert_eq!(outcomes, (1, 1, 3));
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+
+        // Once over, run anew
+        assert_eq!(inflight.run("example.com", lookup(4)).await, 4);
+        assert_eq!(runs.load(Ordering::Relaxed), 3);
+        assert!(inflight.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn abandoned() {
+        let inflight = Inflight::new();
+        let abandoned = inflight.run("example.com", std::future::pending());
+        assert!(tokio::time::timeout(Duration::from_millis(10), abandoned)
+            .await
+            .is_err());
+        // The lookup of the next caller runs instead
+        assert_eq!(inflight.run("example.com", async { 2 }).await, 2);
+    }
+}
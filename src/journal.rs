@@ -0,0 +1,145 @@
+//! Per-zone history of changes, from which IXFR requests are answered (RFC 1995). Whenever the
+//! served data changes, each zone whose SOA serial moved forward gets a delta of the records
+//! removed and added, whatever the change came from. A zone changing without a newer serial
+//! loses its history. So does the oldest part of a history once it outgrows the zone itself, at
+//! which point a full transfer is cheaper anyway.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use crate::parser::Type;
+use crate::record::{Name, Record, RecordInner};
+use crate::BaseStorage;
+
+/// Records with their owner names
+pub type Records = Vec<(Name, Record)>;
+
+/// Records removed and added, but the SOA, by zone
+pub type Changes = HashMap<Name, (Records, Records)>;
+
+pub struct Delta {
+    pub old_soa: Record,
+    pub new_soa: Record,
+    pub removed: Records,
+    pub added: Records,
+}
+
+impl Delta {
+    fn len(&self) -> usize {
+        self.removed.len() + self.added.len() + 2
+    }
+}
+
+/// Deltas of each zone, oldest first. Shared between snapshots, which each hold the journal
+/// leading to their own data.
+#[derive(Clone, Default)]
+pub struct Journal {
+    zones: HashMap<Name, VecDeque<Arc<Delta>>>,
+}
+
+pub fn serial(soa: &Record) -> Option<u32> {
+    match soa.inner {
+        RecordInner::SOA { serial, .. } => Some(serial),
+        _ => None,
+    }
+}
+
+fn soa<'a>(base: &'a BaseStorage, zone: &[String]) -> Option<&'a Record> {
+    base.get(zone)?.iter().find(|r| r.inner.ty() == Type::SOA)
+}
+
+/// Apex of the closest zone enclosing `name`
+pub fn zone_of<'a>(base: &BaseStorage, name: &'a [String]) -> Option<&'a [String]> {
+    (0..name.len())
+        .map(|i| &name[i..])
+        .find(|suffix| soa(base, suffix).is_some())
+}
+
+/// The changes from `old` to `new`, for each zone of either whose records changed
+pub fn changes(old: &BaseStorage, new: &BaseStorage) -> Changes {
+    let mut changed = Changes::new();
+    let names = old
+        .keys()
+        .chain(new.keys().filter(|name| !old.contains_key(name.as_ref())));
+    for name in names {
+        let before = old.get(name.as_ref()).map(Vec::as_slice).unwrap_or(&[]);
+        let after = new.get(name.as_ref()).map(Vec::as_slice).unwrap_or(&[]);
+        if before == after {
+            continue;
+        }
+        let zone = match zone_of(new, name.as_ref()).or_else(|| zone_of(old, name.as_ref())) {
+            Some(zone) => Name::from(zone.to_vec()),
+            None => continue,
+        };
+        let (removed, added) = changed.entry(zone).or_default();
+        let not_soa = |r: &&Record| r.inner.ty() != Type::SOA;
+        for record in before.iter().filter(not_soa) {
+            if !after.contains(record) {
+                removed.push((name.clone(), record.clone()));
+            }
+        }
+        for record in after.iter().filter(not_soa) {
+            if !before.contains(record) {
+                added.push((name.clone(), record.clone()));
+            }
+        }
+    }
+    changed
+}
+
+impl Journal {
+    /// The journal leading to `new`, from `old` of which this is the journal, `changed` being
+    /// the changes between them
+    pub fn advance(&self, old: &BaseStorage, new: &BaseStorage, changed: Changes) -> Journal {
+        let mut journal = self.clone();
+        for (zone, (removed, added)) in changed {
+            let (old_soa, new_soa) = match (soa(old, zone.as_ref()), soa(new, zone.as_ref())) {
+                (Some(old_soa), Some(new_soa)) => (old_soa, new_soa),
+                _ => {
+                    journal.zones.remove(&zone);
+                    continue;
+                }
+            };
+            let newer = match (serial(new_soa), serial(old_soa)) {
+                (Some(new), Some(old)) => crate::serial::serial_gt(new, old),
+                _ => false,
+            };
+            if !newer {
+                if journal.zones.remove(&zone).is_some() {
+                    log::warn!(
+                        "{} changed without a newer SOA serial, dropping its IXFR history",
+                        zone
+                    );
+                }
+                continue;
+            }
+
+            let zone_len: usize = new
+                .iter()
+                .filter(|(name, _)| zone_of(new, name.as_ref()) == Some(zone.as_ref()))
+                .map(|(_, records)| records.len())
+                .sum();
+            let deltas = journal.zones.entry(zone).or_default();
+            deltas.push_back(Arc::new(Delta {
+                old_soa: old_soa.clone(),
+                new_soa: new_soa.clone(),
+                removed,
+                added,
+            }));
+            while deltas.iter().map(|d| d.len()).sum::<usize>() > zone_len.max(1) {
+                deltas.pop_front();
+            }
+        }
+        journal
+    }
+
+    /// Deltas of `zone` from `serial` up to the current data, or None if the history does not
+    /// reach back that far
+    pub fn since(&self, zone: &[String], serial: u32) -> Option<Vec<&Delta>> {
+        let deltas = self.zones.get(zone)?;
+        let start = deltas
+            .iter()
+            .position(|d| self::serial(&d.old_soa) == Some(serial))?;
+        Some(deltas.iter().skip(start).map(Arc::as_ref).collect())
+    }
+}
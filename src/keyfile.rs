@@ -0,0 +1,119 @@
+//! Files of private keys, which only their owner may read: of mode 0600 on Unix, and with a
+//! protected ACL granting access to the owner alone on Windows. Elsewhere, files are opened as
+//! they are.
+//!
+//! Keys may also be encrypted with a passphrase, as PKCS#8 encrypted PEM (RFC 5958 Section 3)
+//! with scrypt and AES-256-CBC. The passphrase is read from a file, the first line of which it
+//! is, from the DNS_KEY_PASSPHRASE environment variable, or else asked for on the terminal.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, IsTerminal};
+use std::path::Path;
+
+/// Environment variable holding the passphrase of encrypted keys
+pub const PASSPHRASE_ENV: &str = "DNS_KEY_PASSPHRASE";
+
+/// The passphrase of encrypted keys given by the first line of `file`, if any, or else `value`,
+/// as given on the command line or by DNS_KEY_PASSPHRASE
+pub fn passphrase(value: Option<&str>, file: Option<&Path>) -> io::Result<Option<String>> {
+    match file {
+        Some(file) => {
+            let content = std::fs::read_to_string(file)?;
+            Ok(Some(content.lines().next().unwrap_or_default().to_owned()))
+        }
+        None => Ok(value.map(str::to_owned)),
+    }
+}
+
+/// Asks for a passphrase on the terminal with `prompt`
+pub fn prompt(prompt: &str) -> io::Result<String> {
+    if !io::stdin().is_terminal() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "no terminal to ask for the passphrase on, set {}",
+                PASSPHRASE_ENV
+            ),
+        ));
+    }
+    rpassword::prompt_password(prompt)
+}
+
+/// `der`, a private key in PKCS#8 DER, encrypted with `passphrase` in PEM
+pub fn encrypt(der: &[u8], passphrase: &str) -> anyhow::Result<String> {
+    let encrypted = pkcs8::PrivateKeyInfo::try_from(der)?.encrypt(rand_core::OsRng, passphrase)?;
+    Ok(encrypted
+        .to_pem("ENCRYPTED PRIVATE KEY", pkcs8::LineEnding::LF)?
+        .to_string())
+}
+
+/// Opens the file at `path` with `options`, to write a private key to
+#[cfg(unix)]
+pub fn open(options: &mut OpenOptions, path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    options.mode(0o600).open(path)
+}
+
+/// Opens the file at `path` with `options`, to write a private key to. Its ACL is replaced before
+/// anything gets written, and it is removed again if that fails.
+#[cfg(windows)]
+pub fn open(options: &mut OpenOptions, path: &Path) -> io::Result<File> {
+    let file = options.open(path)?;
+    if let Err(e) = restrict(path) {
+        drop(file);
+        let _ = std::fs::remove_file(path);
+        return Err(e);
+    }
+    Ok(file)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn open(options: &mut OpenOptions, path: &Path) -> io::Result<File> {
+    options.open(path)
+}
+
+/// Sets the DACL of `path` to grant full access to the owner only, without inheriting entries
+/// from the directory
+#[cfg(windows)]
+fn restrict(path: &Path) -> io::Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+
+    use windows_sys::Win32::Security::Authorization::{
+        ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+    };
+    use windows_sys::Win32::Security::{
+        SetFileSecurityW, DACL_SECURITY_INFORMATION, PROTECTED_DACL_SECURITY_INFORMATION,
+        PSECURITY_DESCRIPTOR,
+    };
+    use windows_sys::Win32::System::Memory::LocalFree;
+
+    // P: protected from inheritance, A;;FA;;;OW: allow all file access to the owner rights SID
+    let sddl: Vec<u16> = "D:P(A;;FA;;;OW)".encode_utf16().chain([0]).collect();
+    let path: Vec<u16> = path.as_os_str().encode_wide().chain([0]).collect();
+    let mut descriptor: PSECURITY_DESCRIPTOR = std::ptr::null_mut();
+    // SAFETY: both strings are NUL-terminated, and the descriptor allocated by the conversion is
+    // freed once set
+    unsafe {
+        if ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            sddl.as_ptr(),
+            SDDL_REVISION_1,
+            &mut descriptor,
+            std::ptr::null_mut(),
+        ) == 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        let set = SetFileSecurityW(
+            path.as_ptr(),
+            DACL_SECURITY_INFORMATION | PROTECTED_DACL_SECURITY_INFORMATION,
+            descriptor,
+        );
+        let error = io::Error::last_os_error();
+        LocalFree(descriptor as isize);
+        if set == 0 {
+            return Err(error);
+        }
+    }
+    Ok(())
+}
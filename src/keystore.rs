@@ -0,0 +1,441 @@
+//! Key stores: directories holding the keys of a signed zone, configured with `key-store` rather
+//! than `keys`, see the sign module:
+//!
+//! ```yaml
+//! example.com:
+//!   type: primary
+//!   key-store: /var/lib/dns/keys/example.com
+//! ```
+//!
+//! The keys are files of Ed25519 keys in PKCS#8 PEM, or describing remote keys (see the
+//! remote_key module), listed in the keys.yml file of the directory with their role, key signing
+//! (`ksk`) or zone signing (`zsk`), and the times they go through each state, as written in RRSIG
+//! records or in seconds since the epoch:
+//!
+//! ```yaml
+//! - file: Kexample.com.+015+06394.pem
+//!   role: zsk
+//!   publish: "20261016000000"
+//!   activate: "20261017000000"
+//!   retire: "20261116000000"
+//!   remove: "20261117000000"
+//! ```
+//!
+//! A key is published in the DNSKEY RRset from `publish` on, signs from `activate` on, key signing
+//! keys the DNSKEY RRset and zone signing keys the others, stops signing once retired, and is no
+//! longer published once removed. Without an active zone signing key, the key signing keys sign
+//! every RRset. States change as time passes, and the store is polled for changes.
+//!
+//! The `keys` subcommand lists the keys of a store with their state, adds keys to it, encrypted
+//! with the passphrase given by --key-passphrase-file or DNS_KEY_PASSPHRASE if any, and
+//! schedules rollovers (RFC 6781 Section 4.1): with `pre-publish`, the default for zone signing
+//! keys, the new key is published at once and replaces the current ones at --at, which stay
+//! published for --hold seconds more so that the signatures they made expire from caches. With
+//! `double-signature`, the default for key signing keys, the new key signs along with the current
+//! ones until --at, by which the DS records of the parent zone must have been updated. Parents
+//! scanning the CDS records of the zone pick the new key up as soon as it is published, and drop
+//! the current ones once they are retired.
+
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use ed25519::pkcs8::EncodePrivateKey;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::record::{parse_signature_time, signature_time, Name};
+use crate::sign::Signer;
+
+/// The file listing the keys of a store
+pub const FILE: &str = "keys.yml";
+
+/// How often stores are checked for changes, and keys for changes of state
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+const DAY: u32 = 86400;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Ksk,
+    Zsk,
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ksk" => Ok(Self::Ksk),
+            "zsk" => Ok(Self::Zsk),
+            _ => Err(format!("unknown role {}, expected ksk or zsk", s)),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ksk => "KSK",
+            Self::Zsk => "ZSK",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Not published yet
+    Scheduled,
+    Published,
+    Active,
+    /// Published, no longer signing
+    Retired,
+    Removed,
+}
+
+impl State {
+    /// Whether the key is in the DNSKEY RRset
+    pub fn is_published(&self) -> bool {
+        matches!(self, Self::Published | Self::Active | Self::Retired)
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Scheduled => "scheduled",
+            Self::Published => "published",
+            Self::Active => "active",
+            Self::Retired => "retired",
+            Self::Removed => "removed",
+        })
+    }
+}
+
+/// A key of the store, its file relative to the store
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Entry {
+    pub file: PathBuf,
+    pub role: Role,
+    #[serde(default, with = "time", skip_serializing_if = "Option::is_none")]
+    pub publish: Option<u32>,
+    #[serde(default, with = "time", skip_serializing_if = "Option::is_none")]
+    pub activate: Option<u32>,
+    #[serde(default, with = "time", skip_serializing_if = "Option::is_none")]
+    pub retire: Option<u32>,
+    #[serde(default, with = "time", skip_serializing_if = "Option::is_none")]
+    pub remove: Option<u32>,
+}
+
+impl Entry {
+    /// A key given as a file rather than through a store, active for good
+    pub fn active(file: PathBuf, role: Role) -> Self {
+        Self {
+            file,
+            role,
+            publish: Some(0),
+            activate: Some(0),
+            retire: None,
+            remove: None,
+        }
+    }
+
+    /// Its state at `now`, in seconds since the epoch
+    pub fn state(&self, now: u32) -> State {
+        let reached = |time: Option<u32>| time.is_some_and(|time| now >= time);
+        if reached(self.remove) {
+            State::Removed
+        } else if reached(self.retire) {
+            State::Retired
+        } else if reached(self.activate) {
+            State::Active
+        } else if reached(self.publish) {
+            State::Published
+        } else {
+            State::Scheduled
+        }
+    }
+}
+
+/// Times in the format of RRSIG records, see `signature_time`
+mod time {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(time: &Option<u32>, serializer: S) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(time) => serializer.serialize_str(&crate::record::signature_time(*time)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u32>, D::Error> {
+        let Some(s) = Option::<String>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+        crate::record::parse_signature_time(&s)
+            .map(Some)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid time {}", s)))
+    }
+}
+
+/// The keys listed by the store at `dir`
+pub fn read(dir: &Path) -> anyhow::Result<Vec<Entry>> {
+    let path = dir.join(FILE);
+    let content = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_yaml::from_str(&content).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))
+}
+
+fn write(dir: &Path, entries: &[Entry]) -> anyhow::Result<()> {
+    let path = dir.join(FILE);
+    // Never leaves a partly written file behind for the servers polling it
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_yaml::to_string(entries)?)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+/// Writes a new key for `zone` with `role` to the store at `dir`, encrypted with `passphrase` if
+/// given, returning its file name
+fn generate(
+    dir: &Path,
+    zone: &Name,
+    role: Role,
+    passphrase: Option<&str>,
+) -> anyhow::Result<PathBuf> {
+    let keypair = ed25519_dalek::Keypair::generate(&mut rand::rngs::OsRng);
+    let dnskey = crate::sign::dnskey(keypair.public.as_bytes(), role);
+    let tag = crate::dnssec::key_tag(&dnskey.serialize()?);
+    let file = PathBuf::from(format!("K{}.+015+{:05}.pem", zone, tag));
+    let bytes = ed25519::pkcs8::KeypairBytes::from_bytes(&keypair.to_bytes());
+    let pem = match passphrase {
+        Some(passphrase) => bytes
+            .to_pkcs8_der()
+            .map_err(anyhow::Error::from)
+            .and_then(|der| crate::keyfile::encrypt(der.as_ref(), passphrase)),
+        None => bytes
+            .to_pkcs8_pem(pem_rfc7468::LineEnding::LF)
+            .map(|pem| pem.to_string())
+            .map_err(anyhow::Error::from),
+    }
+    .map_err(|e| anyhow::anyhow!("Failed to encode the key: {}", e))?;
+    let path = dir.join(&file);
+    crate::keyfile::open(std::fs::File::options().write(true).create_new(true), &path)
+        .and_then(|mut out| out.write_all(pem.as_bytes()))
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(file)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    PrePublish,
+    DoubleSignature,
+}
+
+impl std::str::FromStr for Method {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pre-publish" => Ok(Self::PrePublish),
+            "double-signature" => Ok(Self::DoubleSignature),
+            _ => Err(format!(
+                "unknown method {}, expected pre-publish or double-signature",
+                s
+            )),
+        }
+    }
+}
+
+fn parse_time(s: &str) -> Result<u32, String> {
+    parse_signature_time(s).ok_or_else(|| format!("invalid time {}", s))
+}
+
+#[derive(StructOpt)]
+pub enum Action {
+    /// List the keys of the store, with their state and the DS records of key signing keys
+    List,
+    /// Print the DNSKEY records of the keys published, for the other signers of the zone to
+    /// import, see the multi_signer module
+    Export,
+    /// Generate a key, published and active from now on unless told otherwise
+    Add {
+        /// ksk or zsk
+        #[structopt(long)]
+        role: Role,
+
+        /// When the key is published, as YYYYMMDDHHmmSS in UTC or in seconds since the epoch
+        #[structopt(long, parse(try_from_str = parse_time))]
+        publish: Option<u32>,
+
+        /// When the key starts signing
+        #[structopt(long, parse(try_from_str = parse_time))]
+        activate: Option<u32>,
+    },
+    /// Generate a key replacing the active ones of its role
+    Rollover {
+        /// ksk or zsk
+        #[structopt(long)]
+        role: Role,
+
+        /// pre-publish or double-signature, by default the former for zone signing keys and the
+        /// latter for key signing keys
+        #[structopt(long)]
+        method: Option<Method>,
+
+        /// When the current keys are retired, a day from now by default
+        #[structopt(long, parse(try_from_str = parse_time))]
+        at: Option<u32>,
+
+        /// Seconds the keys retired with pre-publish stay published
+        #[structopt(long, default_value = "86400")]
+        hold: u32,
+    },
+}
+
+/// `keys` subcommand, managing the store at `dir` of the keys of `zone`
+pub fn run(
+    zone: &Name,
+    dir: &Path,
+    action: &Action,
+    mut passphrase: Option<String>,
+) -> anyhow::Result<()> {
+    let now = crate::dnssec::now();
+    let mut entries = match action {
+        // Stores are created by adding their first key
+        Action::Add { .. } if !dir.join(FILE).exists() => {
+            std::fs::create_dir_all(dir)?;
+            Vec::new()
+        }
+        _ => read(dir)?,
+    };
+    match action {
+        Action::List => {
+            let signer = Signer::open(zone, dir, None, false, passphrase)?;
+            for (entry, (tag, ds)) in entries.iter().zip(signer.describe()) {
+                let time = |time: Option<u32>| time.map_or("-".to_string(), signature_time);
+                println!(
+                    "{:05} {} {:<9} publish {} activate {} retire {} remove {} {}",
+                    tag,
+                    entry.role,
+                    entry.state(now).to_string(),
+                    time(entry.publish),
+                    time(entry.activate),
+                    time(entry.retire),
+                    time(entry.remove),
+                    entry.file.display()
+                );
+                if let Some(ds) = ds {
+                    println!("      {}", ds);
+                }
+            }
+            return Ok(());
+        }
+        Action::Export => {
+            for dnskey in Signer::open(zone, dir, None, false, passphrase)?.export() {
+                println!("{}", dnskey);
+            }
+            return Ok(());
+        }
+        Action::Add {
+            role,
+            publish,
+            activate,
+        } => {
+            let file = generate(dir, zone, *role, passphrase.as_deref())?;
+            println!("{}", file.display());
+            entries.push(Entry {
+                file,
+                role: *role,
+                publish: Some(publish.unwrap_or(now)),
+                activate: Some(activate.or(*publish).unwrap_or(now)),
+                retire: None,
+                remove: None,
+            });
+        }
+        Action::Rollover {
+            role,
+            method,
+            at,
+            hold,
+        } => {
+            let method = method.unwrap_or(match role {
+                Role::Ksk => Method::DoubleSignature,
+                Role::Zsk => Method::PrePublish,
+            });
+            let at = at.unwrap_or(now + DAY);
+            let current: Vec<&mut Entry> = entries
+                .iter_mut()
+                .filter(|entry| {
+                    entry.role == *role
+                        && entry.retire.is_none()
+                        && [State::Published, State::Active].contains(&entry.state(now))
+                })
+                .collect();
+            if current.is_empty() {
+                return Err(anyhow::anyhow!("No {} of {} to roll over", role, zone));
+            }
+            for entry in current {
+                let (retire, remove) = match method {
+                    Method::PrePublish => (at, at.saturating_add(*hold)),
+                    Method::DoubleSignature => (at, at),
+                };
+                entry.retire = Some(retire);
+                entry.remove = Some(remove);
+            }
+            let file = generate(dir, zone, *role, passphrase.as_deref())?;
+            println!("{}", file.display());
+            entries.push(Entry {
+                file,
+                role: *role,
+                publish: Some(now),
+                activate: Some(match method {
+                    Method::PrePublish => at,
+                    Method::DoubleSignature => now,
+                }),
+                retire: None,
+                remove: None,
+            });
+        }
+    }
+    write(dir, &entries)?;
+    // The DS records to submit to the parent zone
+    let signer = Signer::open(zone, dir, None, false, passphrase.take())?;
+    for ds in signer.describe().into_iter().filter_map(|(_, ds)| ds) {
+        eprintln!("{}", ds);
+    }
+    Ok(())
+}
+
+/// Polls the store at `dir` of `signer`, reloading its keys whenever it changes, and logs the
+/// changes of state of the keys
+pub async fn watch(zone: Name, dir: PathBuf, signer: Arc<Signer>) {
+    let path = dir.join(FILE);
+    let mtime = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut read_at: Option<SystemTime> = mtime(&path);
+    let mut states = signer.states();
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let current = mtime(&path);
+        if current != read_at {
+            read_at = current;
+            match signer.reload(&dir) {
+                Ok(()) => log::info!("{} changed, keys of {} reloaded", path.display(), zone),
+                Err(e) => log::error!("Keeping the current keys of {}: {}", zone, e),
+            }
+        }
+        let current = signer.states();
+        for (tag, role, state) in current.iter() {
+            if !states.contains(&(*tag, *role, *state)) {
+                log::info!("{} {} of {} is now {}", role, tag, zone, state);
+            }
+        }
+        states = current;
+    }
+}
@@ -0,0 +1,169 @@
+//! Records from `DNSRecord` custom resources of a Kubernetes cluster, one per RRset:
+//!
+//! ```text
+//! apiVersion: dns.impl-cat.io/v1
+//! kind: DNSRecord
+//! metadata:
+//!   name: www
+//! spec:
+//!   name: www.example.com
+//!   type: A
+//!   ttl: 60
+//!   rdata: ["10.0.0.1", "10.0.0.2"]
+//! ```
+//!
+//! RDATA is in master file syntax, and the TTL defaults to 300. The resources are listed, in every
+//! namespace unless --kubernetes-namespace is given, then watched, so that changes are served as
+//! soon as the API server accepts them. Resources which do not parse are skipped with a warning.
+//!
+//! Running in the cluster, the API server is reached over TLS, e.g. as
+//! `--kubernetes https://kubernetes.default.svc`, with the service account of the pod: its
+//! certificate is checked against the certificate authority mounted in the pod, and the token
+//! mounted next to it is sent, read again as the kubelet rotates it. Elsewhere, it may be reached
+//! through `kubectl proxy` listening on a host:port, over plain HTTP. The service account needs to
+//! get, list and watch `dnsrecords.dns.impl-cat.io`. Requests are made with kube-rs, whose
+//! timeouts end requests to an API server gone silent.
+
+use std::sync::Arc;
+
+use kube::api::{Api, ApiResource, DynamicObject, ListParams, WatchEvent, WatchParams};
+use serde::Deserialize;
+use tokio_stream::StreamExt;
+
+use crate::store::Store;
+use crate::BaseStorage;
+
+const GROUP: &str = "dns.impl-cat.io";
+const VERSION: &str = "v1";
+const KIND: &str = "DNSRecord";
+const PLURAL: &str = "dnsrecords";
+
+const DEFAULT_TTL: u32 = 300;
+
+/// After which the API server ends a watch, which is then started anew. Below the read timeout of
+/// kube-rs, 295 seconds.
+const WATCH_TIMEOUT: u32 = 290;
+
+/// Where the credentials of the service account of the pod are mounted
+pub const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+#[derive(Deserialize)]
+struct Spec {
+    name: String,
+    #[serde(rename = "type")]
+    ty: String,
+    #[serde(default)]
+    ttl: Option<u32>,
+    rdata: Vec<String>,
+}
+
+impl Spec {
+    fn records(&self, source: &str) -> anyhow::Result<BaseStorage> {
+        let ttl = self.ttl.unwrap_or(DEFAULT_TTL);
+        let rows = self
+            .rdata
+            .iter()
+            .map(|rdata| (self.name.as_str(), self.ty.as_str(), ttl, rdata.as_str()));
+        crate::zonefile::parse_rows(rows, source)
+    }
+}
+
+pub struct KubernetesSource {
+    /// The API server or `kubectl proxy`, as given
+    server: String,
+    api: Api<DynamicObject>,
+}
+
+impl KubernetesSource {
+    /// Reading the resources from `api`, https://host[:port] of the API server, or host:port of
+    /// `kubectl proxy`, in `namespace` only if given
+    pub fn new(api: &str, namespace: Option<String>) -> anyhow::Result<Self> {
+        let config = match api.starts_with("https://") {
+            true => kube::Config {
+                cluster_url: api.parse()?,
+                ..kube::Config::incluster_dns()?
+            },
+            false => kube::Config::new(format!("http://{}", api).parse()?),
+        };
+        let client = kube::Client::try_from(config)?;
+        let resource = ApiResource {
+            group: GROUP.to_owned(),
+            version: VERSION.to_owned(),
+            api_version: format!("{}/{}", GROUP, VERSION),
+            kind: KIND.to_owned(),
+            plural: PLURAL.to_owned(),
+        };
+        let api_resources = match &namespace {
+            Some(namespace) => Api::namespaced_with(client, namespace, &resource),
+            None => Api::all_with(client, &resource),
+        };
+        Ok(Self {
+            server: api.trim_start_matches("https://").to_owned(),
+            api: api_resources,
+        })
+    }
+
+    fn name(&self) -> String {
+        format!("kubernetes://{}", self.server)
+    }
+
+    /// Reads every record, along with the resource version to watch from
+    async fn fetch(&self) -> anyhow::Result<(BaseStorage, String)> {
+        let list = self.api.list(&ListParams::default()).await?;
+
+        let mut base = BaseStorage::new();
+        for item in list.items {
+            let spec = item.data.get("spec").cloned().unwrap_or_default();
+            let records = serde_json::from_value::<Spec>(spec)
+                .map_err(anyhow::Error::from)
+                .and_then(|spec| spec.records(&self.name()));
+            match records {
+                Ok(records) => {
+                    for (name, records) in records {
+                        base.entry(name).or_default().extend(records);
+                    }
+                }
+                Err(e) => log::warn!(
+                    "{}: skipping {}/{}: {}",
+                    self.name(),
+                    item.metadata.namespace.unwrap_or_default(),
+                    item.metadata.name.unwrap_or_default(),
+                    e
+                ),
+            }
+        }
+        let version = list.metadata.resource_version.unwrap_or_default();
+        Ok((base, version))
+    }
+
+    pub async fn read(&self) -> anyhow::Result<BaseStorage> {
+        Ok(self.fetch().await?.0)
+    }
+
+    /// Waits until a resource changes past `version`, or the watch times out
+    async fn wait(&self, version: &str) -> anyhow::Result<()> {
+        let params = WatchParams::default().timeout(WATCH_TIMEOUT);
+        let events = self.api.watch(&params, version).await?;
+        tokio::pin!(events);
+        while let Some(event) = events.next().await {
+            match event? {
+                // Only telling how far the watch got
+                WatchEvent::Bookmark(_) => continue,
+                _ => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the resources anew whenever the watch reports an event, rather than applying the
+    /// events. The last records read are served while the API server is unreachable.
+    pub async fn watch(self, store: Arc<Store>) {
+        let (source, store) = (&self, &store);
+        crate::retry::forever(&self.name(), || async move {
+            let (base, version) = source.fetch().await?;
+            store.set_source("kubernetes", base);
+            source.wait(&version).await
+        })
+        .await
+    }
+}
@@ -0,0 +1,104 @@
+//! Conversion between raw wire-format labels and their textual (presentation) form, which is
+//! what storage keys and logs use.
+//!
+//! Printable characters are kept as-is, including non-ASCII ones, so that UTF-8 names written in
+//! the zone keep matching. Everything else is escaped RFC 1035 style as `\DDD`, and `.` / `\` are
+//! backslash-escaped, so that any byte sequence round-trips unambiguously.
+
+/// Is the label valid UTF-8 without any control character?
+pub fn is_clean(raw: &[u8]) -> bool {
+    match std::str::from_utf8(raw) {
+        Ok(s) => !s.chars().any(char::is_control),
+        Err(_) => false,
+    }
+}
+
+pub fn escape(raw: &[u8]) -> String {
+    let mut ret = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while !rest.is_empty() {
+        let (valid, invalid) = match std::str::from_utf8(rest) {
+            Ok(s) => (s, &rest[rest.len()..]),
+            Err(e) => {
+                let (valid, invalid) = rest.split_at(e.valid_up_to());
+                // Safe: checked by from_utf8 above
+                (std::str::from_utf8(valid).unwrap(), invalid)
+            }
+        };
+
+        for c in valid.chars() {
+            match c {
+                '.' | '\\' => {
+                    ret.push('\\');
+                    ret.push(c);
+                }
+                c if c.is_control() || c == ' ' => {
+                    let mut buf = [0; 4];
+                    for b in c.encode_utf8(&mut buf).bytes() {
+                        ret.push_str(&format!("\\{:03}", b));
+                    }
+                }
+                c => ret.push(c),
+            }
+        }
+
+        if let Some((b, tail)) = invalid.split_first() {
+            ret.push_str(&format!("\\{:03}", b));
+            rest = tail;
+        } else {
+            rest = invalid;
+        }
+    }
+    ret
+}
+
+/// Inverse of `escape`. Malformed escapes are kept literally.
+pub fn unescape(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' || i + 1 == bytes.len() {
+            ret.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+
+        let digits = &bytes[i + 1..bytes.len().min(i + 4)];
+        if digits.len() == 3 && digits.iter().all(u8::is_ascii_digit) {
+            let val = digits
+                .iter()
+                .fold(0u16, |acc, d| acc * 10 + (d - b'0') as u16);
+            if val <= 255 {
+                ret.push(val as u8);
+                i += 4;
+                continue;
+            }
+        }
+
+        ret.push(bytes[i + 1]);
+        i += 2;
+    }
+    ret
+}
+
+/// Splits a textual domain name on unescaped dots
+pub fn split_name(s: &str) -> Vec<String> {
+    let mut ret = Vec::new();
+    let mut cur = String::new();
+    let mut escaped = false;
+    for c in s.chars() {
+        match c {
+            '.' if !escaped => ret.push(std::mem::take(&mut cur)),
+            '\\' if !escaped => {
+                escaped = true;
+                cur.push(c);
+                continue;
+            }
+            c => cur.push(c),
+        }
+        escaped = false;
+    }
+    ret.push(cur);
+    ret
+}
@@ -0,0 +1,478 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::generate::Generator;
+use crate::parser::Type;
+use crate::record::{Name, Record, RecordInner};
+use crate::BaseStorage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Guess from the file extension
+    Auto,
+    Yaml,
+    Json,
+    Toml,
+    /// RFC 1035 master file
+    Zone,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "yaml" => Ok(Self::Yaml),
+            "json" => Ok(Self::Json),
+            "toml" => Ok(Self::Toml),
+            "zone" | "bind" => Ok(Self::Zone),
+            _ => Err(anyhow::anyhow!(
+                "Unknown format {}, expected one of auto, yaml, json, toml, zone (or bind)",
+                s
+            )),
+        }
+    }
+}
+
+impl Format {
+    pub fn detect(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yml") | Some("yaml") => Self::Yaml,
+            Some("json") => Self::Json,
+            Some("toml") => Self::Toml,
+            _ => Self::Zone,
+        }
+    }
+}
+
+/// Top level of YAML, JSON and TOML zone files
+#[derive(Deserialize)]
+struct SerdeZone {
+    /// Other files whose records are added to this one, relative to this file
+    #[serde(rename = "$include", default)]
+    include: Vec<PathBuf>,
+
+    #[serde(rename = "$generate", default)]
+    generate: Vec<Generator>,
+
+    // `$vars` is handled by the vars module, before the rest of the file is read
+    #[serde(rename = "$defaults", alias = "defaults", default)]
+    defaults: Defaults,
+
+    #[serde(flatten)]
+    records: HashMap<Name, Vec<SerdeRecord>>,
+}
+
+/// Applied to the records of the file declaring them, when they leave the field out
+#[derive(Deserialize, Default)]
+struct Defaults {
+    ttl: Option<u32>,
+
+    /// NS set added at every SOA owner without NS records of its own
+    #[serde(default)]
+    ns: Vec<Name>,
+}
+
+#[derive(Deserialize)]
+struct SerdeRecord {
+    #[serde(flatten)]
+    inner: RecordInner,
+
+    ttl: Option<u32>,
+
+    comment: Option<String>,
+
+    #[serde(default = "enabled")]
+    enabled: bool,
+
+    #[serde(default, deserialize_with = "crate::geo::deserialize")]
+    geo: Vec<String>,
+
+    weight: Option<u32>,
+
+    location: Option<String>,
+
+    check: Option<crate::failover::Check>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl SerdeZone {
+    fn into_base(self) -> anyhow::Result<BaseStorage> {
+        let mut records: Vec<(Name, SerdeRecord)> = Vec::new();
+        for (name, recs) in self.records {
+            records.extend(recs.into_iter().map(|r| (name.clone(), r)));
+        }
+        for generator in self.generate.iter() {
+            records.extend(generator.expand()?);
+        }
+
+        let mut base = BaseStorage::new();
+        for (name, record) in records {
+            let ttl = record.ttl.or(self.defaults.ttl).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "{}: {:?} record without ttl, and no default one",
+                    name.as_ref().join("."),
+                    record.inner.ty()
+                )
+            })?;
+            base.entry(name).or_default().push(Record {
+                inner: record.inner,
+                ttl,
+                comment: record.comment,
+                enabled: record.enabled,
+                geo: record.geo,
+                weight: record.weight,
+                location: record.location,
+                check: record.check,
+            });
+        }
+
+        if !self.defaults.ns.is_empty() {
+            for records in base.values_mut() {
+                let soa_ttl = match records.iter().find(|r| r.inner.ty() == Type::SOA) {
+                    Some(soa) => soa.ttl,
+                    None => continue,
+                };
+                if records.iter().any(|r| r.inner.ty() == Type::NS) {
+                    continue;
+                }
+                for ns in self.defaults.ns.iter() {
+                    records.push(Record::new(
+                        RecordInner::NS { ns: ns.clone() },
+                        self.defaults.ttl.unwrap_or(soa_ttl),
+                    ));
+                }
+            }
+        }
+        Ok(base)
+    }
+}
+
+/// Reads records from `path`, in the given format, following includes
+pub fn read(path: &Path, format: Format) -> anyhow::Result<BaseStorage> {
+    read_including(path, format, &mut Vec::new())
+}
+
+/// Reads records from `path` as `read` does, adding the files it includes to `included`, even if
+/// reading one of them fails
+pub fn read_including(
+    path: &Path,
+    format: Format,
+    included: &mut Vec<PathBuf>,
+) -> anyhow::Result<BaseStorage> {
+    read_nested(path, format, None, &mut Vec::new(), included)
+}
+
+/// `stack` holds the files currently being read, to detect include cycles, and `included` gets
+/// the files included, as they are about to be read
+fn read_nested(
+    path: &Path,
+    format: Format,
+    origin: Option<Vec<String>>,
+    stack: &mut Vec<PathBuf>,
+    included: &mut Vec<PathBuf>,
+) -> anyhow::Result<BaseStorage> {
+    let format = match format {
+        Format::Auto => Format::detect(path),
+        f => f,
+    };
+
+    let canonical = path
+        .canonicalize()
+        .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+    if let Some(pos) = stack.iter().position(|p| p == &canonical) {
+        let cycle: Vec<String> = stack[pos..]
+            .iter()
+            .chain(std::iter::once(&canonical))
+            .map(|p| p.display().to_string())
+            .collect();
+        return Err(anyhow::anyhow!("include cycle: {}", cycle.join(" -> ")));
+    }
+
+    let content = std::fs::read_to_string(path)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(".")).to_owned();
+
+    stack.push(canonical);
+    let result = match format {
+        Format::Yaml | Format::Json | Format::Toml => {
+            let zone: SerdeZone = crate::vars::parse(&content, format)
+                .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+            let include = zone.include.clone();
+            let mut base = zone
+                .into_base()
+                .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+            for file in include.iter() {
+                let file = dir.join(file);
+                included.push(file.clone());
+                for (name, records) in read_nested(&file, Format::Auto, None, stack, included)? {
+                    base.entry(name).or_default().extend(records);
+                }
+            }
+            Ok(base)
+        }
+        Format::Zone => crate::zonefile::parse(
+            &content,
+            &path.display().to_string(),
+            origin,
+            &mut |file, origin| {
+                let file = dir.join(file);
+                included.push(file.clone());
+                read_nested(&file, Format::Auto, origin, stack, included)
+            },
+        ),
+        Format::Auto => unreachable!(),
+    };
+    stack.pop();
+    result
+}
+
+/// Writes records to `path` in the given format
+pub fn write(path: &Path, format: Format, base: &BaseStorage) -> anyhow::Result<()> {
+    let format = match format {
+        Format::Auto => Format::detect(path),
+        f => f,
+    };
+    let content = to_string(format, base)?;
+
+    // Readers never see a partially written file
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, content)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Records in the given format, sorted by name
+pub fn to_string(format: Format, base: &BaseStorage) -> anyhow::Result<String> {
+    let sorted: BTreeMap<String, &Vec<Record>> = base
+        .iter()
+        .map(|(name, records)| (name.to_string(), records))
+        .collect();
+
+    let content = match format {
+        Format::Yaml => serde_yaml::to_string(&sorted)?,
+        Format::Json => serde_json::to_string_pretty(&sorted)? + "\n",
+        Format::Toml => toml::to_string(&sorted)?,
+        Format::Zone => {
+            // Zone apexes first, followed by the names below them, SOA records leading
+            let mut names: Vec<&Name> = base.keys().collect();
+            names.sort_by_key(|name| {
+                let mut labels = name.as_ref().to_vec();
+                labels.reverse();
+                labels
+            });
+            let mut content = String::new();
+            for name in names {
+                let mut records: Vec<&Record> = base[name].iter().collect();
+                records.sort_by_key(|r| r.inner.ty() != Type::SOA);
+                for record in records {
+                    content.push_str(&record.to_zone_line(name));
+                    content.push('\n');
+                }
+            }
+            content
+        }
+        Format::Auto => return Err(anyhow::anyhow!("no format given")),
+    };
+    Ok(content)
+}
+
+/// Reads every file in `dir` as an independent zone. Each must hold exactly one SOA, whose owner
+/// is the zone origin, and nothing outside of that origin. The files they include are added to
+/// `included`.
+pub fn read_zones_dir(
+    dir: &Path,
+    format: Format,
+    included: &mut Vec<PathBuf>,
+) -> anyhow::Result<Vec<(PathBuf, BaseStorage)>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if entry.file_type()?.is_file() && !hidden {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+
+    let mut zones = Vec::new();
+    for path in paths {
+        let zone = read_including(&path, format, included)?;
+        let origin = zone_origin(&zone)
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?
+            .to_vec();
+        if let Some(name) = zone.keys().find(|name| !name.as_ref().ends_with(&origin)) {
+            return Err(anyhow::anyhow!(
+                "{}: {} is outside of the zone origin {}",
+                path.display(),
+                name.as_ref().join("."),
+                origin.join(".")
+            ));
+        }
+        zones.push((path, zone));
+    }
+    Ok(zones)
+}
+
+fn zone_origin(zone: &BaseStorage) -> anyhow::Result<&[String]> {
+    let mut apexes = zone
+        .iter()
+        .filter(|(_, records)| records.iter().any(|r| r.inner.ty() == Type::SOA))
+        .map(|(name, _)| name.as_ref());
+    let origin = apexes
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("no SOA record"))?;
+    if apexes.next().is_some() {
+        return Err(anyhow::anyhow!("more than one SOA record"));
+    }
+    Ok(origin)
+}
+
+/// Merges zones into a single storage, refusing names defined by more than one of them
+pub fn merge(zones: Vec<(PathBuf, BaseStorage)>) -> anyhow::Result<BaseStorage> {
+    let mut merged = BaseStorage::new();
+    let mut owners: HashMap<Vec<String>, PathBuf> = HashMap::new();
+    for (path, zone) in zones {
+        for (name, records) in zone {
+            if let Some(prev) = owners.get(name.as_ref()) {
+                return Err(anyhow::anyhow!(
+                    "{} is defined in both {} and {}",
+                    name.as_ref().join("."),
+                    prev.display(),
+                    path.display()
+                ));
+            }
+            owners.insert(name.as_ref().to_vec(), path.clone());
+            merged.insert(name, records);
+        }
+    }
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of its own for `test`, holding `files`
+    fn dir(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("load-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, content) in files {
+            std::fs::write(dir.join(name), content).unwrap();
+        }
+        dir
+    }
+
+    fn names(base: &BaseStorage) -> Vec<String> {
+        let mut names: Vec<String> = base.keys().map(|name| name.to_string()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn zone_includes() {
+        let dir = dir(
+            "zone",
+            &[
+                (
+                    "main.zone",
+                    "$ORIGIN example.com.\n$TTL 60\n$INCLUDE hosts.zone\n$INCLUDE sub/sub.zone sub\n",
+                ),
+                ("hosts.zone", "$TTL 60\nwww A 192.0.2.1\n"),
+                ("sub.zone", "unused A 192.0.2.9\n"),
+            ],
+        );
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        // Relative to the including file
+        std::fs::write(dir.join("sub/sub.zone"), "$TTL 60\nhost A 192.0.2.2\n").unwrap();
+
+        let mut included = Vec::new();
+        let base = read_including(&dir.join("main.zone"), Format::Auto, &mut included).unwrap();
+        assert_eq!(names(&base), ["host.sub.example.com", "www.example.com"]);
+        assert_eq!(included, [dir.join("hosts.zone"), dir.join("sub/sub.zone")]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn yaml_includes() {
+        let dir = dir(
+            "yaml",
+            &[
+                (
+                    "main.yml",
+                    "$include: [hosts.json, more.zone]\nwww.example.com:\n  - {type: A, ttl: 60, addr: 192.0.2.1}\n",
+                ),
+                (
+                    "hosts.json",
+                    r#"{"$include": ["deep.toml"], "mail.example.com": [{"type": "TXT", "ttl": 60, "content": "a\/b 😀"}]}"#,
+                ),
+                ("deep.toml", "[[\"deep.example.com\"]]\ntype = \"A\"\nttl = 60\naddr = \"192.0.2.3\"\n"),
+                ("more.zone", "more.example.com. 60 A 192.0.2.4\n"),
+            ],
+        );
+        let mut included = Vec::new();
+        let base = read_including(&dir.join("main.yml"), Format::Auto, &mut included).unwrap();
+        assert_eq!(
+            names(&base),
+            [
+                "deep.example.com",
+                "mail.example.com",
+                "more.example.com",
+                "www.example.com"
+            ]
+        );
+        assert_eq!(
+            included,
+            [
+                dir.join("hosts.json"),
+                dir.join("deep.toml"),
+                dir.join("more.zone")
+            ]
+        );
+        let mail = &base[crate::label::split_name("mail.example.com").as_slice()];
+        assert_eq!(
+            mail[0].inner,
+            RecordInner::TXT {
+                content: "a/b 😀".into()
+            }
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn include_cycle() {
+        let dir = dir(
+            "cycle",
+            &[
+                ("a.zone", "$TTL 60\n$INCLUDE b.zone\n"),
+                ("b.zone", "$INCLUDE a.zone\n"),
+            ],
+        );
+        let mut included = Vec::new();
+        let err = read_including(&dir.join("a.zone"), Format::Auto, &mut included).unwrap_err();
+        assert!(err.to_string().contains("include cycle"), "{}", err);
+        assert_eq!(included, [dir.join("b.zone"), dir.join("a.zone")]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_include_reported() {
+        // Watched, so that creating it reloads the zone
+        let dir = dir(
+            "missing",
+            &[("main.zone", "$TTL 60\n$INCLUDE later.zone\n")],
+        );
+        let mut included = Vec::new();
+        assert!(read_including(&dir.join("main.zone"), Format::Auto, &mut included).is_err());
+        assert_eq!(included, [dir.join("later.zone")]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+//! Log output, filtered with RUST_LOG as usual. With --log-format json, each line is a JSON
+//! object, for log collectors such as Loki or Elasticsearch to ingest:
+//!
+//! ```text
+//! {"time": "2026-10-16T07:02:34.123Z", "level": "INFO", "target": "impl_cat_dns", "message": "Refused: query for \"www.example.com\" from 192.0.2.1:53124", "client": "192.0.2.1", "port": 53124, "proto": "udp", "qname": "www.example.com.", "qtype": "A"}
+//! ```
+//!
+//! Events logged while a request is handled carry the address, port and transport of its client
+//! and, unless malformed, the name and type it asks for. Other events have none of those.
+
+use std::future::Future;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde_json::Value;
+
+/// Whether events are logged as JSON, for requests to be given a context
+static JSON: AtomicBool = AtomicBool::new(false);
+
+tokio::task_local! {
+    static CONTEXT: Context;
+}
+
+/// Format of log lines, with --log-format
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("Expected text or json, got {}", s)),
+        }
+    }
+}
+
+/// The request being handled
+pub struct Context {
+    remote: SocketAddr,
+    tcp: bool,
+    /// Parsed only as events are logged
+    buf: Vec<u8>,
+}
+
+pub fn init(format: Format) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if let Format::Json = format {
+        JSON.store(true, Ordering::Relaxed);
+        builder.format(|out, record| {
+            let mut line = format!(
+                "{{\"time\": \"{}\", \"level\": \"{}\", \"target\": {}, \"message\": {}",
+                out.timestamp_millis(),
+                record.level(),
+                Value::from(record.target()),
+                Value::from(record.args().to_string())
+            );
+            let _ = CONTEXT.try_with(|context| context.write(&mut line));
+            line.push('}');
+            writeln!(out, "{}", line)
+        });
+    }
+    builder.init();
+}
+
+impl Context {
+    /// The context of the request in `buf`, if events are logged as JSON
+    pub fn new(buf: &[u8], remote: SocketAddr, tcp: bool) -> Option<Self> {
+        JSON.load(Ordering::Relaxed).then(|| Self {
+            remote,
+            tcp,
+            buf: buf.to_vec(),
+        })
+    }
+
+    fn write(&self, line: &mut String) {
+        line.push_str(&format!(
+            ", \"client\": {}, \"port\": {}, \"proto\": {}",
+            Value::from(self.remote.ip().to_string()),
+            self.remote.port(),
+            Value::from(if self.tcp { "tcp" } else { "udp" })
+        ));
+        if let Some((name, ty)) = crate::querylog::question(&self.buf) {
+            line.push_str(&format!(
+                ", \"qname\": {}, \"qtype\": {}",
+                Value::from(name),
+                Value::from(ty)
+            ));
+        }
+    }
+}
+
+/// Runs `request` within `context`, if any, for the events it logs
+pub async fn scope<F: Future>(context: Option<Context>, request: F) -> F::Output {
+    match context {
+        Some(context) => CONTEXT.scope(context, request).await,
+        None => request.await,
+    }
+}
@@ -0,0 +1,274 @@
+//! Automated updates of trust anchors (RFC 5011), with --managed-keys: the key signing keys of the
+//! zones of the trust anchors, see the dnssec module, are tracked through their rollovers, and
+//! their state kept in the file given, created as needed:
+//!
+//! ```yaml
+//! - zone: .
+//!   key: 257 3 8 AwEAAaz/tAm8yTn4Mfeh...
+//!   tag: 20326
+//!   state: valid
+//!   since: 1760572800
+//! ```
+//!
+//! The DNSKEY RRset of each zone is asked to the upstream resolvers every 12 hours, and taken
+//! into account when signed by a trusted key. New key signing keys are trusted once they were
+//! seen for 30 days, the add hold-down time, and keys revoked by their owner, signing the RRset
+//! with the REVOKE flag set, are trusted no more. Keys no longer published stay trusted, as
+//! missing. At first, the keys matching the trust anchors of --trust-anchors or
+//! --root-trust-anchor are trusted at once; from then on, the keys of the file are the trust
+//! anchors of their zone.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use base64ct::{Base64, Encoding};
+use serde::{Deserialize, Serialize};
+
+use crate::dnssec::{AnchorKey, Validator, REVOKED, SEP};
+use crate::forward::Upstreams;
+use crate::label::split_name;
+
+const INTERVAL: Duration = Duration::from_secs(12 * 3600);
+/// How soon the keys of a zone are asked again after failing to
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How long new keys must be seen before they are trusted, and revoked keys are remembered,
+/// RFC 5011 Section 2.4.1
+const HOLD_DOWN: u32 = 30 * 86400;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum State {
+    /// Seen, not trusted before the add hold-down time
+    AddPending,
+    Valid,
+    /// Trusted, no longer published
+    Missing,
+    Revoked,
+}
+
+impl State {
+    fn is_trusted(&self) -> bool {
+        matches!(self, Self::Valid | Self::Missing)
+    }
+}
+
+/// A key of the zone of a trust anchor
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    zone: String,
+    /// Its DNSKEY RDATA in presentation format, without the REVOKE flag
+    key: String,
+    tag: u16,
+    state: State,
+    /// When it entered its state, in seconds since the epoch
+    since: u32,
+}
+
+pub struct ManagedKeys {
+    path: PathBuf,
+    entries: Vec<Entry>,
+}
+
+impl ManagedKeys {
+    /// The state of the file at `path`, which may not exist yet
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let entries = match std::fs::read_to_string(path) {
+            Ok(content) => serde_yaml::from_str(&content)
+                .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        Ok(Self {
+            path: path.to_owned(),
+            entries,
+        })
+    }
+
+    /// Makes the keys trusted the trust anchors of their zones in `validator`
+    pub fn apply(&self, validator: &Validator) -> anyhow::Result<()> {
+        for zone in validator.anchor_zones() {
+            let keys = self.trusted(&zone)?;
+            if !keys.is_empty() {
+                validator.set_anchor(&zone, &keys);
+            }
+        }
+        Ok(())
+    }
+
+    /// Tracks the keys of the zones of the trust anchors of `validator`, asking `upstreams`
+    pub async fn track(mut self, validator: Arc<Validator>, upstreams: Arc<Upstreams>) {
+        loop {
+            let mut interval = INTERVAL;
+            for zone in validator.anchor_zones() {
+                let changed = match validator.anchor_keys(&upstreams, &zone).await {
+                    Ok(keys) => self.refresh(&zone, &keys),
+                    Err(e) => Err(anyhow::anyhow!(e)),
+                };
+                let result = match changed {
+                    Ok(true) => self.write().and_then(|()| self.apply(&validator)),
+                    Ok(false) => Ok(()),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    let zone = crate::dnssec::display(&zone);
+                    log::warn!("Tracking the trust anchor of {}: {:#}", zone, e);
+                    interval = RETRY_INTERVAL;
+                }
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// The RDATA of the trusted keys of `zone`
+    fn trusted(&self, zone: &[String]) -> anyhow::Result<Vec<Vec<u8>>> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.state.is_trusted() && zone_of(entry) == zone)
+            .map(|entry| {
+                parse_key(&entry.key).ok_or_else(|| {
+                    anyhow::anyhow!("{}: invalid key {}", self.path.display(), entry.key)
+                })
+            })
+            .collect()
+    }
+
+    /// Updates the state of the keys of `zone` from `keys`, its DNSKEY RRset, RFC 5011 Section 4.
+    /// Returns whether any changed.
+    fn refresh(&mut self, zone: &[String], keys: &[AnchorKey]) -> anyhow::Result<bool> {
+        let trusted = self.trusted(zone)?;
+        let first = trusted.is_empty();
+        let authentic = keys.iter().any(|key| {
+            key.signs
+                && flags(&key.rdata) & REVOKED == 0
+                && ((first && key.trusted) || trusted.contains(&key.rdata))
+        });
+        if !authentic {
+            return Err(anyhow::anyhow!("DNSKEY RRset not signed by a trusted key"));
+        }
+
+        let now = crate::dnssec::now();
+        let name = crate::dnssec::display(zone);
+        let mut changed = false;
+        let mut seen = Vec::new();
+        for key in keys.iter().filter(|key| flags(&key.rdata) & SEP != 0) {
+            let revoked = flags(&key.rdata) & REVOKED != 0;
+            let rdata = unrevoked(&key.rdata);
+            let text = format_key(&rdata);
+            seen.push(text.clone());
+            let entry = self
+                .entries
+                .iter_mut()
+                .find(|entry| zone_of(entry) == zone && entry.key == text);
+            let tag = crate::dnssec::key_tag(&rdata);
+            match (entry, revoked) {
+                (Some(entry), true) if key.signs && entry.state != State::Revoked => {
+                    log::warn!("Key {} of {} revoked", tag, name);
+                    (entry.state, entry.since) = (State::Revoked, now);
+                    changed = true;
+                }
+                (_, true) => (),
+                (None, false) => {
+                    let state = match first && key.trusted {
+                        true => State::Valid,
+                        false => State::AddPending,
+                    };
+                    log::info!("Key {} of {} seen, {:?}", tag, name, state);
+                    self.entries.push(Entry {
+                        zone: name.clone(),
+                        key: text,
+                        tag,
+                        state,
+                        since: now,
+                    });
+                    changed = true;
+                }
+                (Some(entry), false) => {
+                    let state = match entry.state {
+                        State::AddPending if now.wrapping_sub(entry.since) >= HOLD_DOWN => {
+                            State::Valid
+                        }
+                        State::Missing => State::Valid,
+                        state => state,
+                    };
+                    if state != entry.state {
+                        log::info!("Key {} of {} now {:?}", tag, name, state);
+                        (entry.state, entry.since) = (state, now);
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        let before = self.entries.len();
+        self.entries.retain(|entry| {
+            zone_of(entry) != zone
+                || seen.contains(&entry.key)
+                || match entry.state {
+                    State::AddPending => false,
+                    State::Revoked => now.wrapping_sub(entry.since) < HOLD_DOWN,
+                    _ => true,
+                }
+        });
+        changed |= self.entries.len() != before;
+        for entry in &mut self.entries {
+            if zone_of(entry) == zone && entry.state == State::Valid && !seen.contains(&entry.key) {
+                log::warn!("Key {} of {} missing", entry.tag, name);
+                (entry.state, entry.since) = (State::Missing, now);
+                changed = true;
+            }
+        }
+        if changed && self.trusted(zone)?.is_empty() {
+            log::error!("No trusted key left for {}, its names are bogus", name);
+        }
+        Ok(changed)
+    }
+
+    fn write(&self) -> anyhow::Result<()> {
+        // Never leaves a partly written file behind
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, serde_yaml::to_string(&self.entries)?)
+            .and_then(|()| std::fs::rename(&tmp, &self.path))
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+fn zone_of(entry: &Entry) -> Vec<String> {
+    match entry.zone.trim_end_matches('.') {
+        "" => vec![],
+        zone => split_name(&zone.to_ascii_lowercase()),
+    }
+}
+
+fn flags(rdata: &[u8]) -> u16 {
+    u16::from_be_bytes([rdata[0], rdata[1]])
+}
+
+/// `rdata` without the REVOKE flag
+fn unrevoked(rdata: &[u8]) -> Vec<u8> {
+    let mut out = rdata.to_vec();
+    out[..2].copy_from_slice(&(flags(rdata) & !REVOKED).to_be_bytes());
+    out
+}
+
+fn format_key(rdata: &[u8]) -> String {
+    format!(
+        "{} {} {} {}",
+        flags(rdata),
+        rdata[2],
+        rdata[3],
+        Base64::encode_string(&rdata[4..])
+    )
+}
+
+fn parse_key(text: &str) -> Option<Vec<u8>> {
+    let mut words = text.split_whitespace();
+    let mut rdata = words.next()?.parse::<u16>().ok()?.to_be_bytes().to_vec();
+    rdata.push(words.next()?.parse().ok()?);
+    rdata.push(words.next()?.parse().ok()?);
+    rdata.extend(Base64::decode_vec(&words.collect::<String>()).ok()?);
+    Some(rdata)
+}
@@ -0,0 +1,283 @@
+use crate::parser::{Class, ReqHeaderStatus, Type};
+use crate::record::{serialize_name, Record, TtlBounds};
+use crate::tsig::Signer;
+
+/// Maximum payload of a plain UDP response, RFC 1035 Section 4.2.1
+pub const UDP_PAYLOAD_SIZE: usize = 512;
+
+const HEADER_SIZE: usize = 12;
+
+/// Root name + TYPE + CLASS + TTL + RDLENGTH, with empty RDATA
+const OPT_SIZE: usize = 11;
+
+/// OPTION-CODE of Extended DNS Errors, RFC 8914 Section 2
+const EDE_OPTION: u16 = 15;
+
+/// OPTION-CODE + OPTION-LENGTH + INFO-CODE, without EXTRA-TEXT
+const EDE_SIZE: usize = 6;
+
+/// INFO-CODE of answers served from expired cache entries, RFC 8914 Section 4.4
+pub const EDE_STALE_ANSWER: u16 = 3;
+
+/// INFO-CODE of answers replaced by local data, RFC 8914 Section 4.5
+pub const EDE_FORGED_ANSWER: u16 = 4;
+
+/// INFO-CODE of answers failing DNSSEC validation, RFC 8914 Section 4.7
+pub const EDE_DNSSEC_BOGUS: u16 = 6;
+
+/// INFO-CODE of answers to names blocked by policy, RFC 8914 Section 4.16
+pub const EDE_BLOCKED: u16 = 15;
+
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Rcode {
+    OK = 0,
+    Format = 1,
+    Internal = 2,
+    Name = 3,
+    NotImpl = 4,
+    Refused = 5,
+    YXDomain = 6,
+    YXRRSet = 7,
+    NXRRSet = 8,
+    NotAuth = 9,
+    NotZone = 10,
+
+    // Extended RCODEs, upper 8 bits go into the OPT RR
+    BadVers = 16,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Section {
+    Answer = 1,
+    Authority = 2,
+    Additional = 3,
+}
+
+/// Builds a response, keeping track of its encoded size so that nothing is written past the
+/// payload size the client can accept
+pub struct MessageWriter<'a> {
+    id: u16,
+    status: &'a ReqHeaderStatus,
+    rcode: Rcode,
+    is_aa: bool,
+    is_ra: bool,
+    /// Whether the answer is authentic, see `set_ad`
+    is_ad: bool,
+
+    limit: usize,
+    body: Vec<u8>,
+    cnts: [u16; 4],
+    truncated: bool,
+
+    /// Payload size advertised in our OPT RR, if the response carries one
+    edns_payload_size: Option<u16>,
+    /// DO bit of the request, echoed in our OPT RR, RFC 3225 Section 3
+    dnssec_ok: bool,
+    /// INFO-CODE of the Extended DNS Error option of the OPT RR, RFC 8914
+    ede: Option<u16>,
+    /// Client subnet option of the OPT RR, RFC 7871
+    ecs: Option<Vec<u8>>,
+    tsig: Option<Signer>,
+}
+
+impl<'a> MessageWriter<'a> {
+    pub fn new(id: u16, status: &'a ReqHeaderStatus, limit: usize) -> Self {
+        Self {
+            id,
+            status,
+            rcode: Rcode::OK,
+            is_aa: true,
+            is_ra: false,
+            is_ad: false,
+            limit,
+            body: Vec::new(),
+            cnts: [0; 4],
+            truncated: false,
+            edns_payload_size: None,
+            dnssec_ok: false,
+            ede: None,
+            ecs: None,
+            tsig: None,
+        }
+    }
+
+    /// Attaches an OPT RR to the response, `dnssec_ok` being the DO bit of the request. Its space
+    /// is accounted for immediately.
+    pub fn set_edns(&mut self, payload_size: u16, dnssec_ok: bool) {
+        self.edns_payload_size = Some(payload_size);
+        self.dnssec_ok = dnssec_ok;
+    }
+
+    /// Adds an Extended DNS Error to the OPT RR, if the response carries one. Its space is
+    /// accounted for immediately.
+    pub fn set_ede(&mut self, info_code: u16) {
+        self.ede = Some(info_code);
+    }
+
+    /// Adds a client subnet option to the OPT RR, if the response carries one. Its space is
+    /// accounted for immediately.
+    pub fn set_ecs(&mut self, option: Vec<u8>) {
+        self.ecs = Some(option);
+    }
+
+    /// Signs the response with a TSIG RR, or attaches the TSIG error. Its space is accounted for
+    /// immediately.
+    pub fn set_tsig(&mut self, signer: Signer) {
+        self.tsig = Some(signer);
+    }
+
+    pub fn with_rcode(mut self, rcode: Rcode) -> Self {
+        self.rcode = rcode;
+        self
+    }
+
+    pub fn set_rcode(&mut self, rcode: Rcode) {
+        self.rcode = rcode;
+    }
+
+    pub fn set_aa(&mut self, is_aa: bool) {
+        self.is_aa = is_aa;
+    }
+
+    /// Whether recursion is available, with --forward
+    pub fn set_ra(&mut self, is_ra: bool) {
+        self.is_ra = is_ra;
+    }
+
+    /// Whether the answer was validated as authentic. AD is only set for the requests which had
+    /// the DO or AD bit, RFC 6840 Section 5.8.
+    pub fn set_ad(&mut self, is_ad: bool) {
+        self.is_ad = is_ad;
+    }
+
+    /// Encoded size of the message so far, header, OPT and TSIG RRs included
+    pub fn len(&self) -> usize {
+        HEADER_SIZE
+            + self.body.len()
+            + match self.edns_payload_size {
+                Some(_) => {
+                    OPT_SIZE
+                        + self.ede.map_or(0, |_| EDE_SIZE)
+                        + self.ecs.as_ref().map_or(0, Vec::len)
+                }
+                None => 0,
+            }
+            + self.tsig.as_ref().map(Signer::len).unwrap_or(0)
+    }
+
+    /// Flags the response as truncated, for the client to ask again over TCP
+    pub fn set_truncated(&mut self) {
+        self.truncated = true;
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Appends a RR to `section`. Returns false without writing anything if the RR does not fit,
+    /// in which case the message is flagged as truncated unless the RR was an additional one.
+    pub fn push(
+        &mut self,
+        section: Section,
+        name: &[String],
+        record: &Record,
+        class: Class,
+        ttl_bounds: &TtlBounds,
+    ) -> std::io::Result<bool> {
+        let mut rr = Vec::new();
+        serialize_name(name, &mut rr)?;
+        record.serialize(&mut rr, class, ttl_bounds)?;
+
+        let idx = section as usize;
+        if self.truncated || self.len() + rr.len() > self.limit || self.cnts[idx] == u16::MAX {
+            // RFC 2181 Section 9: dropping additional data does not warrant TC
+            if !matches!(section, Section::Additional) {
+                self.truncated = true;
+            }
+            return Ok(false);
+        }
+
+        self.body.extend_from_slice(&rr);
+        self.cnts[idx] += 1;
+        Ok(true)
+    }
+
+    /// Appends the question. Must come before any RR.
+    pub fn push_question(
+        &mut self,
+        name: &[String],
+        ty: Type,
+        class: Class,
+    ) -> std::io::Result<()> {
+        serialize_name(name, &mut self.body)?;
+        self.body.extend_from_slice(&(ty as u16).to_be_bytes());
+        self.body.extend_from_slice(&u16::from(class).to_be_bytes());
+        self.cnts[0] += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.encode()
+    }
+
+    /// Finishes this message of a multi-message response, such as a zone transfer, and returns
+    /// an empty one to continue with. TSIG signatures chain from one message to the next.
+    pub fn finish_continued(mut self) -> (Vec<u8>, Self) {
+        // What did not fit goes into the next message
+        self.truncated = false;
+        let ret = self.encode();
+        self.body.clear();
+        self.cnts = [0; 4];
+        (ret, self)
+    }
+
+    fn write_header(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.id.to_be_bytes());
+        out.extend_from_slice(&[
+            0x80 // QR(1 = R)
+            | (self.status.opcode as u8) << 3
+            | (if self.is_aa { 1 << 2 } else { 0 }) // AA
+            | (if self.truncated { 1 << 1 } else { 0 }) // TC
+            | self.status.rd as u8,
+            (if self.is_ra { 1 << 7 } else { 0 }) // RA
+            | (if self.is_ad && (self.status.ad || self.dnssec_ok) { 1 << 5 } else { 0 }) // AD
+            | (self.rcode as u16 & 0xF) as u8,
+        ]);
+        let arcnt = self.cnts[3] + self.edns_payload_size.is_some() as u16;
+        for cnt in [self.cnts[0], self.cnts[1], self.cnts[2], arcnt] {
+            out.extend_from_slice(&cnt.to_be_bytes());
+        }
+    }
+
+    fn encode(&mut self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(self.len());
+        self.write_header(&mut ret);
+        ret.extend_from_slice(&self.body);
+
+        if let Some(payload_size) = self.edns_payload_size {
+            ret.push(0); // Root
+            ret.extend_from_slice(&(crate::parser::Type::OPT as u16).to_be_bytes());
+            ret.extend_from_slice(&payload_size.to_be_bytes());
+            // EXTENDED-RCODE, VERSION = 0, DO, Z = 0
+            let flags = if self.dnssec_ok { 0x80 } else { 0 };
+            ret.extend_from_slice(&[(self.rcode as u16 >> 4) as u8, 0, flags, 0]);
+            let mut options = Vec::new();
+            if let Some(info_code) = self.ede {
+                options.extend_from_slice(&EDE_OPTION.to_be_bytes());
+                options.extend_from_slice(&2u16.to_be_bytes());
+                options.extend_from_slice(&info_code.to_be_bytes());
+            }
+            if let Some(ecs) = &self.ecs {
+                options.extend_from_slice(ecs);
+            }
+            ret.extend_from_slice(&(options.len() as u16).to_be_bytes()); // RDLENGTH
+            ret.extend_from_slice(&options);
+        }
+        if let Some(tsig) = &mut self.tsig {
+            tsig.sign(&mut ret);
+        }
+        ret
+    }
+}
@@ -0,0 +1,332 @@
+//! Metrics in the Prometheus text format, served at `GET /metrics` on the address given with
+//! --metrics: the health of each upstream resolver, as measured by the queries forwarded to it
+//! and by probes, see the forward module.
+//!
+//! - `dns_upstream_up`, 0 once marked down
+//! - `dns_upstream_rtt_seconds`, smoothed round trip time, once it answered
+//! - `dns_upstream_queries_total`, queries sent, probes included
+//! - `dns_upstream_failures_total`, those unanswered, or probes answered SERVFAIL
+//!
+//! Each is labelled with the address of the upstream resolver, `upstream`, and with the ones it
+//! is of, `pool`: `default` for --forward, `zone:<name>` for a zone of type forward, or
+//! `group:<name>` for a client group, see the clients module.
+//!
+//! The signatures of the zones signed online, see the sign module, labelled with the zone:
+//!
+//! - `dns_signatures_made_total`, signatures made
+//! - `dns_signing_seconds_total`, time spent making them
+//! - `dns_signature_cache_hits_total`, RRsets served with cached signatures
+//! - `dns_signature_cache_misses_total`, those signed as they were served
+//!
+//! And `dns_signature_expiry_seconds`, the time left until the earliest expiration of the
+//! signatures of each signed zone, signed online or offline, see the expiry module.
+//!
+//! The requests being handled, `dns_queries_inflight`, and those dropped as too many were,
+//! `dns_queries_overload_dropped_total`, as well as the TCP connections open,
+//! `dns_tcp_connections`, and those closed at once, `dns_tcp_connections_refused_total`, see the
+//! overload module. With --rate-limit,
+//! `dns_queries_rate_limited_total` counts the queries dropped as their client went over its
+//! rate, see the ratelimit module. With --nxdomain-flood, `dns_nxdomain_flood_active` is 1 for
+//! each zone under a random-subdomain attack, labelled with it, `dns_nxdomain_flood_detected_total`
+//! counts the attacks detected and `dns_nxdomain_flood_dropped_total` the queries dropped, see
+//! the flood module. With --query-log, `dns_query_log_dropped_total` counts the entries of the
+//! query log dropped as written too slowly, see the querylog module. With
+//! --dnstap, `dns_dnstap_dropped_total` counts the frames dropped, see the dnstap module, and with
+//! --otlp, `dns_trace_spans_dropped_total` the trace spans, see the trace module.
+//!
+//! The state of the checks of the endpoints of records, `dns_check_up`, 0 once failed, labelled
+//! with the address checked, `target`, and the check, `check`, see the failover module.
+//!
+//! The queries answered from each zone, labelled with the zone, see the stats module:
+//!
+//! - `dns_zone_queries_total`, queries
+//! - `dns_zone_answers_total`, those answered with records
+//! - `dns_zone_nxdomain_total`, those answered NXDOMAIN
+
+use std::fmt::Write;
+use std::sync::Arc;
+
+use crate::forward::Stats;
+use crate::http::{Request, Response};
+use crate::sign;
+use crate::Options;
+
+/// Name, type and help of each metric
+const METRICS: [(&str, &str, &str); 4] = [
+    (
+        "dns_upstream_up",
+        "gauge",
+        "Whether the upstream resolver is up",
+    ),
+    (
+        "dns_upstream_rtt_seconds",
+        "gauge",
+        "Smoothed round trip time to the upstream resolver",
+    ),
+    (
+        "dns_upstream_queries_total",
+        "counter",
+        "Queries sent to the upstream resolver, probes included",
+    ),
+    (
+        "dns_upstream_failures_total",
+        "counter",
+        "Queries the upstream resolver failed to answer",
+    ),
+];
+
+/// The value of the metric `i` of METRICS in `stats`, if any
+fn value(i: usize, stats: &Stats) -> Option<f64> {
+    match i {
+        0 => Some(if stats.up { 1.0 } else { 0.0 }),
+        1 => stats.srtt.map(|srtt| srtt.as_secs_f64()),
+        2 => Some(stats.sent as f64),
+        _ => Some(stats.lost as f64),
+    }
+}
+
+/// Name, type and help of each metric of the signers
+const SIGNING_METRICS: [(&str, &str, &str); 4] = [
+    (
+        "dns_signatures_made_total",
+        "counter",
+        "Signatures made for the zone",
+    ),
+    (
+        "dns_signing_seconds_total",
+        "counter",
+        "Time spent signing the zone",
+    ),
+    (
+        "dns_signature_cache_hits_total",
+        "counter",
+        "RRsets of the zone served with cached signatures",
+    ),
+    (
+        "dns_signature_cache_misses_total",
+        "counter",
+        "RRsets of the zone signed as they were served",
+    ),
+];
+
+/// Name and help of each counter of the zones
+const ZONE_METRICS: [(&str, &str); 3] = [
+    ("dns_zone_queries_total", "Queries answered from the zone"),
+    (
+        "dns_zone_answers_total",
+        "Queries answered from the zone with records",
+    ),
+    (
+        "dns_zone_nxdomain_total",
+        "Queries answered from the zone with NXDOMAIN",
+    ),
+];
+
+/// The value of the metric `i` of SIGNING_METRICS in `stats`
+fn signing_value(i: usize, stats: &sign::Stats) -> f64 {
+    match i {
+        0 => stats.made as f64,
+        1 => stats.seconds,
+        2 => stats.hits as f64,
+        _ => stats.misses as f64,
+    }
+}
+
+fn render(opts: &Options) -> String {
+    let forward = opts.forward.iter().map(|(zone, upstreams)| {
+        let pool = match zone {
+            Some(zone) => format!("zone:{}", zone),
+            None => "default".to_owned(),
+        };
+        (pool, upstreams.stats())
+    });
+    let groups = opts.clients.iter().filter_map(|group| {
+        let upstreams = group.forward.as_ref()?;
+        Some((format!("group:{}", group.name), upstreams.stats()))
+    });
+    let pools: Vec<(String, Vec<Stats>)> = forward.chain(groups).collect();
+
+    let mut out = String::new();
+    for (i, (name, ty, help)) in METRICS.iter().enumerate() {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, ty);
+        for (pool, stats) in pools.iter() {
+            for stats in stats {
+                if let Some(value) = value(i, stats) {
+                    let labels = format!(
+                        "upstream={},pool={}",
+                        label(&stats.server.to_string()),
+                        label(pool)
+                    );
+                    let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+                }
+            }
+        }
+    }
+
+    let signers: Vec<(String, sign::Stats)> = opts
+        .signers
+        .iter()
+        .map(|(zone, signer)| (zone.to_string(), signer.stats()))
+        .collect();
+    for (i, (name, ty, help)) in SIGNING_METRICS.iter().enumerate() {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, ty);
+        for (zone, stats) in signers.iter() {
+            let labels = format!("zone={}", label(zone));
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, signing_value(i, stats));
+        }
+    }
+
+    let name = "dns_signature_expiry_seconds";
+    let _ = writeln!(
+        out,
+        "# HELP {} Seconds until the earliest expiration of the signatures of the zone",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for (zone, remaining) in opts.expiries.remaining() {
+        let labels = format!("zone={}", label(&zone.to_string()));
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, remaining);
+    }
+
+    let name = "dns_check_up";
+    let _ = writeln!(
+        out,
+        "# HELP {} Whether the check of the endpoint of records passes",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for (target, check, up) in opts.failover.states() {
+        let labels = format!(
+            "target={},check={}",
+            label(&target.to_string()),
+            label(&check.to_string())
+        );
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, up as u8);
+    }
+
+    let zones = opts.stats.snapshot();
+    for (i, (name, help)) in ZONE_METRICS.iter().enumerate() {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for zone in zones.iter() {
+            let value = [zone.queries, zone.answers, zone.nxdomain][i];
+            let labels = format!("zone={}", label(&zone.zone));
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+
+    let name = "dns_queries_inflight";
+    let _ = writeln!(out, "# HELP {} Requests being handled", name);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, opts.overload.inflight());
+    let name = "dns_queries_overload_dropped_total";
+    let _ = writeln!(
+        out,
+        "# HELP {} Requests dropped as too many were being handled",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, opts.overload.dropped());
+    let name = "dns_tcp_connections";
+    let _ = writeln!(out, "# HELP {} TCP connections open", name);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, opts.overload.connections());
+    let name = "dns_tcp_connections_refused_total";
+    let _ = writeln!(
+        out,
+        "# HELP {} TCP connections closed at once as too many were open",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, opts.overload.refused());
+
+    if let Some(limiter) = &opts.rate_limit {
+        let name = "dns_queries_rate_limited_total";
+        let _ = writeln!(
+            out,
+            "# HELP {} Queries dropped as their client went over its rate",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{} {}", name, limiter.dropped());
+    }
+
+    if let Some(flood) = &opts.flood {
+        let name = "dns_nxdomain_flood_active";
+        let _ = writeln!(
+            out,
+            "# HELP {} Whether the zone is under a random-subdomain attack",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for zone in flood.attacked() {
+            let labels = format!("zone={}", label(&zone.to_string()));
+            let _ = writeln!(out, "{}{{{}}} 1", name, labels);
+        }
+        let name = "dns_nxdomain_flood_detected_total";
+        let _ = writeln!(out, "# HELP {} Random-subdomain attacks detected", name);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{} {}", name, flood.detected());
+        let name = "dns_nxdomain_flood_dropped_total";
+        let _ = writeln!(
+            out,
+            "# HELP {} Queries dropped under zones under a random-subdomain attack",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{} {}", name, flood.dropped());
+    }
+
+    if let Some(log) = &opts.query_log {
+        let name = "dns_query_log_dropped_total";
+        let _ = writeln!(
+            out,
+            "# HELP {} Query log entries dropped as written too slowly",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{} {}", name, log.dropped());
+    }
+
+    if let Some(tap) = &opts.dnstap {
+        let name = "dns_dnstap_dropped_total";
+        let _ = writeln!(
+            out,
+            "# HELP {} dnstap frames dropped as written too slowly or not at all",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{} {}", name, tap.dropped());
+    }
+
+    if let Some(tracer) = &opts.tracer {
+        let name = "dns_trace_spans_dropped_total";
+        let _ = writeln!(out, "# HELP {} Trace spans dropped as not exported", name);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{} {}", name, tracer.dropped());
+    }
+    out
+}
+
+/// `value` as a quoted label value, escaped as in the text exposition format
+fn label(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+pub async fn handle(opts: Arc<Options>, request: Request) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => Response {
+            status: 200,
+            body: Some(render(&opts)),
+            content_type: Some("text/plain; version=0.0.4"),
+        },
+        (_, "/metrics") => Response::error(405, "expected GET"),
+        _ => Response::error(404, "no such endpoint"),
+    }
+}
@@ -0,0 +1,102 @@
+//! Multi-signer DNSSEC (RFC 8901), for zones served signed by several providers at once, such as
+//! while migrating from one to another, each signing the zone with its own keys (model 2). The
+//! other providers' keys are imported from a file of their DNSKEY records in master file syntax,
+//! given with `imported-keys`:
+//!
+//! ```yaml
+//! example.com:
+//!   type: primary
+//!   key-store: /var/lib/dns/keys/example.com
+//!   imported-keys: /var/lib/dns/keys/example.com.imported
+//! ```
+//!
+//! Their zone signing keys are published in the DNSKEY RRset of the zone along with ours, signed
+//! by our key signing keys, so that the answers of either provider validate whichever DNSKEY
+//! RRset resolvers got. Their key signing keys, which sign their own DNSKEY RRset only, are
+//! listed in the CDS and CDNSKEY RRsets instead, so that the parent zone keeps the DS records of
+//! every provider. The file is polled for changes, and the `sign` subcommand takes it with
+//! --imported-keys. Ours, to import at the other providers, are printed by the `export` action of
+//! the `keys` subcommand, or by ed25519_keygen as they are made. Every key must be of the
+//! algorithm of ours, Ed25519, for resolvers to validate either provider's signatures.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+
+use crate::record::{Name, RecordInner};
+use crate::sign::Signer;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Ed25519, RFC 8080
+const ED25519: u8 = 15;
+
+/// The DNSKEY records of the file at `path`, of the other signers of `zone`
+pub fn read(zone: &Name, path: &Path) -> anyhow::Result<Vec<RecordInner>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    // TTLs may be left out, the keys being published with that of the apex
+    let content = format!("$TTL 0\n{}", content);
+    let source = path.display().to_string();
+    let mut keys = Vec::new();
+    for (name, records) in crate::zonefile::parse_standalone(&content, &source)? {
+        if !crate::acl::names_eq(name.as_ref(), zone.as_ref()) {
+            return Err(anyhow::anyhow!(
+                "{}: {} is not the apex of {}",
+                source,
+                name,
+                zone
+            ));
+        }
+        for record in records {
+            match record.inner {
+                RecordInner::DNSKEY { algorithm, .. } if algorithm != ED25519 => {
+                    return Err(anyhow::anyhow!(
+                        "{}: key of algorithm {}, ours are of algorithm {}",
+                        source,
+                        algorithm,
+                        ED25519
+                    ))
+                }
+                RecordInner::DNSKEY { .. } => keys.push(record.inner),
+                inner => {
+                    return Err(anyhow::anyhow!(
+                        "{}: {:?} record, only DNSKEY ones are imported",
+                        source,
+                        inner.ty()
+                    ))
+                }
+            }
+        }
+    }
+    Ok(keys)
+}
+
+/// Polls the file at `path` of the keys imported by `signer`, the signer of `zone`, importing
+/// them again whenever it changes
+pub async fn watch(zone: Name, path: PathBuf, signer: Arc<Signer>) {
+    let mtime = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut read_at: Option<SystemTime> = mtime(&path);
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let current = mtime(&path);
+        if current == read_at {
+            continue;
+        }
+        read_at = current;
+        match read(&zone, &path) {
+            Ok(keys) => {
+                log::info!(
+                    "{} changed, {} key(s) of {} imported",
+                    path.display(),
+                    keys.len(),
+                    zone
+                );
+                signer.import(keys);
+            }
+            Err(e) => log::error!("Keeping the imported keys of {}: {:#}", zone, e),
+        }
+    }
+}
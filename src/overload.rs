@@ -0,0 +1,118 @@
+//! Bounded concurrency of the requests being answered, so that floods cannot exhaust memory: at
+//! most --max-inflight of them are handled at once, over UDP and TCP alike. With --overload drop,
+//! the default, those received beyond are dropped unanswered. With --overload queue, they wait
+//! for others to be done, the sockets not being read meanwhile, so that the queues of the system
+//! fill up and overflow instead. The requests being handled are exported as the
+//! `dns_queries_inflight` gauge, and those dropped as `dns_queries_overload_dropped_total`, see
+//! the metrics module.
+//!
+//! TCP connections are bounded the same way, at most --max-tcp-connections of them being open at
+//! once, each closed once idle for 10 seconds. With --overload drop, those accepted beyond are
+//! closed at once. With --overload queue, no connection is accepted until another is closed, the
+//! others waiting in the backlog of the listening socket. The connections open are exported as
+//! the `dns_tcp_connections` gauge, and those closed at once as
+//! `dns_tcp_connections_refused_total`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// What becomes of the requests received beyond --max-inflight, with --overload
+#[derive(Debug, Clone, Copy)]
+pub enum Policy {
+    Drop,
+    Queue,
+}
+
+impl std::str::FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(Self::Drop),
+            "queue" => Ok(Self::Queue),
+            _ => Err(format!("Expected drop or queue, got {}", s)),
+        }
+    }
+}
+
+pub struct Limiter {
+    max: usize,
+    policy: Policy,
+    permits: Arc<Semaphore>,
+    dropped: AtomicU64,
+    max_connections: usize,
+    connections: Arc<Semaphore>,
+    refused: AtomicU64,
+}
+
+impl Limiter {
+    pub fn new(max: usize, max_connections: usize, policy: Policy) -> anyhow::Result<Self> {
+        if max == 0 || max_connections == 0 {
+            return Err(anyhow::anyhow!(
+                "--max-inflight and --max-tcp-connections must be positive"
+            ));
+        }
+        Ok(Self {
+            max,
+            policy,
+            permits: Arc::new(Semaphore::new(max)),
+            dropped: AtomicU64::new(0),
+            max_connections,
+            connections: Arc::new(Semaphore::new(max_connections)),
+            refused: AtomicU64::new(0),
+        })
+    }
+
+    /// A permit to handle a request, held until it is answered, or None if it is to be dropped
+    pub async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        let permit = match self.policy {
+            Policy::Drop => self.permits.clone().try_acquire_owned().ok(),
+            Policy::Queue => self.permits.clone().acquire_owned().await.ok(),
+        };
+        if permit.is_none() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        permit
+    }
+
+    /// Requests being handled
+    pub fn inflight(&self) -> usize {
+        self.max - self.permits.available_permits()
+    }
+
+    /// Requests dropped so far
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// With --overload queue, a permit to serve the next TCP connection, waited for before it is
+    /// accepted, None otherwise
+    pub async fn reserve_connection(&self) -> Option<OwnedSemaphorePermit> {
+        match self.policy {
+            Policy::Drop => None,
+            Policy::Queue => self.connections.clone().acquire_owned().await.ok(),
+        }
+    }
+
+    /// A permit to serve a TCP connection just accepted, held until it is closed, or None if it
+    /// is to be closed at once
+    pub fn connection(&self) -> Option<OwnedSemaphorePermit> {
+        let permit = self.connections.clone().try_acquire_owned().ok();
+        if permit.is_none() {
+            self.refused.fetch_add(1, Ordering::Relaxed);
+        }
+        permit
+    }
+
+    /// TCP connections open
+    pub fn connections(&self) -> usize {
+        self.max_connections - self.connections.available_permits()
+    }
+
+    /// TCP connections closed at once so far
+    pub fn refused(&self) -> u64 {
+        self.refused.load(Ordering::Relaxed)
+    }
+}
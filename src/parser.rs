@@ -3,15 +3,15 @@ use nom::{
     branch::alt,
     bytes::complete::{tag, take},
     combinator::{eof, flat_map, map, map_res, verify},
-    error::Error,
-    multi::{count, many_till},
+    error::{Error, ErrorKind},
+    multi::count,
     number::complete::{be_u16, be_u32, be_u8},
     sequence::tuple,
     IResult,
 };
 use nom_derive::Nom;
 use num_enum::TryFromPrimitive;
-use std::borrow::Cow;
+use std::fmt;
 
 #[derive(TryFromPrimitive, Debug, Clone, Copy)]
 #[repr(u8)]
@@ -19,9 +19,11 @@ pub enum OpCode {
     Query = 0,
     IQuery = 1,
     Status = 2,
+    Notify = 4,
+    Update = 5,
 }
 
-#[derive(TryFromPrimitive, Nom, Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(TryFromPrimitive, Nom, Debug, PartialEq, Eq, Hash, Clone, Copy)]
 #[repr(u16)]
 pub enum Type {
     A = 1,
@@ -32,13 +34,76 @@ pub enum Type {
     MX = 15,
     TXT = 16,
     AAAA = 28,
+    SRV = 33,
 
     OPT = 41,
+    DS = 43,
+    RRSIG = 46,
+    NSEC = 47,
+    DNSKEY = 48,
+    NSEC3 = 50,
+    NSEC3PARAM = 51,
+    CDS = 59,
+    CDNSKEY = 60,
+    ZONEMD = 63,
+    /// Listed by the NSEC records of compact denial for names which do not exist, RFC 9824
+    NXNAME = 128,
 
+    TSIG = 250,
+    IXFR = 251,
     AXFR = 252,
     ANY = 255,
 }
 
+impl std::str::FromStr for Type {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_uppercase().as_str() {
+            "A" => Self::A,
+            "NS" => Self::NS,
+            "CNAME" => Self::CNAME,
+            "SOA" => Self::SOA,
+            "PTR" => Self::PTR,
+            "MX" => Self::MX,
+            "TXT" => Self::TXT,
+            "AAAA" => Self::AAAA,
+            "SRV" => Self::SRV,
+            "OPT" => Self::OPT,
+            "DS" => Self::DS,
+            "RRSIG" => Self::RRSIG,
+            "NSEC" => Self::NSEC,
+            "DNSKEY" => Self::DNSKEY,
+            "NSEC3" => Self::NSEC3,
+            "NSEC3PARAM" => Self::NSEC3PARAM,
+            "CDS" => Self::CDS,
+            "CDNSKEY" => Self::CDNSKEY,
+            "ZONEMD" => Self::ZONEMD,
+            "NXNAME" => Self::NXNAME,
+            "TSIG" => Self::TSIG,
+            "IXFR" => Self::IXFR,
+            "AXFR" => Self::AXFR,
+            "ANY" => Self::ANY,
+            _ => return Err(anyhow::anyhow!("Unknown record type {}", s)),
+        })
+    }
+}
+
+/// Types are written by their mnemonic, e.g. in the bitmaps of NSEC records
+impl serde::Serialize for Type {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("{:?}", self))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Type {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        <String as serde::Deserialize>::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
 impl Type {
     pub fn need_recursive(&self) -> bool {
         match self {
@@ -51,29 +116,94 @@ impl Type {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Class {
+    IN,
+    CH,
+    HS,
+    NONE,
+    ANY,
+
+    Other(u16),
+}
+
+impl From<u16> for Class {
+    fn from(raw: u16) -> Self {
+        match raw {
+            1 => Self::IN,
+            3 => Self::CH,
+            4 => Self::HS,
+            254 => Self::NONE,
+            255 => Self::ANY,
+            _ => Self::Other(raw),
+        }
+    }
+}
+
+impl From<Class> for u16 {
+    fn from(cls: Class) -> Self {
+        match cls {
+            Class::IN => 1,
+            Class::CH => 3,
+            Class::HS => 4,
+            Class::NONE => 254,
+            Class::ANY => 255,
+            Class::Other(raw) => raw,
+        }
+    }
+}
+
 pub struct Name<'a> {
-    pub labels: Vec<Cow<'a, str>>,
-    pub ptr: Option<u16>,
+    /// Raw labels, with compression pointers already followed. See `crate::label` for their
+    /// textual form.
+    pub labels: Vec<&'a [u8]>,
+}
+
+impl<'a> fmt::Debug for Name<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|l| crate::label::escape(l))
+            .collect();
+        f.debug_tuple("Name").field(&labels.join(".")).finish()
+    }
+}
+
+impl<'a> Name<'a> {
+    /// Textual form, as used in storage
+    pub fn to_record_name(&self) -> crate::record::Name {
+        crate::record::Name::from(
+            self.labels
+                .iter()
+                .map(|l| crate::label::escape(l))
+                .collect::<Vec<_>>(),
+        )
+    }
 }
 
 #[derive(Debug)]
 pub struct Question<'a> {
     pub name: Name<'a>,
     pub ty: Type,
-    // Right now, silently ignores QCLASS
+    pub class: Class,
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct RR<'a> {
+    /// Offset of the RR in the message
+    pub offset: usize,
     pub name: Name<'a>,
     pub ty: Type,
-    // Right now, silently ignores CLASS
+    // Kept raw, as OPT uses it for the UDP payload size
+    pub class: u16,
     pub ttl: u32,
     pub rdata: &'a [u8],
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct ReqHeaderStatus {
     pub qr: bool,
     pub opcode: OpCode,
@@ -91,21 +221,69 @@ pub struct ReqHeader {
     pub status: ReqHeaderStatus,
 
     pub qdcnt: u16,
-    pub arcnt: u16, // We are only looking for EDNS0
+    pub ancnt: u16,
+    pub nscnt: u16,
+    pub arcnt: u16,
 }
 
 #[derive(Debug)]
 pub struct Req<'a> {
     pub header: ReqHeader,
+    /// The zone section of updates
     pub questions: Vec<Question<'a>>,
+    /// The prerequisite section of updates
+    pub answers: Vec<RR<'a>>,
+    /// The update section of updates
+    pub authorities: Vec<RR<'a>>,
     pub additionals: Vec<RR<'a>>,
 }
 
+/// Upper bound on compression pointers followed within a single name
+const MAX_POINTER_HOPS: usize = 16;
+
+/// RFC 1035 Section 2.3.4
+const MAX_LABEL_LEN: u8 = 63;
+const MAX_NAME_LEN: usize = 255;
+const MAX_LABELS: usize = 128;
+
+/// EDNS(0) parameters carried by an OPT pseudo-RR, RFC 6891 Section 6.1
+#[derive(Debug, Clone, Copy)]
+pub struct Edns {
+    pub payload_size: u16,
+    pub version: u8,
+    pub dnssec_ok: bool,
+}
+
+impl<'a> Req<'a> {
+    /// Extracts EDNS parameters from the additional section. More than one OPT RR, or one not
+    /// owned by the root, is an error (RFC 6891 Section 6.1.1).
+    pub fn edns(&self) -> anyhow::Result<Option<Edns>> {
+        let mut opts = self.additionals.iter().filter(|rr| rr.ty == Type::OPT);
+        let opt = match opts.next() {
+            Some(opt) => opt,
+            None => return Ok(None),
+        };
+        if opts.next().is_some() {
+            return Err(anyhow::anyhow!("Multiple OPT RRs"));
+        }
+        if !opt.name.labels.is_empty() {
+            return Err(anyhow::anyhow!("OPT RR not owned by the root"));
+        }
+
+        Ok(Some(Edns {
+            payload_size: opt.class,
+            version: (opt.ttl >> 16) as u8,
+            dnssec_ok: opt.ttl & 0x8000 != 0,
+        }))
+    }
+}
+
 pub fn parse_header_status(input: &[u8]) -> IResult<&[u8], ReqHeaderStatus> {
     let parser = tuple::<_, _, Error<(&[u8], usize)>, _>((
         bits::complete::take(1usize),   // QR
         bits::complete::take(4usize),   // OPCODE
-        bits::complete::tag(0, 2usize), // AA + TC
+        bits::complete::take(1usize),   // AA, set in NOTIFY, RFC 1996 Section 3.7
+        bits::complete::tag(0, 1usize), // TC
         bits::complete::take(1usize),   // RD
         bits::complete::tag(0, 2usize), // RA + Z(1)
         bits::complete::take(1usize),   // AD
@@ -113,7 +291,7 @@ pub fn parse_header_status(input: &[u8]) -> IResult<&[u8], ReqHeaderStatus> {
         bits::complete::tag(0, 4usize), // RCODE
     ));
 
-    map_res(bits::bits(parser), |(qr, opcode_raw, _, rd, _, ad, cd, _): (u8, u8, _, u8, _, u8, u8, _)| -> Result<_, <OpCode as TryFrom<u8>>::Error> {
+    map_res(bits::bits(parser), |(qr, opcode_raw, _, _, rd, _, ad, cd, _): (u8, u8, u8, _, u8, _, u8, u8, _)| -> Result<_, <OpCode as TryFrom<u8>>::Error> {
         Ok(ReqHeaderStatus {
             qr: qr != 0,
             opcode: OpCode::try_from(opcode_raw)?,
@@ -125,84 +303,644 @@ pub fn parse_header_status(input: &[u8]) -> IResult<&[u8], ReqHeaderStatus> {
 }
 
 fn parse_header(input: &[u8]) -> IResult<&[u8], ReqHeader> {
-    let parser = tuple((
-        be_u16,
-        parse_header_status,
-        be_u16,
-        count(tag(b"\0\0"), 2),
-        be_u16,
-    ));
+    let parser = tuple((be_u16, parse_header_status, be_u16, be_u16, be_u16, be_u16));
 
-    map(parser, |(id, status, qdcnt, _, arcnt)| ReqHeader {
-        id,
-        status,
-        qdcnt,
-        arcnt,
+    map(parser, |(id, status, qdcnt, ancnt, nscnt, arcnt)| {
+        ReqHeader {
+            id,
+            status,
+            qdcnt,
+            ancnt,
+            nscnt,
+            arcnt,
+        }
     })(input)
 }
 
-fn parse_label<'a>(input: &'a [u8]) -> IResult<&'a [u8], Cow<'a, str>> {
-    map(flat_map(be_u8, |cnt| take(cnt)), |slice| {
-        String::from_utf8_lossy(slice)
-    })(input)
+fn parse_label(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    // The two upper bits are reserved for pointers and extended label types
+    flat_map(verify(be_u8, |len| *len <= MAX_LABEL_LEN), take)(input)
 }
 
-fn parse_ptr<'a>(input: &'a [u8]) -> IResult<&'a [u8], Option<u16>> {
+fn parse_ptr(input: &[u8]) -> IResult<&[u8], Option<u16>> {
     alt((
         map(tag(b"\0"), |_| None),
         map(verify(be_u16, |parsed| (parsed >> 14) == 3), Option::Some),
     ))(input)
 }
 
-fn parse_name<'a>(input: &'a [u8]) -> IResult<&'a [u8], Name<'a>> {
-    map(many_till(parse_label, parse_ptr), |(labels, ptr)| Name {
-        labels,
-        ptr,
-    })(input)
+/// Parses labels into `labels` until the root label or a compression pointer, which is returned.
+/// `wire_len` accumulates the uncompressed length of the name, across pointers.
+fn parse_name_labels<'a>(
+    mut input: &'a [u8],
+    labels: &mut Vec<&'a [u8]>,
+    wire_len: &mut usize,
+) -> IResult<&'a [u8], Option<u16>> {
+    loop {
+        if let Ok((rest, ptr)) = parse_ptr(input) {
+            return Ok((rest, ptr));
+        }
+
+        let (rest, label) = parse_label(input)?;
+        *wire_len += label.len() + 1;
+        // Account for the terminating root label
+        if *wire_len + 1 > MAX_NAME_LEN || labels.len() >= MAX_LABELS {
+            return Err(nom::Err::Failure(Error::new(input, ErrorKind::TooLarge)));
+        }
+        labels.push(label);
+        input = rest;
+    }
+}
+
+/// Parses a name, following compression pointers into `msg`, the whole message.
+///
+/// Pointers may only point strictly before themselves, and at most `MAX_POINTER_HOPS` are
+/// followed, so crafted packets cannot loop the parser or blow it up quadratically.
+pub fn parse_name<'a>(msg: &'a [u8]) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], Name<'a>> {
+    move |input: &'a [u8]| {
+        let mut labels = Vec::new();
+        let mut wire_len = 0;
+        let (rest, mut ptr) = parse_name_labels(input, &mut labels, &mut wire_len)?;
+
+        // Offset past the pointer currently being followed
+        let mut ptr_end = msg.len() - rest.len();
+        let mut hops = 0;
+        while let Some(raw) = ptr {
+            let target = (raw & 0x3fff) as usize;
+            hops += 1;
+            if target + 2 >= ptr_end || hops > MAX_POINTER_HOPS {
+                return Err(nom::Err::Failure(Error::new(input, ErrorKind::Verify)));
+            }
+
+            let (after, next) = parse_name_labels(&msg[target..], &mut labels, &mut wire_len)?;
+            ptr = next;
+            ptr_end = msg.len() - after.len();
+        }
+
+        Ok((rest, Name { labels }))
+    }
 }
 
-fn parse_question<'a>(input: &'a [u8]) -> IResult<&'a [u8], Question<'a>> {
+fn parse_question<'a>(msg: &'a [u8]) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], Question<'a>> {
     use nom_derive::Parse;
     map(
-        tuple((parse_name, Type::parse, be_u16)),
-        |(name, ty, _cls)| Question { name, ty },
-    )(input)
+        tuple((parse_name(msg), Type::parse, map(be_u16, Class::from))),
+        |(name, ty, class)| Question { name, ty, class },
+    )
 }
 
-fn parse_rr<'a>(input: &'a [u8]) -> IResult<&'a [u8], RR<'a>> {
+/// A question of any type and class
+fn parse_question_raw<'a>(
+    msg: &'a [u8],
+) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], (Name<'a>, u16, Class)> {
+    tuple((parse_name(msg), be_u16, map(be_u16, Class::from)))
+}
+
+fn parse_rr<'a>(msg: &'a [u8]) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], RR<'a>> {
     use nom_derive::Parse;
-    map(
-        tuple((
-            parse_name,
-            Type::parse,
-            be_u16,                 // Class
-            be_u32,                 // TTL
-            flat_map(be_u16, take), // RDLENGRTH + RDATA
-        )),
-        |(name, ty, _cls, ttl, rdata)| RR {
+    move |input: &'a [u8]| {
+        let offset = msg.len() - input.len();
+        map(
+            tuple((
+                parse_name(msg),
+                Type::parse,
+                be_u16,                 // Class
+                be_u32,                 // TTL
+                flat_map(be_u16, take), // RDLENGRTH + RDATA
+            )),
+            move |(name, ty, class, ttl, rdata)| RR {
+                offset,
+                name,
+                ty,
+                class,
+                ttl,
+                rdata,
+            },
+        )(input)
+    }
+}
+
+fn parse_request<'a>(input: &'a [u8]) -> IResult<&'a [u8], Req<'a>> {
+    let msg = input;
+    let (input, hdr) = parse_header(input)?;
+    let (input, questions) = count(parse_question(msg), hdr.qdcnt as usize)(input)?;
+    let (input, answers) = count(parse_rr(msg), hdr.ancnt as usize)(input)?;
+    let (input, authorities) = count(parse_rr(msg), hdr.nscnt as usize)(input)?;
+    let (input, additionals) = count(parse_rr(msg), hdr.arcnt as usize)(input)?;
+
+    Ok((
+        input,
+        Req {
+            header: hdr,
+            questions,
+            answers,
+            authorities,
+            additionals,
+        },
+    ))
+}
+
+/// Decodes the RDATA of `rr`, a RR of `msg`, the whole message, in which compressed names point
+pub fn parse_rdata(msg: &[u8], rr: &RR<'_>) -> anyhow::Result<crate::record::RecordInner> {
+    use crate::record::RecordInner;
+
+    let start = rr.rdata.as_ptr() as usize - msg.as_ptr() as usize;
+    // Names may point anywhere before them in the message, so parse from the message itself
+    let input = &msg[start..];
+    let end = msg.len() - (input.len() - rr.rdata.len());
+    let bad = |_| anyhow::anyhow!("Malformed {:?} RDATA", rr.ty);
+
+    let (rest, inner) = match rr.ty {
+        Type::A => {
+            let (rest, addr) = take(4usize)(input).map_err(bad)?;
+            (
+                rest,
+                RecordInner::A {
+                    addr: addr.try_into().unwrap(),
+                },
+            )
+        }
+        Type::AAAA => {
+            let (rest, addr) = take(16usize)(input).map_err(bad)?;
+            (
+                rest,
+                RecordInner::AAAA {
+                    addr: addr.try_into().unwrap(),
+                },
+            )
+        }
+        Type::NS => {
+            let (rest, ns) = parse_name(msg)(input).map_err(bad)?;
+            (
+                rest,
+                RecordInner::NS {
+                    ns: ns.to_record_name(),
+                },
+            )
+        }
+        Type::CNAME => {
+            let (rest, to) = parse_name(msg)(input).map_err(bad)?;
+            (
+                rest,
+                RecordInner::CNAME {
+                    to: to.to_record_name(),
+                },
+            )
+        }
+        Type::PTR => {
+            let (rest, ptr) = parse_name(msg)(input).map_err(bad)?;
+            (
+                rest,
+                RecordInner::PTR {
+                    ptr: ptr.to_record_name(),
+                },
+            )
+        }
+        Type::SRV => {
+            let (rest, (priority, weight, port, target)) =
+                tuple((be_u16, be_u16, be_u16, parse_name(msg)))(input).map_err(bad)?;
+            (
+                rest,
+                RecordInner::SRV {
+                    priority,
+                    weight,
+                    port,
+                    target: target.to_record_name(),
+                },
+            )
+        }
+        Type::SOA => {
+            let (rest, (mname, rname, serial, refresh, retry, expire, minimum)) =
+                tuple((
+                    parse_name(msg),
+                    parse_name(msg),
+                    be_u32,
+                    be_u32,
+                    be_u32,
+                    be_u32,
+                    be_u32,
+                ))(input)
+                .map_err(bad)?;
+            (
+                rest,
+                RecordInner::SOA {
+                    serial,
+                    mname: mname.to_record_name(),
+                    rname: rname.to_record_name(),
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                },
+            )
+        }
+        Type::TXT => {
+            let mut content = Vec::new();
+            let mut rest = input;
+            while msg.len() - rest.len() < end {
+                let (after, chunk) = flat_map(be_u8, take)(rest).map_err(bad)?;
+                content.extend_from_slice(chunk);
+                rest = after;
+            }
+            let content = String::from_utf8(content)
+                .map_err(|_| anyhow::anyhow!("TXT RDATA is not valid UTF-8"))?;
+            (rest, RecordInner::TXT { content })
+        }
+        ty => return Err(anyhow::anyhow!("Unsupported record type {:?}", ty)),
+    };
+
+    if msg.len() - rest.len() != end {
+        return Err(anyhow::anyhow!("Malformed {:?} RDATA", rr.ty));
+    }
+    Ok(inner)
+}
+
+/// A RR of any type, its RDATA as is
+pub struct RawRR<'a> {
+    pub name: Name<'a>,
+    pub ty: u16,
+    pub class: u16,
+    pub ttl: u32,
+    /// Offset of the RDATA in the message, where its compressed names point from
+    pub rdata_offset: usize,
+    pub rdata: &'a [u8],
+}
+
+/// A response whose RRs are of any type, as relayed from upstream resolvers
+pub struct RawResp<'a> {
+    pub flags: u16,
+    /// Name and type of the question
+    pub question: Option<(Name<'a>, u16)>,
+    /// Offset past the question section
+    pub question_end: usize,
+    pub answers: Vec<RawRR<'a>>,
+    pub authorities: Vec<RawRR<'a>>,
+    pub additionals: Vec<RawRR<'a>>,
+}
+
+fn parse_raw_rr<'a>(msg: &'a [u8]) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], RawRR<'a>> {
+    move |input: &'a [u8]| {
+        let (rest, (name, ty, class, ttl, len)) =
+            tuple((parse_name(msg), be_u16, be_u16, be_u32, be_u16))(input)?;
+        let rdata_offset = msg.len() - rest.len();
+        let (rest, rdata) = take(len)(rest)?;
+        let rr = RawRR {
             name,
             ty,
+            class,
             ttl,
+            rdata_offset,
             rdata,
+        };
+        Ok((rest, rr))
+    }
+}
+
+pub fn parse_raw_response(msg: &[u8]) -> IResult<&[u8], RawResp<'_>> {
+    let input = msg;
+    let (input, (_, flags, qdcnt, ancnt, nscnt, arcnt)) =
+        tuple((be_u16, be_u16, be_u16, be_u16, be_u16, be_u16))(input)?;
+    let (input, questions) = count(parse_question_raw(msg), qdcnt as usize)(input)?;
+    let question_end = msg.len() - input.len();
+    let (input, answers) = count(parse_raw_rr(msg), ancnt as usize)(input)?;
+    let (input, authorities) = count(parse_raw_rr(msg), nscnt as usize)(input)?;
+    let (input, additionals) = count(parse_raw_rr(msg), arcnt as usize)(input)?;
+    let (input, _) = eof(input)?;
+    Ok((
+        input,
+        RawResp {
+            flags,
+            question: questions.into_iter().next().map(|(name, ty, _)| (name, ty)),
+            question_end,
+            answers,
+            authorities,
+            additionals,
         },
-    )(input)
+    ))
 }
 
-fn parse_request<'a>(input: &'a [u8]) -> IResult<&'a [u8], Req<'a>> {
-    let (input, hdr) = parse_header(input)?;
-    let (input, questions) = count(parse_question, hdr.qdcnt as usize)(input)?;
-    let (input, additionals) = count(parse_rr, hdr.arcnt as usize)(input)?;
+/// Offset past the name at `i` in `msg`
+fn skip_name(msg: &[u8], mut i: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(i)? as usize;
+        match len & 0xc0 {
+            0xc0 => return Some(i + 2),
+            0 if len == 0 => return Some(i + 1),
+            0 => i += 1 + len,
+            _ => return None,
+        }
+    }
+}
 
+/// Where the OPT RR of `msg` starts, its RDATA starts, and it ends, if it has one. None if `msg`
+/// is malformed.
+pub fn find_opt(msg: &[u8]) -> Option<Option<(usize, usize, usize)>> {
+    let count = |i: usize| -> Option<usize> {
+        Some(u16::from_be_bytes(msg.get(i..i + 2)?.try_into().ok()?) as usize)
+    };
+    let (qdcount, rrs, arcount) = (count(4)?, count(6)? + count(8)?, count(10)?);
+    let mut i = 12;
+    for _ in 0..qdcount {
+        i = skip_name(msg, i)? + 4;
+    }
+    for n in 0..rrs + arcount {
+        let start = i;
+        i = skip_name(msg, i)?;
+        let ty = count(i)?;
+        let rdata = i + 10;
+        let end = rdata + count(i + 8)?;
+        if end > msg.len() {
+            return None;
+        }
+        if n >= rrs && ty == Type::OPT as usize {
+            return Some(Some((start, rdata, end)));
+        }
+        i = end;
+    }
+    Some(None)
+}
+
+/// The options of the RDATA of an OPT RR, as code and data
+pub fn options(rdata: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    let mut rest = rdata;
+    std::iter::from_fn(move || {
+        let code = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?);
+        let len = u16::from_be_bytes(rest.get(2..4)?.try_into().ok()?) as usize;
+        let data = rest.get(4..4 + len)?;
+        rest = &rest[4 + len..];
+        Some((code, data))
+    })
+}
+
+/// A response to one of our own requests, e.g. a zone transfer as a secondary
+#[derive(Debug)]
+pub struct Resp<'a> {
+    pub id: u16,
+    pub rcode: u8,
+    /// Names, types and classes of the questions, of any type
+    pub questions: Vec<(Name<'a>, u16, Class)>,
+    pub arcnt: u16,
+    pub answers: Vec<RR<'a>>,
+    pub authorities: Vec<RR<'a>>,
+    pub additionals: Vec<RR<'a>>,
+    /// RRs of types we do not know, left out of the sections above
+    pub unknown: usize,
+}
+
+/// Parses a RR, or skips it if its type is unknown
+fn parse_any_rr<'a>(msg: &'a [u8]) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], Option<RR<'a>>> {
+    move |input: &'a [u8]| {
+        let offset = msg.len() - input.len();
+        let (rest, (name, ty, class, ttl, rdata)) = tuple((
+            parse_name(msg),
+            be_u16,
+            be_u16,
+            be_u32,
+            flat_map(be_u16, take),
+        ))(input)?;
+        let rr = Type::try_from(ty).ok().map(|ty| RR {
+            offset,
+            name,
+            ty,
+            class,
+            ttl,
+            rdata,
+        });
+        Ok((rest, rr))
+    }
+}
+
+pub fn parse_response<'a>(input: &'a [u8]) -> IResult<&'a [u8], Resp<'a>> {
+    let msg = input;
+    let (input, (id, flags, qdcnt, ancnt, nscnt, arcnt)) =
+        tuple((be_u16, be_u16, be_u16, be_u16, be_u16, be_u16))(input)?;
+    let (input, questions) = count(parse_question_raw(msg), qdcnt as usize)(input)?;
+    let (input, answers) = count(parse_any_rr(msg), ancnt as usize)(input)?;
+    let (input, authorities) = count(parse_any_rr(msg), nscnt as usize)(input)?;
+    let (input, additionals) = count(parse_any_rr(msg), arcnt as usize)(input)?;
+    let (input, _) = eof(input)?;
+
+    let total = answers.len() + authorities.len() + additionals.len();
+    let answers: Vec<RR<'a>> = answers.into_iter().flatten().collect();
+    let authorities: Vec<RR<'a>> = authorities.into_iter().flatten().collect();
+    let additionals: Vec<RR<'a>> = additionals.into_iter().flatten().collect();
+    let known = answers.len() + authorities.len() + additionals.len();
     Ok((
         input,
-        Req {
-            header: hdr,
+        Resp {
+            id,
+            rcode: (flags & 0xf) as u8,
             questions,
+            arcnt,
+            answers,
+            authorities,
             additionals,
+            unknown: total - known,
         },
     ))
 }
 
+/// RDATA of a TSIG RR, RFC 8945 Section 4.2
+#[derive(Debug)]
+pub struct Tsig<'a> {
+    pub algorithm: Name<'a>,
+    /// Seconds since the epoch, 48 bits
+    pub time_signed: u64,
+    pub fudge: u16,
+    pub mac: &'a [u8],
+    pub original_id: u16,
+    pub error: u16,
+    pub other: &'a [u8],
+}
+
+/// Decodes the RDATA of `rr`, a TSIG RR of `msg`
+pub fn parse_tsig<'a>(msg: &'a [u8], rr: &RR<'a>) -> anyhow::Result<Tsig<'a>> {
+    let start = rr.rdata.as_ptr() as usize - msg.as_ptr() as usize;
+    let end = start + rr.rdata.len();
+    let parsed: IResult<_, _> = tuple((
+        parse_name(msg),
+        be_u16,
+        be_u32,
+        be_u16,
+        flat_map(be_u16, take), // MAC size + MAC
+        be_u16,
+        be_u16,
+        flat_map(be_u16, take), // Other len + other data
+    ))(&msg[start..]);
+    let malformed = || anyhow::anyhow!("Malformed TSIG RDATA");
+    let (rest, (algorithm, time_hi, time_lo, fudge, mac, original_id, error, other)) =
+        parsed.map_err(|_| malformed())?;
+    if msg.len() - rest.len() != end {
+        return Err(malformed());
+    }
+
+    Ok(Tsig {
+        algorithm,
+        time_signed: (time_hi as u64) << 32 | time_lo as u64,
+        fudge,
+        mac,
+        original_id,
+        error,
+        other,
+    })
+}
+
 pub fn parse<'a>(input: &'a [u8]) -> IResult<&'a [u8], Req<'a>> {
     map(tuple((parse_request, eof)), |(res, _)| res)(input)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `name` in wire format, uncompressed
+    fn wire(name: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        for label in name.split('.').filter(|l| !l.is_empty()) {
+            out.push(label.len() as u8);
+            out.extend_from_slice(label.as_bytes());
+        }
+        out.push(0);
+        out
+    }
+
+    /// Parses the name at `offset` of `msg`, as text
+    fn name_at(msg: &[u8], offset: usize) -> Result<String, ()> {
+        let (_, name) = parse_name(msg)(&msg[offset..]).map_err(|_| ())?;
+        let labels: Vec<String> = name
+            .labels
+            .iter()
+            .map(|l| crate::label::escape(l))
+            .collect();
+        Ok(labels.join("."))
+    }
+
+    #[test]
+    fn uncompressed() {
+        let msg = wire("www.example.com");
+        assert_eq!(name_at(&msg, 0).unwrap(), "www.example.com");
+        assert_eq!(name_at(&[0], 0).unwrap(), "");
+    }
+
+    #[test]
+    fn backward_pointer() {
+        let mut msg = wire("example.com");
+        msg.extend_from_slice(b"\x03www\xc0\x00");
+        assert_eq!(name_at(&msg, 13).unwrap(), "www.example.com");
+        // A pointer to the middle of a name
+        msg.extend_from_slice(b"\x04mail\xc0\x08");
+        assert_eq!(name_at(&msg, 19).unwrap(), "mail.com");
+    }
+
+    #[test]
+    fn pointer_to_itself() {
+        assert!(name_at(b"\xc0\x00", 0).is_err());
+        assert!(name_at(b"\x03www\xc0\x00", 0).is_err());
+    }
+
+    #[test]
+    fn forward_pointer() {
+        let mut msg = b"\xc0\x02".to_vec();
+        msg.extend_from_slice(&wire("example.com"));
+        assert!(name_at(&msg, 0).is_err());
+    }
+
+    #[test]
+    fn pointer_loop() {
+        // Each name points at the other
+        assert!(name_at(b"\xc0\x02\xc0\x00", 2).is_err());
+        assert!(name_at(b"\xc0\x02\xc0\x00", 0).is_err());
+    }
+
+    #[test]
+    fn pointer_out_of_bounds() {
+        assert!(name_at(b"\x00\xc0\x40", 1).is_err());
+    }
+
+    /// A name followed by `hops` pointers, each to the one before, the last of which is returned
+    fn chain(hops: usize) -> (Vec<u8>, usize) {
+        let mut msg = wire("example.com");
+        let mut last = 0;
+        for _ in 0..hops {
+            let here = msg.len();
+            msg.extend_from_slice(&(0xc000 | last as u16).to_be_bytes());
+            last = here;
+        }
+        (msg, last)
+    }
+
+    #[test]
+    fn pointer_hops() {
+        let (msg, last) = chain(MAX_POINTER_HOPS);
+        assert_eq!(name_at(&msg, last).unwrap(), "example.com");
+        let (msg, last) = chain(MAX_POINTER_HOPS + 1);
+        assert!(name_at(&msg, last).is_err());
+    }
+
+    #[test]
+    fn label_length() {
+        let label = "a".repeat(63);
+        assert_eq!(name_at(&wire(&label), 0).unwrap(), label);
+        assert!(name_at(&wire(&"a".repeat(64)), 0).is_err());
+        // Extended label types
+        assert!(name_at(b"\x41a\x00", 0).is_err());
+    }
+
+    #[test]
+    fn name_length() {
+        // 3 * 64 + 62 + 1 = 255 bytes
+        let label = "a".repeat(63);
+        let longest = format!("{0}.{0}.{0}.{1}", label, "b".repeat(61));
+        assert_eq!(name_at(&wire(&longest), 0).unwrap(), longest);
+        let over = format!("{0}.{0}.{0}.{1}", label, "b".repeat(62));
+        assert!(name_at(&wire(&over), 0).is_err());
+    }
+
+    #[test]
+    fn label_count() {
+        // 127 labels of one byte and the root make 255 bytes
+        let most = vec!["x"; 127].join(".");
+        assert_eq!(name_at(&wire(&most), 0).unwrap(), most);
+        let over = vec!["x"; 128].join(".");
+        assert!(name_at(&wire(&over), 0).is_err());
+    }
+
+    #[test]
+    fn name_length_across_pointers() {
+        // 4 labels of 63 bytes, of which 3 behind a pointer, make 257 bytes
+        let label = "a".repeat(63);
+        let mut msg = wire(&format!("{0}.{0}.{0}", label));
+        let start = msg.len();
+        msg.push(63);
+        msg.extend_from_slice(label.as_bytes());
+        msg.extend_from_slice(b"\xc0\x00");
+        assert!(name_at(&msg, start).is_err());
+    }
+
+    /// A query for `name` of `ty` in class IN
+    fn query(name: &[u8], ty: u16) -> Vec<u8> {
+        let mut msg = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        msg.extend_from_slice(name);
+        msg.extend_from_slice(&ty.to_be_bytes());
+        msg.extend_from_slice(&1u16.to_be_bytes());
+        msg
+    }
+
+    #[test]
+    fn request() {
+        let msg = query(&wire("www.example.com"), 1);
+        let (_, req) = parse(&msg).unwrap();
+        assert_eq!(req.header.id, 0x1234);
+        assert_eq!(req.questions.len(), 1);
+        assert_eq!(req.questions[0].ty, Type::A);
+        assert_eq!(req.questions[0].name.labels.len(), 3);
+    }
+
+    #[test]
+    fn request_with_looping_question() {
+        // The question name points at itself, past the header
+        assert!(parse(&query(b"\xc0\x0c", 1)).is_err());
+        // Truncated
+        assert!(parse(&query(&wire("example.com"), 1)[..20]).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+//! Dropping root privileges once the DNS sockets are bound, with --user and --group, so that the
+//! server may listen on port 53 without parsing zone data and queries as root. The process
+//! switches to the user and group given, by name or ID, the primary group of the user unless
+//! --group is given, with no supplementary groups, before reading any zone data or key: those
+//! must be readable by that user, and the addresses of --api, --metrics and --external-dns
+//! bindable. Being left running as root is refused unless --allow-root is given.
+
+/// Switches to `user` and `group`, if given. Refuses to go on as root unless `allow_root`.
+#[cfg(unix)]
+pub fn drop(user: Option<&str>, group: Option<&str>, allow_root: bool) -> anyhow::Result<()> {
+    let user = user.map(passwd).transpose()?;
+    let gid = match (group, user) {
+        (Some(group), _) => Some(gid(group)?),
+        (None, Some((_, Some(gid)))) => Some(gid),
+        (None, Some((uid, None))) => {
+            return Err(anyhow::anyhow!("No user {}, give --group", uid));
+        }
+        (None, None) => None,
+    };
+    if let Some(gid) = gid {
+        // SAFETY: plain system calls, given a single group
+        if unsafe { libc::setgroups(1, &gid) != 0 || libc::setgid(gid) != 0 } {
+            let e = std::io::Error::last_os_error();
+            return Err(anyhow::anyhow!("Failed to switch to group {}: {}", gid, e));
+        }
+    }
+    if let Some((uid, _)) = user {
+        // SAFETY: a plain system call
+        if unsafe { libc::setuid(uid) } != 0 {
+            let e = std::io::Error::last_os_error();
+            return Err(anyhow::anyhow!("Failed to switch to user {}: {}", uid, e));
+        }
+        // SAFETY: a plain system call, which must fail
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(anyhow::anyhow!("Root privileges could be regained"));
+        }
+        log::info!("Running as user {}, group {}", uid, gid.unwrap_or_default());
+    }
+    // SAFETY: plain system calls
+    let root = unsafe { libc::geteuid() == 0 || libc::getegid() == 0 };
+    if root && !allow_root {
+        return Err(anyhow::anyhow!(
+            "Refusing to run as root, give --user or --allow-root"
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop(user: Option<&str>, group: Option<&str>, _allow_root: bool) -> anyhow::Result<()> {
+    match user.or(group) {
+        Some(_) => Err(anyhow::anyhow!(
+            "--user and --group are only supported on Unix"
+        )),
+        None => Ok(()),
+    }
+}
+
+/// The ID and primary group of `user`, a name or a numeric ID, the latter maybe without an entry
+#[cfg(unix)]
+fn passwd(user: &str) -> anyhow::Result<(libc::uid_t, Option<libc::gid_t>)> {
+    let name = std::ffi::CString::new(user)?;
+    let mut buf = vec![0; 16384];
+    // SAFETY: zeroed, it is a plain C struct of integers and pointers
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    // SAFETY: the buffer outlives the entry, read before either is dropped
+    let error = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut found,
+        )
+    };
+    match (error, found.is_null(), user.parse::<libc::uid_t>()) {
+        (0, false, _) => Ok((entry.pw_uid, Some(entry.pw_gid))),
+        (_, _, Ok(uid)) => {
+            // SAFETY: as above
+            let error = unsafe {
+                libc::getpwuid_r(uid, &mut entry, buf.as_mut_ptr(), buf.len(), &mut found)
+            };
+            Ok((
+                uid,
+                (error == 0 && !found.is_null()).then_some(entry.pw_gid),
+            ))
+        }
+        _ => Err(anyhow::anyhow!("No user {}", user)),
+    }
+}
+
+/// The ID of `group`, a name or a numeric ID
+#[cfg(unix)]
+fn gid(group: &str) -> anyhow::Result<libc::gid_t> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = std::ffi::CString::new(group)?;
+    let mut buf = vec![0; 16384];
+    // SAFETY: zeroed, it is a plain C struct of integers and pointers
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    // SAFETY: the buffer outlives the entry, read before either is dropped
+    let error = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut found,
+        )
+    };
+    match (error, found.is_null()) {
+        (0, false) => Ok(entry.gr_gid),
+        _ => Err(anyhow::anyhow!("No group {}", group)),
+    }
+}
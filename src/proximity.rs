@@ -0,0 +1,96 @@
+//! Ordering of answers by proximity, for clients to try the nearest endpoints first: records with
+//! a `location`, a continent or ISO 3166 country code as for geo constraints, e.g.
+//!
+//! ```yaml
+//! www:
+//!   - {type: A, ttl: 300, addr: 192.0.2.1, location: eu}
+//!   - {type: A, ttl: 300, addr: 198.51.100.1, location: na}
+//!   - {type: A, ttl: 300, addr: 203.0.113.1, location: jp}
+//! ```
+//!
+//! are served those of the country of the client first, then those of the regions nearest to its
+//! country, then those of its continent, then those of the regions nearest to its continent, the
+//! records without a location or of other regions last, in the order they would have been
+//! otherwise. Clients are located as for geo constraints, with --geoip, see the geo module, after
+//! which, and after weights, see the weight module, answers are ordered.
+//!
+//! Continents are near one another as by default:
+//!
+//! ```yaml
+//! af: [eu, as, sa, na, oc, an]
+//! as: [oc, eu, na, af, sa, an]
+//! eu: [af, as, na, sa, oc, an]
+//! na: [sa, eu, as, oc, af, an]
+//! oc: [as, na, sa, eu, af, an]
+//! sa: [na, af, eu, oc, as, an]
+//! an: [sa, oc, af, na, as, eu]
+//! ```
+//!
+//! which the file of --proximity, in the same layout, overrides for the regions it lists, nearest
+//! first. It may list countries too, e.g. `ca: [us]`, or `at: [de, ch]`.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::record::Record;
+
+const DEFAULT: [(&str, [&str; 6]); 7] = [
+    ("af", ["eu", "as", "sa", "na", "oc", "an"]),
+    ("as", ["oc", "eu", "na", "af", "sa", "an"]),
+    ("eu", ["af", "as", "na", "sa", "oc", "an"]),
+    ("na", ["sa", "eu", "as", "oc", "af", "an"]),
+    ("oc", ["as", "na", "sa", "eu", "af", "an"]),
+    ("sa", ["na", "af", "eu", "oc", "as", "an"]),
+    ("an", ["sa", "oc", "af", "na", "as", "eu"]),
+];
+
+/// The regions nearest to each region, nearest first
+pub struct Proximity(HashMap<String, Vec<String>>);
+
+impl Proximity {
+    /// The default proximities, overridden by those of the file at `path`, if any
+    pub fn new(path: Option<&Path>) -> anyhow::Result<Self> {
+        let mut nearest: HashMap<String, Vec<String>> = DEFAULT
+            .iter()
+            .map(|(region, nearest)| {
+                let nearest = nearest.iter().map(|region| region.to_string()).collect();
+                (region.to_string(), nearest)
+            })
+            .collect();
+        if let Some(path) = path {
+            let data = std::fs::read(path)
+                .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+            let regions: HashMap<String, Vec<String>> = serde_yaml::from_slice(&data)
+                .map_err(|e| anyhow::anyhow!("Invalid proximities in {}: {}", path.display(), e))?;
+            for (region, near) in regions {
+                let near = near.iter().map(|region| region.to_ascii_lowercase());
+                nearest.insert(region.to_ascii_lowercase(), near.collect());
+            }
+        }
+        Ok(Self(nearest))
+    }
+
+    /// Orders `records` by proximity to a client in `regions`, its continent then its country
+    pub fn order(&self, records: &mut [&Record], regions: &[String]) {
+        if regions.is_empty() || records.iter().all(|record| record.location.is_none()) {
+            return;
+        }
+        // Its country first, then its continent
+        let mut preferred: Vec<&str> = Vec::new();
+        for region in regions.iter().rev() {
+            preferred.push(region);
+            let nearest = self.0.get(region).into_iter().flatten();
+            preferred.extend(nearest.map(String::as_str));
+        }
+        // Stable, for records as near to keep their order
+        records.sort_by_key(|record| {
+            let location = record.location.as_deref();
+            location
+                .and_then(|location| {
+                    let mut regions = preferred.iter();
+                    regions.position(|region| location.eq_ignore_ascii_case(region))
+                })
+                .unwrap_or(usize::MAX)
+        });
+    }
+}
@@ -0,0 +1,289 @@
+//! Query log, with --query-log: one JSON object per line for each query, giving when it was
+//! received, its client, transport, name and type, the RCODE and size of the response, and how
+//! long it took to answer, in microseconds:
+//!
+//! ```text
+//! {"time": 1760600000.123, "client": "192.0.2.1", "port": 53124, "proto": "udp", "qname": "www.example.com.", "qtype": "A", "rcode": "NOERROR", "latency_us": 85, "size": 63}
+//! ```
+//!
+//! Queries left unanswered, such as those over their client's rate, have a null RCODE, and
+//! malformed ones a null name and type. Zone transfers are logged once, with the size of all
+//! their messages. With --query-log-sample N, only one query in N is logged. Entries are written
+//! by a thread of their own, those it cannot keep up with being dropped and counted by
+//! `dns_query_log_dropped_total`, see the metrics module. Those queued are written out when the
+//! server shuts down.
+//!
+//! The file is rotated once over --query-log-max-size megabytes, or open for
+//! --query-log-rotate-interval seconds: it is renamed with the suffix .1, older ones shifting to
+//! .2 and so on, up to --query-log-keep files, the oldest being removed.
+
+use std::cell::Cell;
+use std::fs::File;
+use std::future::Future;
+use std::io::{BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use tokio::sync::mpsc;
+
+/// Entries waiting to be written, beyond which they are dropped
+const QUEUE: usize = 10_000;
+
+tokio::task_local! {
+    /// RCODE and total size of the responses sent to the query being logged
+    static RESPONSE: Cell<Option<(u8, usize)>>;
+}
+
+/// A query, as received
+pub struct Query {
+    time: SystemTime,
+    start: Instant,
+    remote: SocketAddr,
+    tcp: bool,
+    question: Option<(String, String)>,
+}
+
+impl Query {
+    pub fn new(buf: &[u8], remote: SocketAddr, tcp: bool) -> Self {
+        Self {
+            time: SystemTime::now(),
+            start: Instant::now(),
+            remote,
+            tcp,
+            question: question(buf),
+        }
+    }
+}
+
+/// The name and type of the first question of the request in `buf`, unless malformed
+pub fn question(buf: &[u8]) -> Option<(String, String)> {
+    crate::parser::parse(buf)
+        .ok()
+        .and_then(|(_, req)| req.questions.into_iter().next())
+        .map(|q| (q.name.to_record_name().to_absolute(), format!("{:?}", q.ty)))
+}
+
+pub struct QueryLog {
+    sample: u64,
+    received: AtomicU64,
+    /// Entries to write, an empty one, which no query makes, stopping the writer
+    entries: mpsc::Sender<String>,
+    dropped: AtomicU64,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl QueryLog {
+    /// Logs one query in `sample` to the file at `path`, rotated once over `max_size` megabytes
+    /// or `interval` old, keeping `keep` rotated files
+    pub fn open(
+        path: PathBuf,
+        sample: u64,
+        max_size: u64,
+        interval: Duration,
+        keep: usize,
+    ) -> anyhow::Result<Self> {
+        if sample == 0 || max_size == 0 || interval.is_zero() {
+            return Err(anyhow::anyhow!(
+                "--query-log-sample, --query-log-max-size and --query-log-rotate-interval must be positive"
+            ));
+        }
+        let (file, size) = open(&path)?;
+        let mut writer = Writer {
+            path,
+            file,
+            size,
+            opened: Instant::now(),
+            max_size: max_size * 1024 * 1024,
+            interval,
+            keep,
+        };
+        let (entries, mut queue) = mpsc::channel(QUEUE);
+        let writer = std::thread::Builder::new()
+            .name("query-log".into())
+            .spawn(move || writer.run(&mut queue))?;
+        Ok(Self {
+            sample,
+            received: AtomicU64::new(0),
+            entries,
+            dropped: AtomicU64::new(0),
+            writer: Mutex::new(Some(writer)),
+        })
+    }
+
+    /// Whether the next query is to be logged
+    pub fn sampled(&self) -> bool {
+        self.received
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(self.sample)
+    }
+
+    /// Runs `handle`, answering `query`, then logs it with its response
+    pub async fn log<F>(&self, query: Query, handle: F) -> anyhow::Result<()>
+    where
+        F: Future<Output = anyhow::Result<()>>,
+    {
+        RESPONSE
+            .scope(Cell::new(None), async {
+                let result = handle.await;
+                let response = RESPONSE.with(Cell::get);
+                if self.entries.try_send(entry(&query, response)).is_err() {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                result
+            })
+            .await
+    }
+
+    /// Entries dropped so far
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Writes out the entries queued and stops the writer, for the server to shut down
+    pub async fn close(&self) {
+        let writer = self.writer.lock().unwrap().take();
+        if let (Some(writer), Ok(())) = (writer, self.entries.send(String::new()).await) {
+            let _ = tokio::task::spawn_blocking(move || writer.join()).await;
+        }
+    }
+}
+
+/// Records `msg` as sent in response to the query being logged, if any
+pub fn sent(msg: &[u8]) {
+    let _ = RESPONSE.try_with(|response| {
+        let (rcode, size) = response
+            .get()
+            .unwrap_or((msg.get(3).map_or(0, |b| b & 0x0f), 0));
+        response.set(Some((rcode, size + msg.len())));
+    });
+}
+
+fn entry(query: &Query, response: Option<(u8, usize)>) -> String {
+    let time = query
+        .time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let string = |s: &str| serde_json::Value::from(s).to_string();
+    let (qname, qtype) = match &query.question {
+        Some((name, ty)) => (string(name), string(ty)),
+        None => ("null".into(), "null".into()),
+    };
+    let (rcode, size) = match response {
+        Some((rcode, size)) => (string(&rcode_name(rcode)), size),
+        None => ("null".into(), 0),
+    };
+    format!(
+        "{{\"time\": {:.3}, \"client\": {}, \"port\": {}, \"proto\": {}, \"qname\": {}, \"qtype\": {}, \"rcode\": {}, \"latency_us\": {}, \"size\": {}}}\n",
+        time,
+        string(&query.remote.ip().to_string()),
+        query.remote.port(),
+        string(if query.tcp { "tcp" } else { "udp" }),
+        qname,
+        qtype,
+        rcode,
+        query.start.elapsed().as_micros(),
+        size
+    )
+}
+
+pub fn rcode_name(rcode: u8) -> String {
+    let names = [
+        "NOERROR", "FORMERR", "SERVFAIL", "NXDOMAIN", "NOTIMP", "REFUSED", "YXDOMAIN", "YXRRSET",
+        "NXRRSET", "NOTAUTH", "NOTZONE",
+    ];
+    match names.get(rcode as usize) {
+        Some(name) => name.to_string(),
+        None => rcode.to_string(),
+    }
+}
+
+/// The file at `path` opened for appending, and its size
+fn open(path: &Path) -> anyhow::Result<(BufWriter<File>, u64)> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let size = file.metadata()?.len();
+    Ok((BufWriter::new(file), size))
+}
+
+struct Writer {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    opened: Instant,
+    /// In bytes
+    max_size: u64,
+    interval: Duration,
+    keep: usize,
+}
+
+impl Writer {
+    /// Writes the entries of `queue` until the log is closed or dropped, flushing whenever it is
+    /// empty
+    fn run(&mut self, queue: &mut mpsc::Receiver<String>) {
+        while let Some(mut entry) = queue.blocking_recv() {
+            while !entry.is_empty() {
+                self.write(&entry);
+                match queue.try_recv() {
+                    Ok(next) => entry = next,
+                    Err(_) => break,
+                }
+            }
+            if let Err(e) = self.file.flush() {
+                log::error!("Failed to write {}: {}", self.path.display(), e);
+            }
+            if entry.is_empty() {
+                return;
+            }
+        }
+    }
+
+    fn write(&mut self, entry: &str) {
+        if self.size + entry.len() as u64 > self.max_size || self.opened.elapsed() >= self.interval
+        {
+            if let Err(e) = self.rotate() {
+                log::error!("Failed to rotate {}: {:#}", self.path.display(), e);
+                // Not attempted again before as much more was written
+                self.size = 0;
+            }
+        }
+        match self.file.write_all(entry.as_bytes()) {
+            Ok(()) => self.size += entry.len() as u64,
+            Err(e) => log::error!("Failed to write {}: {}", self.path.display(), e),
+        }
+    }
+
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        self.file.flush()?;
+        // Whether it succeeds or not, the next rotation is not attempted right away
+        self.opened = Instant::now();
+        let rotated = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+        for n in (1..=self.keep).rev() {
+            let from = match n {
+                1 => self.path.clone(),
+                n => rotated(n - 1),
+            };
+            match std::fs::rename(&from, rotated(n)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        }
+        (self.file, self.size) = open(&self.path)?;
+        Ok(())
+    }
+}
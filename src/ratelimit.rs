@@ -0,0 +1,178 @@
+//! Per-client query rate limiting, with --rate-limit: each client address has a bucket of
+//! --rate-limit-burst tokens, as many as --rate-limit unless given, refilled at --rate-limit
+//! tokens per second. Every query takes one, and those arriving while the bucket of their client
+//! is empty are dropped unanswered, counted by `dns_queries_rate_limited_total`, see the metrics
+//! module. Clients are limited alike whatever they ask, unlike with response rate limiting.
+//!
+//! With --rate-limit-slip N, every Nth query of a client dropped over UDP is answered instead,
+//! empty and truncated, so that a client whose address is spoofed by a flood can still be
+//! answered over TCP, its TCP queries being limited all the same.
+//!
+//! Buckets are kept in shards, picked by a keyed hash of the address, each with two generations:
+//! once the current one of a shard is full, it becomes the previous one and the buckets of the
+//! previous one are forgotten, those used since having been moved to the current one. Floods
+//! from spoofed addresses thus forget the buckets of the clients idle longest, in constant
+//! amortized time per query.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Of the buckets kept, beyond which those of the clients idle longest are forgotten
+const MAX_CLIENTS: usize = 100_000;
+const SHARDS: usize = 64;
+
+struct Bucket {
+    tokens: f64,
+    at: Instant,
+    /// Queries over the limit so far, for --rate-limit-slip
+    over: u32,
+}
+
+/// What becomes of a query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Drop,
+    /// Answered truncated, should it come over UDP, and dropped otherwise
+    Slip,
+}
+
+#[derive(Default)]
+struct Shard {
+    current: HashMap<IpAddr, Bucket>,
+    previous: HashMap<IpAddr, Bucket>,
+}
+
+pub struct RateLimiter {
+    /// Tokens per second
+    rate: f64,
+    burst: f64,
+    /// Every how many queries over the limit one slips, 0 for none
+    slip: u32,
+    hasher: RandomState,
+    shards: Vec<Mutex<Shard>>,
+    dropped: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32, burst: Option<u32>, slip: u32) -> anyhow::Result<Self> {
+        let burst = burst.unwrap_or(rate);
+        if rate == 0 || burst == 0 {
+            return Err(anyhow::anyhow!(
+                "--rate-limit and --rate-limit-burst must be positive"
+            ));
+        }
+        Ok(Self {
+            rate: rate as f64,
+            burst: burst as f64,
+            slip,
+            hasher: RandomState::new(),
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Takes a token from the bucket of `client`, telling what becomes of its query
+    pub fn check(&self, client: IpAddr) -> Verdict {
+        self.check_at(client, Instant::now())
+    }
+
+    fn check_at(&self, client: IpAddr, now: Instant) -> Verdict {
+        let shard = self.hasher.hash_one(client) as usize % SHARDS;
+        let mut shard = self.shards[shard].lock().unwrap();
+        if !shard.current.contains_key(&client) {
+            let bucket = shard.previous.remove(&client).unwrap_or(Bucket {
+                tokens: self.burst,
+                at: now,
+                over: 0,
+            });
+            if shard.current.len() >= MAX_CLIENTS / SHARDS {
+                shard.previous = std::mem::take(&mut shard.current);
+            }
+            shard.current.insert(client, bucket);
+        }
+        let bucket = shard.current.get_mut(&client).expect("bucket inserted");
+        bucket.tokens = self.refill(bucket, now);
+        bucket.at = now;
+        if bucket.tokens < 1.0 {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            bucket.over = bucket.over.wrapping_add(1);
+            return match self.slip {
+                0 => Verdict::Drop,
+                slip if bucket.over.is_multiple_of(slip) => Verdict::Slip,
+                _ => Verdict::Drop,
+            };
+        }
+        bucket.tokens -= 1.0;
+        Verdict::Allow
+    }
+
+    /// Queries dropped so far
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// The tokens of `bucket` at `now`
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.at).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn tokens() {
+        let limiter = RateLimiter::new(2, Some(3), 0).unwrap();
+        let (client, other) = (IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2]));
+        let start = Instant::now();
+        let check = |client: IpAddr, secs: f64| {
+            limiter.check_at(client, start + Duration::from_secs_f64(secs))
+        };
+
+        // A burst, and then as many as refilled
+        for _ in 0..3 {
+            assert_eq!(check(client, 0.0), Verdict::Allow);
+        }
+        assert_eq!(check(client, 0.0), Verdict::Drop);
+        assert_eq!(check(other, 0.0), Verdict::Allow);
+        assert_eq!(check(client, 0.25), Verdict::Drop);
+        assert_eq!(check(client, 0.5), Verdict::Allow);
+        assert_eq!(check(client, 0.5), Verdict::Drop);
+        // No more than a burst after a while
+        for _ in 0..3 {
+            assert_eq!(check(client, 60.0), Verdict::Allow);
+        }
+        assert_eq!(check(client, 60.0), Verdict::Drop);
+        assert_eq!(limiter.dropped(), 4);
+
+        assert!(RateLimiter::new(0, None, 0).is_err());
+        assert!(RateLimiter::new(10, Some(0), 0).is_err());
+    }
+
+    #[test]
+    fn slip() {
+        let client = IpAddr::from([192, 0, 2, 1]);
+        let verdicts = |slip: u32| {
+            let limiter = RateLimiter::new(1, None, slip).unwrap();
+            let now = Instant::now();
+            assert_eq!(limiter.check_at(client, now), Verdict::Allow);
+            (0..4)
+                .map(|_| limiter.check_at(client, now))
+                .collect::<Vec<_>>()
+        };
+        use Verdict::{Drop, Slip};
+        assert_eq!(verdicts(0), [Drop, Drop, Drop, Drop]);
+        assert_eq!(verdicts(1), [Slip, Slip, Slip, Slip]);
+        assert_eq!(verdicts(2), [Drop, Slip, Drop, Slip]);
+    }
+}
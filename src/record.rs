@@ -1,8 +1,10 @@
 use std::{borrow::Borrow, io::Write};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Hash, PartialEq, Eq)]
+use crate::parser::Class;
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct Name(Vec<String>);
 
 impl Borrow<[String]> for Name {
@@ -11,20 +13,214 @@ impl Borrow<[String]> for Name {
     }
 }
 
+impl From<Vec<String>> for Name {
+    fn from(segs: Vec<String>) -> Self {
+        Self(segs)
+    }
+}
+
+impl AsRef<[String]> for Name {
+    fn as_ref(&self) -> &[String] {
+        &self.0
+    }
+}
+
+impl Name {
+    /// Absolute form, with a trailing dot, as written in master files
+    pub fn to_absolute(&self) -> String {
+        format!("{}.", self.0.join("."))
+    }
+}
+
+impl std::fmt::Display for Name {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0.join("."))
+    }
+}
+
+impl Serialize for Name {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0.join("."))
+    }
+}
+
 impl<'de> Deserialize<'de> for Name {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        Ok(Self(s.split(".").map(str::to_owned).collect()))
+        // Names may be written absolute, with a trailing dot, `.` being the root
+        let s = match s.strip_suffix('.') {
+            Some(relative) if !relative.ends_with('\\') => relative,
+            _ => &s,
+        };
+        match s.is_empty() {
+            true => Ok(Self(Vec::new())),
+            false => Ok(Self(crate::label::split_name(s))),
+        }
+    }
+}
+
+/// Addresses are accepted either as octets or in textual form
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Addr<T> {
+    Octets(T),
+    Text(String),
+}
+
+fn de_ipv4<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<[u8; 4], D::Error> {
+    match Addr::<[u8; 4]>::deserialize(deserializer)? {
+        Addr::Octets(octets) => Ok(octets),
+        Addr::Text(s) => s
+            .parse::<std::net::Ipv4Addr>()
+            .map(|addr| addr.octets())
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+fn ser_ipv4<S: serde::Serializer>(addr: &[u8; 4], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&std::net::Ipv4Addr::from(*addr))
+}
+
+fn ser_ipv6<S: serde::Serializer>(addr: &[u8; 16], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&std::net::Ipv6Addr::from(*addr))
+}
+
+fn de_ipv6<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<[u8; 16], D::Error> {
+    match Addr::<[u8; 16]>::deserialize(deserializer)? {
+        Addr::Octets(octets) => Ok(octets),
+        Addr::Text(s) => s
+            .parse::<std::net::Ipv6Addr>()
+            .map(|addr| addr.octets())
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+fn ser_base64<S: serde::Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    use base64ct::{Base64, Encoding};
+    serializer.serialize_str(&Base64::encode_string(data))
+}
+
+fn de_base64<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    use base64ct::{Base64, Encoding};
+    let s = String::deserialize(deserializer)?;
+    Base64::decode_vec(&s).map_err(|_| serde::de::Error::custom(format!("invalid base64 {}", s)))
+}
+
+fn ser_hex<S: serde::Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&hex_text(data))
+}
+
+fn de_hex<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    crate::dnssec::hex(&s).ok_or_else(|| serde::de::Error::custom(format!("invalid hex {}", s)))
+}
+
+fn hex_text(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// Salts of NSEC3 records, in hexadecimal, `-` when empty as in master files
+pub fn salt_text(salt: &[u8]) -> String {
+    match salt.is_empty() {
+        true => "-".to_string(),
+        false => salt.iter().map(|b| format!("{:02X}", b)).collect(),
+    }
+}
+
+/// Inverse of `salt_text`
+pub fn parse_salt(s: &str) -> Option<Vec<u8>> {
+    if s == "-" || s.is_empty() {
+        return Some(Vec::new());
+    }
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub fn ser_salt<S: serde::Serializer>(salt: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&salt_text(salt))
+}
+
+pub fn de_salt<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_salt(&s).ok_or_else(|| serde::de::Error::custom(format!("invalid salt {}", s)))
+}
+
+fn ser_base32hex<S: serde::Serializer>(hash: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&crate::dnssec::to_base32hex(hash))
+}
+
+fn de_base32hex<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    crate::dnssec::base32hex(&s)
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid base32hex {}", s)))
+}
+
+/// Time of a signature, as written in master files, RFC 4034 Section 3.2
+pub fn signature_time(secs: u32) -> String {
+    let (year, month, day) = crate::serial::civil_from_days(secs as i64 / 86400);
+    let secs = secs % 86400;
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Inverse of `signature_time`, which also takes plain numbers of seconds
+pub fn parse_signature_time(s: &str) -> Option<u32> {
+    if s.len() != 14 {
+        return s.parse().ok();
     }
+    let field = |range: std::ops::Range<usize>| s.get(range)?.parse::<u32>().ok();
+    let days = crate::serial::days_from_civil(field(0..4)? as i64, field(4..6)?, field(6..8)?);
+    let secs = days * 86400 + (field(8..10)? * 3600 + field(10..12)? * 60 + field(12..14)?) as i64;
+    Some(secs as u32)
 }
 
-#[derive(Deserialize, Debug)]
+/// Type bitmap of NSEC and NSEC3 records, RFC 4034 Section 4.1.2
+pub fn type_bitmap(types: &[crate::parser::Type]) -> Vec<u8> {
+    let mut types: Vec<u16> = types.iter().map(|ty| *ty as u16).collect();
+    types.sort_unstable();
+    types.dedup();
+    let mut ret = Vec::new();
+    for window in 0..=255u8 {
+        let bits: Vec<u8> = types
+            .iter()
+            .filter(|ty| (*ty >> 8) as u8 == window)
+            .map(|ty| *ty as u8)
+            .collect();
+        let Some(last) = bits.last() else {
+            continue;
+        };
+        let mut bitmap = vec![0u8; *last as usize / 8 + 1];
+        for bit in bits {
+            bitmap[bit as usize / 8] |= 0x80 >> (bit % 8);
+        }
+        ret.push(window);
+        ret.push(bitmap.len() as u8);
+        ret.extend(bitmap);
+    }
+    ret
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 #[serde(tag = "type")]
 pub enum RecordInner {
     SOA {
+        // May be left out when the serial is managed by the server, see `--serial`
+        #[serde(default)]
         serial: u32,
         mname: Name,
         rname: Name,
@@ -39,10 +235,12 @@ pub enum RecordInner {
     },
 
     A {
+        #[serde(deserialize_with = "de_ipv4", serialize_with = "ser_ipv4")]
         addr: [u8; 4],
     },
 
     AAAA {
+        #[serde(deserialize_with = "de_ipv6", serialize_with = "ser_ipv6")]
         addr: [u8; 16],
     },
 
@@ -50,9 +248,96 @@ pub enum RecordInner {
         to: Name,
     },
 
+    PTR {
+        ptr: Name,
+    },
+
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: Name,
+    },
+
     TXT {
         content: String,
     },
+
+    /// Served at the apex of the zones signed, see the sign module
+    DNSKEY {
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        #[serde(deserialize_with = "de_base64", serialize_with = "ser_base64")]
+        public_key: Vec<u8>,
+    },
+
+    RRSIG {
+        type_covered: crate::parser::Type,
+        algorithm: u8,
+        labels: u8,
+        original_ttl: u32,
+        /// Seconds since the epoch, modulo 2^32
+        expiration: u32,
+        inception: u32,
+        key_tag: u16,
+        signer: Name,
+        #[serde(deserialize_with = "de_base64", serialize_with = "ser_base64")]
+        signature: Vec<u8>,
+    },
+
+    NSEC {
+        next: Name,
+        types: Vec<crate::parser::Type>,
+    },
+
+    /// Owned by the hash of the name whose types it lists, RFC 5155 Section 3
+    NSEC3 {
+        hash_algorithm: u8,
+        /// 1 for opt-out
+        flags: u8,
+        iterations: u16,
+        #[serde(deserialize_with = "de_salt", serialize_with = "ser_salt")]
+        salt: Vec<u8>,
+        #[serde(deserialize_with = "de_base32hex", serialize_with = "ser_base32hex")]
+        next: Vec<u8>,
+        types: Vec<crate::parser::Type>,
+    },
+
+    NSEC3PARAM {
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        #[serde(deserialize_with = "de_salt", serialize_with = "ser_salt")]
+        salt: Vec<u8>,
+    },
+
+    /// The DS records the parent zone should have, for it to pick up, RFC 7344
+    CDS {
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        #[serde(deserialize_with = "de_hex", serialize_with = "ser_hex")]
+        digest: Vec<u8>,
+    },
+
+    /// The keys of the DS records the parent zone should have, RFC 7344
+    CDNSKEY {
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        #[serde(deserialize_with = "de_base64", serialize_with = "ser_base64")]
+        public_key: Vec<u8>,
+    },
+
+    /// Digest of the zone it is at the apex of, RFC 8976
+    ZONEMD {
+        serial: u32,
+        scheme: u8,
+        hash_algorithm: u8,
+        #[serde(deserialize_with = "de_hex", serialize_with = "ser_hex")]
+        digest: Vec<u8>,
+    },
 }
 
 impl RecordInner {
@@ -65,7 +350,174 @@ impl RecordInner {
             A { .. } => Type::A,
             AAAA { .. } => Type::AAAA,
             CNAME { .. } => Type::CNAME,
+            PTR { .. } => Type::PTR,
+            SRV { .. } => Type::SRV,
             TXT { .. } => Type::TXT,
+            DNSKEY { .. } => Type::DNSKEY,
+            RRSIG { .. } => Type::RRSIG,
+            NSEC { .. } => Type::NSEC,
+            NSEC3 { .. } => Type::NSEC3,
+            NSEC3PARAM { .. } => Type::NSEC3PARAM,
+            CDS { .. } => Type::CDS,
+            CDNSKEY { .. } => Type::CDNSKEY,
+            ZONEMD { .. } => Type::ZONEMD,
+        }
+    }
+
+    /// RDATA in master file syntax, with absolute names
+    pub fn rdata_text(&self) -> String {
+        match self {
+            RecordInner::SOA {
+                serial,
+                mname,
+                rname,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => format!(
+                "{} {} {} {} {} {} {}",
+                mname.to_absolute(),
+                rname.to_absolute(),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            RecordInner::NS { ns } => ns.to_absolute(),
+            RecordInner::A { addr } => std::net::Ipv4Addr::from(*addr).to_string(),
+            RecordInner::AAAA { addr } => std::net::Ipv6Addr::from(*addr).to_string(),
+            RecordInner::CNAME { to } => to.to_absolute(),
+            RecordInner::PTR { ptr } => ptr.to_absolute(),
+            RecordInner::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => format!("{} {} {} {}", priority, weight, port, target.to_absolute()),
+            RecordInner::TXT { content } => {
+                let quoted: Vec<String> = character_strings(content)
+                    .into_iter()
+                    .map(|string| {
+                        let mut quoted = String::from('"');
+                        for c in string.chars() {
+                            if c == '"' || c == '\\' {
+                                quoted.push('\\');
+                            }
+                            quoted.push(c);
+                        }
+                        quoted.push('"');
+                        quoted
+                    })
+                    .collect();
+                quoted.join(" ")
+            }
+            RecordInner::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+            }
+            | RecordInner::CDNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+            } => {
+                use base64ct::{Base64, Encoding};
+                let key = Base64::encode_string(public_key);
+                format!("{} {} {} {}", flags, protocol, algorithm, key)
+            }
+            RecordInner::RRSIG {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer,
+                signature,
+            } => {
+                use base64ct::{Base64, Encoding};
+                format!(
+                    "{:?} {} {} {} {} {} {} {} {}",
+                    type_covered,
+                    algorithm,
+                    labels,
+                    original_ttl,
+                    signature_time(*expiration),
+                    signature_time(*inception),
+                    key_tag,
+                    signer.to_absolute(),
+                    Base64::encode_string(signature)
+                )
+            }
+            RecordInner::NSEC { next, types } => {
+                let mut text = next.to_absolute();
+                for ty in types {
+                    text.push_str(&format!(" {:?}", ty));
+                }
+                text
+            }
+            RecordInner::NSEC3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                next,
+                types,
+            } => {
+                let mut text = format!(
+                    "{} {} {} {} {}",
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt_text(salt),
+                    crate::dnssec::to_base32hex(next).to_ascii_uppercase()
+                );
+                for ty in types {
+                    text.push_str(&format!(" {:?}", ty));
+                }
+                text
+            }
+            RecordInner::NSEC3PARAM {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+            } => format!(
+                "{} {} {} {}",
+                hash_algorithm,
+                flags,
+                iterations,
+                salt_text(salt)
+            ),
+            RecordInner::CDS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+            } => format!(
+                "{} {} {} {}",
+                key_tag,
+                algorithm,
+                digest_type,
+                hex_text(digest)
+            ),
+            RecordInner::ZONEMD {
+                serial,
+                scheme,
+                hash_algorithm,
+                digest,
+            } => format!(
+                "{} {} {} {}",
+                serial,
+                scheme,
+                hash_algorithm,
+                hex_text(digest)
+            ),
         }
     }
 
@@ -83,26 +535,130 @@ impl RecordInner {
             } => {
                 serialize_name(&mname.0, &mut ret)?;
                 serialize_name(&rname.0, &mut ret)?;
-                ret.write(&serial.to_be_bytes())?;
-                ret.write(&refresh.to_be_bytes())?;
-                ret.write(&retry.to_be_bytes())?;
-                ret.write(&expire.to_be_bytes())?;
-                ret.write(&minimum.to_be_bytes())?;
+                ret.write_all(&serial.to_be_bytes())?;
+                ret.write_all(&refresh.to_be_bytes())?;
+                ret.write_all(&retry.to_be_bytes())?;
+                ret.write_all(&expire.to_be_bytes())?;
+                ret.write_all(&minimum.to_be_bytes())?;
             }
             RecordInner::NS { ns } => {
                 serialize_name(&ns.0, &mut ret)?;
             }
             RecordInner::A { addr } => {
-                ret.write(addr)?;
+                ret.write_all(addr)?;
             }
             RecordInner::AAAA { addr } => {
-                ret.write(addr)?;
+                ret.write_all(addr)?;
             }
             RecordInner::CNAME { to } => {
                 serialize_name(&to.0, &mut ret)?;
             }
+            RecordInner::PTR { ptr } => {
+                serialize_name(&ptr.0, &mut ret)?;
+            }
+            RecordInner::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                ret.write_all(&priority.to_be_bytes())?;
+                ret.write_all(&weight.to_be_bytes())?;
+                ret.write_all(&port.to_be_bytes())?;
+                serialize_name(&target.0, &mut ret)?;
+            }
             RecordInner::TXT { content } => {
-                ret.write(content.as_bytes())?;
+                for string in character_strings(content) {
+                    ret.write_all(&[string.len() as u8])?;
+                    ret.write_all(string.as_bytes())?;
+                }
+            }
+            RecordInner::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+            }
+            | RecordInner::CDNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+            } => {
+                ret.write_all(&flags.to_be_bytes())?;
+                ret.write_all(&[*protocol, *algorithm])?;
+                ret.write_all(public_key)?;
+            }
+            RecordInner::RRSIG {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer,
+                signature,
+            } => {
+                ret.write_all(&(*type_covered as u16).to_be_bytes())?;
+                ret.write_all(&[*algorithm, *labels])?;
+                ret.write_all(&original_ttl.to_be_bytes())?;
+                ret.write_all(&expiration.to_be_bytes())?;
+                ret.write_all(&inception.to_be_bytes())?;
+                ret.write_all(&key_tag.to_be_bytes())?;
+                serialize_name(&signer.0, &mut ret)?;
+                ret.write_all(signature)?;
+            }
+            RecordInner::NSEC { next, types } => {
+                serialize_name(&next.0, &mut ret)?;
+                ret.write_all(&type_bitmap(types))?;
+            }
+            RecordInner::NSEC3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                next,
+                types,
+            } => {
+                ret.write_all(&[*hash_algorithm, *flags])?;
+                ret.write_all(&iterations.to_be_bytes())?;
+                ret.write_all(&[salt.len() as u8])?;
+                ret.write_all(salt)?;
+                ret.write_all(&[next.len() as u8])?;
+                ret.write_all(next)?;
+                ret.write_all(&type_bitmap(types))?;
+            }
+            RecordInner::NSEC3PARAM {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+            } => {
+                ret.write_all(&[*hash_algorithm, *flags])?;
+                ret.write_all(&iterations.to_be_bytes())?;
+                ret.write_all(&[salt.len() as u8])?;
+                ret.write_all(salt)?;
+            }
+            RecordInner::CDS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+            } => {
+                ret.write_all(&key_tag.to_be_bytes())?;
+                ret.write_all(&[*algorithm, *digest_type])?;
+                ret.write_all(digest)?;
+            }
+            RecordInner::ZONEMD {
+                serial,
+                scheme,
+                hash_algorithm,
+                digest,
+            } => {
+                ret.write_all(&serial.to_be_bytes())?;
+                ret.write_all(&[*scheme, *hash_algorithm])?;
+                ret.write_all(digest)?;
             }
         }
 
@@ -110,26 +666,109 @@ impl RecordInner {
     }
 }
 
-#[derive(Deserialize, Debug)]
+/// Bounds applied to TTLs as they are put on the wire
+#[derive(Debug, Clone, Copy)]
+pub struct TtlBounds {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl TtlBounds {
+    pub fn clamp(&self, ttl: u32) -> u32 {
+        ttl.clamp(self.min, self.max)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct Record {
     #[serde(flatten)]
     pub inner: RecordInner,
 
     pub ttl: u32,
+
+    /// Free-form annotation, never served
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+
+    /// Disabled records stay in the zone data, but are neither served nor transferred
+    #[serde(default = "enabled", skip_serializing_if = "is_enabled")]
+    pub enabled: bool,
+
+    /// Regions of the clients the record is served to, see the geo module
+    #[serde(
+        default,
+        deserialize_with = "crate::geo::deserialize",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub geo: Vec<String>,
+
+    /// Share of the responses the record is served in, see the weight module
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+
+    /// Region of the endpoint of the record, served first to clients nearby, see the proximity
+    /// module
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+
+    /// Check of the endpoint of an A or AAAA record, see the failover module
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub check: Option<crate::failover::Check>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+fn is_enabled(enabled: &bool) -> bool {
+    *enabled
 }
 
 impl Record {
-    pub fn serialize<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+    pub fn new(inner: RecordInner, ttl: u32) -> Self {
+        Self {
+            inner,
+            ttl,
+            comment: None,
+            enabled: true,
+            geo: Vec::new(),
+            weight: None,
+            location: None,
+            check: None,
+        }
+    }
+
+    /// The record as a master file line, commented out if disabled
+    pub fn to_zone_line(&self, name: &Name) -> String {
+        let mut line = format!(
+            "{}{} {} IN {:?} {}",
+            if self.enabled { "" } else { "; " },
+            name.to_absolute(),
+            self.ttl,
+            self.inner.ty(),
+            self.inner.rdata_text()
+        );
+        if let Some(comment) = &self.comment {
+            line.push_str(" ; ");
+            line.push_str(&comment.replace('\n', " "));
+        }
+        line
+    }
+
+    pub fn serialize<W: Write>(
+        &self,
+        w: &mut W,
+        class: Class,
+        ttl_bounds: &TtlBounds,
+    ) -> std::io::Result<()> {
         // TYPE
         w.write_all(&(self.inner.ty() as u16).to_be_bytes())?;
 
         // CLASS
-        w.write_all(
-            &[0, 1], // IN
-        )?;
+        w.write_all(&u16::from(class).to_be_bytes())?;
 
         // TTL
-        w.write_all(&self.ttl.to_be_bytes())?;
+        w.write_all(&ttl_bounds.clamp(self.ttl).to_be_bytes())?;
 
         let rdata = self.inner.serialize()?;
 
@@ -141,13 +780,107 @@ impl Record {
     }
 }
 
+/// `content` as the <character-string>s of TXT RDATA, of at most 255 bytes each, split between
+/// characters, or a single empty one if `content` is empty
+fn character_strings(content: &str) -> Vec<&str> {
+    let mut strings = Vec::new();
+    let mut rest = content;
+    while rest.len() > 255 {
+        let mut end = 255;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (string, after) = rest.split_at(end);
+        strings.push(string);
+        rest = after;
+    }
+    strings.push(rest);
+    strings
+}
+
 pub fn serialize_name<W: Write>(segs: &[String], w: &mut W) -> std::io::Result<()> {
     for seg in segs.iter() {
-        w.write_all(&[seg.len() as u8])?;
-        w.write_all(seg.as_bytes())?;
+        let raw = crate::label::unescape(seg);
+        w.write_all(&[raw.len() as u8])?;
+        w.write_all(&raw)?;
     }
 
     w.write_all(&[0])?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn txt(content: &str) -> RecordInner {
+        RecordInner::TXT {
+            content: content.to_owned(),
+        }
+    }
+
+    /// Decodes `rdata` as the parser would from a response
+    fn parse_txt(rdata: &[u8]) -> RecordInner {
+        let rr = crate::parser::RR {
+            offset: 0,
+            name: crate::parser::Name { labels: Vec::new() },
+            ty: crate::parser::Type::TXT,
+            class: 1,
+            ttl: 0,
+            rdata,
+        };
+        crate::parser::parse_rdata(rdata, &rr).unwrap()
+    }
+
+    #[test]
+    fn txt_short() {
+        let rdata = txt("hello").serialize().unwrap();
+        assert_eq!(rdata, b"\x05hello");
+        assert_eq!(parse_txt(&rdata), txt("hello"));
+        assert_eq!(txt("hello").rdata_text(), "\"hello\"");
+    }
+
+    #[test]
+    fn txt_empty() {
+        assert_eq!(txt("").serialize().unwrap(), [0]);
+        assert_eq!(txt("").rdata_text(), "\"\"");
+    }
+
+    #[test]
+    fn txt_over_255_bytes() {
+        let content = "a".repeat(600);
+        let rdata = txt(&content).serialize().unwrap();
+        assert_eq!(rdata.len(), 600 + 3);
+        assert_eq!(rdata[0], 255);
+        assert_eq!(rdata[256], 255);
+        assert_eq!(rdata[512], 90);
+        assert_eq!(parse_txt(&rdata), txt(&content));
+        let text = txt(&content).rdata_text();
+        let expected = format!(
+            "\"{}\" \"{}\" \"{}\"",
+            "a".repeat(255),
+            "a".repeat(255),
+            "a".repeat(90)
+        );
+        assert_eq!(text, expected);
+    }
+
+    #[test]
+    fn txt_exactly_255_bytes() {
+        let content = "b".repeat(255);
+        let rdata = txt(&content).serialize().unwrap();
+        assert_eq!(rdata.len(), 256);
+        assert_eq!(rdata[0], 255);
+    }
+
+    #[test]
+    fn txt_split_between_characters() {
+        // 254 bytes, then a 4-byte character which would straddle the limit
+        let content = format!("{}😀tail", "c".repeat(254));
+        let rdata = txt(&content).serialize().unwrap();
+        assert_eq!(rdata[0], 254);
+        assert_eq!(rdata[255] as usize, "😀tail".len());
+        assert_eq!(parse_txt(&rdata), txt(&content));
+    }
+}
@@ -0,0 +1,84 @@
+//! NXDOMAIN redirection, with --nxdomain-redirect: names below the suffixes given, which
+//! upstream resolvers answer do not exist, are answered with the address of a landing page
+//! instead, such as a captive portal or an internal search page, queries for its type at least,
+//! the others having no data. Names below those given with --nxdomain-redirect-exclude are
+//! answered as they are.
+//!
+//! Redirected answers carry an Extended DNS Error telling they are forged (RFC 8914). Neither
+//! the names of the zones served nor those answered NXDOMAIN by a blocklist or an RPZ policy are
+//! redirected.
+
+use std::net::IpAddr;
+
+use crate::message::Rcode;
+use crate::parser::Type;
+use crate::record::{Name, Record, RecordInner};
+use crate::resolver::Answer;
+
+/// TTL of the records of redirected answers
+const REDIRECT_TTL: u32 = 60;
+
+pub struct Redirect {
+    addr: IpAddr,
+    /// Lowercased, as the names excluded
+    suffixes: Vec<Name>,
+    excluded: Vec<Name>,
+}
+
+fn lower(name: &[String]) -> Vec<String> {
+    name.iter().map(|l| l.to_ascii_lowercase()).collect()
+}
+
+/// Whether `name` is one of `names`, or below one
+fn within(name: &[String], names: &[Name]) -> bool {
+    names.iter().any(|n| name.ends_with(n.as_ref()))
+}
+
+impl Redirect {
+    pub fn new(addr: IpAddr, suffixes: &[Name], excluded: &[Name]) -> Self {
+        let lower_all = |names: &[Name]| -> Vec<Name> {
+            names
+                .iter()
+                .map(|n| Name::from(lower(n.as_ref())))
+                .collect()
+        };
+        Self {
+            addr,
+            suffixes: lower_all(suffixes),
+            excluded: lower_all(excluded),
+        }
+    }
+
+    /// The answer replacing `answer`, to a query of type `ty` for `name`, if it is an NXDOMAIN to
+    /// redirect
+    pub fn answer(&self, name: &[String], ty: Type, answer: &Answer) -> Option<Answer> {
+        let lowered = lower(name);
+        if answer.rcode != Rcode::Name
+            || !within(&lowered, &self.suffixes)
+            || within(&lowered, &self.excluded)
+        {
+            return None;
+        }
+        let inner = match (self.addr, ty) {
+            (IpAddr::V4(addr), Type::A | Type::ANY) => Some(RecordInner::A {
+                addr: addr.octets(),
+            }),
+            (IpAddr::V6(addr), Type::AAAA | Type::ANY) => Some(RecordInner::AAAA {
+                addr: addr.octets(),
+            }),
+            _ => None,
+        };
+        let owner = Name::from(name.to_vec());
+        let answers = inner
+            .map(|inner| (owner, Record::new(inner, REDIRECT_TTL)))
+            .into_iter()
+            .collect();
+        Some(Answer {
+            rcode: Rcode::OK,
+            answers,
+            authorities: Vec::new(),
+            name_servers: Vec::new(),
+            secure: false,
+        })
+    }
+}
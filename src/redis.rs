@@ -0,0 +1,290 @@
+//! Records stored in Redis, one hash per owner name under a common key prefix. Hash fields are
+//! record types, and values hold one record per line as "TTL RDATA", RDATA being in master file
+//! syntax:
+//!
+//! ```text
+//! HSET dns:www.example.com A "60 10.0.0.1\n60 10.0.0.2" TXT "300 \"hello\""
+//! ```
+//!
+//! Every key is read again each refresh interval, which bounds how long a change takes to be
+//! served. Only the parts of RESP needed for that are implemented, over plain TCP.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::store::Store;
+use crate::BaseStorage;
+
+/// Connection parameters, from a redis://[[user]:password@]host[:port][/db] URL
+#[derive(Debug, Clone)]
+pub struct ConnInfo {
+    host: String,
+    port: u16,
+    user: Option<String>,
+    password: Option<String>,
+    db: u32,
+}
+
+impl FromStr for ConnInfo {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("redis://")
+            .ok_or_else(|| anyhow::anyhow!("Expected a redis:// URL, got {}", s))?;
+        let (authority, db) = rest.split_once('/').unwrap_or((rest, ""));
+        let (userinfo, hostport) = match authority.rsplit_once('@') {
+            Some((userinfo, hostport)) => (Some(userinfo), hostport),
+            None => (None, authority),
+        };
+        let (user, password) = match userinfo.map(|u| u.split_once(':')) {
+            Some(Some(("", password))) => (None, Some(password.to_owned())),
+            Some(Some((user, password))) => (Some(user.to_owned()), Some(password.to_owned())),
+            Some(None) => (None, Some(userinfo.unwrap().to_owned())),
+            None => (None, None),
+        };
+        let (host, port) = match hostport.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse()?),
+            None => (hostport, 6379),
+        };
+        let host = match host {
+            "" => "localhost",
+            host => host.trim_start_matches('[').trim_end_matches(']'),
+        };
+        let db = match db {
+            "" => 0,
+            db => db.parse()?,
+        };
+
+        Ok(Self {
+            host: host.to_owned(),
+            port,
+            user,
+            password,
+            db,
+        })
+    }
+}
+
+#[derive(Debug)]
+enum Reply {
+    Nil,
+    Bulk(Vec<u8>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn into_string(self) -> anyhow::Result<String> {
+        match self {
+            Reply::Bulk(data) => Ok(String::from_utf8(data)?),
+            reply => Err(anyhow::anyhow!("Expected a string, got {:?}", reply)),
+        }
+    }
+
+    fn into_array(self) -> anyhow::Result<Vec<Reply>> {
+        match self {
+            Reply::Array(items) => Ok(items),
+            Reply::Nil => Ok(Vec::new()),
+            reply => Err(anyhow::anyhow!("Expected an array, got {:?}", reply)),
+        }
+    }
+}
+
+/// Replies nest, so reading them recurses through a boxed future
+type ReplyFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<Reply>> + Send + 'a>>;
+
+struct Conn {
+    stream: BufReader<TcpStream>,
+}
+
+impl Conn {
+    async fn connect(info: &ConnInfo) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect((info.host.as_str(), info.port)).await?;
+        let mut conn = Self {
+            stream: BufReader::new(stream),
+        };
+        match (&info.user, &info.password) {
+            (Some(user), Some(password)) => {
+                conn.command(&["AUTH", user, password]).await?;
+            }
+            (None, Some(password)) => {
+                conn.command(&["AUTH", password]).await?;
+            }
+            _ => (),
+        }
+        if info.db != 0 {
+            conn.command(&["SELECT", &info.db.to_string()]).await?;
+        }
+        Ok(conn)
+    }
+
+    async fn command(&mut self, args: &[&str]) -> anyhow::Result<Reply> {
+        let mut packet = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            packet.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            packet.extend_from_slice(arg.as_bytes());
+            packet.extend_from_slice(b"\r\n");
+        }
+        self.stream.get_mut().write_all(&packet).await?;
+        self.reply().await
+    }
+
+    async fn line(&mut self) -> anyhow::Result<String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(anyhow::anyhow!("Connection closed"));
+        }
+        Ok(line.trim_end_matches("\r\n").to_owned())
+    }
+
+    fn reply(&mut self) -> ReplyFuture<'_> {
+        Box::pin(async move {
+            let line = self.line().await?;
+            let (kind, rest) = line.split_at(line.len().min(1));
+            match kind {
+                // Integers are kept in their textual form, like simple strings
+                "+" | ":" => Ok(Reply::Bulk(rest.as_bytes().to_vec())),
+                "-" => Err(anyhow::anyhow!("Redis error: {}", rest)),
+                "$" => {
+                    let len: i64 = rest.parse()?;
+                    if len < 0 {
+                        return Ok(Reply::Nil);
+                    }
+                    let mut data = vec![0; len as usize + 2];
+                    self.stream.read_exact(&mut data).await?;
+                    data.truncate(len as usize);
+                    Ok(Reply::Bulk(data))
+                }
+                "*" => {
+                    let len: i64 = rest.parse()?;
+                    if len < 0 {
+                        return Ok(Reply::Nil);
+                    }
+                    let mut items = Vec::new();
+                    for _ in 0..len {
+                        items.push(self.reply().await?);
+                    }
+                    Ok(Reply::Array(items))
+                }
+                _ => Err(anyhow::anyhow!("Invalid Redis reply {:?}", line)),
+            }
+        })
+    }
+}
+
+pub struct RedisSource {
+    pub conn: ConnInfo,
+    /// Prepended to owner names to form hash keys
+    pub prefix: String,
+}
+
+impl RedisSource {
+    fn name(&self) -> String {
+        format!(
+            "redis://{}:{}/{}",
+            self.conn.host, self.conn.port, self.conn.db
+        )
+    }
+
+    async fn read_with(&self, conn: &mut Conn) -> anyhow::Result<BaseStorage> {
+        let mut pattern = String::new();
+        for c in self.prefix.chars() {
+            if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+        pattern.push('*');
+        let mut keys = Vec::new();
+        let mut cursor = "0".to_owned();
+        loop {
+            let mut reply = conn
+                .command(&["SCAN", &cursor, "MATCH", &pattern, "COUNT", "1000"])
+                .await?
+                .into_array()?
+                .into_iter();
+            cursor = reply
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("Invalid SCAN reply"))?
+                .into_string()?;
+            for key in reply
+                .next()
+                .map(Reply::into_array)
+                .transpose()?
+                .unwrap_or_default()
+            {
+                keys.push(key.into_string()?);
+            }
+            if cursor == "0" {
+                break;
+            }
+        }
+        // SCAN may return a key more than once
+        keys.sort();
+        keys.dedup();
+
+        let mut rows = Vec::new();
+        for key in keys.iter() {
+            let name = &key[self.prefix.len()..];
+            let fields = match conn.command(&["HGETALL", key]).await {
+                Ok(fields) => fields.into_array()?,
+                // Keys of other types under the prefix are not ours
+                Err(e) if e.to_string().contains("WRONGTYPE") => continue,
+                Err(e) => return Err(e),
+            };
+            let mut fields = fields.into_iter();
+            while let (Some(ty), Some(value)) = (fields.next(), fields.next()) {
+                let ty = ty.into_string()?;
+                let value = value.into_string()?;
+                let records = crate::zonefile::split_rrset(&value)
+                    .map_err(|e| anyhow::anyhow!("{} {}: {}", key, ty, e))?;
+                for (ttl, content) in records {
+                    rows.push((name.to_owned(), ty.clone(), ttl, content.to_owned()));
+                }
+            }
+        }
+
+        crate::zonefile::parse_rows(
+            rows.iter().map(|(name, ty, ttl, content)| {
+                (name.as_str(), ty.as_str(), *ttl, content.as_str())
+            }),
+            &self.name(),
+        )
+    }
+
+    pub async fn read(&self) -> anyhow::Result<BaseStorage> {
+        let mut conn = Conn::connect(&self.conn).await?;
+        self.read_with(&mut conn).await
+    }
+
+    /// Re-reads every record each `interval`, keeping the connection open between reads. The
+    /// last records read are served while Redis is unreachable.
+    pub async fn watch(self, store: Arc<Store>, interval: Duration) {
+        let mut conn: Option<Conn> = None;
+        loop {
+            tokio::time::sleep(interval).await;
+            if conn.is_none() {
+                match Conn::connect(&self.conn).await {
+                    Ok(c) => conn = Some(c),
+                    Err(e) => {
+                        log::error!("Failed to connect to {}: {}", self.name(), e);
+                        continue;
+                    }
+                }
+            }
+            match self.read_with(conn.as_mut().unwrap()).await {
+                Ok(base) => store.set_source("redis", base),
+                Err(e) => {
+                    log::error!("Failed to reload records from {}: {}", self.name(), e);
+                    conn = None;
+                }
+            }
+        }
+    }
+}
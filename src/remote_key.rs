@@ -0,0 +1,96 @@
+//! Keys held by a remote signing service, such as an HSM behind an HTTP API, so that their private
+//! part never touches the filesystem of the server. They are given in place of the PEM files of
+//! Ed25519 keys, in `keys` and key stores alike, as YAML files describing them:
+//!
+//! ```yaml
+//! url: http://127.0.0.1:8200/keys/example.com-ksk
+//! public-key: 0B6aU4I1Q1vTrYb9vPsvmSgIUgk2sxpiQFgzpN5zY3w=
+//! token: s3cr3t
+//! ```
+//!
+//! The data to sign is POSTed to `url` as application/octet-stream, with `token`, if any, as a
+//! bearer token, and the service answers 200 with the Ed25519 signature of it, its 64 bytes as
+//! the body. Signatures are checked against `public-key` before they are used. Requests are plain
+//! HTTP, so the service had better listen on the loopback or a trusted network.
+
+use std::time::Duration;
+
+use base64ct::{Base64, Encoding};
+use ed25519_dalek::Verifier;
+use serde::Deserialize;
+
+/// How long the service may take to answer a request
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Descriptor {
+    url: String,
+    public_key: String,
+    token: Option<String>,
+}
+
+pub struct RemoteKey {
+    /// host:port of the service
+    addr: String,
+    path: String,
+    token: Option<String>,
+    public: ed25519_dalek::PublicKey,
+}
+
+impl RemoteKey {
+    /// The key described by `yaml`
+    pub fn parse(yaml: &str) -> anyhow::Result<Self> {
+        let descriptor: Descriptor = serde_yaml::from_str(yaml)?;
+        let url = descriptor
+            .url
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow::anyhow!("url {} is not a plain HTTP one", descriptor.url))?;
+        let (addr, path) = match url.find('/') {
+            Some(i) => url.split_at(i),
+            None => (url, "/"),
+        };
+        let addr = match addr.contains(':') {
+            true => addr.to_owned(),
+            false => format!("{}:80", addr),
+        };
+        let public_key = Base64::decode_vec(&descriptor.public_key)
+            .map_err(|_| anyhow::anyhow!("invalid public-key {}", descriptor.public_key))?;
+        let public = ed25519_dalek::PublicKey::from_bytes(&public_key)
+            .map_err(|e| anyhow::anyhow!("invalid public-key: {}", e))?;
+        Ok(Self {
+            addr,
+            path: path.to_owned(),
+            token: descriptor.token,
+            public,
+        })
+    }
+
+    pub fn public(&self) -> &ed25519_dalek::PublicKey {
+        &self.public
+    }
+
+    /// The signature of `data`, as made by the service. Blocks until it answers, so must be called
+    /// from a thread of the multi-threaded runtime or a blocking one.
+    pub fn sign(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let authorization = self.token.as_ref().map(|token| format!("Bearer {}", token));
+        let mut headers = vec![("Content-Type", "application/octet-stream")];
+        if let Some(authorization) = &authorization {
+            headers.push(("Authorization", authorization));
+        }
+        let request = crate::http::post(&self.addr, &self.path, &headers, data);
+        let reply = tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current().block_on(tokio::time::timeout(TIMEOUT, request))
+        })
+        .map_err(|_| anyhow::anyhow!("{} timed out", self.addr))??;
+        if reply.status != 200 {
+            return Err(reply.error());
+        }
+        let signature = ed25519_dalek::Signature::from_bytes(&reply.body)
+            .map_err(|_| anyhow::anyhow!("malformed signature from {}", self.addr))?;
+        self.public
+            .verify(data, &signature)
+            .map_err(|_| anyhow::anyhow!("bad signature from {}", self.addr))?;
+        Ok(reply.body)
+    }
+}
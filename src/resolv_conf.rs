@@ -0,0 +1,75 @@
+//! Upstream resolvers read from /etc/resolv.conf, with `--forward system`, so that this server
+//! can be dropped onto a laptop or into a container without being told which resolvers to use.
+//! Its `nameserver` lines are read, on port 53, and read again whenever the file changes, as it
+//! does when joining another network. Name servers on the loopback at --port are skipped, as
+//! those are likely this server itself, and IPv6 link-local ones given with a zone index.
+
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::forward::{expand, Target, Upstreams};
+
+pub const PATH: &str = "/etc/resolv.conf";
+
+/// How often the file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The name servers of `content`, those of the loopback at `port` aside
+fn parse(content: &str, port: u16) -> Vec<SocketAddr> {
+    let mut servers = Vec::new();
+    for line in content.lines() {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("nameserver") {
+            continue;
+        }
+        let addr = match words.next().map(|w| w.parse::<IpAddr>()) {
+            Some(Ok(ip)) => SocketAddr::new(ip, 53),
+            _ => {
+                log::debug!("Skipping {:?} of {}", line, PATH);
+                continue;
+            }
+        };
+        if addr.ip().is_loopback() && addr.port() == port {
+            log::info!("Skipping {} of {}, at our own port", addr, PATH);
+            continue;
+        }
+        servers.push(addr);
+    }
+    servers
+}
+
+/// The name servers of the file at `path`
+pub fn read(path: &Path, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+    let content =
+        std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+    let servers = parse(&content, port);
+    if servers.is_empty() {
+        log::warn!("No name server to forward to in {}", path.display());
+    }
+    Ok(servers)
+}
+
+/// Polls the file at `path`, setting the upstream resolvers of `upstreams` to `targets` whenever
+/// it changes
+pub async fn watch(path: PathBuf, port: u16, targets: Vec<Target>, upstreams: Arc<Upstreams>) {
+    let mtime = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut read_at: Option<SystemTime> = mtime(&path);
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let current = mtime(&path);
+        if current == read_at {
+            continue;
+        }
+        read_at = current;
+        match read(&path, port) {
+            Ok(system) => {
+                let servers = expand(&targets, &system);
+                log::info!("{} changed, forwarding to {:?}", path.display(), servers);
+                upstreams.set_servers(servers);
+            }
+            Err(e) => log::error!("Keeping the current upstream resolvers: {}", e),
+        }
+    }
+}
@@ -0,0 +1,369 @@
+//! Iterative resolution of the names outside of the zones served, with --recursive, for this
+//! server to be a recursive resolver of its own (RFC 1034 Section 5.3.3). Queries go to the root
+//! servers, then follow the referrals down to the servers authoritative for the name, and CNAME
+//! records to their targets.
+//!
+//! Only what the server queried is authoritative for is believed: records outside of its zone,
+//! referrals to zones not below it and glue for names outside of it are dropped. Name servers
+//! without glue are resolved in turn, up to a depth. Answers are cached, see the cache module, but
+//! not validated with DNSSEC. Only queries with RD set are resolved, and only for the clients
+//! --allow-recursion allows, those of the loopback and private networks by default.
+//!
+//! The servers of each zone are only told as much of the name as they need, one label below
+//! their zone, and asked for its A records (QNAME minimisation, RFC 9156): a referral is followed
+//! as usual, a name error ends the resolution, and any other answer has them asked again with one
+//! more label, until the whole name. Servers failing to answer the names minimised, or referring
+//! them to name servers whose addresses cannot be found, are asked the whole name at once.
+
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+
+use crate::message::Rcode;
+use crate::parser::Type;
+use crate::record::{Name, Record, RecordInner};
+
+/// IPv4 addresses of the root servers, a to m.root-servers.net
+pub const ROOT_HINTS: [Ipv4Addr; 13] = [
+    Ipv4Addr::new(198, 41, 0, 4),
+    Ipv4Addr::new(170, 247, 170, 2),
+    Ipv4Addr::new(192, 33, 4, 12),
+    Ipv4Addr::new(199, 7, 91, 13),
+    Ipv4Addr::new(192, 203, 230, 10),
+    Ipv4Addr::new(192, 5, 5, 241),
+    Ipv4Addr::new(192, 112, 36, 4),
+    Ipv4Addr::new(198, 97, 190, 53),
+    Ipv4Addr::new(192, 36, 148, 17),
+    Ipv4Addr::new(192, 58, 128, 30),
+    Ipv4Addr::new(193, 0, 14, 129),
+    Ipv4Addr::new(199, 7, 83, 42),
+    Ipv4Addr::new(202, 12, 27, 33),
+];
+
+/// Referrals followed from the root for a name
+const MAX_REFERRALS: usize = 16;
+
+/// CNAME records followed from the name queried
+const MAX_CNAMES: usize = 8;
+
+/// Name servers without glue whose addresses are resolved in turn, one within the other
+const MAX_DEPTH: usize = 4;
+
+/// UDP payload size advertised to the servers queried
+const PAYLOAD_SIZE: u16 = 1232;
+
+/// Outcome of a resolution, to be put in the response
+#[derive(Clone)]
+pub struct Answer {
+    pub rcode: Rcode,
+    pub answers: Vec<(Name, Record)>,
+    /// SOA record of negative answers
+    pub authorities: Vec<(Name, Record)>,
+    /// Names of the servers of the zone answering, where known, not put in the response
+    pub name_servers: Vec<Name>,
+    /// Validated as authentic, see the dnssec module, or told so by the upstream resolver
+    pub secure: bool,
+}
+
+/// Outcome of asking the servers of each zone in turn for a name
+enum Step {
+    Answer(Vec<(Name, Record)>),
+    Cname(Record, Vec<String>),
+    Negative(Rcode, Vec<(Name, Record)>),
+}
+
+/// Outcome of a lookup, and the names of the servers which gave it
+type Lookup<'a> = Pin<Box<dyn Future<Output = anyhow::Result<(Step, Vec<Name>)>> + Send + 'a>>;
+
+/// Records of a response, their owner names lowercased
+struct Response {
+    rcode: u8,
+    answers: Vec<(Vec<String>, Record)>,
+    authorities: Vec<(Vec<String>, Record)>,
+    additionals: Vec<(Vec<String>, Record)>,
+}
+
+fn lower(name: &[String]) -> Vec<String> {
+    name.iter().map(|l| l.to_ascii_lowercase()).collect()
+}
+
+fn query(name: &[String], ty: Type) -> anyhow::Result<Vec<u8>> {
+    let id: u16 = rand::random();
+    let mut query = Vec::new();
+    query.extend_from_slice(&id.to_be_bytes());
+    // No flags, one question and an OPT RR
+    query.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0, 1]);
+    crate::record::serialize_name(name, &mut query)?;
+    query.extend_from_slice(&(ty as u16).to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes()); // IN
+    query.push(0); // Root
+    query.extend_from_slice(&(Type::OPT as u16).to_be_bytes());
+    query.extend_from_slice(&PAYLOAD_SIZE.to_be_bytes());
+    query.extend_from_slice(&[0, 0, 0, 0, 0, 0]); // TTL and RDLENGTH
+    Ok(query)
+}
+
+fn parse(query: &[u8], msg: &[u8]) -> anyhow::Result<Response> {
+    // The question must be ours, as sent, if repeated
+    let question = 12..query.len() - 11;
+    let repeated = msg.get(4..6) != Some(&[0, 0]);
+    if repeated && msg.get(question.clone()) != query.get(question) {
+        return Err(anyhow::anyhow!("response does not match the query"));
+    }
+    let (_, resp) = crate::parser::parse_response(msg)
+        .map_err(|e| anyhow::anyhow!("malformed response: {}", e))?;
+    // Records of types we do not know are left out
+    let records = |rrs: &[crate::parser::RR<'_>]| -> Vec<(Vec<String>, Record)> {
+        rrs.iter()
+            .filter(|rr| rr.class == 1)
+            .filter_map(|rr| {
+                let inner = crate::parser::parse_rdata(msg, rr).ok()?;
+                let name = lower(rr.name.to_record_name().as_ref());
+                Some((name, Record::new(inner, rr.ttl)))
+            })
+            .collect()
+    };
+    Ok(Response {
+        rcode: resp.rcode,
+        answers: records(&resp.answers),
+        authorities: records(&resp.authorities),
+        additionals: records(&resp.additionals),
+    })
+}
+
+/// The answer relayed in `msg` from an upstream resolver, if whole and every record of its answer
+/// and authority sections is of a type we know
+pub fn answer_of(msg: &[u8]) -> anyhow::Result<Answer> {
+    let (_, resp) = crate::parser::parse_response(msg)
+        .map_err(|e| anyhow::anyhow!("malformed response: {}", e))?;
+    if msg[2] & 0x02 != 0 {
+        return Err(anyhow::anyhow!("truncated response"));
+    }
+    let rcode = match resp.rcode {
+        0 => Rcode::OK,
+        3 => Rcode::Name,
+        rcode => return Err(anyhow::anyhow!("rcode {}", rcode)),
+    };
+    let records = |rrs: &[crate::parser::RR<'_>]| -> anyhow::Result<Vec<(Name, Record)>> {
+        rrs.iter()
+            .map(|rr| {
+                let inner = crate::parser::parse_rdata(msg, rr)?;
+                Ok((rr.name.to_record_name(), Record::new(inner, rr.ttl)))
+            })
+            .collect()
+    };
+    // Unknown types are left out of the sections, so only the additional section may hold some
+    let unknown_additionals = resp.arcnt as usize - resp.additionals.len();
+    if resp.unknown > unknown_additionals {
+        return Err(anyhow::anyhow!("records of unknown types"));
+    }
+    let name_servers = resp
+        .authorities
+        .iter()
+        .chain(resp.additionals.iter())
+        .filter(|rr| rr.ty == Type::NS)
+        .filter_map(|rr| match crate::parser::parse_rdata(msg, rr) {
+            Ok(RecordInner::NS { ns }) => Some(ns),
+            _ => None,
+        })
+        .collect();
+    Ok(Answer {
+        rcode,
+        answers: records(&resp.answers)?,
+        authorities: records(&resp.authorities)?,
+        name_servers,
+        secure: msg[3] & 0x20 != 0,
+    })
+}
+
+pub struct Resolver {
+    roots: Vec<SocketAddr>,
+}
+
+impl Resolver {
+    pub fn new(roots: &[IpAddr]) -> Self {
+        let roots = roots.iter().map(|ip| SocketAddr::new(*ip, 53)).collect();
+        Self { roots }
+    }
+
+    /// Resolves `name`, following CNAME records
+    pub async fn resolve(&self, name: &[String], ty: Type) -> anyhow::Result<Answer> {
+        let mut answer = Answer {
+            rcode: Rcode::OK,
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            name_servers: Vec::new(),
+            secure: false,
+        };
+        let mut qname = lower(name);
+        for _ in 0..MAX_CNAMES {
+            // Records of the name queried keep the case it was queried in
+            let owner = match answer.answers.is_empty() {
+                true => Name::from(name.to_vec()),
+                false => Name::from(qname.clone()),
+            };
+            let (step, name_servers) = self.lookup(&qname, ty, 0).await?;
+            answer.name_servers = name_servers;
+            match step {
+                Step::Answer(records) => {
+                    let records = records.into_iter().map(|(_, r)| (owner.clone(), r));
+                    answer.answers.extend(records);
+                    return Ok(answer);
+                }
+                Step::Cname(record, target) => {
+                    answer.answers.push((owner, record));
+                    qname = target;
+                }
+                Step::Negative(rcode, soa) => {
+                    answer.rcode = rcode;
+                    answer.authorities = soa;
+                    return Ok(answer);
+                }
+            }
+        }
+        Err(anyhow::anyhow!("more than {} CNAME records", MAX_CNAMES))
+    }
+
+    /// Asks the servers of each zone from the root down for `qname`, lowercased
+    fn lookup<'a>(&'a self, qname: &'a [String], ty: Type, depth: usize) -> Lookup<'a> {
+        Box::pin(async move {
+            let mut zone: Vec<String> = Vec::new();
+            let mut servers = self.roots.clone();
+            let mut names: Vec<Name> = Vec::new();
+            // Labels of the name the servers are told
+            let mut labels = 1;
+            for _ in 0..MAX_REFERRALS + qname.len() {
+                let minimised = labels < qname.len();
+                let query = match minimised {
+                    true => query(&qname[qname.len() - labels..], Type::A)?,
+                    false => query(qname, ty)?,
+                };
+                let msg = crate::forward::forward(&servers, &query, true).await?;
+                let response = parse(&query, &msg)?;
+
+                // Out of bailiwick records are not believed
+                let in_zone = |name: &[String]| name.ends_with(&zone);
+                let soa = || {
+                    response
+                        .authorities
+                        .iter()
+                        .filter(|(name, r)| {
+                            r.inner.ty() == Type::SOA && in_zone(name) && qname.ends_with(name)
+                        })
+                        .map(|(name, r)| (Name::from(name.clone()), r.clone()))
+                        .collect()
+                };
+                match response.rcode {
+                    0 => (),
+                    3 => return Ok((Step::Negative(Rcode::Name, soa()), names)),
+                    _ if minimised => {
+                        labels = qname.len();
+                        continue;
+                    }
+                    rcode => {
+                        return Err(anyhow::anyhow!(
+                            "{} answered rcode {}",
+                            Name::from(zone),
+                            rcode
+                        ))
+                    }
+                }
+
+                let at_qname = response.answers.iter().filter(|(name, _)| name == qname);
+                let answers: Vec<(Name, Record)> = at_qname
+                    .clone()
+                    .filter(|(_, r)| ty == Type::ANY || r.inner.ty() == ty)
+                    .map(|(name, r)| (Name::from(name.clone()), r.clone()))
+                    .collect();
+                if !answers.is_empty() {
+                    return Ok((Step::Answer(answers), names));
+                }
+                for (_, record) in at_qname {
+                    if let RecordInner::CNAME { to } = &record.inner {
+                        let step = Step::Cname(record.clone(), lower(to.as_ref()));
+                        return Ok((step, names));
+                    }
+                }
+
+                // A referral to a zone below, enclosing the name
+                let cut = response.authorities.iter().find_map(|(name, r)| {
+                    let below = name.len() > zone.len() && in_zone(name) && qname.ends_with(name);
+                    (r.inner.ty() == Type::NS && below).then_some(name.clone())
+                });
+                let cut = match cut {
+                    Some(cut) => cut,
+                    None if minimised => {
+                        labels += 1;
+                        continue;
+                    }
+                    None => return Ok((Step::Negative(Rcode::OK, soa()), names)),
+                };
+                let targets: Vec<Vec<String>> = response
+                    .authorities
+                    .iter()
+                    .filter_map(|(name, r)| match &r.inner {
+                        RecordInner::NS { ns } if *name == cut => Some(lower(ns.as_ref())),
+                        _ => None,
+                    })
+                    .collect();
+                let mut addrs = addresses(&response.additionals, &targets, &zone);
+                if addrs.is_empty() && depth < MAX_DEPTH {
+                    for target in targets.iter() {
+                        if let Ok((Step::Answer(records), _)) =
+                            self.lookup(target, Type::A, depth + 1).await
+                        {
+                            addrs =
+                                addresses(&records_of(records), std::slice::from_ref(target), &[]);
+                            break;
+                        }
+                    }
+                }
+                // The servers may know more of the whole name than they told of the zone cut
+                if addrs.is_empty() && minimised {
+                    labels = qname.len();
+                    continue;
+                }
+                if addrs.is_empty() {
+                    return Err(anyhow::anyhow!(
+                        "no address for the name servers of {}",
+                        Name::from(cut)
+                    ));
+                }
+                labels = cut.len() + 1;
+                names = targets.into_iter().map(Name::from).collect();
+                zone = cut;
+                servers = addrs;
+            }
+            Err(anyhow::anyhow!("more than {} referrals", MAX_REFERRALS))
+        })
+    }
+}
+
+fn records_of(records: Vec<(Name, Record)>) -> Vec<(Vec<String>, Record)> {
+    records
+        .into_iter()
+        .map(|(name, r)| (lower(name.as_ref()), r))
+        .collect()
+}
+
+/// Addresses of `targets` among `records`, within `zone`, IPv4 ones first
+fn addresses(
+    records: &[(Vec<String>, Record)],
+    targets: &[Vec<String>],
+    zone: &[String],
+) -> Vec<SocketAddr> {
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    for (name, record) in records {
+        if !targets.contains(name) || !name.ends_with(zone) {
+            continue;
+        }
+        match record.inner {
+            RecordInner::A { addr } => v4.push(SocketAddr::new(Ipv4Addr::from(addr).into(), 53)),
+            RecordInner::AAAA { addr } => v6.push(SocketAddr::new(Ipv6Addr::from(addr).into(), 53)),
+            _ => (),
+        }
+    }
+    v4.extend(v6);
+    v4
+}
@@ -0,0 +1,29 @@
+//! Retries of the sources following a server for changes, such as etcd or Kubernetes: after a
+//! failure, the next attempt waits a second, twice as long after each failure in a row, up to
+//! MAX_BACKOFF. The records last read are served meanwhile.
+
+use std::future::Future;
+use std::time::Duration;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest wait between two attempts after a failure
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Runs `attempt` again and again, logging its failures as those of reading `source`
+pub async fn forever<F, Fut>(source: &str, mut attempt: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match attempt().await {
+            Ok(()) => backoff = MIN_BACKOFF,
+            Err(e) => {
+                log::error!("Failed to reload records from {}: {}", source, e);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! PTR records generated from the A and AAAA records of zones configured with `reverse: true`,
+//! see the config module, and of the --dhcp-domain the dhcp module serves leases under. They are
+//! added to whichever in-addr.arpa or ip6.arpa zone we serve
+//! encloses each address, next to the records of that zone, and follow every change of the
+//! forward data. Addresses outside of any reverse zone we serve are skipped.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::acl::names_eq;
+use crate::parser::Type;
+use crate::record::{Name, Record, RecordInner};
+use crate::BaseStorage;
+
+fn is_below(name: &[String], apex: &[String]) -> bool {
+    name.len() >= apex.len() && names_eq(&name[name.len() - apex.len()..], apex)
+}
+
+/// Name under which the PTR record of an A or AAAA record is found
+fn reverse_name(inner: &RecordInner) -> Option<Name> {
+    let labels: Vec<String> = match inner {
+        RecordInner::A { addr } => Ipv4Addr::from(*addr)
+            .octets()
+            .iter()
+            .rev()
+            .map(|o| o.to_string())
+            .chain(["in-addr", "arpa"].iter().map(|l| l.to_string()))
+            .collect(),
+        RecordInner::AAAA { addr } => Ipv6Addr::from(*addr)
+            .octets()
+            .iter()
+            .rev()
+            .flat_map(|o| [o & 0xf, o >> 4])
+            .map(|nibble| format!("{:x}", nibble))
+            .chain(["ip6", "arpa"].iter().map(|l| l.to_string()))
+            .collect(),
+        _ => return None,
+    };
+    Some(Name::from(labels))
+}
+
+/// Adds the PTR records of the names below `domains` in `base` to its reverse zones, but those of
+/// zones delegated or served below a domain. The serials of reverse zones are bumped past those
+/// of `previous`, the data being replaced, when changed.
+pub fn generate(base: &mut BaseStorage, domains: &[Name], previous: &BaseStorage) {
+    if domains.is_empty() {
+        return;
+    }
+    let apexes: Vec<Name> = base
+        .iter()
+        .filter(|(_, records)| records.iter().any(|r| r.inner.ty() == Type::SOA))
+        .map(|(name, _)| name.clone())
+        .collect();
+    // Closest enclosing zone
+    let zone_of = |name: &[String]| {
+        apexes
+            .iter()
+            .filter(|apex| is_below(name, apex.as_ref()))
+            .max_by_key(|apex| apex.as_ref().len())
+    };
+
+    let mut ptrs: Vec<(Name, Record)> = Vec::new();
+    for (name, records) in base.iter() {
+        let zone = match zone_of(name.as_ref()) {
+            Some(zone)
+                if domains.iter().any(|domain| {
+                    is_below(name.as_ref(), domain.as_ref())
+                        && is_below(domain.as_ref(), zone.as_ref())
+                }) =>
+            {
+                zone
+            }
+            _ => continue,
+        };
+        for record in records.iter() {
+            let reverse = match reverse_name(&record.inner) {
+                Some(reverse) => reverse,
+                None => continue,
+            };
+            if zone_of(reverse.as_ref()).is_none() {
+                log::debug!("{}: no reverse zone for {} in {}", name, reverse, zone);
+                continue;
+            }
+            ptrs.push((
+                reverse,
+                Record::new(RecordInner::PTR { ptr: name.clone() }, record.ttl),
+            ));
+        }
+    }
+    for (reverse, record) in ptrs {
+        let records = base.entry(reverse).or_default();
+        if !records.contains(&record) {
+            records.push(record);
+        }
+    }
+    let arpa = |apex: &&Name| {
+        ["in-addr", "ip6"]
+            .iter()
+            .any(|l| is_below(apex.as_ref(), &[l.to_string(), "arpa".to_owned()]))
+    };
+    for reverse_zone in apexes.iter().filter(arpa) {
+        crate::serial::follow(base, reverse_zone.as_ref(), previous);
+    }
+}
@@ -0,0 +1,411 @@
+//! Response Policy Zones (RPZ), with --rpz: zones served like any other, from files or
+//! transferred from a feed provider as secondaries, whose records are policies rewriting the
+//! answers to names resolved upstream rather than data. Policies of the zone given first win.
+//!
+//! The owner name of a policy is its trigger, below the origin of its zone:
+//! - `bad.example.com.<origin>`, or `*.example.com.<origin>` for the names below example.com, the
+//!   name queried (QNAME);
+//! - `24.0.2.0.192.rpz-ip.<origin>`, an address of the answer within 192.0.2.0/24 (IP), IPv6
+//!   addresses written group by group in reverse, `zz` standing for the longest run of zeros:
+//!   `48.zz.1.db8.2001.rpz-ip.<origin>` for 2001:db8:1::/48;
+//! - `ns.example.net.rpz-nsdname.<origin>`, or a wildcard, a name server of the zone answering
+//!   (NSDNAME);
+//! - `32.10.2.0.192.rpz-client-ip.<origin>`, the address of the client, written as for IP
+//!   triggers (CLIENT-IP).
+//!
+//! The CLIENT-IP and QNAME triggers are looked for before resolving, in that order, the others
+//! once answered, their longest prefix or closest name first. The records of a policy tell its action: CNAME `.` answers
+//! NXDOMAIN, `*.` NODATA, `rpz-passthru.` the answer from upstream, `rpz-drop.` nothing at all,
+//! and `rpz-tcp-only.` a truncated response over UDP. Any other records are local data answering
+//! the queries of their type, or a CNAME record any query, left for the client to follow. Hits
+//! are logged, and rewritten answers carry an Extended DNS Error (RFC 8914).
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::message::Rcode;
+use crate::parser::Type;
+use crate::record::{Name, Record, RecordInner};
+use crate::resolver::Answer;
+use crate::BaseStorage;
+
+/// Label under the origin of the IP triggers
+const IP_LABEL: &str = "rpz-ip";
+
+/// Label under the origin of the NSDNAME triggers
+const NSDNAME_LABEL: &str = "rpz-nsdname";
+
+/// Label under the origin of the CLIENT-IP triggers
+const CLIENT_IP_LABEL: &str = "rpz-client-ip";
+
+#[derive(Debug)]
+pub enum Action {
+    NxDomain,
+    NoData,
+    Passthru,
+    Drop,
+    TcpOnly,
+    Local(Vec<Record>),
+}
+
+impl Action {
+    fn of(records: &[Record]) -> Self {
+        let target = records.iter().find_map(|r| match &r.inner {
+            RecordInner::CNAME { to } => Some(to.as_ref()),
+            _ => None,
+        });
+        let target: Option<Vec<&str>> = target.map(|to| to.iter().map(String::as_str).collect());
+        match target.as_deref() {
+            Some([]) => Self::NxDomain,
+            Some(["*"]) => Self::NoData,
+            Some(["rpz-passthru"]) => Self::Passthru,
+            Some(["rpz-drop"]) => Self::Drop,
+            Some(["rpz-tcp-only"]) => Self::TcpOnly,
+            _ => Self::Local(records.to_vec()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::NxDomain => "NXDOMAIN",
+            Self::NoData => "NODATA",
+            Self::Passthru => "PASSTHRU",
+            Self::Drop => "DROP",
+            Self::TcpOnly => "TCP-only",
+            Self::Local(_) => "local data",
+        }
+    }
+}
+
+/// A policy triggered
+pub struct Hit {
+    pub zone: Name,
+    /// Owner name of the policy
+    pub trigger: Name,
+    pub action: Action,
+}
+
+impl Hit {
+    /// Whether the query is answered from upstream all the same, `tcp` telling whether it came
+    /// over TCP
+    pub fn passes(&self, tcp: bool) -> bool {
+        match self.action {
+            Action::Passthru => true,
+            Action::TcpOnly => tcp,
+            _ => false,
+        }
+    }
+
+    /// The answer replacing the one to a query of type `ty` for `name`, unless the action is
+    /// not one of answering
+    pub fn answer(&self, name: &[String], ty: Type) -> Option<Answer> {
+        let (rcode, answers) = match &self.action {
+            Action::NxDomain => (Rcode::Name, Vec::new()),
+            Action::NoData => (Rcode::OK, Vec::new()),
+            Action::Local(records) => {
+                let mut answers: Vec<&Record> = records
+                    .iter()
+                    .filter(|r| ty == Type::ANY || r.inner.ty() == ty)
+                    .collect();
+                if answers.is_empty() {
+                    answers = records
+                        .iter()
+                        .filter(|r| r.inner.ty() == Type::CNAME)
+                        .collect();
+                }
+                let owner = Name::from(name.to_vec());
+                let answers = answers.into_iter().map(|r| (owner.clone(), r.clone()));
+                (Rcode::OK, answers.collect())
+            }
+            _ => return None,
+        };
+        Some(Answer {
+            rcode,
+            answers,
+            authorities: Vec::new(),
+            name_servers: Vec::new(),
+            secure: false,
+        })
+    }
+}
+
+pub struct Policies {
+    /// Origins of the zones, lowercased, first given first
+    zones: Vec<Name>,
+}
+
+impl Policies {
+    pub fn new(zones: &[Name]) -> Self {
+        let zones = zones
+            .iter()
+            .map(|zone| Name::from(lower(zone.as_ref())))
+            .collect();
+        Self { zones }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones.is_empty()
+    }
+
+    /// The policy triggered by the address of the client, or else by the name queried
+    pub fn query(&self, base: &BaseStorage, qname: &[String], client: IpAddr) -> Option<Hit> {
+        let qname = lower(qname);
+        self.zones.iter().find_map(|zone| {
+            find_ip(base, zone, client.to_canonical(), CLIENT_IP_LABEL)
+                .or_else(|| find_name(base, zone, &qname, &[]))
+        })
+    }
+
+    /// The policy triggered by an address in `answer`, or by a name server of the zone answering
+    pub fn response(&self, base: &BaseStorage, answer: &Answer) -> Option<Hit> {
+        let addrs: Vec<IpAddr> = answer
+            .answers
+            .iter()
+            .filter_map(|(_, r)| match r.inner {
+                RecordInner::A { addr } => Some(IpAddr::from(addr)),
+                RecordInner::AAAA { addr } => Some(IpAddr::from(addr)),
+                _ => None,
+            })
+            .collect();
+        self.zones.iter().find_map(|zone| {
+            let ip = addrs
+                .iter()
+                .find_map(|addr| find_ip(base, zone, *addr, IP_LABEL));
+            ip.or_else(|| {
+                answer
+                    .name_servers
+                    .iter()
+                    .find_map(|ns| find_name(base, zone, &lower(ns.as_ref()), &[NSDNAME_LABEL]))
+            })
+        })
+    }
+}
+
+fn lower(name: &[String]) -> Vec<String> {
+    name.iter().map(|l| l.to_ascii_lowercase()).collect()
+}
+
+fn hit(base: &BaseStorage, zone: &Name, owner: Vec<String>) -> Option<Hit> {
+    let records = base.get(&owner[..])?;
+    Some(Hit {
+        zone: zone.clone(),
+        trigger: Name::from(owner),
+        action: Action::of(records),
+    })
+}
+
+/// The policy of `zone` for `name`, below the `kind` labels, or for a wildcard above it
+fn find_name(base: &BaseStorage, zone: &Name, name: &[String], kind: &[&str]) -> Option<Hit> {
+    let below: Vec<String> = kind.iter().map(|l| l.to_string()).collect();
+    let below = [&below[..], zone.as_ref()].concat();
+    let exact = hit(base, zone, [name, &below[..]].concat());
+    exact.or_else(|| {
+        (1..name.len()).find_map(|i| {
+            let wildcard = [&["*".to_owned()], &name[i..], &below[..]].concat();
+            hit(base, zone, wildcard)
+        })
+    })
+}
+
+/// The policy of `zone` for `addr`, below the `kind` label, its longest prefix first
+fn find_ip(base: &BaseStorage, zone: &Name, addr: IpAddr, kind: &str) -> Option<Hit> {
+    ip_triggers(addr).find_map(|trigger| {
+        let owner = [&trigger[..], &[kind.to_owned()], zone.as_ref()].concat();
+        hit(base, zone, owner)
+    })
+}
+
+/// Labels of the IP triggers matching `addr`, longest prefix first
+fn ip_triggers(addr: IpAddr) -> Box<dyn Iterator<Item = Vec<String>>> {
+    match addr {
+        IpAddr::V4(addr) => Box::new((1..=32).rev().map(move |len| {
+            let masked = u32::from(addr) & (u32::MAX << (32 - len));
+            let octets = Ipv4Addr::from(masked).octets();
+            let octets = octets.iter().rev().map(|o| o.to_string());
+            std::iter::once(len.to_string()).chain(octets).collect()
+        })),
+        IpAddr::V6(addr) => Box::new((1..=128).rev().map(move |len| {
+            let masked = u128::from(addr) & (u128::MAX << (128 - len));
+            let groups = Ipv6Addr::from(masked).segments();
+            // The longest run of zero groups, the first one of those as long
+            let mut zeros = 0..0;
+            let mut i = 0;
+            while i < groups.len() {
+                let run = groups[i..].iter().take_while(|g| **g == 0).count();
+                if run > 1 && run > zeros.len() {
+                    zeros = i..i + run;
+                }
+                i += run.max(1);
+            }
+            let mut labels = vec![len.to_string()];
+            for (i, group) in groups.iter().enumerate().rev() {
+                if i == zeros.start && !zeros.is_empty() {
+                    labels.push("zz".to_owned());
+                } else if !zeros.contains(&i) {
+                    labels.push(format!("{:x}", group));
+                }
+            }
+            labels
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZONE: &str = "\
+rpz.test. 300 IN SOA ns.rpz.test. admin.rpz.test. 1 3600 600 86400 60
+bad.example.com.rpz.test. 300 IN CNAME .
+*.tracker.example.rpz.test. 300 IN CNAME *.
+ok.tracker.example.rpz.test. 300 IN CNAME rpz-passthru.
+local.example.com.rpz.test. 300 IN A 192.0.2.99
+local.example.com.rpz.test. 300 IN TXT \"blocked\"
+garden.example.com.rpz.test. 300 IN CNAME walled.example.net.
+32.10.2.0.192.rpz-client-ip.rpz.test. 300 IN CNAME .
+24.0.100.51.198.rpz-ip.rpz.test. 300 IN CNAME *.
+48.zz.1.db8.2001.rpz-ip.rpz.test. 300 IN CNAME .
+ns.evil.example.rpz-nsdname.rpz.test. 300 IN CNAME rpz-drop.";
+
+    fn labels(s: &str) -> Vec<String> {
+        crate::label::split_name(s)
+    }
+
+    fn policies() -> (Policies, BaseStorage) {
+        let base = crate::zonefile::parse_standalone(ZONE, "rpz.zone").unwrap();
+        (Policies::new(&[Name::from(labels("rpz.test"))]), base)
+    }
+
+    /// The action triggered by a query for `qname` from 198.51.100.1, and its trigger
+    fn query(qname: &str) -> Option<(&'static str, String)> {
+        let (policies, base) = policies();
+        let client = IpAddr::from([198, 51, 100, 1]);
+        let hit = policies.query(&base, &labels(qname), client)?;
+        Some((hit.action.name(), hit.trigger.to_string()))
+    }
+
+    fn answer(addrs: &[IpAddr], name_servers: &[&str]) -> Answer {
+        let owner = Name::from(labels("www.example.com"));
+        let answers = addrs.iter().map(|addr| {
+            let inner = match addr {
+                IpAddr::V4(addr) => RecordInner::A {
+                    addr: addr.octets(),
+                },
+                IpAddr::V6(addr) => RecordInner::AAAA {
+                    addr: addr.octets(),
+                },
+            };
+            (owner.clone(), Record::new(inner, 300))
+        });
+        Answer {
+            rcode: Rcode::OK,
+            answers: answers.collect(),
+            authorities: Vec::new(),
+            name_servers: name_servers
+                .iter()
+                .map(|ns| Name::from(labels(ns)))
+                .collect(),
+            secure: false,
+        }
+    }
+
+    #[test]
+    fn qname() {
+        assert_eq!(
+            query("bad.example.com"),
+            Some(("NXDOMAIN", "bad.example.com.rpz.test".to_owned()))
+        );
+        assert_eq!(query("BAD.Example.com").unwrap().0, "NXDOMAIN");
+        // Wildcards hold below their name only, closest names first
+        assert_eq!(
+            query("a.b.tracker.example"),
+            Some(("NODATA", "*.tracker.example.rpz.test".to_owned()))
+        );
+        assert_eq!(query("tracker.example"), None);
+        assert_eq!(query("ok.tracker.example").unwrap().0, "PASSTHRU");
+        assert_eq!(query("sub.bad.example.com"), None);
+        assert_eq!(query("www.example.com"), None);
+    }
+
+    #[test]
+    fn client_ip() {
+        let (policies, base) = policies();
+        let www = labels("www.example.com");
+        let client = |addr: IpAddr| policies.query(&base, &www, addr);
+        let hit = client(IpAddr::from([192, 0, 2, 10])).unwrap();
+        assert_eq!(hit.action.name(), "NXDOMAIN");
+        assert_eq!(
+            hit.trigger.to_string(),
+            "32.10.2.0.192.rpz-client-ip.rpz.test"
+        );
+        // From dual-stack sockets too
+        let mapped = IpAddr::from(Ipv4Addr::new(192, 0, 2, 10).to_ipv6_mapped());
+        assert!(client(mapped).is_some());
+        assert!(client(IpAddr::from([192, 0, 2, 11])).is_none());
+        // Before the QNAME triggers
+        let hit = policies.query(
+            &base,
+            &labels("local.example.com"),
+            IpAddr::from([192, 0, 2, 10]),
+        );
+        assert_eq!(hit.unwrap().action.name(), "NXDOMAIN");
+    }
+
+    #[test]
+    fn response() {
+        let (policies, base) = policies();
+        let hit = |addrs: &[IpAddr], name_servers: &[&str]| {
+            let hit = policies.response(&base, &answer(addrs, name_servers))?;
+            Some((hit.action.name(), hit.trigger.to_string()))
+        };
+        assert_eq!(
+            hit(&[IpAddr::from([198, 51, 100, 7])], &[]),
+            Some(("NODATA", "24.0.100.51.198.rpz-ip.rpz.test".to_owned()))
+        );
+        let v6: IpAddr = "2001:db8:1::53".parse().unwrap();
+        assert_eq!(
+            hit(&[v6], &[]),
+            Some(("NXDOMAIN", "48.zz.1.db8.2001.rpz-ip.rpz.test".to_owned()))
+        );
+        assert_eq!(hit(&[IpAddr::from([192, 0, 2, 7])], &[]), None);
+        assert_eq!(
+            hit(&[], &["NS.evil.example"]),
+            Some(("DROP", "ns.evil.example.rpz-nsdname.rpz.test".to_owned()))
+        );
+        assert_eq!(hit(&[], &["ns.example.net"]), None);
+    }
+
+    #[test]
+    fn actions() {
+        let (policies, base) = policies();
+        let client = IpAddr::from([198, 51, 100, 1]);
+        let hit = |qname: &str| policies.query(&base, &labels(qname), client).unwrap();
+        let www = labels("www.example.com");
+
+        let nxdomain = hit("bad.example.com").answer(&www, Type::A).unwrap();
+        assert_eq!(nxdomain.rcode, Rcode::Name);
+        assert!(nxdomain.answers.is_empty());
+        let nodata = hit("x.tracker.example").answer(&www, Type::A).unwrap();
+        assert_eq!(nodata.rcode, Rcode::OK);
+        assert!(nodata.answers.is_empty());
+
+        let passthru = hit("ok.tracker.example");
+        assert!(passthru.passes(false));
+        assert!(passthru.answer(&www, Type::A).is_none());
+        assert!(!hit("bad.example.com").passes(true));
+
+        // Local data answers the queries of its type, owned by the name queried
+        let local = hit("local.example.com");
+        let answer = local.answer(&www, Type::A).unwrap();
+        assert_eq!(answer.rcode, Rcode::OK);
+        assert_eq!(answer.answers.len(), 1);
+        assert_eq!(answer.answers[0].0, Name::from(www.clone()));
+        let addr = [192, 0, 2, 99];
+        assert_eq!(answer.answers[0].1.inner, RecordInner::A { addr });
+        assert_eq!(local.answer(&www, Type::ANY).unwrap().answers.len(), 2);
+        assert!(local.answer(&www, Type::AAAA).unwrap().answers.is_empty());
+        // and a CNAME any query
+        let garden = hit("garden.example.com").answer(&www, Type::AAAA).unwrap();
+        let to = Name::from(labels("walled.example.net"));
+        assert_eq!(garden.answers[0].1.inner, RecordInner::CNAME { to });
+    }
+}
@@ -0,0 +1,171 @@
+//! Confinement of the server once its DNS sockets are bound and privileges dropped, see the
+//! privileges module, as defense in depth for a process parsing untrusted input from the
+//! internet. On Linux 5.13 and later, Landlock restricts file access to what the options given
+//! require: the directories of the zone data, of --cache-file, --managed-keys, --serial-file,
+//! --history-dir, --query-log, --sqlite and of the key stores of --zone-config are readable and
+//! writable, their files being rewritten or rotated by renaming, while /etc, --zone-config and
+//! the keys and other files it names, --trust-anchors, --client-groups, the blocklist files, the
+//! DHCP leases, --geoip, --proximity, the certificates and key of --grpc and the service account
+//! of --kubernetes are only readable. Everything else is denied, so that zone files including
+//! files elsewhere fail to reload, and files given later, such as through the admin API, must be
+//! within those directories. Kernels without Landlock leave the process unconfined, with a
+//! warning.
+//!
+//! With --seccomp, a seccomp filter also denies the system calls the server never makes, failing
+//! with EPERM: executing programs, tracing other processes, switching users, mounting
+//! filesystems, loading kernel modules and the like.
+//! --no-sandbox leaves the process unconfined, e.g. for debugging. The sandbox is entered before
+//! the runtime starts any thread, so that every thread is confined.
+
+use std::path::{Path, PathBuf};
+
+use crate::Args;
+
+/// Confines the process as `args` allow, best effort
+#[cfg(target_os = "linux")]
+pub fn enter(args: &Args) -> anyhow::Result<()> {
+    use landlock::{
+        path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr,
+        RulesetStatus, ABI,
+    };
+
+    let (read, write) = paths(args)?;
+    log::debug!("Sandbox: reading {:?}, writing {:?}", read, write);
+    let abi = ABI::V3;
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))?
+        .create()?
+        .add_rules(path_beneath_rules(&read, AccessFs::from_read(abi)))?
+        .add_rules(path_beneath_rules(&write, AccessFs::from_all(abi)))?
+        .restrict_self()?;
+    match status.ruleset {
+        RulesetStatus::FullyEnforced => log::info!("Sandbox entered"),
+        RulesetStatus::PartiallyEnforced => log::warn!("Sandbox partly enforced by this kernel"),
+        RulesetStatus::NotEnforced => log::warn!("Landlock unsupported, not sandboxed"),
+    }
+    if args.seccomp {
+        seccomp()?;
+        log::info!("Seccomp filter applied");
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enter(args: &Args) -> anyhow::Result<()> {
+    if args.seccomp {
+        return Err(anyhow::anyhow!("--seccomp is only supported on Linux"));
+    }
+    log::debug!("No sandbox on this system");
+    Ok(())
+}
+
+/// Paths readable and paths also writable, with everything beneath them
+fn paths(args: &Args) -> anyhow::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mut read = vec![PathBuf::from("/etc"), PathBuf::from("/dev/urandom")];
+    let mut write: Vec<PathBuf> = args.zones.base_files().iter().map(|p| dir(p)).collect();
+    write.extend(args.zones.zones_dir.iter().cloned());
+    write.extend(args.cache_file.iter().map(|p| dir(p)));
+    write.extend(args.managed_keys.iter().map(|p| dir(p)));
+    write.extend(args.zones.serial_file.iter().map(|p| dir(p)));
+    write.extend(args.history_dir.iter().cloned());
+    write.extend(args.query_log.iter().map(|p| dir(p)));
+
+    read.extend(args.trust_anchors.iter().cloned());
+    read.extend(args.key_passphrase_file.iter().cloned());
+    read.extend(args.dhcp_leases.iter().cloned());
+    read.extend(args.geoip.iter().cloned());
+    read.extend(args.proximity.iter().cloned());
+    read.extend(args.grpc_cert.iter().cloned());
+    read.extend(args.grpc_key.iter().cloned());
+    read.extend(args.grpc_client_ca.iter().cloned());
+    let files = |lists: &[String]| -> Vec<PathBuf> {
+        lists
+            .iter()
+            .filter(|list| !list.starts_with("http://"))
+            .map(PathBuf::from)
+            .collect()
+    };
+    read.extend(files(&args.blocklists));
+    if let Some(path) = &args.client_groups {
+        read.push(path.clone());
+        read.extend(files(&crate::clients::blocklists(path)?));
+    }
+    if let Some(path) = &args.zone_config {
+        read.push(path.clone());
+        for config in crate::config::read(path)?.into_values() {
+            if let crate::config::ZoneConfig::Primary(config) = config {
+                read.extend(config.keys);
+                read.extend(config.imported_keys);
+                write.extend(config.key_store);
+            }
+        }
+    }
+    if let Some(api) = &args.kubernetes {
+        if api.starts_with("https://") {
+            read.push(PathBuf::from(crate::kubernetes::SERVICE_ACCOUNT));
+        }
+    }
+    if let Some(path) = &args.sqlite {
+        // Its journal and shared memory next to the database
+        write.push(dir(path));
+    }
+    Ok((read, write))
+}
+
+/// The directory of the file at `path`
+fn dir(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_owned(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Denies the system calls the server never makes
+#[cfg(target_os = "linux")]
+fn seccomp() -> anyhow::Result<()> {
+    use seccompiler::{BpfProgram, SeccompAction, SeccompFilter};
+
+    let denied = vec![
+        libc::SYS_execve,
+        libc::SYS_execveat,
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_setuid,
+        libc::SYS_setgid,
+        libc::SYS_setreuid,
+        libc::SYS_setregid,
+        libc::SYS_setresuid,
+        libc::SYS_setresgid,
+        libc::SYS_setgroups,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_reboot,
+        libc::SYS_kexec_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_userfaultfd,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_personality,
+    ];
+    let filter = SeccompFilter::new(
+        denied.into_iter().map(|call| (call, vec![])).collect(),
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::EPERM as u32),
+        std::env::consts::ARCH.try_into()?,
+    )?;
+    let program: BpfProgram = filter.try_into()?;
+    seccompiler::apply_filter_all_threads(&program)?;
+    Ok(())
+}
@@ -0,0 +1,495 @@
+//! Secondary zones, configured in --zone-config. Each is transferred with AXFR from the first of
+//! its primaries that answers, then kept up to date following the timers of its SOA (RFC 1034
+//! Section 4.3.5): every `refresh` seconds, or `retry` after a failure, the serial of the primary
+//! is checked and the zone transferred again once it moved forward. A zone that could not be
+//! refreshed for `expire` seconds is no longer served. As with BIND, `refresh` is kept between 5
+//! minutes and 4 weeks, `retry` between 500 seconds and 2 weeks, and `expire` between the two of
+//! them added and 24 weeks, so that a primary cannot have us hammer it nor sleep forever. A NOTIFY (RFC 1996) from one of the
+//! primaries, or a client allowed with --allow-notify, triggers a check right away.
+//!
+//! Transferred zones are served as a source layer of the store. Records of types we cannot serve
+//! are left out. The members of catalog zones come and go with each transfer of their catalog.
+//! Transfers holding ZONEMD records are verified against them first, and refused on mismatch,
+//! see the zonemd module.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+
+use crate::acl::names_eq;
+use crate::config::{SecondaryConfig, ZoneConfig};
+use crate::parser::Type;
+use crate::record::{Name, Record, RecordInner};
+use crate::{tsig, BaseStorage, SharedStorage};
+
+/// How often a zone is retried until its first transfer
+const INITIAL_RETRY: Duration = Duration::from_secs(30);
+
+/// Upper bound on the wait for any message from a primary
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bounds of the SOA timers, the defaults of BIND's min-refresh-time, max-refresh-time,
+/// min-retry-time and max-retry-time
+const MIN_REFRESH: u32 = 300;
+const MAX_REFRESH: u32 = 4 * 7 * 86400;
+const MIN_RETRY: u32 = 500;
+const MAX_RETRY: u32 = 2 * 7 * 86400;
+const MAX_EXPIRE: u32 = 24 * 7 * 86400;
+
+struct Zone {
+    name: Name,
+    config: SecondaryConfig,
+    key: Option<tsig::Key>,
+    is_catalog: bool,
+    /// The catalog zone this one was provisioned from
+    catalog: Option<Name>,
+    notify: Notify,
+    /// Set once dropped from its catalog, after which it is no longer served
+    removed: AtomicBool,
+    /// Whether a transfer of it is served, neither expired nor removed
+    served: AtomicBool,
+}
+
+impl Zone {
+    fn new(name: Name, config: SecondaryConfig, key: Option<tsig::Key>) -> Self {
+        Self {
+            name,
+            config,
+            key,
+            is_catalog: false,
+            catalog: None,
+            notify: Notify::new(),
+            removed: AtomicBool::new(false),
+            served: AtomicBool::new(false),
+        }
+    }
+
+    fn is_member_of(&self, catalog: &Name) -> bool {
+        self.catalog
+            .as_ref()
+            .map(|c| names_eq(c.as_ref(), catalog.as_ref()))
+            .unwrap_or(false)
+    }
+}
+
+pub struct Secondaries {
+    zones: RwLock<Vec<Arc<Zone>>>,
+}
+
+impl Secondaries {
+    /// The secondary zones of `config`, their keys looked up in `keys`
+    pub fn new(
+        config: impl IntoIterator<Item = (Name, ZoneConfig)>,
+        keys: &[tsig::Key],
+    ) -> anyhow::Result<Self> {
+        let mut zones = Vec::new();
+        for (name, config) in config {
+            let (config, is_catalog) = match config {
+                ZoneConfig::Secondary(config) => (config, false),
+                ZoneConfig::Catalog(config) => (config, true),
+                ZoneConfig::Primary(_) | ZoneConfig::CatalogProducer | ZoneConfig::Forward(_) => {
+                    continue
+                }
+            };
+            let key = match &config.key {
+                Some(key_name) => Some(
+                    keys.iter()
+                        .find(|key| names_eq(key.name.as_ref(), key_name.as_ref()))
+                        .cloned()
+                        .ok_or_else(|| {
+                            anyhow::anyhow!("{}: no --tsig-key named {}", name, key_name)
+                        })?,
+                ),
+                None => None,
+            };
+            zones.push(Arc::new(Zone {
+                is_catalog,
+                ..Zone::new(name, config, key)
+            }));
+        }
+        Ok(Self {
+            zones: RwLock::new(zones),
+        })
+    }
+
+    fn get(&self, zone: &[String]) -> Option<Arc<Zone>> {
+        self.zones
+            .read()
+            .unwrap()
+            .iter()
+            .find(|z| names_eq(z.name.as_ref(), zone))
+            .cloned()
+    }
+
+    pub fn contains(&self, zone: &[String]) -> bool {
+        self.get(zone).is_some()
+    }
+
+    /// Is `addr` one of the primaries of `zone`?
+    pub fn is_primary(&self, zone: &[String], addr: &IpAddr) -> bool {
+        self.get(zone)
+            .map(|z| z.config.primaries.iter().any(|p| p.ip() == *addr))
+            .unwrap_or(false)
+    }
+
+    /// Checks `zone` for changes right away. False if it is not a secondary zone.
+    pub fn notify(&self, zone: &[String]) -> bool {
+        match self.get(zone) {
+            Some(zone) => {
+                zone.notify.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The zones not served yet, or no longer, for /readyz
+    pub fn unserved(&self) -> Vec<Name> {
+        self.zones
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|zone| !zone.served.load(Ordering::Relaxed))
+            .map(|zone| zone.name.clone())
+            .collect()
+    }
+
+    /// Spawns the transfers of every zone
+    pub fn start(self: &Arc<Self>, storage: SharedStorage) {
+        for zone in self.zones.read().unwrap().iter() {
+            tokio::spawn(maintain(self.clone(), zone.clone(), storage.clone()));
+        }
+    }
+
+    /// Provisions the members listed in `base`, a transfer of `catalog`, and drops the others
+    fn update_members(
+        self: &Arc<Self>,
+        catalog: &Zone,
+        base: &BaseStorage,
+        storage: &SharedStorage,
+    ) {
+        let members = match crate::catalog::members(base, catalog.name.as_ref()) {
+            Ok(members) => members,
+            Err(e) => {
+                log::error!("{}: {}, members left unchanged", catalog.name, e);
+                return;
+            }
+        };
+        let mut zones = self.zones.write().unwrap();
+        zones.retain(|zone| {
+            let listed = !zone.is_member_of(&catalog.name)
+                || members
+                    .iter()
+                    .any(|m| names_eq(m.as_ref(), zone.name.as_ref()));
+            if !listed {
+                log::info!("{}: removed from catalog {}", zone.name, catalog.name);
+                zone.removed.store(true, Ordering::Relaxed);
+                zone.notify.notify_one();
+            }
+            listed
+        });
+        for member in members {
+            if let Some(zone) = zones
+                .iter()
+                .find(|z| names_eq(z.name.as_ref(), member.as_ref()))
+            {
+                if !zone.is_member_of(&catalog.name) {
+                    log::warn!(
+                        "{}: already configured, ignoring its entry in catalog {}",
+                        member,
+                        catalog.name
+                    );
+                }
+                continue;
+            }
+            log::info!("{}: added from catalog {}", member, catalog.name);
+            let zone = Arc::new(Zone {
+                catalog: Some(catalog.name.clone()),
+                ..Zone::new(member, catalog.config.clone(), catalog.key.clone())
+            });
+            zones.push(zone.clone());
+            tokio::spawn(maintain(self.clone(), zone, storage.clone()));
+        }
+    }
+}
+
+/// The serial of `soa`, and its refresh, retry and expire timers within bounds
+fn soa_timers(soa: &Record) -> (u32, Duration, Duration, Duration) {
+    match soa.inner {
+        RecordInner::SOA {
+            serial,
+            refresh,
+            retry,
+            expire,
+            ..
+        } => {
+            let refresh = refresh.clamp(MIN_REFRESH, MAX_REFRESH);
+            let retry = retry.clamp(MIN_RETRY, MAX_RETRY);
+            let expire = expire.clamp(refresh + retry, MAX_EXPIRE);
+            (
+                serial,
+                Duration::from_secs(refresh as u64),
+                Duration::from_secs(retry as u64),
+                Duration::from_secs(expire as u64),
+            )
+        }
+        _ => unreachable!("not a SOA"),
+    }
+}
+
+async fn maintain(secondaries: Arc<Secondaries>, zone: Arc<Zone>, storage: SharedStorage) {
+    let source = format!("secondary {}", zone.name);
+    // SOA of the served data, and when it was last confirmed with a primary
+    let mut current: Option<(Record, Instant)> = None;
+    loop {
+        if zone.removed.load(Ordering::Relaxed) {
+            storage.set_source(&source, BaseStorage::new());
+            zone.served.store(false, Ordering::Relaxed);
+            return;
+        }
+        let serial = current.as_ref().map(|(soa, _)| soa_timers(soa).0);
+        let wait = match refresh(&zone, serial).await {
+            Ok(Some((base, soa))) => {
+                if zone.is_catalog {
+                    secondaries.update_members(&zone, &base, &storage);
+                }
+                storage.set_source(&source, base);
+                zone.served.store(true, Ordering::Relaxed);
+                log::info!("{}: transferred serial {}", zone.name, soa_timers(&soa).0);
+                let refresh = soa_timers(&soa).1;
+                current = Some((soa, Instant::now()));
+                refresh
+            }
+            Ok(None) => {
+                let (soa, confirmed) = current.as_mut().expect("only checked once transferred");
+                *confirmed = Instant::now();
+                soa_timers(soa).1
+            }
+            Err(e) => {
+                log::error!("{}: refresh failed: {}", zone.name, e);
+                match &current {
+                    Some((soa, confirmed)) if confirmed.elapsed() >= soa_timers(soa).3 => {
+                        log::error!("{}: expired, no longer served", zone.name);
+                        storage.set_source(&source, BaseStorage::new());
+                        zone.served.store(false, Ordering::Relaxed);
+                        current = None;
+                        INITIAL_RETRY
+                    }
+                    Some((soa, _)) => soa_timers(soa).2,
+                    None => INITIAL_RETRY,
+                }
+            }
+        };
+
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => (),
+            _ = zone.notify.notified() => {
+                if !zone.removed.load(Ordering::Relaxed) {
+                    log::info!("{}: NOTIFY received, refreshing", zone.name);
+                }
+            }
+        }
+    }
+}
+
+/// Transfers the zone from the first primary that answers, unless it is still at `serial`
+async fn refresh(
+    zone: &Zone,
+    serial: Option<u32>,
+) -> anyhow::Result<Option<(BaseStorage, Record)>> {
+    let mut last_error = anyhow::anyhow!("no primaries");
+    for primary in zone.config.primaries.iter() {
+        let result = async {
+            if let Some(serial) = serial {
+                let messages = request(zone, primary, Type::SOA).await?;
+                let (_, soa) = records(zone, &messages)?;
+                if !crate::serial::serial_gt(soa_timers(&soa).0, serial) {
+                    return Ok(None);
+                }
+            }
+            let messages = request(zone, primary, Type::AXFR).await?;
+            let (base, soa) = records(zone, &messages)?;
+            if verify(zone, &messages, soa_timers(&soa).0)? {
+                log::debug!("{}: ZONEMD verified", zone.name);
+            }
+            Ok::<_, anyhow::Error>(Some((base, soa)))
+        }
+        .await;
+        match result {
+            Ok(result) => return Ok(result),
+            Err(e) => last_error = anyhow::anyhow!("{}: {}", primary, e),
+        }
+    }
+    Err(last_error)
+}
+
+async fn read_message(stream: &mut TcpStream) -> anyhow::Result<Vec<u8>> {
+    let read = async {
+        let len = stream.read_u16().await?;
+        let mut buf = vec![0; len as usize];
+        stream.read_exact(&mut buf).await?;
+        Ok::<_, std::io::Error>(buf)
+    };
+    Ok(tokio::time::timeout(READ_TIMEOUT, read)
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out"))??)
+}
+
+/// Sends a query of type `ty` for the zone to `primary` over TCP, returning every message of the
+/// response: one for SOA, up to the closing SOA for AXFR
+async fn request(zone: &Zone, primary: &SocketAddr, ty: Type) -> anyhow::Result<Vec<Vec<u8>>> {
+    let id: u16 = rand::random();
+    let mut query = Vec::new();
+    query.extend_from_slice(&id.to_be_bytes());
+    // No flags, one question
+    query.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    crate::record::serialize_name(zone.name.as_ref(), &mut query)?;
+    query.extend_from_slice(&(ty as u16).to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes()); // IN
+    let mut tsig = zone
+        .key
+        .as_ref()
+        .map(|key| tsig::Client::sign(key, &mut query));
+
+    let mut stream = tokio::time::timeout(READ_TIMEOUT, TcpStream::connect(primary))
+        .await
+        .map_err(|_| anyhow::anyhow!("connection timed out"))??;
+    let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(&query);
+    stream.write_all(&framed).await?;
+
+    let mut messages = Vec::new();
+    let mut soas = 0;
+    loop {
+        let msg = read_message(&mut stream).await?;
+        let (_, resp) = crate::parser::parse_response(&msg)
+            .map_err(|e| anyhow::anyhow!("malformed response: {}", e))?;
+        if resp.id != id {
+            return Err(anyhow::anyhow!("response with a different ID"));
+        }
+        if resp.rcode != 0 {
+            return Err(anyhow::anyhow!(
+                "{:?} answered with RCODE {}",
+                ty,
+                resp.rcode
+            ));
+        }
+        if let Some(tsig) = tsig.as_mut() {
+            tsig.verify(&msg, &resp)?;
+        }
+        soas += resp.answers.iter().filter(|rr| rr.ty == Type::SOA).count();
+        messages.push(msg);
+        if ty != Type::AXFR || soas >= 2 {
+            break;
+        }
+    }
+    if let Some(tsig) = tsig {
+        tsig.finish()?;
+    }
+    Ok(messages)
+}
+
+/// Checks the records of the zone in `messages`, of an AXFR at `serial`, against its ZONEMD
+/// records, see the zonemd module. Returns whether one was verified.
+fn verify(zone: &Zone, messages: &[Vec<u8>], serial: u32) -> anyhow::Result<bool> {
+    let mut rrs = Vec::new();
+    for msg in messages {
+        let (_, resp) = crate::parser::parse_raw_response(msg)
+            .map_err(|e| anyhow::anyhow!("malformed response: {}", e))?;
+        for rr in resp.answers.iter() {
+            let rr = crate::zonemd::Rr::from_raw(msg, rr)
+                .ok_or_else(|| anyhow::anyhow!("malformed {} RDATA", rr.ty))?;
+            rrs.push(rr);
+        }
+    }
+    crate::zonemd::verify(zone.name.as_ref(), &rrs, serial)
+}
+
+/// Records of the zone in `messages`, and its SOA
+fn records(zone: &Zone, messages: &[Vec<u8>]) -> anyhow::Result<(BaseStorage, Record)> {
+    let mut base = BaseStorage::new();
+    let mut soa = None;
+    let mut skipped = 0;
+    for msg in messages {
+        let (_, resp) = crate::parser::parse_response(msg)
+            .map_err(|e| anyhow::anyhow!("malformed response: {}", e))?;
+        skipped += resp.unknown;
+        for rr in resp.answers.iter() {
+            let name = rr.name.to_record_name();
+            let in_zone = name.as_ref().len() >= zone.name.as_ref().len()
+                && names_eq(
+                    &name.as_ref()[name.as_ref().len() - zone.name.as_ref().len()..],
+                    zone.name.as_ref(),
+                );
+            let inner = match crate::parser::parse_rdata(msg, rr) {
+                Ok(inner) if in_zone => inner,
+                _ => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+            let record = Record::new(inner, rr.ttl);
+            if rr.ty == Type::SOA && names_eq(name.as_ref(), zone.name.as_ref()) {
+                // The closing SOA of the transfer repeats the first one
+                if soa.is_none() {
+                    soa = Some(record.clone());
+                    base.entry(name).or_default().push(record);
+                }
+                continue;
+            }
+            let records = base.entry(name).or_default();
+            if !records.contains(&record) {
+                records.push(record);
+            }
+        }
+    }
+    if skipped > 0 {
+        log::warn!(
+            "{}: left out {} record(s) of unsupported types or outside of the zone",
+            zone.name,
+            skipped
+        );
+    }
+    let soa = soa.ok_or_else(|| anyhow::anyhow!("no SOA in the response"))?;
+    Ok((base, soa))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn soa(refresh: u32, retry: u32, expire: u32) -> Record {
+        let name = |s: &str| Name::from(crate::label::split_name(s));
+        let soa = RecordInner::SOA {
+            serial: 1,
+            mname: name("ns.example.com"),
+            rname: name("admin.example.com"),
+            refresh,
+            retry,
+            expire,
+            minimum: 60,
+        };
+        Record::new(soa, 300)
+    }
+
+    #[test]
+    fn timers_within_bounds() {
+        let secs = |soa: Record| {
+            let (_, refresh, retry, expire) = soa_timers(&soa);
+            (refresh.as_secs(), retry.as_secs(), expire.as_secs())
+        };
+        assert_eq!(secs(soa(3600, 600, 86400)), (3600, 600, 86400));
+        assert_eq!(secs(soa(1, 1, 1)), (300, 500, 800));
+        assert_eq!(
+            secs(soa(u32::MAX, u32::MAX, u32::MAX)),
+            (2419200, 1209600, 14515200)
+        );
+        // Not expiring before the first retry
+        assert_eq!(secs(soa(86400, 3600, 3600)), (86400, 3600, 90000));
+    }
+}
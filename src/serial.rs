@@ -0,0 +1,477 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::parser::Type;
+use crate::record::{Name, RecordInner};
+use crate::BaseStorage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialPolicy {
+    /// Use whatever is written in the zone
+    Manual,
+    /// Seconds since epoch of the zone file's modification time
+    Mtime,
+    /// YYYYMMDDnn, nn being bumped for each change within the same day. Zones are only bumped
+    /// when their records changed, as told by the zones served before, or after a restart by the
+    /// state kept in --serial-file.
+    Date,
+    /// The --serial-revision given, or else the zone file's modification time, unless the zone
+    /// has a newer serial written. Never goes back from the serial previously served, being bumped
+    /// past it if the zone changed.
+    Auto,
+}
+
+impl FromStr for SerialPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "manual" => Ok(Self::Manual),
+            "mtime" => Ok(Self::Mtime),
+            "date" => Ok(Self::Date),
+            "auto" => Ok(Self::Auto),
+            _ => Err(anyhow::anyhow!(
+                "Unknown serial policy {}, expected one of manual, mtime, date, auto",
+                s
+            )),
+        }
+    }
+}
+
+/// RFC 1982 serial number comparison: is `a` newer than `b`?
+pub fn serial_gt(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < (1 << 31)
+}
+
+/// Civil (year, month, day) from days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+/// Inverse of `civil_from_days`
+pub fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = if m > 2 { m - 3 } else { m + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn date_serial(now: SystemTime) -> u32 {
+    let (y, m, d) = civil_from_days((unix_secs(now) / 86400) as i64);
+    (y as u32) * 1000000 + m * 10000 + d * 100
+}
+
+/// Computes the serial to publish, given the one written in the zone (or previously served)
+pub fn next_serial(policy: SerialPolicy, prev: u32, mtime: SystemTime, now: SystemTime) -> u32 {
+    match policy {
+        SerialPolicy::Manual | SerialPolicy::Auto => prev,
+        SerialPolicy::Mtime => unix_secs(mtime) as u32,
+        SerialPolicy::Date => {
+            let base = date_serial(now);
+            if serial_gt(base, prev) {
+                base
+            } else {
+                prev.wrapping_add(1)
+            }
+        }
+    }
+}
+
+/// Serial of a zone generated by the server: seconds since epoch, so that it keeps moving forward
+/// across restarts, or past `prev` if that is newer
+pub fn generated_serial(prev: u32) -> u32 {
+    let now = unix_secs(SystemTime::now()) as u32;
+    if serial_gt(now, prev) {
+        now
+    } else {
+        prev.wrapping_add(1)
+    }
+}
+
+/// Sets the serial of `zone`, whose data was generated anew, after the one served in `previous`:
+/// kept if the rest of the zone is unchanged, bumped past it if the zone changed without a newer
+/// serial of its own.
+pub fn follow(base: &mut BaseStorage, zone: &[String], previous: &BaseStorage) {
+    let in_zone = |name: &[String]| {
+        name.len() >= zone.len() && crate::acl::names_eq(&name[name.len() - zone.len()..], zone)
+    };
+    let contents = |base: &BaseStorage| -> (Option<u32>, BaseStorage) {
+        let mut serial = None;
+        let mut contents = BaseStorage::new();
+        for (name, records) in base.iter().filter(|(name, _)| in_zone(name.as_ref())) {
+            let mut records = records.clone();
+            if name.as_ref().len() == zone.len() {
+                if let Some(i) = records.iter().position(|r| r.inner.ty() == Type::SOA) {
+                    serial = crate::journal::serial(&records.remove(i));
+                }
+            }
+            if !records.is_empty() {
+                contents.insert(name.clone(), records);
+            }
+        }
+        (serial, contents)
+    };
+    let (prev, old) = match contents(previous) {
+        (Some(prev), old) => (prev, old),
+        (None, _) => return,
+    };
+    let (current, new) = contents(base);
+    let serial = match current {
+        Some(current) if serial_gt(current, prev) => return,
+        Some(_) if old == new => prev,
+        Some(_) => generated_serial(prev),
+        None => return,
+    };
+    for record in base.get_mut(zone).into_iter().flatten() {
+        if let RecordInner::SOA { serial: s, .. } = &mut record.inner {
+            *s = serial;
+        }
+    }
+}
+
+/// The serial and digest of the records of each zone last served, kept in --serial-file
+#[derive(Default, Serialize, Deserialize)]
+pub struct State(BTreeMap<String, Served>);
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+struct Served {
+    serial: u32,
+    /// SHA-256 of the records of the zone, serial aside, in hex
+    digest: String,
+}
+
+impl State {
+    /// The state kept in `path`, empty if there is none yet
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        match std::fs::read(path) {
+            Ok(data) => serde_yaml::from_slice(&data)
+                .map_err(|e| anyhow::anyhow!("Invalid serial state {}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(anyhow::anyhow!("Failed to read {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Keeps the serials of the zones of `base` in `path`, unless they are already
+    pub fn save(path: &Path, base: &BaseStorage) -> anyhow::Result<()> {
+        let state = Self(
+            apexes(base)
+                .into_iter()
+                .filter_map(|apex| Some((apex.to_string(), served(base, apex.as_ref())?)))
+                .collect(),
+        );
+        let previous = Self::read(path).unwrap_or_default();
+        if previous.0 == state.0 {
+            return Ok(());
+        }
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_yaml::to_string(&state)?)
+            .and_then(|()| std::fs::rename(&tmp, path))
+            .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path.display(), e))
+    }
+}
+
+/// The names of `base` holding an SOA record
+fn apexes(base: &BaseStorage) -> Vec<Name> {
+    base.iter()
+        .filter(|(_, records)| records.iter().any(|r| r.inner.ty() == Type::SOA))
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// The serial of the zone of `apex` in `base`, and the digest of its records, serial aside, those
+/// of zones below it aside too
+fn served(base: &BaseStorage, apex: &[String]) -> Option<Served> {
+    let mut serial = None;
+    let mut records = BTreeMap::new();
+    for (name, rrs) in base.iter() {
+        let zone = crate::journal::zone_of(base, name.as_ref());
+        if !zone.is_some_and(|zone| crate::acl::names_eq(zone, apex)) {
+            continue;
+        }
+        let mut rrs = rrs.clone();
+        for record in rrs.iter_mut() {
+            if let RecordInner::SOA { serial: s, .. } = &mut record.inner {
+                serial = Some(*s);
+                *s = 0;
+            }
+        }
+        records.insert(name.to_string().to_ascii_lowercase(), rrs);
+    }
+    let records = serde_yaml::to_string(&records).ok()?;
+    let digest = Sha256::digest(records.as_bytes());
+    Some(Served {
+        serial: serial?,
+        digest: digest.iter().map(|b| format!("{:02x}", b)).collect(),
+    })
+}
+
+/// Rewrites every SOA serial in `base` according to `policy`.
+///
+/// `previous` is the storage being replaced (e.g. on reload), and `state` the serials kept in
+/// --serial-file, if any. Under the date and auto policies, serials are bumped past the
+/// previously served ones if the zone changed, so that secondaries notice the change.
+pub fn assign_serials(
+    base: &mut BaseStorage,
+    policy: SerialPolicy,
+    mtime: SystemTime,
+    revision: Option<u32>,
+    previous: Option<&BaseStorage>,
+    state: Option<&State>,
+) {
+    match policy {
+        SerialPolicy::Manual => (),
+        SerialPolicy::Auto => {
+            assign_auto(base, revision.unwrap_or(unix_secs(mtime) as u32), previous)
+        }
+        SerialPolicy::Mtime => {
+            for record in base.values_mut().flatten() {
+                if let RecordInner::SOA { serial, .. } = &mut record.inner {
+                    *serial = next_serial(policy, *serial, mtime, SystemTime::now());
+                }
+            }
+        }
+        SerialPolicy::Date => assign_date(base, previous, state),
+    }
+}
+
+fn assign_date(base: &mut BaseStorage, previous: Option<&BaseStorage>, state: Option<&State>) {
+    let now = SystemTime::now();
+    for apex in apexes(base) {
+        let current = match served(base, apex.as_ref()) {
+            Some(current) => current,
+            None => continue,
+        };
+        let last = previous
+            .and_then(|previous| served(previous, apex.as_ref()))
+            .or_else(|| state.and_then(|state| state.0.get(&apex.to_string()).cloned()));
+        let serial = match last {
+            // Bumped by hand past the one served
+            Some(last) if serial_gt(current.serial, last.serial) => current.serial,
+            Some(last) if last.digest == current.digest => last.serial,
+            Some(last) => next_serial(SerialPolicy::Date, last.serial, now, now),
+            None => next_serial(SerialPolicy::Date, current.serial, now, now),
+        };
+        for record in base.get_mut(&apex).into_iter().flatten() {
+            if let RecordInner::SOA { serial: s, .. } = &mut record.inner {
+                *s = serial;
+                log::debug!("SOA serial of {} set to {}", apex, serial);
+            }
+        }
+    }
+}
+
+fn assign_auto(base: &mut BaseStorage, serial: u32, previous: Option<&BaseStorage>) {
+    let mut apexes = Vec::new();
+    for (name, records) in base.iter_mut() {
+        for record in records.iter_mut() {
+            if let RecordInner::SOA {
+                serial: written, ..
+            } = &mut record.inner
+            {
+                if serial_gt(serial, *written) {
+                    *written = serial;
+                }
+                apexes.push(name.clone());
+            }
+        }
+    }
+    if let Some(previous) = previous {
+        for apex in apexes {
+            follow(base, apex.as_ref(), previous);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::record::Record;
+
+    fn name(s: &str) -> Name {
+        Name::from(crate::label::split_name(s))
+    }
+
+    /// A zone at example.com with `serial`, and www at `addr`
+    fn zone(serial: u32, addr: [u8; 4]) -> BaseStorage {
+        let soa = RecordInner::SOA {
+            serial,
+            mname: name("ns.example.com"),
+            rname: name("admin.example.com"),
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 60,
+        };
+        let mut base = BaseStorage::new();
+        base.insert(name("example.com"), vec![Record::new(soa, 300)]);
+        base.insert(
+            name("www.example.com"),
+            vec![Record::new(RecordInner::A { addr }, 300)],
+        );
+        base
+    }
+
+    fn serial_of(base: &BaseStorage) -> u32 {
+        served(base, name("example.com").as_ref()).unwrap().serial
+    }
+
+    #[test]
+    fn comparison() {
+        assert!(serial_gt(2, 1));
+        assert!(!serial_gt(1, 2));
+        assert!(!serial_gt(1, 1));
+        assert!(serial_gt(1 << 30, 0));
+    }
+
+    #[test]
+    fn comparison_wraps_around() {
+        assert!(serial_gt(0, u32::MAX));
+        assert!(serial_gt(5, u32::MAX - 5));
+        assert!(!serial_gt(u32::MAX, 0));
+        assert!(serial_gt((1 << 31) - 1, 0));
+        // Half the space apart, neither is newer, RFC 1982 Section 3.2
+        assert!(!serial_gt(1 << 31, 0));
+        assert!(!serial_gt(0, 1 << 31));
+        assert!(!serial_gt(u32::MAX, (1 << 31) - 1));
+    }
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(20742), (2026, 10, 16));
+        for days in [-719468, -1, 0, 59, 11016, 20742, 2932896] {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+    }
+
+    #[test]
+    fn date_policy() {
+        let day = UNIX_EPOCH + Duration::from_secs(20742 * 86400 + 3600);
+        let next = |prev| next_serial(SerialPolicy::Date, prev, day, day);
+        assert_eq!(next(2026101500), 2026101600);
+        assert_eq!(next(1), 2026101600);
+        // Bumped within the day
+        assert_eq!(next(2026101600), 2026101601);
+        assert_eq!(next(2026101699), 2026101700);
+        // Ahead of the date, only bumped
+        assert_eq!(next(2030010100), 2030010101);
+    }
+
+    #[test]
+    fn date_policy_wraps_around() {
+        let day = UNIX_EPOCH + Duration::from_secs(20742 * 86400);
+        // Less than half the space behind the date, so older, RFC 1982
+        assert_eq!(
+            next_serial(SerialPolicy::Date, u32::MAX, day, day),
+            2026101600
+        );
+        // Half the space ahead, so not older, and bumped
+        let ahead = 2026101600 + (1 << 31);
+        assert_eq!(next_serial(SerialPolicy::Date, ahead, day, day), ahead + 1);
+        // Past the dates that fit below 2^31, the serial wraps around to 0
+        let day = UNIX_EPOCH + Duration::from_secs(days_from_civil(2148, 1, 1) as u64 * 86400);
+        assert_eq!(next_serial(SerialPolicy::Date, u32::MAX, day, day), 0);
+    }
+
+    #[test]
+    fn other_policies() {
+        let mtime = UNIX_EPOCH + Duration::from_secs(1700000000);
+        let now = SystemTime::now();
+        assert_eq!(next_serial(SerialPolicy::Manual, 42, mtime, now), 42);
+        assert_eq!(next_serial(SerialPolicy::Mtime, 42, mtime, now), 1700000000);
+        assert!(serial_gt(generated_serial(42), 42));
+        let ahead = unix_secs(now) as u32 + 1000;
+        assert_eq!(generated_serial(ahead), ahead + 1);
+    }
+
+    fn assign_date(base: &mut BaseStorage, previous: Option<&BaseStorage>, state: Option<&State>) {
+        let mtime = SystemTime::now();
+        assign_serials(base, SerialPolicy::Date, mtime, None, previous, state);
+    }
+
+    #[test]
+    fn date_only_bumped_on_change() {
+        let today = date_serial(SystemTime::now());
+        let mut first = zone(1, [192, 0, 2, 1]);
+        assign_date(&mut first, None, None);
+        assert_eq!(serial_of(&first), today);
+
+        // Reloaded unchanged
+        let mut same = zone(1, [192, 0, 2, 1]);
+        assign_date(&mut same, Some(&first), None);
+        assert_eq!(serial_of(&same), today);
+
+        let mut changed = zone(1, [192, 0, 2, 2]);
+        assign_date(&mut changed, Some(&same), None);
+        assert_eq!(serial_of(&changed), today + 1);
+
+        // Bumped by hand past the one served
+        let mut manual = zone(today + 50, [192, 0, 2, 2]);
+        assign_date(&mut manual, Some(&changed), None);
+        assert_eq!(serial_of(&manual), today + 50);
+    }
+
+    #[test]
+    fn date_kept_across_restarts() {
+        let path = std::env::temp_dir().join(format!("serial-test-{}.yml", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        assert!(State::read(&path).unwrap().0.is_empty());
+
+        let today = date_serial(SystemTime::now());
+        let mut served = zone(1, [192, 0, 2, 1]);
+        assign_date(&mut served, None, Some(&State::read(&path).unwrap()));
+        let mut changed = zone(1, [192, 0, 2, 2]);
+        assign_date(&mut changed, Some(&served), None);
+        assert_eq!(serial_of(&changed), today + 1);
+        State::save(&path, &changed).unwrap();
+
+        // After a restart, unchanged then changed
+        let state = State::read(&path).unwrap();
+        let mut same = zone(1, [192, 0, 2, 2]);
+        assign_date(&mut same, None, Some(&state));
+        assert_eq!(serial_of(&same), today + 1);
+        let mut changed = zone(1, [192, 0, 2, 3]);
+        assign_date(&mut changed, None, Some(&state));
+        assert_eq!(serial_of(&changed), today + 2);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn digest_ignores_serial() {
+        let apex = name("example.com");
+        let a = served(&zone(1, [192, 0, 2, 1]), apex.as_ref()).unwrap();
+        let b = served(&zone(2, [192, 0, 2, 1]), apex.as_ref()).unwrap();
+        let c = served(&zone(1, [192, 0, 2, 2]), apex.as_ref()).unwrap();
+        assert_eq!(a.digest, b.digest);
+        assert_ne!(a.digest, c.digest);
+    }
+}
@@ -0,0 +1,1074 @@
+//! Online DNSSEC signing (RFC 4035 Section 3) of the primary zones configured with `keys`, files of
+//! Ed25519 keys (algorithm 15, RFC 8080) in PKCS#8 PEM as written by ed25519_keygen:
+//!
+//! ```yaml
+//! example.com:
+//!   type: primary
+//!   keys: [ksk.pem, zsk.pem]
+//! ```
+//!
+//! The first key is the key signing key, with the SEP flag, which signs the DNSKEY RRset served at
+//! the apex, and the others are zone signing keys, which sign every other RRset. A single key signs
+//! them all. The DS record of the key signing key, for the parent zone, is logged on startup. With
+//! `key-store` instead, the keys, their roles and their rollovers are those of a key store, see the
+//! keystore module. Either may also be keys held by a remote signing service, given by files
+//! describing them, see the remote_key module. Keys in PEM may be encrypted, see the keyfile
+//! module, with the passphrase given by --key-passphrase-file or DNS_KEY_PASSPHRASE, or else asked
+//! for on the terminal on startup.
+//!
+//! The apex also holds the CDS and CDNSKEY RRsets (RFC 7344) of the key signing keys published and
+//! not retired, signed by them, so that parent zones scanning for them keep their DS records up to
+//! date through rollovers. The keys of the other signers of zones signed by several providers
+//! are published along with ours, see the multi_signer module.
+//!
+//! RRsets are signed as they are answered to clients setting DO, with signatures valid from an
+//! hour ago to a week from now. Signatures are cached along with a digest of the RRset they cover,
+//! and made again once the RRset or the signing keys change, or two days before they expire. The
+//! RRsets of the zone, its NSEC or NSEC3 records and negative answers included, are signed ahead
+//! at startup and every hour after, see the expiry module, so that only data changing at runtime
+//! is signed on the first query for it. How many signatures are made, how long that takes, and
+//! how often they come from the cache, are exported as metrics, see the metrics module. The
+//! nonexistence of names and types is proven by the NSEC chain of the zone, its names in canonical
+//! order, indexed along with the zone data as it changes, see the zone module: negative answers
+//! carry the NSEC record of the name, or those covering it and the wildcard at its closest
+//! encloser. Delegations are insecure, proven by the NSEC record of the delegation point. Zone
+//! transfers are not signed.
+//!
+//! With `nsec3`, nonexistence is proven by NSEC3 records instead (RFC 5155), owned by the hashes
+//! of the names so that they cannot be listed by walking the chain, served at the apex with their
+//! NSEC3PARAM record. Negative answers then carry the proof of the closest encloser of the name.
+//! As RFC 9276 recommends, names are hashed without salt nor additional iterations unless
+//! configured, and more than 100 iterations are refused. With `opt-out`, the delegations without
+//! DS records are left out of the chain, covered by records marked so, which spares the zones
+//! with many of them:
+//!
+//! ```yaml
+//! example.com:
+//!   type: primary
+//!   keys: [ksk.pem, zsk.pem]
+//!   nsec3: {salt: "", iterations: 0, opt-out: true}
+//! ```
+//!
+//! With `compact-denial: true` instead, the zone has no chain: negative answers carry a single
+//! NSEC record made for them (RFC 9824), owned by the name queried and covering nothing else, its
+//! next name being `\000.` prepended to it. Names which do not exist are answered NODATA to the
+//! clients setting DO, their NSEC record listing the NXNAME type, and NXDOMAIN to the others. The
+//! names of the zone cannot be listed by walking the chain, and nothing needs indexing as it
+//! changes, at the cost of signing negative answers as they are served.
+//!
+//! Zones may be signed offline instead, with the `sign` subcommand, which adds the DNSKEY RRset,
+//! the NSEC chain of the names of the zone, or the NSEC3 one with --nsec3, and the signatures of
+//! its RRsets, valid for 30 days unless told otherwise, and the ZONEMD record of the whole, see
+//! the zonemd module. The RRSIG records of zones signed so are served along with the RRsets they
+//! cover to the clients setting DO, and their NSEC or NSEC3 records as proofs alike.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::Instant;
+
+use anyhow::Context;
+use ed25519::pkcs8::DecodePrivateKey;
+use ed25519_dalek::Signer as _;
+use sha2::{Digest, Sha256};
+
+use crate::config::Nsec3Config;
+use crate::keystore::{Entry, Role, State};
+use crate::label::{escape, unescape};
+use crate::load::Format;
+use crate::parser::{Class, Type};
+use crate::record::{serialize_name, Name, Record, RecordInner, TtlBounds};
+use crate::remote_key::RemoteKey;
+use crate::zone::Link;
+use crate::{Args, BaseStorage, RecordStorage};
+
+/// Ed25519, RFC 8080
+const ED25519: u8 = 15;
+/// Flags of DNSKEY records, RFC 4034 Section 2.1.1
+const KSK: u16 = 257;
+const ZSK: u16 = 256;
+const SEP: u16 = 1;
+const DIGEST_SHA256: u8 = 2;
+/// Hash algorithm of NSEC3 records, RFC 5155 Section 11
+const NSEC3_SHA1: u8 = 1;
+/// The records the signer makes, replaced when signing zones offline
+const DNSSEC_TYPES: [Type; 7] = [
+    Type::DNSKEY,
+    Type::RRSIG,
+    Type::NSEC,
+    Type::NSEC3,
+    Type::NSEC3PARAM,
+    Type::CDS,
+    Type::CDNSKEY,
+];
+
+/// How long before being made signatures are valid, for clients whose clock is late
+const INCEPTION_OFFSET: u32 = 3600;
+const VALIDITY: u32 = 7 * 86400;
+/// Cached signatures are made again once they expire within this many seconds
+const RESIGN_BEFORE: u32 = 2 * 86400;
+/// RRsets whose signatures are cached, the cache being emptied once full
+const CACHE_CAPACITY: usize = 100_000;
+
+/// The private part of a key
+enum Private {
+    Pem(ed25519_dalek::Keypair),
+    Remote(RemoteKey),
+}
+
+struct Key {
+    private: Private,
+    dnskey: RecordInner,
+    tag: u16,
+    entry: Entry,
+}
+
+impl Key {
+    /// The key of the file at `path`: in PKCS#8 PEM, encrypted with `passphrase` or one then asked
+    /// for, or describing a remote key
+    fn read(path: &Path, entry: Entry, passphrase: &mut Option<String>) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let private = match text.trim_start().starts_with("-----BEGIN") {
+            true => {
+                let bytes = decode(&text, path, passphrase)
+                    .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+                let secret = ed25519_dalek::SecretKey::from_bytes(&bytes.secret_key)
+                    .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+                let public = ed25519_dalek::PublicKey::from(&secret);
+                Private::Pem(ed25519_dalek::Keypair { secret, public })
+            }
+            false => Private::Remote(
+                RemoteKey::parse(&text).with_context(|| format!("{}", path.display()))?,
+            ),
+        };
+        let public = match &private {
+            Private::Pem(keypair) => keypair.public,
+            Private::Remote(remote) => *remote.public(),
+        };
+        let dnskey = dnskey(public.as_bytes(), entry.role);
+        let tag = crate::dnssec::key_tag(&dnskey.serialize()?);
+        Ok(Self {
+            private,
+            dnskey,
+            tag,
+            entry,
+        })
+    }
+
+    fn sign(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        match &self.private {
+            Private::Pem(keypair) => Ok(keypair.sign(data).to_bytes().to_vec()),
+            Private::Remote(remote) => remote.sign(data),
+        }
+    }
+}
+
+/// The Ed25519 key of `pem`, the file at `path`, decrypted if encrypted with `passphrase`, or one
+/// then asked for and kept there for the next keys
+fn decode(
+    pem: &str,
+    path: &Path,
+    passphrase: &mut Option<String>,
+) -> anyhow::Result<ed25519::pkcs8::KeypairBytes> {
+    let (label, document) = pkcs8::SecretDocument::from_pem(pem)?;
+    if label != "ENCRYPTED PRIVATE KEY" {
+        return Ok(ed25519::pkcs8::KeypairBytes::from_pkcs8_pem(pem)?);
+    }
+    let passphrase = match passphrase {
+        Some(passphrase) => passphrase,
+        None => passphrase.insert(crate::keyfile::prompt(&format!(
+            "Passphrase of {}: ",
+            path.display()
+        ))?),
+    };
+    let der = pkcs8::EncryptedPrivateKeyInfo::try_from(document.as_bytes())?
+        .decrypt(passphrase.as_bytes())
+        .map_err(|_| anyhow::anyhow!("cannot be decrypted, wrong passphrase?"))?;
+    Ok(ed25519::pkcs8::KeypairBytes::from_pkcs8_der(
+        der.as_bytes(),
+    )?)
+}
+
+/// The DNSKEY record of the Ed25519 public key of a key of `role`
+pub fn dnskey(public_key: &[u8], role: Role) -> RecordInner {
+    RecordInner::DNSKEY {
+        flags: match role {
+            Role::Ksk => KSK,
+            Role::Zsk => ZSK,
+        },
+        protocol: 3,
+        algorithm: ED25519,
+        public_key: public_key.to_vec(),
+    }
+}
+
+/// An RRset as its signatures cover it, RFC 4034 Section 3.1.8.1
+struct Rrset {
+    owner: Vec<String>,
+    ty: Type,
+    ttl: u32,
+    labels: u8,
+    /// Its records in canonical form and order, each with its owner, type, class and TTL
+    data: Vec<u8>,
+}
+
+impl Rrset {
+    fn new(
+        owner: &[String],
+        records: &[&Record],
+        class: Class,
+        ttl_bounds: &TtlBounds,
+    ) -> Option<Self> {
+        let ty = records.first()?.inner.ty();
+        let owner = lowercase(owner);
+        let ttl = records
+            .iter()
+            .map(|record| ttl_bounds.clamp(record.ttl))
+            .min()
+            .unwrap_or_default();
+        let mut rdatas: Vec<Vec<u8>> = records
+            .iter()
+            .filter_map(|record| canonical(&record.inner).serialize().ok())
+            .collect();
+        rdatas.sort();
+        rdatas.dedup();
+        let mut data = Vec::new();
+        for rdata in rdatas.iter() {
+            data.extend(wire(&owner));
+            data.extend((ty as u16).to_be_bytes());
+            data.extend(u16::from(class).to_be_bytes());
+            data.extend(ttl.to_be_bytes());
+            data.extend((rdata.len() as u16).to_be_bytes());
+            data.extend(rdata);
+        }
+        // A leading wildcard label is not counted, RFC 4034 Section 3.1.3
+        let labels = owner.len() - owner.first().is_some_and(|label| label == "*") as usize;
+        Some(Self {
+            owner,
+            ty,
+            ttl,
+            labels: labels as u8,
+            data,
+        })
+    }
+}
+
+/// The signatures served for an RRset
+struct Cached {
+    /// Of the RRset as signed, so that changes to it are noticed
+    digest: Vec<u8>,
+    /// Of the keys which signed it
+    tags: Vec<u16>,
+    expiration: u32,
+    signatures: Vec<Record>,
+}
+
+/// Counters of the signatures served, for --metrics
+#[derive(Default)]
+struct Counters {
+    made: AtomicU64,
+    nanos: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Signatures served by a signer, for --metrics
+pub struct Stats {
+    /// Signatures made, the zone presigned at startup included
+    pub made: u64,
+    /// Time spent making them
+    pub seconds: f64,
+    /// RRsets whose signatures were served from the cache
+    pub hits: u64,
+    /// Those signed as they were served
+    pub misses: u64,
+}
+
+pub struct Signer {
+    zone: Vec<String>,
+    /// Swapped as the key store changes, see the keystore module
+    keys: RwLock<Vec<Key>>,
+    /// Denying existence with NSEC3 records of these parameters rather than NSEC ones
+    nsec3: Option<Nsec3Config>,
+    /// Denying existence with compact answers, see `compact`
+    compact: bool,
+    /// Of the encrypted keys, for when the key store changes
+    passphrase: Option<String>,
+    /// DNSKEY records of the other signers of the zone, see the multi_signer module
+    imported: RwLock<Vec<RecordInner>>,
+    /// Signatures served, by owner and type
+    cache: Mutex<HashMap<(Vec<String>, Type), Cached>>,
+    counters: Counters,
+}
+
+impl Signer {
+    /// The signer of the keys of `paths`, the first one the key signing key, active for good,
+    /// those encrypted with `passphrase`
+    pub fn read(
+        zone: &Name,
+        paths: &[PathBuf],
+        nsec3: Option<Nsec3Config>,
+        compact: bool,
+        mut passphrase: Option<String>,
+    ) -> anyhow::Result<Self> {
+        let keys = paths
+            .iter()
+            .enumerate()
+            .map(|(i, path)| {
+                let role = if i == 0 { Role::Ksk } else { Role::Zsk };
+                Key::read(path, Entry::active(path.clone(), role), &mut passphrase)
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Self::new(zone, keys, nsec3, compact, passphrase)
+    }
+
+    /// The signer of the keys of the store at `dir`, those encrypted with `passphrase`
+    pub fn open(
+        zone: &Name,
+        dir: &Path,
+        nsec3: Option<Nsec3Config>,
+        compact: bool,
+        mut passphrase: Option<String>,
+    ) -> anyhow::Result<Self> {
+        let keys = read_store(dir, &mut passphrase)?;
+        Self::new(zone, keys, nsec3, compact, passphrase)
+    }
+
+    fn new(
+        zone: &Name,
+        keys: Vec<Key>,
+        nsec3: Option<Nsec3Config>,
+        compact: bool,
+        passphrase: Option<String>,
+    ) -> anyhow::Result<Self> {
+        if let Some(nsec3) = &nsec3 {
+            nsec3.check().with_context(|| format!("Zone {}", zone))?;
+            if compact {
+                return Err(anyhow::anyhow!(
+                    "Zone {}: nsec3 and compact-denial are exclusive",
+                    zone
+                ));
+            }
+        }
+        Ok(Self {
+            zone: lowercase(zone.as_ref()),
+            keys: RwLock::new(keys),
+            nsec3,
+            compact,
+            passphrase,
+            imported: RwLock::new(Vec::new()),
+            cache: Mutex::new(HashMap::new()),
+            counters: Counters::default(),
+        })
+    }
+
+    /// Reads the store at `dir` again
+    pub fn reload(&self, dir: &Path) -> anyhow::Result<()> {
+        *self.keys.write().unwrap() = read_store(dir, &mut self.passphrase.clone())?;
+        self.cache.lock().unwrap().clear();
+        Ok(())
+    }
+
+    /// Publishes `keys`, the DNSKEY records of the other signers of the zone, in place of those
+    /// imported before
+    pub fn import(&self, keys: Vec<RecordInner>) {
+        *self.imported.write().unwrap() = keys;
+    }
+
+    /// Our DNSKEY records published, for the other signers of the zone to import
+    pub fn export(&self) -> Vec<String> {
+        let now = crate::dnssec::now();
+        let keys = self.keys.read().unwrap();
+        keys.iter()
+            .filter(|key| key.entry.state(now).is_published())
+            .map(|key| {
+                let dnskey = key.dnskey.rdata_text();
+                format!("{}. IN DNSKEY {}", self.zone.join("."), dnskey)
+            })
+            .collect()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Stats {
+            made: load(&self.counters.made),
+            seconds: load(&self.counters.nanos) as f64 / 1e9,
+            hits: load(&self.counters.hits),
+            misses: load(&self.counters.misses),
+        }
+    }
+
+    /// The tag, role and current state of each key
+    pub fn states(&self) -> Vec<(u16, Role, State)> {
+        let now = crate::dnssec::now();
+        let keys = self.keys.read().unwrap();
+        keys.iter()
+            .map(|key| (key.tag, key.entry.role, key.entry.state(now)))
+            .collect()
+    }
+
+    /// The tag of each key, with its DS record if a key signing key
+    pub fn describe(&self) -> Vec<(u16, Option<String>)> {
+        let keys = self.keys.read().unwrap();
+        keys.iter()
+            .map(|key| {
+                (
+                    key.tag,
+                    (key.entry.role == Role::Ksk).then(|| self.ds_of(&key.dnskey)),
+                )
+            })
+            .collect()
+    }
+
+    /// The DS records of the key signing keys published or to be, for the parent zone
+    pub fn ds(&self) -> Vec<String> {
+        let now = crate::dnssec::now();
+        let keys = self.keys.read().unwrap();
+        keys.iter()
+            .filter(|key| {
+                key.entry.role == Role::Ksk
+                    && [State::Scheduled, State::Published, State::Active]
+                        .contains(&key.entry.state(now))
+            })
+            .map(|key| self.ds_of(&key.dnskey))
+            .collect()
+    }
+
+    /// The DS record of `dnskey`, with a SHA-256 digest (RFC 4509)
+    fn ds_of(&self, dnskey: &RecordInner) -> String {
+        format!(
+            "{}. IN DS {}",
+            self.zone.join("."),
+            self.cds_of(dnskey).rdata_text()
+        )
+    }
+
+    /// The CDS record of `dnskey`, with the RDATA of its DS record
+    fn cds_of(&self, dnskey: &RecordInner) -> RecordInner {
+        let rdata = dnskey.serialize().expect("DNSKEY serializes");
+        let mut data = wire(&self.zone);
+        data.extend(&rdata);
+        RecordInner::CDS {
+            key_tag: crate::dnssec::key_tag(&rdata),
+            algorithm: match dnskey {
+                RecordInner::DNSKEY { algorithm, .. } => *algorithm,
+                _ => ED25519,
+            },
+            digest_type: DIGEST_SHA256,
+            digest: Sha256::digest(&data).to_vec(),
+        }
+    }
+
+    /// The CDS and CDNSKEY RRsets of the apex (RFC 7344 Section 4), of the key signing keys
+    /// published and not retired, and those of the other signers: the DS records the parent zone
+    /// should have as of now, which follow the rollovers of the key store
+    fn cds(&self, ty: Type, ttl: u32) -> Vec<Record> {
+        let now = crate::dnssec::now();
+        let keys = self.keys.read().unwrap();
+        let imported = self.imported.read().unwrap();
+        let ours = keys
+            .iter()
+            .filter(|key| {
+                key.entry.role == Role::Ksk
+                    && [State::Published, State::Active].contains(&key.entry.state(now))
+            })
+            .map(|key| &key.dnskey);
+        let theirs = imported.iter().filter(
+            |dnskey| matches!(dnskey, RecordInner::DNSKEY { flags, .. } if flags & SEP != 0),
+        );
+        ours.chain(theirs)
+            .map(|dnskey| match (ty, dnskey) {
+                (
+                    Type::CDNSKEY,
+                    RecordInner::DNSKEY {
+                        flags,
+                        protocol,
+                        algorithm,
+                        public_key,
+                    },
+                ) => RecordInner::CDNSKEY {
+                    flags: *flags,
+                    protocol: *protocol,
+                    algorithm: *algorithm,
+                    public_key: public_key.clone(),
+                },
+                _ => self.cds_of(dnskey),
+            })
+            .map(|inner| Record::new(inner, ttl))
+            .collect()
+    }
+
+    /// The DNSKEY RRset of the apex, of the keys published and the zone signing keys of the other
+    /// signers
+    pub fn dnskeys(&self, ttl: u32) -> Vec<Record> {
+        let now = crate::dnssec::now();
+        let keys = self.keys.read().unwrap();
+        let imported = self.imported.read().unwrap();
+        let ours = keys
+            .iter()
+            .filter(|key| key.entry.state(now).is_published())
+            .map(|key| &key.dnskey);
+        let theirs = imported.iter().filter(
+            |dnskey| matches!(dnskey, RecordInner::DNSKEY { flags, .. } if flags & SEP == 0),
+        );
+        ours.chain(theirs)
+            .map(|dnskey| Record::new(dnskey.clone(), ttl))
+            .collect()
+    }
+
+    /// The RRset of type `ty` the signer adds at the apex: its DNSKEY, CDS and CDNSKEY records,
+    /// and the NSEC3PARAM record when denying with NSEC3 ones
+    pub fn apex(&self, ty: Type, ttl: u32) -> Vec<Record> {
+        match (ty, &self.nsec3) {
+            (Type::DNSKEY, _) => self.dnskeys(ttl),
+            (Type::CDS | Type::CDNSKEY, _) => self.cds(ty, ttl),
+            (Type::NSEC3PARAM, Some(nsec3)) => {
+                let param = RecordInner::NSEC3PARAM {
+                    hash_algorithm: NSEC3_SHA1,
+                    flags: 0,
+                    iterations: nsec3.iterations,
+                    salt: nsec3.salt.clone(),
+                };
+                vec![Record::new(param, ttl)]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// The signatures of `records`, the RRset at `owner`, with the TTLs they are served with:
+    /// those cached for it unless it changed since, or they are about to expire
+    pub fn sign(
+        &self,
+        owner: &[String],
+        records: &[&Record],
+        class: Class,
+        ttl_bounds: &TtlBounds,
+    ) -> Vec<Record> {
+        let (signatures, hit) = self.cached(owner, records, class, ttl_bounds);
+        let counter = match hit {
+            true => &self.counters.hits,
+            false => &self.counters.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        signatures
+    }
+
+    /// The signatures `sign` serves, and whether they were cached
+    fn cached(
+        &self,
+        owner: &[String],
+        records: &[&Record],
+        class: Class,
+        ttl_bounds: &TtlBounds,
+    ) -> (Vec<Record>, bool) {
+        let Some(rrset) = Rrset::new(owner, records, class, ttl_bounds) else {
+            return (Vec::new(), true);
+        };
+        let now = crate::dnssec::now();
+        let all = self.keys.read().unwrap();
+        let keys = signing_keys(&all, rrset.ty, now);
+        let tags: Vec<u16> = keys.iter().map(|key| key.tag).collect();
+        let digest = Sha256::digest(&rrset.data).to_vec();
+        let id = (rrset.owner.clone(), rrset.ty);
+        // Signatures due to be made again, served meanwhile if remote keys fail to
+        let mut current = None;
+        if let Some(cached) = self.cache.lock().unwrap().get(&id) {
+            if cached.digest == digest && cached.tags == tags {
+                if crate::serial::serial_gt(cached.expiration, now.wrapping_add(RESIGN_BEFORE)) {
+                    return (cached.signatures.clone(), true);
+                }
+                if crate::serial::serial_gt(cached.expiration, now) {
+                    current = Some(cached.signatures.clone());
+                }
+            }
+        }
+        let signatures = match self.make(&rrset, &keys, now, VALIDITY) {
+            Ok(signatures) => signatures,
+            Err(e) => {
+                log::error!(
+                    "Signing {:?} at {}: {:#}",
+                    rrset.ty,
+                    rrset.owner.join("."),
+                    e
+                );
+                return (current.unwrap_or_default(), false);
+            }
+        };
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= CACHE_CAPACITY {
+            cache.clear();
+        }
+        cache.insert(
+            id,
+            Cached {
+                digest,
+                tags,
+                expiration: now.wrapping_add(VALIDITY),
+                signatures: signatures.clone(),
+            },
+        );
+        (signatures, false)
+    }
+
+    /// The signatures of `records`, the RRset at `owner`, valid for `validity` seconds
+    fn signatures(
+        &self,
+        owner: &[String],
+        records: &[&Record],
+        class: Class,
+        ttl_bounds: &TtlBounds,
+        validity: u32,
+    ) -> anyhow::Result<Vec<Record>> {
+        let Some(rrset) = Rrset::new(owner, records, class, ttl_bounds) else {
+            return Ok(Vec::new());
+        };
+        let now = crate::dnssec::now();
+        let all = self.keys.read().unwrap();
+        self.make(&rrset, &signing_keys(&all, rrset.ty, now), now, validity)
+    }
+
+    /// The signatures of `rrset` by `keys`, made at `now`
+    fn make(
+        &self,
+        rrset: &Rrset,
+        keys: &[&Key],
+        now: u32,
+        validity: u32,
+    ) -> anyhow::Result<Vec<Record>> {
+        let start = Instant::now();
+        let signatures = keys
+            .iter()
+            .map(|key| {
+                let mut rrsig = RecordInner::RRSIG {
+                    type_covered: rrset.ty,
+                    algorithm: ED25519,
+                    labels: rrset.labels,
+                    original_ttl: rrset.ttl,
+                    expiration: now.wrapping_add(validity),
+                    inception: now.wrapping_sub(INCEPTION_OFFSET),
+                    key_tag: key.tag,
+                    signer: Name::from(self.zone.clone()),
+                    signature: Vec::new(),
+                };
+                // RFC 4034 Section 3.1.8.1
+                let mut data = rrsig.serialize().expect("RRSIG serializes");
+                data.extend(&rrset.data);
+                if let RecordInner::RRSIG { signature, .. } = &mut rrsig {
+                    *signature = key
+                        .sign(&data)
+                        .with_context(|| format!("Key {} failed to sign", key.tag))?;
+                }
+                Ok(Record::new(rrsig, rrset.ttl))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let nanos = start.elapsed().as_nanos() as u64;
+        self.counters
+            .made
+            .fetch_add(keys.len() as u64, Ordering::Relaxed);
+        self.counters.nanos.fetch_add(nanos, Ordering::Relaxed);
+        Ok(signatures)
+    }
+
+    /// Signs ahead the RRsets of the zone in `storage` as queries are answered, with
+    /// `ttl_bounds`: its authoritative RRsets, those added at its apex, its NSEC or NSEC3
+    /// records and the SOA record of its negative answers. Those whose cached signatures are
+    /// still good are left alone, and the signatures of the RRsets the zone no longer holds are
+    /// dropped from the cache. Returns how many RRsets were signed.
+    pub fn presign(&self, storage: &RecordStorage, ttl_bounds: &TtlBounds) -> usize {
+        let Some(zone) = storage
+            .zones
+            .iter()
+            .find(|zone| crate::acl::names_eq(zone.origin.as_ref(), &self.zone))
+        else {
+            return 0;
+        };
+        let origin: &[String] = zone.origin.as_ref();
+        let negative_soa = zone.negative_soa();
+        let mut rrsets: Vec<(Name, Vec<Record>)> = self
+            .authoritative(&storage.base)
+            .into_iter()
+            .map(|(name, records)| (name.clone(), records.into_iter().cloned().collect()))
+            .collect();
+        for ty in [Type::DNSKEY, Type::CDS, Type::CDNSKEY, Type::NSEC3PARAM] {
+            rrsets.push((zone.origin.clone(), self.apex(ty, zone.soa.ttl)));
+        }
+        rrsets.push((zone.origin.clone(), vec![negative_soa.clone()]));
+        for link in storage.zones.chain(origin) {
+            let records = storage.query_all(link.name().as_ref());
+            let (owner, record) = self.link(&link, records, negative_soa.ttl);
+            rrsets.push((owner, vec![record]));
+        }
+        let mut signed = 0;
+        let mut held = HashSet::new();
+        for (owner, records) in rrsets.iter().filter(|(_, records)| !records.is_empty()) {
+            let records: Vec<&Record> = records.iter().collect();
+            let (_, hit) = self.cached(owner.as_ref(), &records, Class::IN, ttl_bounds);
+            signed += !hit as usize;
+            held.insert((lowercase(owner.as_ref()), records[0].inner.ty()));
+        }
+        self.cache.lock().unwrap().retain(|id, _| held.contains(id));
+        signed
+    }
+
+    /// The earliest expiration of the signatures cached, in seconds since the epoch
+    pub fn earliest_expiration(&self) -> Option<u32> {
+        let now = crate::dnssec::now();
+        let cache = self.cache.lock().unwrap();
+        cache
+            .values()
+            .flat_map(|cached| cached.signatures.iter())
+            .filter_map(|rrsig| match rrsig.inner {
+                RecordInner::RRSIG { expiration, .. } => Some(expiration),
+                _ => None,
+            })
+            .min_by_key(|expiration| expiration.wrapping_sub(now) as i32)
+    }
+
+    /// The RRsets of the zone in `base` which it signs, by owner: not those of the names below
+    /// its delegations, which are glue, nor the NS RRsets of the delegations, which belong to the
+    /// child zones
+    fn authoritative<'a>(&self, base: &'a BaseStorage) -> Vec<(&'a Name, Vec<&'a Record>)> {
+        let names: Vec<(&Name, Vec<String>)> = base
+            .keys()
+            .map(|name| (name, lowercase(name.as_ref())))
+            .filter(|(_, segs)| segs.ends_with(&self.zone))
+            .collect();
+        let cuts: Vec<&Vec<String>> = names
+            .iter()
+            .filter(|(name, segs)| {
+                *segs != self.zone && base[*name].iter().any(|r| r.inner.ty() == Type::NS)
+            })
+            .map(|(_, segs)| segs)
+            .collect();
+        let mut rrsets = Vec::new();
+        for (name, segs) in names.iter() {
+            if cuts
+                .iter()
+                .any(|cut| segs.len() > cut.len() && segs.ends_with(cut))
+            {
+                continue;
+            }
+            let delegation = cuts.contains(&segs);
+            let records = &base[*name];
+            let mut types: Vec<Type> = records.iter().map(|r| r.inner.ty()).collect();
+            types.sort_by_key(|ty| *ty as u16);
+            types.dedup();
+            for ty in types {
+                if (delegation && ty == Type::NS) || ty == Type::RRSIG {
+                    continue;
+                }
+                let rrset = records.iter().filter(|r| r.inner.ty() == ty).collect();
+                rrsets.push((*name, rrset));
+            }
+        }
+        rrsets
+    }
+
+    /// `zone`, the records of the zone of the signer, signed: with the DNSKEY RRset at its apex,
+    /// the NSEC or NSEC3 chain of its names, the signatures of its authoritative RRsets, valid
+    /// for `validity` seconds, and its ZONEMD record. The DNSSEC and ZONEMD records it had are
+    /// replaced.
+    pub fn sign_zone(&self, zone: &BaseStorage, validity: u32) -> anyhow::Result<BaseStorage> {
+        let mut signed = BaseStorage::new();
+        for (name, records) in zone {
+            let records: Vec<Record> = records
+                .iter()
+                .filter(|record| {
+                    let ty = record.inner.ty();
+                    record.enabled && !DNSSEC_TYPES.contains(&ty) && ty != Type::ZONEMD
+                })
+                .cloned()
+                .collect();
+            let field = records
+                .iter()
+                .find_map(|r| Some((r, crate::validate::served_in_part(r)?)));
+            if let Some((record, field)) = field {
+                return Err(anyhow::anyhow!(
+                    "{}: the {:?} records have `{}`, which zones signed offline cannot have",
+                    name,
+                    record.inner.ty(),
+                    field
+                ));
+            }
+            if !records.is_empty() {
+                signed.insert(name.clone(), records);
+            }
+        }
+        let apex = signed
+            .keys()
+            .find(|name| crate::acl::names_eq(name.as_ref(), &self.zone))
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("no records at the apex of {}", self.zone.join(".")))?;
+        let soa = match signed[&apex].iter().find(|r| r.inner.ty() == Type::SOA) {
+            Some(soa) => soa.clone(),
+            _ => return Err(anyhow::anyhow!("no SOA record at {}", apex)),
+        };
+        let negative_ttl = match soa.inner {
+            RecordInner::SOA { minimum, .. } => soa.ttl.min(minimum),
+            _ => unreachable!(),
+        };
+        let ttl = signed[&apex][0].ttl;
+        let mut added = self.dnskeys(ttl);
+        added.extend(self.cds(Type::CDS, ttl));
+        added.extend(self.cds(Type::CDNSKEY, ttl));
+        added.extend(self.apex(Type::NSEC3PARAM, negative_ttl));
+        // Digested once everything else is signed, listed in the NSEC record of the apex
+        // meanwhile
+        added.push(crate::zonemd::record(&self.zone, &[], &soa));
+        signed.get_mut(&apex).unwrap().extend(added);
+
+        // The chain, as it is indexed for zones signed online
+        let zones = crate::zone::Zones::index(&signed, &[(apex.clone(), self.nsec3.clone())]);
+        let links: Vec<(Name, Record)> = zones
+            .chain(apex.as_ref())
+            .iter()
+            .map(|link| {
+                self.link(
+                    link,
+                    signed.get(link.name()).into_iter().flatten(),
+                    negative_ttl,
+                )
+            })
+            .collect();
+        for (owner, record) in links {
+            signed.entry(owner).or_default().push(record);
+        }
+
+        let bounds = TtlBounds {
+            min: 0,
+            max: u32::MAX,
+        };
+        let sigs: Vec<(Name, Vec<Record>)> = self
+            .authoritative(&signed)
+            .into_iter()
+            .filter(|(_, rrset)| rrset[0].inner.ty() != Type::ZONEMD)
+            .map(|(name, rrset)| {
+                let sigs = self.signatures(name.as_ref(), &rrset, Class::IN, &bounds, validity)?;
+                Ok((name.clone(), sigs))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        for (name, sigs) in sigs {
+            signed.get_mut(&name).unwrap().extend(sigs);
+        }
+
+        let rrs: Vec<crate::zonemd::Rr> = signed
+            .iter()
+            .flat_map(|(name, records)| {
+                records
+                    .iter()
+                    .map(|record| crate::zonemd::Rr::new(name.as_ref(), record, &bounds))
+            })
+            .collect();
+        let zonemd = crate::zonemd::record(&self.zone, &rrs, &soa);
+        let records = signed.get_mut(&apex).unwrap();
+        records.retain(|record| record.inner.ty() != Type::ZONEMD);
+        let sigs = self.signatures(apex.as_ref(), &[&zonemd], Class::IN, &bounds, validity)?;
+        records.push(zonemd);
+        records.extend(sigs);
+        Ok(signed)
+    }
+
+    /// The record of `link`, of the chain of the zone, with its owner: the NSEC or NSEC3 record
+    /// of a name holding `records`, see `Zones::denial`
+    pub fn link<'a>(
+        &self,
+        link: &Link,
+        records: impl Iterator<Item = &'a Record>,
+        ttl: u32,
+    ) -> (Name, Record) {
+        let types = self.types(link.name().as_ref(), records);
+        match link {
+            Link::Nsec(owner, next) => {
+                let nsec = RecordInner::NSEC {
+                    next: Name::from(lowercase(next.as_ref())),
+                    types,
+                };
+                ((*owner).clone(), Record::new(nsec, ttl))
+            }
+            Link::Nsec3 {
+                hash, next, params, ..
+            } => {
+                let nsec3 = RecordInner::NSEC3 {
+                    hash_algorithm: NSEC3_SHA1,
+                    flags: params.opt_out as u8,
+                    iterations: params.iterations,
+                    salt: params.salt.clone(),
+                    next: next.to_vec(),
+                    types,
+                };
+                let owner = std::iter::once(crate::dnssec::to_base32hex(hash))
+                    .chain(self.zone.iter().cloned())
+                    .collect::<Vec<_>>();
+                (Name::from(owner), Record::new(nsec3, ttl))
+            }
+        }
+    }
+
+    /// Whether nonexistence is denied with compact answers rather than a chain
+    pub fn is_compact(&self) -> bool {
+        self.compact
+    }
+
+    /// The NSEC record of compact denial (RFC 9824) at `name`, holding `records`, and its
+    /// signatures, with the TTLs they are served with: its next name is the one right after it,
+    /// so that it covers no other name, and its types include NXNAME when `name` does not exist
+    /// with `nxdomain`. Those of names which do not exist are not cached, so that queries for
+    /// random names do not flush the cache.
+    pub fn compact<'a>(
+        &self,
+        name: &[String],
+        records: impl Iterator<Item = &'a Record>,
+        nxdomain: bool,
+        ttl: u32,
+        class: Class,
+        ttl_bounds: &TtlBounds,
+    ) -> Vec<Record> {
+        let mut types = self.types(name, records);
+        if nxdomain {
+            types.push(Type::NXNAME);
+        }
+        let next = std::iter::once(escape(&[0]))
+            .chain(lowercase(name))
+            .collect::<Vec<_>>();
+        let nsec = RecordInner::NSEC {
+            next: Name::from(next),
+            types,
+        };
+        let nsec = Record::new(nsec, ttl);
+        let signatures = match nxdomain {
+            false => self.sign(name, &[&nsec], class, ttl_bounds),
+            true => {
+                self.counters.misses.fetch_add(1, Ordering::Relaxed);
+                self.signatures(name, &[&nsec], class, ttl_bounds, VALIDITY)
+                    .unwrap_or_else(|e| {
+                        log::error!("Signing NSEC at {}: {:#}", name.join("."), e);
+                        Vec::new()
+                    })
+            }
+        };
+        std::iter::once(nsec).chain(signatures).collect()
+    }
+
+    /// The types of the RRsets of `records` at `name`, only NS and DS at delegation points (RFC
+    /// 4035 Section 2.3), with those of the records the signer adds
+    fn types<'a>(&self, name: &[String], records: impl Iterator<Item = &'a Record>) -> Vec<Type> {
+        let mut types: Vec<Type> = records
+            .map(|record| record.inner.ty())
+            .filter(|ty| !DNSSEC_TYPES.contains(ty))
+            .collect();
+        let apex = crate::acl::names_eq(name, &self.zone);
+        if types.contains(&Type::NS) && !apex {
+            types.retain(|ty| [Type::NS, Type::DS].contains(ty));
+        }
+        if apex {
+            types.push(Type::DNSKEY);
+            if !self.cds(Type::CDS, 0).is_empty() {
+                types.extend([Type::CDS, Type::CDNSKEY]);
+            }
+        }
+        match &self.nsec3 {
+            None => types.extend([Type::RRSIG, Type::NSEC]),
+            Some(_) => {
+                if apex {
+                    types.push(Type::NSEC3PARAM);
+                }
+                // Empty non-terminals and insecure delegations have no signatures, the NSEC3
+                // record being at the hash of the name
+                if types.iter().any(|ty| *ty != Type::NS) {
+                    types.push(Type::RRSIG);
+                }
+            }
+        }
+        types.sort_by_key(|ty| *ty as u16);
+        types.dedup();
+        types
+    }
+}
+
+/// The keys of `keys` signing RRsets of type `ty` at `now`: the key signing keys for the DNSKEY,
+/// CDS and CDNSKEY RRsets, as RFC 7344 Section 4.1 has the latter signed by keys the parent zone
+/// knows, and the zone signing keys for the others if any is active
+fn signing_keys(keys: &[Key], ty: Type, now: u32) -> Vec<&Key> {
+    let active = |role: Role| {
+        keys.iter()
+            .filter(move |key| key.entry.role == role && key.entry.state(now) == State::Active)
+    };
+    match (ty, active(Role::Zsk).next()) {
+        (Type::DNSKEY | Type::CDS | Type::CDNSKEY, _) | (_, None) => active(Role::Ksk).collect(),
+        _ => active(Role::Zsk).collect(),
+    }
+}
+
+/// The keys of the store at `dir`, see the keystore module
+fn read_store(dir: &Path, passphrase: &mut Option<String>) -> anyhow::Result<Vec<Key>> {
+    crate::keystore::read(dir)?
+        .into_iter()
+        .map(|entry| Key::read(&dir.join(&entry.file), entry, passphrase))
+        .collect()
+}
+
+/// `sign` subcommand: prints a zone, loaded as for the `export` one, signed with the keys of
+/// `paths`, or those of `key_store` in their current state, publishing those of `imported_keys`,
+/// and denying with NSEC3 records if given their parameters, with signatures valid for `days`.
+/// The DS records for its parent are written to stderr.
+#[allow(clippy::too_many_arguments)]
+pub async fn run(
+    args: &Args,
+    zone: &Name,
+    paths: &[PathBuf],
+    key_store: Option<&Path>,
+    imported_keys: Option<&Path>,
+    nsec3: Option<Nsec3Config>,
+    days: u32,
+    format: Format,
+) -> anyhow::Result<()> {
+    if format == Format::Auto {
+        return Err(anyhow::anyhow!(
+            "--format auto cannot be used for signed zones"
+        ));
+    }
+    let signer = match key_store {
+        Some(dir) => Signer::open(zone, dir, nsec3, false, crate::key_passphrase(args)?)?,
+        None => Signer::read(zone, paths, nsec3, false, crate::key_passphrase(args)?)?,
+    };
+    if let Some(path) = imported_keys {
+        signer.import(crate::multi_signer::read(zone, path)?);
+    }
+    let signed = signer.sign_zone(&crate::export::load_zone(args, zone).await?, days * 86400)?;
+    for ds in signer.ds() {
+        eprintln!("{}", ds);
+    }
+    crate::export::print(format, signed)
+}
+
+fn lowercase(name: &[String]) -> Vec<String> {
+    name.iter()
+        .map(|label| escape(&unescape(label).to_ascii_lowercase()))
+        .collect()
+}
+
+fn wire(name: &[String]) -> Vec<u8> {
+    let mut out = Vec::new();
+    serialize_name(name, &mut out).expect("writing to a Vec");
+    out
+}
+
+/// `inner` with the names of its RDATA lowercased, RFC 4034 Section 6.2
+pub fn canonical(inner: &RecordInner) -> RecordInner {
+    let lower = |name: &Name| Name::from(lowercase(name.as_ref()));
+    let mut inner = inner.clone();
+    match &mut inner {
+        RecordInner::SOA { mname, rname, .. } => {
+            *mname = lower(mname);
+            *rname = lower(rname);
+        }
+        RecordInner::NS { ns: name }
+        | RecordInner::CNAME { to: name }
+        | RecordInner::PTR { ptr: name }
+        | RecordInner::SRV { target: name, .. } => *name = lower(name),
+        _ => {}
+    }
+    inner
+}
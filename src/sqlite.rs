@@ -0,0 +1,138 @@
+//! Records stored in a SQLite database, read with the SQLite library built into the server. The
+//! database is opened read-only, and must contain:
+//!
+//! ```sql
+//! CREATE TABLE records (
+//!     name    TEXT    NOT NULL, -- Absolute, e.g. www.example.com
+//!     type    TEXT    NOT NULL, -- A, AAAA, NS, CNAME, PTR, SRV, TXT or SOA
+//!     ttl     INTEGER NOT NULL,
+//!     content TEXT    NOT NULL  -- RDATA in master file syntax, e.g. 10.0.0.1
+//! );
+//! ```
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use rusqlite::{Connection, OpenFlags};
+
+use crate::store::Store;
+use crate::BaseStorage;
+
+const QUERY: &str = "SELECT name, type, ttl, content FROM records";
+
+/// Name, type, TTL and content
+type Row = (String, String, u32, String);
+
+pub struct SqliteSource {
+    pub path: PathBuf,
+}
+
+impl SqliteSource {
+    pub async fn read(&self) -> anyhow::Result<BaseStorage> {
+        let path = self.path.clone();
+        let rows = tokio::task::spawn_blocking(move || query(&path))
+            .await?
+            .map_err(|e| anyhow::anyhow!("{}: {}", self.path.display(), e))?;
+
+        crate::zonefile::parse_rows(
+            rows.iter().map(|(name, ty, ttl, content)| {
+                (name.as_str(), ty.as_str(), *ttl, content.as_str())
+            }),
+            &self.path.display().to_string(),
+        )
+    }
+
+    fn mtime(&self) -> Option<SystemTime> {
+        // Writes may only touch the WAL until the next checkpoint
+        let mut wal = self.path.clone().into_os_string();
+        wal.push("-wal");
+        [self.path.as_path(), Path::new(&wal)]
+            .iter()
+            .filter_map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+            .max()
+    }
+
+    /// Re-reads the database whenever it is modified, checking every `interval`
+    pub async fn watch(self, store: std::sync::Arc<Store>, interval: Duration) {
+        let mut served = self.mtime();
+        loop {
+            tokio::time::sleep(interval).await;
+            let current = self.mtime();
+            if current == served {
+                continue;
+            }
+            served = current;
+
+            match self.read().await {
+                Ok(base) => store.set_source("sqlite", base),
+                Err(e) => log::error!("Failed to reload records from SQLite: {}", e),
+            }
+        }
+    }
+}
+
+/// The rows of the records table of the database at `path`, opened anew so that a database
+/// replaced since is read
+fn query(path: &Path) -> rusqlite::Result<Vec<Row>> {
+    let db = Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    let mut statement = db.prepare(QUERY)?;
+    let rows = statement.query_map([], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    })?;
+    rows.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database(test: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("sqlite-{}-{}.db", test, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn records() {
+        let path = database("records");
+        let db = Connection::open(&path).unwrap();
+        db.execute_batch(
+            "CREATE TABLE records (name TEXT NOT NULL, type TEXT NOT NULL, \
+                 ttl INTEGER NOT NULL, content TEXT NOT NULL);
+             INSERT INTO records VALUES ('www.example.com', 'A', 300, '192.0.2.1');
+             INSERT INTO records VALUES ('www.example.com', 'TXT', 60, '\"it''s\"');
+             INSERT INTO records VALUES ('mail.example.com', 'AAAA', 300, '2001:db8::1');",
+        )
+        .unwrap();
+        drop(db);
+
+        let source = SqliteSource { path: path.clone() };
+        let base = source.read().await.unwrap();
+        let mut names: Vec<String> = base.keys().map(|name| name.to_string()).collect();
+        names.sort();
+        assert_eq!(names, ["mail.example.com", "www.example.com"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn errors() {
+        // Opened read-only, a missing database is not created
+        let path = database("missing");
+        let source = SqliteSource { path: path.clone() };
+        assert!(source.read().await.is_err());
+        assert!(!path.exists());
+
+        let path = database("table");
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch("CREATE TABLE other (name TEXT)")
+            .unwrap();
+        let source = SqliteSource { path: path.clone() };
+        let e = source.read().await.unwrap_err().to_string();
+        assert!(e.contains("no such table: records"), "{}", e);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
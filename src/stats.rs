@@ -0,0 +1,153 @@
+//! Statistics of each zone served, for capacity planning: the queries answered from it, those
+//! answered with records, and those answered NXDOMAIN, counted since the server started. Queries
+//! forwarded for names within the zone, with --forward or zones of type forward, are not counted,
+//! nor are those refused before the zone is looked up, e.g. by --rate-limit or --query-acl.
+//!
+//! They are served by the admin API, see the api module, at `GET /stats`, as
+//! `[{"zone": "example.com", "queries": 10, "answers": 8, "nxdomain": 1}]`, sorted by zone, by
+//! `GET /metrics` as `dns_zone_queries_total`, `dns_zone_answers_total` and
+//! `dns_zone_nxdomain_total`, labelled with the zone, see the metrics module, and printed by the
+//! `stats` subcommand, given the --api and --api-token of the server.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::record::Name;
+use crate::Args;
+
+tokio::task_local! {
+    static ZONE: RefCell<Option<Arc<Counters>>>;
+}
+
+/// The counters of the zones queried so far
+#[derive(Default)]
+pub struct Stats {
+    zones: RwLock<HashMap<Name, Arc<Counters>>>,
+}
+
+#[derive(Default)]
+struct Counters {
+    queries: AtomicU64,
+    answers: AtomicU64,
+    nxdomain: AtomicU64,
+}
+
+/// The counters of a zone at one time
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub zone: String,
+    pub queries: u64,
+    pub answers: u64,
+    pub nxdomain: u64,
+}
+
+impl Stats {
+    /// Counts a query answered from the zone of `origin`, its response being counted as it is sent
+    pub fn query(&self, origin: &Name) {
+        let counters = self.zones.read().unwrap().get(origin).cloned();
+        let counters = counters.unwrap_or_else(|| {
+            let mut zones = self.zones.write().unwrap();
+            zones.entry(origin.clone()).or_default().clone()
+        });
+        counters.queries.fetch_add(1, Ordering::Relaxed);
+        let _ = ZONE.try_with(|zone| *zone.borrow_mut() = Some(counters));
+    }
+
+    /// The counters of every zone queried, sorted by zone
+    pub fn snapshot(&self) -> Vec<Snapshot> {
+        let zones = self.zones.read().unwrap();
+        let mut snapshot: Vec<Snapshot> = zones
+            .iter()
+            .map(|(zone, counters)| Snapshot {
+                zone: zone.to_string(),
+                queries: counters.queries.load(Ordering::Relaxed),
+                answers: counters.answers.load(Ordering::Relaxed),
+                nxdomain: counters.nxdomain.load(Ordering::Relaxed),
+            })
+            .collect();
+        snapshot.sort_by(|a, b| a.zone.cmp(&b.zone));
+        snapshot
+    }
+}
+
+/// Runs `request`, for the response to the query it counts, if any, to be counted too
+pub async fn scope<F: Future>(request: F) -> F::Output {
+    ZONE.scope(RefCell::new(None), request).await
+}
+
+/// Counts `msg`, sent in response to the query being counted, if any. Only the first message of
+/// zone transfers is.
+pub fn sent(msg: &[u8]) {
+    let _ = ZONE.try_with(|zone| {
+        let counters = match zone.borrow_mut().take() {
+            Some(counters) => counters,
+            None => return,
+        };
+        let rcode = msg.get(3).map(|b| b & 0x0f);
+        let records = msg
+            .get(6..8)
+            .map(|count| u16::from_be_bytes([count[0], count[1]]));
+        match (rcode, records) {
+            (Some(0), Some(records)) if records > 0 => {
+                counters.answers.fetch_add(1, Ordering::Relaxed);
+            }
+            (Some(3), _) => {
+                counters.nxdomain.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    });
+}
+
+/// Prints the statistics of the server whose admin API is at --api
+pub async fn print(args: &Args, json: bool) -> anyhow::Result<()> {
+    let addr = args
+        .api
+        .ok_or_else(|| anyhow::anyhow!("stats requires --api, the admin API of the server"))?;
+    let token = args
+        .api_token
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("stats requires --api-token"))?;
+    let authorization = format!("Bearer {}", token);
+    let headers = [("Authorization", authorization.as_str())];
+    let reply = crate::http::get(&addr.to_string(), "/stats", &headers).await?;
+    if reply.status != 200 {
+        return Err(reply.error());
+    }
+    if json {
+        print!("{}", String::from_utf8_lossy(&reply.body));
+        return Ok(());
+    }
+    let snapshot: Vec<Snapshot> = serde_json::from_slice(&reply.body)
+        .map_err(|e| anyhow::anyhow!("Malformed statistics: {}", e))?;
+    let width = snapshot
+        .iter()
+        .map(|zone| zone.zone.len())
+        .max()
+        .unwrap_or_default()
+        .max(4);
+    println!(
+        "{:<width$} {:>12} {:>12} {:>12}",
+        "ZONE",
+        "QUERIES",
+        "ANSWERS",
+        "NXDOMAIN",
+        width = width
+    );
+    for zone in snapshot {
+        println!(
+            "{:<width$} {:>12} {:>12} {:>12}",
+            zone.zone,
+            zone.queries,
+            zone.answers,
+            zone.nxdomain,
+            width = width
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,228 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+use tokio::sync::broadcast;
+
+use crate::config::Nsec3Config;
+use crate::history::History;
+use crate::journal::{Journal, Records};
+use crate::parser::Type;
+use crate::record::{Name, Record};
+use crate::zone::Zones;
+use crate::{BaseStorage, RecordStorage};
+
+/// Zone data from every origin: the zone files, plus dynamic sources such as databases
+struct Layers {
+    files: BaseStorage,
+    sources: BTreeMap<String, BaseStorage>,
+    /// RRsets changed at runtime, replacing what the other layers hold for the same name and
+    /// type. Empty ones are deletions.
+    edits: HashMap<(Name, Type), Vec<Record>>,
+    /// Catalog zones generated from the other layers, see the catalog module
+    catalogs: Vec<Name>,
+    /// Domains whose PTR records are generated, see the reverse module
+    reverse: Vec<Name>,
+    /// Zones signed online, whose NSEC or NSEC3 chain is indexed, see the sign module
+    signed: Vec<(Name, Option<Nsec3Config>)>,
+}
+
+/// Changes published but not yet received, past which slow subscribers miss some
+const CHANGES_BUFFERED: usize = 1024;
+
+/// The records of a zone removed and added by one change of the served data, SOA aside
+pub struct ZoneChange {
+    pub zone: Name,
+    /// Of the new SOA, None if the zone was removed
+    pub serial: Option<u32>,
+    pub removed: Records,
+    pub added: Records,
+}
+
+/// Holds each layer of zone data, and the merged snapshot served to queries. The snapshot is
+/// replaced as a whole on every change, queries in flight keep the one they started with.
+pub struct Store {
+    layers: Mutex<Layers>,
+    current: RwLock<Arc<RecordStorage>>,
+    /// Held through runtime changes, from reading the data they depend on to applying them
+    updates: Mutex<()>,
+    history: Option<History>,
+    /// Files included by the zone files, watched along with them
+    included: Mutex<Vec<PathBuf>>,
+    changes: broadcast::Sender<Arc<ZoneChange>>,
+}
+
+impl Store {
+    pub fn new(files: BaseStorage, history: Option<History>) -> Self {
+        let layers = Layers {
+            files,
+            sources: BTreeMap::new(),
+            edits: HashMap::new(),
+            catalogs: Vec::new(),
+            reverse: Vec::new(),
+            signed: Vec::new(),
+        };
+        let mut current = merge(&layers);
+        current.zones = Zones::index(&current.base, &layers.signed);
+        if let Some(history) = &history {
+            history.record(&BaseStorage::new(), &current.base);
+        }
+        Self {
+            layers: Mutex::new(layers),
+            current: RwLock::new(Arc::new(current)),
+            updates: Mutex::new(()),
+            history,
+            included: Mutex::new(Vec::new()),
+            changes: broadcast::channel(CHANGES_BUFFERED).0,
+        }
+    }
+
+    pub fn snapshot(&self) -> Arc<RecordStorage> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Replaces the zone file layer with the result of `load`, given the current one. Nothing
+    /// changes if `load` fails.
+    pub fn update_files(
+        &self,
+        load: impl FnOnce(&BaseStorage) -> anyhow::Result<BaseStorage>,
+    ) -> anyhow::Result<()> {
+        let mut layers = self.layers.lock().unwrap();
+        layers.files = load(&layers.files)?;
+        self.publish(&layers);
+        Ok(())
+    }
+
+    /// Replaces the records provided by the source `name`
+    pub fn set_source(&self, name: &str, base: BaseStorage) {
+        let mut layers = self.layers.lock().unwrap();
+        if layers.sources.get(name) == Some(&base) {
+            return;
+        }
+        crate::watch::log_diff(
+            layers.sources.get(name).unwrap_or(&BaseStorage::new()),
+            &base,
+        );
+        layers.sources.insert(name.to_owned(), base);
+        self.publish(&layers);
+    }
+
+    /// Sets the catalog zones to produce
+    pub fn set_catalogs(&self, catalogs: Vec<Name>) {
+        let mut layers = self.layers.lock().unwrap();
+        layers.catalogs = catalogs;
+        self.publish(&layers);
+    }
+
+    /// Sets the files included by the zone files last read
+    pub fn set_included(&self, files: Vec<PathBuf>) {
+        *self.included.lock().unwrap() = files;
+    }
+
+    /// The files included by the zone files last read
+    pub fn included(&self) -> Vec<PathBuf> {
+        self.included.lock().unwrap().clone()
+    }
+
+    /// Sets the zones to generate PTR records from
+    pub fn set_reverse(&self, zones: Vec<Name>) {
+        let mut layers = self.layers.lock().unwrap();
+        layers.reverse = zones;
+        self.publish(&layers);
+    }
+
+    /// Sets the zones signed online, with their NSEC3 parameters if denying so
+    pub fn set_signed(&self, zones: Vec<(Name, Option<Nsec3Config>)>) {
+        let mut layers = self.layers.lock().unwrap();
+        layers.signed = zones;
+        self.publish(&layers);
+    }
+
+    /// Past versions of the zones, if kept
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// The changes of every zone published from now on, whatever they come from
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<ZoneChange>> {
+        self.changes.subscribe()
+    }
+
+    pub fn lock_updates(&self) -> MutexGuard<'_, ()> {
+        self.updates.lock().unwrap()
+    }
+
+    /// Replaces the RRsets given as (name, type, records), on top of every other layer. An
+    /// empty set of records deletes the RRset. All changes are published at once.
+    pub fn edit(&self, changes: Vec<(Name, Type, Vec<Record>)>) {
+        let mut layers = self.layers.lock().unwrap();
+        for (name, ty, records) in changes {
+            layers.edits.insert((name, ty), records);
+        }
+        let old = self.snapshot();
+        self.publish(&layers);
+        crate::watch::log_diff(&old.base, &self.snapshot().base);
+    }
+
+    fn publish(&self, layers: &Layers) {
+        // Callers hold the layers, so nothing is published in between
+        let current = self.snapshot();
+        let mut next = merge(layers);
+        crate::reverse::generate(&mut next.base, &layers.reverse, &current.base);
+        crate::catalog::produce(&mut next.base, &layers.catalogs, &current.base);
+        next.zones = Zones::index(&next.base, &layers.signed);
+        let changed = crate::journal::changes(&current.base, &next.base);
+        if self.changes.receiver_count() > 0 {
+            for (zone, (removed, added)) in &changed {
+                let soa = next.base.get(zone.as_ref()).into_iter().flatten();
+                let serial = soa.filter_map(crate::journal::serial).next();
+                let change = ZoneChange {
+                    zone: zone.clone(),
+                    serial,
+                    removed: removed.clone(),
+                    added: added.clone(),
+                };
+                // Only fails once every subscriber is gone
+                let _ = self.changes.send(Arc::new(change));
+            }
+        }
+        next.journal = current.journal.advance(&current.base, &next.base, changed);
+        if let Some(history) = &self.history {
+            history.record(&current.base, &next.base);
+        }
+        *self.current.write().unwrap() = Arc::new(next);
+    }
+}
+
+/// Drops the records disabled with `enabled: false`, which are only kept in the layers
+pub fn without_disabled(base: &mut BaseStorage) {
+    base.retain(|_, records| {
+        records.retain(|r| r.enabled);
+        !records.is_empty()
+    });
+}
+
+fn merge(layers: &Layers) -> RecordStorage {
+    let mut base = layers.files.clone();
+    for source in layers.sources.values() {
+        for (name, records) in source.iter() {
+            base.entry(name.clone())
+                .or_default()
+                .extend(records.iter().cloned());
+        }
+    }
+    for ((name, ty), records) in layers.edits.iter() {
+        let rrsets = base.entry(name.clone()).or_default();
+        rrsets.retain(|r| r.inner.ty() != *ty);
+        rrsets.extend(records.iter().cloned());
+        if rrsets.is_empty() {
+            base.remove(name);
+        }
+    }
+    without_disabled(&mut base);
+    RecordStorage {
+        base,
+        journal: Journal::default(),
+        zones: Zones::default(),
+    }
+}
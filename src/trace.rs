@@ -0,0 +1,273 @@
+//! Tracing of requests, exported with --otlp to an OpenTelemetry collector, in OTLP/HTTP with
+//! JSON encoding, at host:port, usually port 4318. Each request traced is a span named
+//! `dns.request`, of kind SERVER, with the attributes `client.address`, `client.port`,
+//! `network.transport`, `dns.question.name`, `dns.question.type` and `dns.response.code`, its
+//! status being an error for SERVFAIL responses. Its child spans are the phases of the request,
+//! in turn: `parse`, checking the request, `lookup`, answering it from the zones, the cache or
+//! upstream, `serialize`, encoding the response, and `send`, handing it to the socket. Relayed
+//! responses are not serialized, and zone transfers go through the last three for each message.
+//!
+//! With --otlp-sample N, only one request in N is traced. Spans are sent in batches, every few
+//! seconds or as soon as many are waiting, with `service.name` --otlp-service-name. Those the
+//! collector does not take, or which pile up while it is slow, are dropped and counted by
+//! `dns_trace_spans_dropped_total`, see the metrics module.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+use tokio::sync::mpsc;
+
+/// Traces waiting to be exported, beyond which they are dropped
+const QUEUE: usize = 10_000;
+/// Spans sent at once at most
+const MAX_BATCH: usize = 512;
+const BATCH_DELAY: Duration = Duration::from_secs(5);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Span kinds
+const INTERNAL: u8 = 1;
+const SERVER: u8 = 2;
+
+tokio::task_local! {
+    static TRACE: RefCell<Option<Trace>>;
+}
+
+pub struct Tracer {
+    sample: u64,
+    received: AtomicU64,
+    traces: mpsc::Sender<Vec<String>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Tracer {
+    /// Exports one request in `sample` to the collector at `addr`, as the service `service`
+    pub fn new(addr: String, service: String, sample: u64) -> anyhow::Result<Self> {
+        if sample == 0 {
+            return Err(anyhow::anyhow!("--otlp-sample must be positive"));
+        }
+        let (traces, queue) = mpsc::channel(QUEUE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        tokio::spawn(export(addr, service, queue, dropped.clone()));
+        Ok(Self {
+            sample,
+            received: AtomicU64::new(0),
+            traces,
+            dropped,
+        })
+    }
+
+    /// Whether the next request is to be traced
+    pub fn sampled(&self) -> bool {
+        self.received
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(self.sample)
+    }
+
+    /// Runs `handle`, answering the request of `question` from `remote`, within a trace
+    pub async fn trace<F>(
+        &self,
+        question: Option<(String, String)>,
+        remote: SocketAddr,
+        tcp: bool,
+        handle: F,
+    ) -> F::Output
+    where
+        F: Future,
+    {
+        let trace = Trace {
+            id: rand::random(),
+            root: rand::random(),
+            start: now(),
+            remote,
+            tcp,
+            question,
+            rcode: None,
+            spans: Vec::new(),
+            phase: Some(("parse", now())),
+        };
+        TRACE
+            .scope(RefCell::new(Some(trace)), async {
+                let result = handle.await;
+                if let Some(trace) = TRACE.with(|trace| trace.borrow_mut().take()) {
+                    let spans = trace.finish();
+                    let count = spans.len() as u64;
+                    if self.traces.try_send(spans).is_err() {
+                        self.dropped.fetch_add(count, Ordering::Relaxed);
+                    }
+                }
+                result
+            })
+            .await
+    }
+
+    /// Spans dropped so far
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Ends the current phase of the request being traced, if any, and starts `name`
+pub fn phase(name: &'static str) {
+    let _ = TRACE.try_with(|trace| {
+        if let Some(trace) = trace.borrow_mut().as_mut() {
+            trace.end_phase();
+            trace.phase = Some((name, now()));
+        }
+    });
+}
+
+/// Records `msg` as being sent in response to the request being traced, if any
+pub fn sent(msg: &[u8]) {
+    let _ = TRACE.try_with(|trace| {
+        if let Some(trace) = trace.borrow_mut().as_mut() {
+            trace.rcode = trace.rcode.or_else(|| msg.get(3).map(|b| b & 0x0f));
+        }
+    });
+    phase("send");
+}
+
+struct Trace {
+    id: u128,
+    root: u64,
+    /// In nanoseconds since the epoch, as every time
+    start: u64,
+    remote: SocketAddr,
+    tcp: bool,
+    question: Option<(String, String)>,
+    rcode: Option<u8>,
+    /// The phases done, as JSON spans
+    spans: Vec<String>,
+    /// The current phase and its start
+    phase: Option<(&'static str, u64)>,
+}
+
+impl Trace {
+    fn end_phase(&mut self) {
+        if let Some((name, start)) = self.phase.take() {
+            let span = self.span(rand::random(), Some(self.root), name, INTERNAL, start, &[]);
+            self.spans.push(span);
+        }
+    }
+
+    /// Its spans, the request last
+    fn finish(mut self) -> Vec<String> {
+        self.end_phase();
+        let mut attributes = vec![
+            ("client.address", string(&self.remote.ip().to_string())),
+            ("client.port", int(self.remote.port().into())),
+            (
+                "network.transport",
+                string(if self.tcp { "tcp" } else { "udp" }),
+            ),
+        ];
+        if let Some((name, ty)) = &self.question {
+            attributes.push(("dns.question.name", string(name)));
+            attributes.push(("dns.question.type", string(ty)));
+        }
+        if let Some(rcode) = self.rcode {
+            let code = crate::querylog::rcode_name(rcode);
+            attributes.push(("dns.response.code", string(&code)));
+        }
+        let root = self.span(
+            self.root,
+            None,
+            "dns.request",
+            SERVER,
+            self.start,
+            &attributes,
+        );
+        self.spans.push(root);
+        self.spans
+    }
+
+    /// A span ending now, as JSON
+    fn span(
+        &self,
+        id: u64,
+        parent: Option<u64>,
+        name: &str,
+        kind: u8,
+        start: u64,
+        attributes: &[(&str, String)],
+    ) -> String {
+        let attributes: Vec<String> = attributes
+            .iter()
+            .map(|(key, value)| format!("{{\"key\": {}, \"value\": {}}}", Value::from(*key), value))
+            .collect();
+        // Error for SERVFAIL, unset otherwise
+        let status = match (parent, self.rcode) {
+            (None, Some(2)) => 2,
+            _ => 0,
+        };
+        format!(
+            "{{\"traceId\": \"{:032x}\", \"spanId\": \"{:016x}\", \"parentSpanId\": \"{}\", \"name\": {}, \"kind\": {}, \"startTimeUnixNano\": \"{}\", \"endTimeUnixNano\": \"{}\", \"attributes\": [{}], \"status\": {{\"code\": {}}}}}",
+            self.id,
+            id,
+            parent.map(|id| format!("{:016x}", id)).unwrap_or_default(),
+            Value::from(name),
+            kind,
+            start,
+            now(),
+            attributes.join(", "),
+            status
+        )
+    }
+}
+
+fn string(value: &str) -> String {
+    format!("{{\"stringValue\": {}}}", Value::from(value))
+}
+
+fn int(value: i64) -> String {
+    format!("{{\"intValue\": \"{}\"}}", value)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// Sends the spans of the traces of `queue` to the collector at `addr` in batches
+async fn export(
+    addr: String,
+    service: String,
+    mut queue: mpsc::Receiver<Vec<String>>,
+    dropped: Arc<AtomicU64>,
+) {
+    while let Some(mut spans) = queue.recv().await {
+        let delay = tokio::time::sleep(BATCH_DELAY);
+        tokio::pin!(delay);
+        while spans.len() < MAX_BATCH {
+            tokio::select! {
+                _ = &mut delay => break,
+                trace = queue.recv() => match trace {
+                    Some(trace) => spans.extend(trace),
+                    None => break,
+                },
+            }
+        }
+        let body = format!(
+            "{{\"resourceSpans\": [{{\"resource\": {{\"attributes\": [{{\"key\": \"service.name\", \"value\": {}}}]}}, \"scopeSpans\": [{{\"scope\": {{\"name\": \"impl-cat-dns\", \"version\": \"{}\"}}, \"spans\": [{}]}}]}}]}}",
+            string(&service),
+            env!("CARGO_PKG_VERSION"),
+            spans.join(", ")
+        );
+        let headers = [("Content-Type", "application/json")];
+        let request = crate::http::post(&addr, "/v1/traces", &headers, body.as_bytes());
+        let error = match tokio::time::timeout(TIMEOUT, request).await {
+            Ok(Ok(reply)) if reply.status == 200 => continue,
+            Ok(Ok(reply)) => reply.error(),
+            Ok(Err(e)) => e,
+            Err(_) => anyhow::anyhow!("timed out"),
+        };
+        log::warn!("Exporting {} span(s) to {}: {:#}", spans.len(), addr, error);
+        dropped.fetch_add(spans.len() as u64, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,719 @@
+//! Transaction signatures, RFC 8945. Requests signed with a key given with --tsig-key are
+//! verified and answered with a signed response; the name of the key then identifies the client
+//! to the zone ACLs. Keys are written as for `dig -y`: `[ALGORITHM:]NAME:SECRET`, the secret in
+//! base64 and the algorithm one of hmac-sha256 (the default), hmac-sha384 or hmac-sha512.
+
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64ct::{Base64, Encoding};
+use sha2::{Sha256, Sha384, Sha512};
+
+use crate::parser::{Req, Resp, Type};
+use crate::record::Name;
+
+/// TSIG error codes, RFC 8945 Section 3
+const BADSIG: u16 = 16;
+const BADKEY: u16 = 17;
+const BADTIME: u16 = 18;
+
+/// Clock skew allowed for the responses we sign, in seconds
+const FUDGE: u16 = 300;
+
+const CLASS_ANY: u16 = 255;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Algorithm {
+    HmacSha256,
+    HmacSha384,
+    HmacSha512,
+}
+
+impl FromStr for Algorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(
+            match s.trim_end_matches('.').to_ascii_lowercase().as_str() {
+                "hmac-sha256" => Self::HmacSha256,
+                "hmac-sha384" => Self::HmacSha384,
+                "hmac-sha512" => Self::HmacSha512,
+                _ => return Err(anyhow::anyhow!("Unsupported TSIG algorithm {}", s)),
+            },
+        )
+    }
+}
+
+impl Algorithm {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::HmacSha256 => "hmac-sha256",
+            Self::HmacSha384 => "hmac-sha384",
+            Self::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    fn mac_len(&self) -> usize {
+        match self {
+            Self::HmacSha256 => 32,
+            Self::HmacSha384 => 48,
+            Self::HmacSha512 => 64,
+        }
+    }
+
+    fn mac(&self, secret: &[u8], data: &[&[u8]]) -> Vec<u8> {
+        match self {
+            Self::HmacSha256 => crate::hmac::hmac::<Sha256>(secret, data).to_vec(),
+            Self::HmacSha384 => crate::hmac::hmac::<Sha384>(secret, data).to_vec(),
+            Self::HmacSha512 => crate::hmac::hmac::<Sha512>(secret, data).to_vec(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Key {
+    pub name: Name,
+    algorithm: Algorithm,
+    secret: Vec<u8>,
+}
+
+impl FromStr for Key {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algorithm, name, secret) = match s.split(':').collect::<Vec<_>>().as_slice() {
+            [name, secret] => (Algorithm::HmacSha256, *name, *secret),
+            [algorithm, name, secret] => (algorithm.parse()?, *name, *secret),
+            _ => return Err(anyhow::anyhow!("Expected [ALGORITHM:]NAME:SECRET")),
+        };
+        let name = name.trim_end_matches('.');
+        if name.is_empty() {
+            return Err(anyhow::anyhow!("Empty TSIG key name"));
+        }
+        let secret = Base64::decode_vec(secret)
+            .map_err(|_| anyhow::anyhow!("TSIG secret of {} is not valid base64", name))?;
+        Ok(Self {
+            name: Name::from(crate::label::split_name(name)),
+            algorithm,
+            secret,
+        })
+    }
+}
+
+/// Canonical wire form of a name: uncompressed and lowercase, RFC 8945 Section 4.3.3
+fn wire_name(labels: &[String]) -> Vec<u8> {
+    let mut ret = Vec::new();
+    for label in labels {
+        let raw = crate::label::unescape(label).to_ascii_lowercase();
+        ret.push(raw.len() as u8);
+        ret.extend_from_slice(&raw);
+    }
+    ret.push(0);
+    ret
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn time_bytes(time: u64) -> [u8; 6] {
+    let b = time.to_be_bytes();
+    [b[2], b[3], b[4], b[5], b[6], b[7]]
+}
+
+/// Signs the response to a signed request, or reports why the signature was rejected
+pub struct Signer {
+    /// Canonical wire form of the key and algorithm names
+    key_name: Vec<u8>,
+    algorithm: Vec<u8>,
+    /// None when the request names a key we do not know
+    key: Option<Key>,
+    /// MAC of the request, then of the previous message of a multi-message response
+    prior_mac: Vec<u8>,
+    /// Whether a message was signed already, which later ones only chain from
+    continued: bool,
+    original_id: u16,
+    request_time: u64,
+    error: u16,
+}
+
+pub enum Error {
+    /// The TSIG RR itself is malformed or misplaced, answered with FORMERR
+    Malformed(anyhow::Error),
+    /// Answered with NOTAUTH and the TSIG error of the signer
+    Rejected(Box<Signer>),
+}
+
+impl Signer {
+    /// The key of a verified request
+    pub fn key(&self) -> Option<&Name> {
+        match (&self.key, self.error) {
+            (Some(key), 0) => Some(&key.name),
+            _ => None,
+        }
+    }
+
+    pub fn describe_error(&self) -> &'static str {
+        match self.error {
+            BADSIG => "TSIG signature mismatch",
+            BADKEY => "unknown TSIG key",
+            BADTIME => "TSIG time outside of the allowed window",
+            _ => "TSIG verified",
+        }
+    }
+
+    /// BADKEY and BADSIG responses are not signed, RFC 8945 Section 5.3.2
+    fn mac_len(&self) -> usize {
+        match (&self.key, self.error) {
+            (Some(key), 0 | BADTIME) => key.algorithm.mac_len(),
+            _ => 0,
+        }
+    }
+
+    fn other_len(&self) -> usize {
+        if self.error == BADTIME {
+            6
+        } else {
+            0
+        }
+    }
+
+    /// Encoded size of the TSIG RR added by `sign`
+    pub fn len(&self) -> usize {
+        // TYPE, CLASS, TTL, RDLENGTH, then time signed, fudge, MAC size, original ID, error and
+        // other len
+        self.key_name.len() + 10 + self.algorithm.len() + 16 + self.mac_len() + self.other_len()
+    }
+
+    /// Appends the TSIG RR to `msg`, a complete response or the next message of one
+    pub fn sign(&mut self, msg: &mut Vec<u8>) {
+        // On BADTIME, the time of the request is echoed and ours goes into other data
+        let (time_signed, other) = match self.error {
+            BADTIME => (self.request_time, time_bytes(now()).to_vec()),
+            _ => (now(), Vec::new()),
+        };
+
+        let timers = timers(time_signed);
+        let mut trailer = Vec::new();
+        trailer.extend_from_slice(&self.error.to_be_bytes());
+        trailer.extend_from_slice(&(other.len() as u16).to_be_bytes());
+        trailer.extend_from_slice(&other);
+
+        let prior_len = (self.prior_mac.len() as u16).to_be_bytes();
+        let mac = match &self.key {
+            // Later messages of a response only cover the timers, RFC 8945 Section 5.3.1
+            Some(key) if self.mac_len() > 0 && self.continued => key
+                .algorithm
+                .mac(&key.secret, &[&prior_len, &self.prior_mac, msg, &timers]),
+            Some(key) if self.mac_len() > 0 => key.algorithm.mac(
+                &key.secret,
+                &[
+                    &prior_len,
+                    &self.prior_mac,
+                    msg,
+                    &self.key_name,
+                    &CLASS_ANY.to_be_bytes(),
+                    &0u32.to_be_bytes(),
+                    &self.algorithm,
+                    &timers,
+                    &trailer,
+                ],
+            ),
+            _ => Vec::new(),
+        };
+        self.continued = true;
+
+        append_rr(
+            msg,
+            &self.key_name,
+            &self.algorithm,
+            &timers,
+            &mac,
+            self.original_id,
+            &trailer,
+        );
+        self.prior_mac = mac;
+    }
+}
+
+fn timers(time_signed: u64) -> Vec<u8> {
+    let mut timers = time_bytes(time_signed).to_vec();
+    timers.extend_from_slice(&FUDGE.to_be_bytes());
+    timers
+}
+
+/// Appends a TSIG RR to `msg`, `trailer` holding its error, other len and other data
+fn append_rr(
+    msg: &mut Vec<u8>,
+    key_name: &[u8],
+    algorithm: &[u8],
+    timers: &[u8],
+    mac: &[u8],
+    original_id: u16,
+    trailer: &[u8],
+) {
+    let mut rdata = algorithm.to_vec();
+    rdata.extend_from_slice(timers);
+    rdata.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+    rdata.extend_from_slice(mac);
+    rdata.extend_from_slice(&original_id.to_be_bytes());
+    rdata.extend_from_slice(trailer);
+
+    msg.extend_from_slice(key_name);
+    msg.extend_from_slice(&(Type::TSIG as u16).to_be_bytes());
+    msg.extend_from_slice(&CLASS_ANY.to_be_bytes());
+    msg.extend_from_slice(&0u32.to_be_bytes());
+    msg.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    msg.extend_from_slice(&rdata);
+
+    let arcnt = u16::from_be_bytes([msg[10], msg[11]]) + 1;
+    msg[10..12].copy_from_slice(&arcnt.to_be_bytes());
+}
+
+/// Compares a MAC, possibly truncated, with the expected one, in constant time
+fn mac_matches(expected: &[u8], mac: &[u8]) -> bool {
+    expected
+        .iter()
+        .zip(mac.iter())
+        .fold(0, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
+
+/// Truncated MACs are accepted down to the minimum of RFC 8945 Section 5.2.2.1
+fn valid_mac_len(algorithm: Algorithm, len: usize) -> bool {
+    let full = algorithm.mac_len();
+    len <= full && len >= (full / 2).max(10)
+}
+
+/// Signs a request of ours, then verifies the responses to it
+pub struct Client {
+    key: Key,
+    key_name: Vec<u8>,
+    algorithm: Vec<u8>,
+    /// MAC of the request, then of the last signed response
+    prior_mac: Vec<u8>,
+    continued: bool,
+    /// Messages received unsigned since the last signed one, which its successor covers
+    unsigned: Vec<u8>,
+}
+
+impl Client {
+    /// Appends a TSIG RR to `msg`, a complete request
+    pub fn sign(key: &Key, msg: &mut Vec<u8>) -> Self {
+        let key_name = wire_name(key.name.as_ref());
+        let algorithm = wire_name(&crate::label::split_name(key.algorithm.name()));
+        let timers = timers(now());
+        // No error, no other data
+        let trailer = [0u8; 4];
+        let mac = key.algorithm.mac(
+            &key.secret,
+            &[
+                msg,
+                &key_name,
+                &CLASS_ANY.to_be_bytes(),
+                &0u32.to_be_bytes(),
+                &algorithm,
+                &timers,
+                &trailer,
+            ],
+        );
+        let id = u16::from_be_bytes([msg[0], msg[1]]);
+        append_rr(msg, &key_name, &algorithm, &timers, &mac, id, &trailer);
+        Self {
+            key: key.clone(),
+            key_name,
+            algorithm,
+            prior_mac: mac,
+            continued: false,
+            unsigned: Vec::new(),
+        }
+    }
+
+    /// Checks the TSIG RR of `resp`, whose raw form is `msg`. Only the first message of a
+    /// response must be signed, the following ones are covered by the next signed one.
+    pub fn verify(&mut self, msg: &[u8], resp: &Resp<'_>) -> anyhow::Result<()> {
+        let rr = match resp.additionals.last() {
+            Some(rr) if rr.ty == Type::TSIG => rr,
+            _ if self.continued => {
+                self.unsigned.extend_from_slice(msg);
+                return Ok(());
+            }
+            _ => return Err(anyhow::anyhow!("Response is not signed")),
+        };
+        let tsig = crate::parser::parse_tsig(msg, rr)?;
+        let owner = wire_name(rr.name.to_record_name().as_ref());
+        let algorithm = wire_name(tsig.algorithm.to_record_name().as_ref());
+        if owner != self.key_name || algorithm != self.algorithm {
+            return Err(anyhow::anyhow!("Response signed with another key"));
+        }
+        if tsig.error != 0 {
+            return Err(anyhow::anyhow!(
+                "Request rejected with TSIG error {}",
+                tsig.error
+            ));
+        }
+        if !valid_mac_len(self.key.algorithm, tsig.mac.len()) {
+            return Err(anyhow::anyhow!("TSIG MAC of invalid size"));
+        }
+
+        let mut signed = msg[..rr.offset].to_vec();
+        signed[0..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+        signed[10..12].copy_from_slice(&(resp.arcnt - 1).to_be_bytes());
+        let prior_len = (self.prior_mac.len() as u16).to_be_bytes();
+        let timers = [&time_bytes(tsig.time_signed)[..], &tsig.fudge.to_be_bytes()].concat();
+        let expected = if self.continued {
+            self.key.algorithm.mac(
+                &self.key.secret,
+                &[
+                    &prior_len,
+                    &self.prior_mac,
+                    &self.unsigned,
+                    &signed,
+                    &timers,
+                ],
+            )
+        } else {
+            self.key.algorithm.mac(
+                &self.key.secret,
+                &[
+                    &prior_len,
+                    &self.prior_mac,
+                    &signed,
+                    &self.key_name,
+                    &CLASS_ANY.to_be_bytes(),
+                    &0u32.to_be_bytes(),
+                    &self.algorithm,
+                    &timers,
+                    &tsig.error.to_be_bytes(),
+                    &(tsig.other.len() as u16).to_be_bytes(),
+                    tsig.other,
+                ],
+            )
+        };
+        if !mac_matches(&expected, tsig.mac) {
+            return Err(anyhow::anyhow!("TSIG signature mismatch"));
+        }
+        if now().abs_diff(tsig.time_signed) > tsig.fudge as u64 {
+            return Err(anyhow::anyhow!("TSIG time outside of the allowed window"));
+        }
+
+        self.prior_mac = tsig.mac.to_vec();
+        self.continued = true;
+        self.unsigned.clear();
+        Ok(())
+    }
+
+    /// Checks that the last message of a response was signed
+    pub fn finish(&self) -> anyhow::Result<()> {
+        if self.unsigned.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "Last message of the response is not signed"
+            ))
+        }
+    }
+}
+
+/// Checks the TSIG RR of `req`, whose raw form is `msg`. Unsigned requests give None.
+pub fn verify(msg: &[u8], req: &Req<'_>, keys: &[Key]) -> Result<Option<Signer>, Error> {
+    let malformed = |e: &str| Error::Malformed(anyhow::anyhow!("{}", e));
+    let rr = match req.additionals.iter().position(|rr| rr.ty == Type::TSIG) {
+        None => return Ok(None),
+        Some(pos) if pos + 1 == req.additionals.len() => &req.additionals[pos],
+        Some(_) => return Err(malformed("TSIG RR is not the last one")),
+    };
+    if rr.class != CLASS_ANY || rr.ttl != 0 {
+        return Err(malformed(
+            "TSIG RR with class other than ANY or nonzero TTL",
+        ));
+    }
+    let tsig = crate::parser::parse_tsig(msg, rr).map_err(Error::Malformed)?;
+
+    let owner: Vec<String> = rr.name.to_record_name().as_ref().to_vec();
+    let algorithm: Vec<String> = tsig.algorithm.to_record_name().as_ref().to_vec();
+    let mut signer = Signer {
+        key_name: wire_name(&owner),
+        algorithm: wire_name(&algorithm),
+        key: None,
+        prior_mac: tsig.mac.to_vec(),
+        continued: false,
+        original_id: tsig.original_id,
+        request_time: tsig.time_signed,
+        error: 0,
+    };
+
+    let algorithm = algorithm.join(".").parse::<Algorithm>().ok();
+    let key = keys.iter().find(|key| {
+        Some(key.algorithm) == algorithm && wire_name(key.name.as_ref()) == signer.key_name
+    });
+    let key = match key {
+        Some(key) => key,
+        None => {
+            signer.error = BADKEY;
+            return Err(Error::Rejected(Box::new(signer)));
+        }
+    };
+
+    if !valid_mac_len(key.algorithm, tsig.mac.len()) {
+        return Err(malformed("TSIG MAC of invalid size"));
+    }
+
+    // The message as it was signed: without the TSIG RR, and with the original ID
+    let mut unsigned = msg[..rr.offset].to_vec();
+    unsigned[0..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+    unsigned[10..12].copy_from_slice(&(req.header.arcnt - 1).to_be_bytes());
+    let expected = key.algorithm.mac(
+        &key.secret,
+        &[
+            &unsigned,
+            &signer.key_name,
+            &CLASS_ANY.to_be_bytes(),
+            &0u32.to_be_bytes(),
+            &signer.algorithm,
+            &time_bytes(tsig.time_signed),
+            &tsig.fudge.to_be_bytes(),
+            &tsig.error.to_be_bytes(),
+            &(tsig.other.len() as u16).to_be_bytes(),
+            tsig.other,
+        ],
+    );
+    if !mac_matches(&expected, tsig.mac) {
+        signer.error = BADSIG;
+        return Err(Error::Rejected(Box::new(signer)));
+    }
+
+    signer.key = Some(key.clone());
+    if now().abs_diff(tsig.time_signed) > tsig.fudge as u64 {
+        signer.error = BADTIME;
+        return Err(Error::Rejected(Box::new(signer)));
+    }
+    Ok(Some(signer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "hmac-sha256:xfr.example.com:c2VjcmV0LXNlY3JldC1zZWNyZXQtc2VjcmV0";
+
+    fn key() -> Key {
+        KEY.parse().unwrap()
+    }
+
+    /// A query for example.com SOA
+    fn query() -> Vec<u8> {
+        let mut msg = vec![0xab, 0xcd, 0x00, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        msg.extend_from_slice(b"\x07example\x03com\x00\x00\x06\x00\x01");
+        msg
+    }
+
+    /// Signs `msg` as `Client::sign` would, but at `time` and with the MAC cut to `mac_len`
+    fn sign_at(key: &Key, msg: &mut Vec<u8>, time: u64, mac_len: usize) {
+        let key_name = wire_name(key.name.as_ref());
+        let algorithm = wire_name(&crate::label::split_name(key.algorithm.name()));
+        let timers = timers(time);
+        let trailer = [0u8; 4];
+        let mac = key.algorithm.mac(
+            &key.secret,
+            &[
+                msg,
+                &key_name,
+                &CLASS_ANY.to_be_bytes(),
+                &0u32.to_be_bytes(),
+                &algorithm,
+                &timers,
+                &trailer,
+            ],
+        );
+        let id = u16::from_be_bytes([msg[0], msg[1]]);
+        append_rr(
+            msg,
+            &key_name,
+            &algorithm,
+            &timers,
+            &mac[..mac_len],
+            id,
+            &trailer,
+        );
+    }
+
+    fn check(msg: &[u8], keys: &[Key]) -> Result<Option<Signer>, Error> {
+        let (_, req) = crate::parser::parse(msg).unwrap();
+        verify(msg, &req, keys)
+    }
+
+    fn rejected(result: Result<Option<Signer>, Error>) -> u16 {
+        match result {
+            Err(Error::Rejected(signer)) => signer.error,
+            Err(Error::Malformed(e)) => panic!("malformed: {}", e),
+            Ok(_) => panic!("verified"),
+        }
+    }
+
+    #[test]
+    fn key_syntax() {
+        assert!("xfr.example.com:c2VjcmV0".parse::<Key>().is_ok());
+        assert!("hmac-md5:xfr.example.com:c2VjcmV0".parse::<Key>().is_err());
+        assert!("xfr.example.com:not base64!".parse::<Key>().is_err());
+        assert!(":c2VjcmV0".parse::<Key>().is_err());
+        assert!("c2VjcmV0".parse::<Key>().is_err());
+    }
+
+    #[test]
+    fn unsigned() {
+        assert!(matches!(check(&query(), &[key()]), Ok(None)));
+    }
+
+    #[test]
+    fn signed() {
+        let mut msg = query();
+        Client::sign(&key(), &mut msg);
+        let signer = check(&msg, &[key()]).ok().flatten().unwrap();
+        assert_eq!(signer.key(), Some(&key().name));
+    }
+
+    #[test]
+    fn bad_mac() {
+        let mut msg = query();
+        Client::sign(&key(), &mut msg);
+        // The MAC is followed by the original ID, error and other len
+        let last = msg.len() - 7;
+        msg[last] ^= 1;
+        assert_eq!(rejected(check(&msg, &[key()])), BADSIG);
+    }
+
+    #[test]
+    fn tampered_message() {
+        let mut msg = query();
+        Client::sign(&key(), &mut msg);
+        // example.com to exbmple.com
+        msg[15] = b'b';
+        assert_eq!(rejected(check(&msg, &[key()])), BADSIG);
+    }
+
+    #[test]
+    fn wrong_secret() {
+        let other: Key = "xfr.example.com:b3RoZXItc2VjcmV0".parse().unwrap();
+        let mut msg = query();
+        Client::sign(&other, &mut msg);
+        assert_eq!(rejected(check(&msg, &[key()])), BADSIG);
+    }
+
+    #[test]
+    fn unknown_key() {
+        let other: Key = "other.example.com:c2VjcmV0".parse().unwrap();
+        let mut msg = query();
+        Client::sign(&other, &mut msg);
+        assert_eq!(rejected(check(&msg, &[key()])), BADKEY);
+        // Same name, other algorithm
+        let other: Key = "hmac-sha512:xfr.example.com:c2VjcmV0".parse().unwrap();
+        let mut msg = query();
+        Client::sign(&other, &mut msg);
+        assert_eq!(rejected(check(&msg, &[key()])), BADKEY);
+    }
+
+    #[test]
+    fn truncated_mac() {
+        let mut msg = query();
+        sign_at(&key(), &mut msg, now(), 16);
+        assert!(matches!(check(&msg, &[key()]), Ok(Some(_))));
+
+        let mut msg = query();
+        sign_at(&key(), &mut msg, now(), 15);
+        assert!(matches!(check(&msg, &[key()]), Err(Error::Malformed(_))));
+
+        let mut msg = query();
+        sign_at(&key(), &mut msg, now(), 0);
+        assert!(matches!(check(&msg, &[key()]), Err(Error::Malformed(_))));
+    }
+
+    #[test]
+    fn bad_time() {
+        let mut msg = query();
+        sign_at(&key(), &mut msg, now() - FUDGE as u64 - 10, 32);
+        assert_eq!(rejected(check(&msg, &[key()])), BADTIME);
+
+        let mut msg = query();
+        sign_at(&key(), &mut msg, now() - FUDGE as u64 + 10, 32);
+        assert!(matches!(check(&msg, &[key()]), Ok(Some(_))));
+    }
+
+    #[test]
+    fn not_last() {
+        let mut msg = query();
+        Client::sign(&key(), &mut msg);
+        // An empty OPT RR after the TSIG RR
+        msg.extend_from_slice(b"\x00\x00\x29\x04\xd0\x00\x00\x00\x00\x00\x00");
+        msg[11] += 1;
+        assert!(matches!(check(&msg, &[key()]), Err(Error::Malformed(_))));
+    }
+
+    /// The response to `query`, without answers
+    fn response(query: &[u8]) -> Vec<u8> {
+        let (_, req) = crate::parser::parse(query).unwrap();
+        let end = req.additionals[0].offset;
+        let mut msg = query[..end].to_vec();
+        msg[2] |= 0x80;
+        msg[10..12].copy_from_slice(&0u16.to_be_bytes());
+        msg
+    }
+
+    fn check_response(client: &mut Client, msg: &[u8]) -> anyhow::Result<()> {
+        let (_, resp) = crate::parser::parse_response(msg).unwrap();
+        client.verify(msg, &resp)
+    }
+
+    #[test]
+    fn signed_response() {
+        let mut query = query();
+        let mut client = Client::sign(&key(), &mut query);
+        let mut signer = check(&query, &[key()]).ok().flatten().unwrap();
+
+        let mut first = response(&query);
+        signer.sign(&mut first);
+        assert_eq!(first.len(), response(&query).len() + signer.len());
+        check_response(&mut client, &first).unwrap();
+
+        // Later messages chain from the MAC of the previous one
+        let mut second = response(&query);
+        signer.sign(&mut second);
+        check_response(&mut client, &second).unwrap();
+        client.finish().unwrap();
+    }
+
+    #[test]
+    fn response_with_bad_mac() {
+        let mut query = query();
+        let mut client = Client::sign(&key(), &mut query);
+        let mut signer = check(&query, &[key()]).ok().flatten().unwrap();
+        let mut msg = response(&query);
+        signer.sign(&mut msg);
+        let last = msg.len() - 7;
+        msg[last] ^= 1;
+        assert!(check_response(&mut client, &msg).is_err());
+    }
+
+    #[test]
+    fn unsigned_response() {
+        let mut query = query();
+        let mut client = Client::sign(&key(), &mut query);
+        assert!(check_response(&mut client, &response(&query)).is_err());
+    }
+
+    #[test]
+    fn unsigned_last_message() {
+        let mut query = query();
+        let mut client = Client::sign(&key(), &mut query);
+        let mut signer = check(&query, &[key()]).ok().flatten().unwrap();
+        let mut first = response(&query);
+        signer.sign(&mut first);
+        check_response(&mut client, &first).unwrap();
+        // Messages in between may be unsigned, but not the last one
+        check_response(&mut client, &response(&query)).unwrap();
+        assert!(client.finish().is_err());
+    }
+}
@@ -0,0 +1,484 @@
+//! Dynamic updates, RFC 2136. Clients allowed with --allow-update, which is checked before the
+//! update is applied, may check prerequisites and add or delete records of a zone in a single
+//! message, applied atomically through the edit module, which also increments the SOA serial
+//! (Section 3.6).
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::message::Rcode;
+use crate::parser::{Class, Req, Type, RR};
+use crate::record::{Name, Record, RecordInner};
+use crate::{edit, Args, SharedStorage};
+
+/// A RR of the prerequisite or update section
+struct UpdateRR {
+    name: Name,
+    ty: Type,
+    class: Class,
+    ttl: u32,
+    /// None when RDLENGTH is 0
+    rdata: Option<RecordInner>,
+}
+
+/// An update message, decoded so that it can be processed away from the request buffer
+pub struct Update {
+    zone: Name,
+    prereqs: Vec<UpdateRR>,
+    updates: Vec<UpdateRR>,
+}
+
+fn is_meta(ty: Type) -> bool {
+    matches!(
+        ty,
+        Type::OPT | Type::TSIG | Type::IXFR | Type::AXFR | Type::ANY
+    )
+}
+
+impl Update {
+    /// Decodes the sections of `req`, whose raw form is `msg`
+    pub fn parse(msg: &[u8], req: &Req<'_>) -> Result<Self, Rcode> {
+        // Section 3.1.1
+        let zone = match req.questions.as_slice() {
+            [zone] if zone.ty == Type::SOA => zone,
+            _ => return Err(Rcode::Format),
+        };
+        if zone.class != Class::IN {
+            return Err(Rcode::NotAuth);
+        }
+        let decode = |rr: &RR<'_>| -> Result<UpdateRR, Rcode> {
+            let rdata = if rr.rdata.is_empty() {
+                None
+            } else {
+                Some(crate::parser::parse_rdata(msg, rr).map_err(|e| {
+                    log::info!("Rejected update: {}", e);
+                    match rr.ty {
+                        Type::A
+                        | Type::AAAA
+                        | Type::NS
+                        | Type::CNAME
+                        | Type::PTR
+                        | Type::SRV
+                        | Type::SOA
+                        | Type::TXT => Rcode::Format,
+                        _ => Rcode::NotImpl,
+                    }
+                })?)
+            };
+            Ok(UpdateRR {
+                name: rr.name.to_record_name(),
+                ty: rr.ty,
+                class: Class::from(rr.class),
+                ttl: rr.ttl,
+                rdata,
+            })
+        };
+
+        Ok(Self {
+            zone: zone.name.to_record_name(),
+            prereqs: req.answers.iter().map(decode).collect::<Result<_, _>>()?,
+            updates: req
+                .authorities
+                .iter()
+                .map(decode)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn zone(&self) -> &Name {
+        &self.zone
+    }
+}
+
+pub struct Updater {
+    args: Arc<Args>,
+    storage: SharedStorage,
+    persist: bool,
+}
+
+/// Working copy of the names an update touches
+struct Working<'a> {
+    base: &'a crate::BaseStorage,
+    names: HashMap<Name, Vec<Record>>,
+}
+
+impl<'a> Working<'a> {
+    fn get(&mut self, name: &Name) -> &mut Vec<Record> {
+        let base = self.base;
+        self.names
+            .entry(name.clone())
+            .or_insert_with(|| base.get(name.as_ref()).cloned().unwrap_or_default())
+    }
+
+    /// RRsets which differ from the served data
+    fn changes(&self) -> Vec<edit::Change> {
+        let mut changes = Vec::new();
+        for (name, records) in self.names.iter() {
+            let old = self
+                .base
+                .get(name.as_ref())
+                .map(Vec::as_slice)
+                .unwrap_or(&[]);
+            let mut types: Vec<Type> = Vec::new();
+            for ty in old.iter().chain(records.iter()).map(|r| r.inner.ty()) {
+                if !types.contains(&ty) {
+                    types.push(ty);
+                }
+            }
+            for ty in types {
+                let rrset = |records: &[Record]| -> Vec<Record> {
+                    records
+                        .iter()
+                        .filter(|r| r.inner.ty() == ty)
+                        .cloned()
+                        .collect()
+                };
+                let new = rrset(records);
+                if new != rrset(old) {
+                    changes.push((name.clone(), ty, new));
+                }
+            }
+        }
+        changes
+    }
+}
+
+impl Updater {
+    pub fn new(args: Arc<Args>, storage: SharedStorage, persist: bool) -> Self {
+        Self {
+            args,
+            storage,
+            persist,
+        }
+    }
+
+    /// Processes an update from `client`, already allowed to update the zone, returning the
+    /// response code. Blocks on file I/O with --persist.
+    pub fn apply(&self, update: Update, client: IpAddr) -> Rcode {
+        let _updating = self.storage.lock_updates();
+        let snapshot = self.storage.snapshot();
+        let base = &snapshot.base;
+        let zone = &update.zone;
+
+        let soa = base
+            .get(zone.as_ref())
+            .and_then(|records| records.iter().find(|r| r.inner.ty() == Type::SOA));
+        let soa = match soa {
+            Some(soa) => soa.clone(),
+            None => return Rcode::NotAuth,
+        };
+
+        let in_zone = |name: &Name| crate::acl::within(name.as_ref(), zone.as_ref());
+        let rrset = |name: &Name, ty: Type| -> Vec<&Record> {
+            base.get(name.as_ref())
+                .map(|records| records.iter().filter(|r| r.inner.ty() == ty).collect())
+                .unwrap_or_default()
+        };
+
+        // Section 3.2
+        let mut expected: HashMap<(Name, Type), Vec<&RecordInner>> = HashMap::new();
+        for rr in update.prereqs.iter() {
+            if rr.ttl != 0 {
+                return Rcode::Format;
+            }
+            if !in_zone(&rr.name) {
+                return Rcode::NotZone;
+            }
+            let exists = base.contains_key(rr.name.as_ref());
+            match (rr.class, &rr.rdata) {
+                (Class::ANY, None) if rr.ty == Type::ANY => {
+                    if !exists {
+                        return Rcode::Name;
+                    }
+                }
+                (Class::ANY, None) => {
+                    if rrset(&rr.name, rr.ty).is_empty() {
+                        return Rcode::NXRRSet;
+                    }
+                }
+                (Class::NONE, None) if rr.ty == Type::ANY => {
+                    if exists {
+                        return Rcode::YXDomain;
+                    }
+                }
+                (Class::NONE, None) => {
+                    if !rrset(&rr.name, rr.ty).is_empty() {
+                        return Rcode::YXRRSet;
+                    }
+                }
+                (Class::IN, Some(rdata)) => expected
+                    .entry((rr.name.clone(), rr.ty))
+                    .or_default()
+                    .push(rdata),
+                _ => return Rcode::Format,
+            }
+        }
+        for ((name, ty), values) in expected {
+            let served: Vec<&RecordInner> = rrset(&name, ty).iter().map(|r| &r.inner).collect();
+            // Compared as sets, RFC 2136 Section 3.2.3
+            let same = values.iter().all(|v| served.contains(v))
+                && served.iter().all(|v| values.contains(v));
+            if !same {
+                return Rcode::NXRRSet;
+            }
+        }
+
+        // Section 3.4.1
+        for rr in update.updates.iter() {
+            if !in_zone(&rr.name) {
+                return Rcode::NotZone;
+            }
+            let valid = match rr.class {
+                Class::IN => !is_meta(rr.ty) && rr.rdata.is_some(),
+                Class::ANY => {
+                    rr.ttl == 0 && rr.rdata.is_none() && (rr.ty == Type::ANY || !is_meta(rr.ty))
+                }
+                Class::NONE => rr.ttl == 0 && !is_meta(rr.ty) && rr.rdata.is_some(),
+                _ => false,
+            };
+            if !valid {
+                return Rcode::Format;
+            }
+        }
+
+        // Section 3.4.2
+        let mut working = Working {
+            base,
+            names: HashMap::new(),
+        };
+        for rr in update.updates {
+            let at_apex = crate::acl::names_eq(rr.name.as_ref(), zone.as_ref());
+            let records = working.get(&rr.name);
+            match (rr.class, rr.rdata) {
+                (Class::IN, Some(inner)) => {
+                    if rr.ty == Type::SOA {
+                        let newer = match (&inner, &soa.inner) {
+                            (
+                                RecordInner::SOA { serial, .. },
+                                RecordInner::SOA { serial: old, .. },
+                            ) => crate::serial::serial_gt(*serial, *old),
+                            _ => false,
+                        };
+                        if !newer {
+                            continue;
+                        }
+                    }
+                    // A CNAME or SOA replaces the one there, other records add to their RRset
+                    let mut rrset: Vec<Record> = records
+                        .iter()
+                        .filter(|r| r.inner.ty() == rr.ty)
+                        .filter(|_| rr.ty != Type::CNAME && rr.ty != Type::SOA)
+                        .cloned()
+                        .collect();
+                    match rrset.iter_mut().find(|r| r.inner == inner) {
+                        Some(existing) => existing.ttl = rr.ttl,
+                        None => rrset.push(Record::new(inner, rr.ttl)),
+                    }
+                    if edit::check(zone, &rr.name, rr.ty, &rrset, records).is_ok() {
+                        records.retain(|r| r.inner.ty() != rr.ty);
+                        records.extend(rrset);
+                    }
+                }
+                (Class::ANY, None) => records.retain(|r| {
+                    let ty = r.inner.ty();
+                    let kept = at_apex && (ty == Type::SOA || ty == Type::NS);
+                    kept || (rr.ty != Type::ANY && ty != rr.ty)
+                }),
+                (Class::NONE, Some(inner)) => {
+                    let rrset: Vec<Record> = records
+                        .iter()
+                        .filter(|r| r.inner.ty() == rr.ty && r.inner != inner)
+                        .cloned()
+                        .collect();
+                    let allowed = edit::check(zone, &rr.name, rr.ty, &rrset, records).is_ok();
+                    if rr.ty != Type::SOA && allowed {
+                        records.retain(|r| r.inner != inner);
+                    }
+                }
+                _ => unreachable!("checked by the prescan"),
+            }
+        }
+
+        let changes = working.changes();
+        if changes.is_empty() {
+            return Rcode::OK;
+        }
+
+        log::info!(
+            "Update of {} from {}: {} RRset(s) changed",
+            zone,
+            client,
+            changes.len()
+        );
+        match edit::apply(&self.args.zones, &self.storage, zone, changes, self.persist) {
+            Ok(()) => Rcode::OK,
+            Err(e) => {
+                log::error!("Failed to apply update of {}: {}", zone, e);
+                match e {
+                    edit::Error::Invalid(_) => Rcode::Refused,
+                    _ => Rcode::Internal,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+
+    use super::*;
+    use crate::store::Store;
+
+    const ZONE: &str = "\
+example.com. 3600 IN SOA ns1.example.com. admin.example.com. 5 3600 600 86400 60
+example.com. 3600 IN NS ns1.example.com.
+example.com. 3600 IN NS ns2.example.com.
+www.example.com. 300 IN A 192.0.2.1
+alias.example.com. 300 IN CNAME www.example.com.";
+
+    fn name(s: &str) -> Name {
+        Name::from(crate::label::split_name(s))
+    }
+
+    fn soa(serial: u32) -> RecordInner {
+        RecordInner::SOA {
+            mname: name("ns1.example.com"),
+            rname: name("admin.example.com"),
+            serial,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 60,
+        }
+    }
+
+    fn ns(to: &str) -> RecordInner {
+        RecordInner::NS { ns: name(to) }
+    }
+
+    fn add(owner: &str, rdata: RecordInner) -> UpdateRR {
+        UpdateRR {
+            name: name(owner),
+            ty: rdata.ty(),
+            class: Class::IN,
+            ttl: 300,
+            rdata: Some(rdata),
+        }
+    }
+
+    /// Deletes one record with `rdata`, or the RRset of `ty` without
+    fn delete(owner: &str, ty: Type, rdata: Option<RecordInner>) -> UpdateRR {
+        UpdateRR {
+            name: name(owner),
+            ty,
+            class: if rdata.is_some() {
+                Class::NONE
+            } else {
+                Class::ANY
+            },
+            ttl: 0,
+            rdata,
+        }
+    }
+
+    fn updater() -> (Updater, SharedStorage) {
+        let base = crate::zonefile::parse_standalone(ZONE, "test.zone").unwrap();
+        let storage = Arc::new(Store::new(base, None));
+        let args = Arc::new(Args::from_iter(["impl-cat-dns"]));
+        (Updater::new(args, storage.clone(), false), storage)
+    }
+
+    fn update(updater: &Updater, zone: &str, updates: Vec<UpdateRR>) -> Rcode {
+        let update = Update {
+            zone: name(zone),
+            prereqs: Vec::new(),
+            updates,
+        };
+        updater.apply(update, IpAddr::from([127, 0, 0, 1]))
+    }
+
+    fn rrset(storage: &SharedStorage, owner: &str, ty: Type) -> Vec<RecordInner> {
+        let snapshot = storage.snapshot();
+        let records = snapshot.base.get(name(owner).as_ref());
+        let records = records.map(Vec::as_slice).unwrap_or_default().iter();
+        records
+            .filter(|r| r.inner.ty() == ty)
+            .map(|r| r.inner.clone())
+            .collect()
+    }
+
+    #[test]
+    fn soa_and_ns_at_the_apex() {
+        let (updater, storage) = updater();
+        let apex = "example.com";
+        let serial = || match rrset(&storage, apex, Type::SOA).as_slice() {
+            [RecordInner::SOA { serial, .. }] => *serial,
+            soa => panic!("{:?}", soa),
+        };
+
+        // Deleting every name server keeps the last one
+        let deletes = vec![
+            delete(apex, Type::NS, Some(ns("ns1.example.com"))),
+            delete(apex, Type::NS, Some(ns("ns2.example.com"))),
+        ];
+        assert_eq!(update(&updater, apex, deletes), Rcode::OK);
+        assert_eq!(rrset(&storage, apex, Type::NS), [ns("ns2.example.com")]);
+        assert_eq!(serial(), 6);
+        // As does deleting everything at the apex, the SOA included
+        let deletes = vec![
+            delete(apex, Type::ANY, None),
+            delete(apex, Type::SOA, Some(soa(6))),
+        ];
+        assert_eq!(update(&updater, apex, deletes), Rcode::OK);
+        assert_eq!(rrset(&storage, apex, Type::NS), [ns("ns2.example.com")]);
+        assert_eq!(serial(), 6);
+
+        // A SOA replaces the one there if newer, and only at the apex
+        assert_eq!(update(&updater, apex, vec![add(apex, soa(5))]), Rcode::OK);
+        assert_eq!(serial(), 6);
+        let below = vec![add("www.example.com", soa(10))];
+        assert_eq!(update(&updater, apex, below), Rcode::OK);
+        assert!(rrset(&storage, "www.example.com", Type::SOA).is_empty());
+        assert_eq!(update(&updater, apex, vec![add(apex, soa(10))]), Rcode::OK);
+        assert_eq!(serial(), 10);
+    }
+
+    #[test]
+    fn cname_alone() {
+        let (updater, storage) = updater();
+        let cname = |to: &str| RecordInner::CNAME { to: name(to) };
+        let a = RecordInner::A {
+            addr: [192, 0, 2, 2],
+        };
+
+        // Nothing beside a CNAME, nor a CNAME beside anything
+        let adds = vec![
+            add("alias.example.com", a.clone()),
+            add("www.example.com", cname("other.example.com")),
+        ];
+        assert_eq!(update(&updater, "example.com", adds), Rcode::OK);
+        assert!(rrset(&storage, "alias.example.com", Type::A).is_empty());
+        assert!(rrset(&storage, "www.example.com", Type::CNAME).is_empty());
+
+        // A CNAME replaces the one there
+        let replace = vec![add("alias.example.com", cname("other.example.com"))];
+        assert_eq!(update(&updater, "example.com", replace), Rcode::OK);
+        let rrset = rrset(&storage, "alias.example.com", Type::CNAME);
+        assert_eq!(rrset, [cname("other.example.com")]);
+    }
+
+    #[test]
+    fn names_in_zone() {
+        let (updater, _) = updater();
+        let a = || RecordInner::A {
+            addr: [192, 0, 2, 2],
+        };
+        // Whatever their case, on label boundaries
+        let update = |owner: &str| update(&updater, "example.com", vec![add(owner, a())]);
+        assert_eq!(update("WWW.Example.COM"), Rcode::OK);
+        assert_eq!(update("www.example.org"), Rcode::NotZone);
+        assert_eq!(update("www.notexample.com"), Rcode::NotZone);
+    }
+}
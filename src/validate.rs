@@ -0,0 +1,251 @@
+use std::collections::HashSet;
+
+use crate::parser::Type;
+use crate::record::{Name, Record, RecordInner};
+use crate::BaseStorage;
+
+fn display(segs: &[String]) -> String {
+    if segs.is_empty() {
+        ".".to_owned()
+    } else {
+        segs.join(".")
+    }
+}
+
+fn is_below(name: &[String], ancestor: &[String]) -> bool {
+    name.len() > ancestor.len() && name.ends_with(ancestor)
+}
+
+fn is_at_or_below(name: &[String], ancestor: &[String]) -> bool {
+    name.ends_with(ancestor)
+}
+
+fn has_type(base: &BaseStorage, name: &[String], ty: Type) -> bool {
+    base.get(name)
+        .map(|recs| recs.iter().any(|r| r.inner.ty() == ty))
+        .unwrap_or(false)
+}
+
+fn cname_target<'a>(base: &'a BaseStorage, name: &[String]) -> Option<&'a Name> {
+    base.get(name)?.iter().find_map(|r| match &r.inner {
+        RecordInner::CNAME { to } => Some(to),
+        _ => None,
+    })
+}
+
+/// Checks RFC 1035 Section 2.3.4 limits, on the wire form of the name
+fn name_issue(segs: &[String]) -> Option<String> {
+    let mut wire_len = 1;
+    for seg in segs {
+        let raw = crate::label::unescape(seg);
+        if raw.is_empty() {
+            return Some("empty label".to_owned());
+        }
+        if raw.len() > 63 {
+            return Some(format!("label {} is longer than 63 bytes", seg));
+        }
+        wire_len += raw.len() + 1;
+    }
+    if wire_len > 255 {
+        return Some(format!("name is {} bytes long, more than 255", wire_len));
+    }
+    None
+}
+
+fn rdata_names(inner: &RecordInner) -> Vec<&Name> {
+    match inner {
+        RecordInner::SOA { mname, rname, .. } => vec![mname, rname],
+        RecordInner::NS { ns } => vec![ns],
+        RecordInner::CNAME { to } => vec![to],
+        RecordInner::PTR { ptr } => vec![ptr],
+        RecordInner::SRV { target, .. } => vec![target],
+        _ => Vec::new(),
+    }
+}
+
+/// The field of `record` by which only part of its RRset may be served, see the geo, weight and
+/// failover modules. The signatures of zones signed offline cover whole RRsets, so that part would not
+/// validate.
+pub fn served_in_part(record: &Record) -> Option<&'static str> {
+    if !record.geo.is_empty() {
+        return Some("geo");
+    }
+    if record.weight.is_some() {
+        return Some("weight");
+    }
+    if record.check.is_some() {
+        return Some("check");
+    }
+    None
+}
+
+/// Runs load-time sanity checks against the zone data, returning a human readable description of
+/// every problem found. The origin of a zone holds exactly one SOA. Names outside of every zone
+/// are local overrides, see the zone module.
+pub fn check(base: &BaseStorage) -> Vec<String> {
+    let mut issues = Vec::new();
+
+    let apexes: Vec<&[String]> = base
+        .iter()
+        .filter(|(_, recs)| recs.iter().any(|r| r.inner.ty() == Type::SOA))
+        .map(|(name, _)| name.as_ref())
+        .collect();
+    let in_zone = |name: &[String]| apexes.iter().any(|apex| is_at_or_below(name, apex));
+
+    // Delegation points are NS sets anywhere but at an apex
+    let delegations: Vec<&[String]> = base
+        .iter()
+        .filter(|(name, recs)| {
+            !apexes.contains(&name.as_ref()) && recs.iter().any(|r| r.inner.ty() == Type::NS)
+        })
+        .map(|(name, _)| name.as_ref())
+        .collect();
+
+    let mut names: Vec<&Name> = base.keys().collect();
+    names.sort_by_key(|name| display(name.as_ref()));
+
+    for name in names {
+        let segs: &[String] = name.as_ref();
+        let records = &base[segs];
+
+        if let Some(issue) = name_issue(segs) {
+            issues.push(format!("{}: {}", display(segs), issue));
+        }
+        let soas = records.iter().filter(|r| r.inner.ty() == Type::SOA).count();
+        if soas > 1 {
+            issues.push(format!(
+                "{}: {} SOA records, expected one",
+                display(segs),
+                soas
+            ));
+        }
+        for record in records {
+            for target in rdata_names(&record.inner) {
+                if let Some(issue) = name_issue(target.as_ref()) {
+                    issues.push(format!(
+                        "{}: {:?} target {}: {}",
+                        display(segs),
+                        record.inner.ty(),
+                        display(target.as_ref()),
+                        issue
+                    ));
+                }
+            }
+        }
+
+        let cnames = records
+            .iter()
+            .filter(|r| r.inner.ty() == Type::CNAME)
+            .count();
+
+        if cnames > 1 {
+            issues.push(format!("{}: multiple CNAME records", display(segs)));
+        }
+        // Signed zones have RRSIG and NSEC records along, RFC 4035 Section 2.5
+        let others: Vec<String> = records
+            .iter()
+            .map(|r| r.inner.ty())
+            .filter(|ty| ![Type::CNAME, Type::RRSIG, Type::NSEC].contains(ty))
+            .map(|ty| format!("{:?}", ty))
+            .collect();
+        if cnames > 0 && !others.is_empty() {
+            issues.push(format!(
+                "{}: CNAME cannot coexist with other records (found {})",
+                display(segs),
+                others.join(", ")
+            ));
+        }
+        if cnames > 0 && apexes.contains(&segs) {
+            issues.push(format!("{}: CNAME at zone apex", display(segs)));
+        }
+
+        let mut signed: Vec<Type> = Vec::new();
+        for record in records {
+            if let RecordInner::RRSIG { type_covered, .. } = &record.inner {
+                if !signed.contains(type_covered) {
+                    signed.push(*type_covered);
+                }
+            }
+        }
+        for ty in signed {
+            let field = records
+                .iter()
+                .filter(|r| r.inner.ty() == ty)
+                .find_map(served_in_part);
+            if let Some(field) = field {
+                issues.push(format!(
+                    "{}: {:?} records signed offline have `{}`, their signatures would not validate",
+                    display(segs),
+                    ty,
+                    field
+                ));
+            }
+        }
+
+        // NS targets inside the delegated (or apex) name need glue to be reachable
+        for record in records {
+            if let RecordInner::NS { ns } = &record.inner {
+                let target: &[String] = ns.as_ref();
+                if is_at_or_below(target, segs)
+                    && !has_type(base, target, Type::A)
+                    && !has_type(base, target, Type::AAAA)
+                {
+                    issues.push(format!(
+                        "{}: NS target {} has no glue A/AAAA record",
+                        display(segs),
+                        display(target)
+                    ));
+                }
+            }
+        }
+
+        // Follow CNAME chains pointing into our own data
+        if cnames > 0 {
+            let mut visited: HashSet<&[String]> = HashSet::new();
+            visited.insert(segs);
+            let mut cur: &[String] = segs;
+            while let Some(to) = cname_target(base, cur) {
+                let to: &[String] = to.as_ref();
+                if !visited.insert(to) {
+                    issues.push(format!(
+                        "{}: CNAME chain loops at {}",
+                        display(segs),
+                        display(to)
+                    ));
+                    break;
+                }
+                if !base.contains_key(to) {
+                    if in_zone(to) && !delegations.iter().any(|d| is_at_or_below(to, d)) {
+                        issues.push(format!(
+                            "{}: CNAME chain ends at {}, which does not exist",
+                            display(segs),
+                            display(to)
+                        ));
+                    }
+                    break;
+                }
+                cur = to;
+            }
+        }
+
+        // Anything under a delegation except glue is invisible to resolvers
+        for delegation in delegations.iter() {
+            if !is_below(segs, delegation) {
+                continue;
+            }
+            for record in records {
+                let ty = record.inner.ty();
+                if ty != Type::A && ty != Type::AAAA {
+                    issues.push(format!(
+                        "{}: {:?} record is occluded by the delegation at {}",
+                        display(segs),
+                        ty,
+                        display(delegation)
+                    ));
+                }
+            }
+        }
+    }
+
+    issues
+}
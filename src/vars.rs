@@ -0,0 +1,137 @@
+//! Variables of YAML, JSON and TOML zone files, declared at the top level and substituted in the
+//! names and record fields of the same file:
+//!
+//! ```yaml
+//! $vars:
+//!   lb_ip: 203.0.113.10
+//!   ttl: 300
+//! www.example.com:
+//!   - type: A
+//!     addr: ${lb_ip}
+//!     ttl: ${ttl}
+//! ```
+//!
+//! A field holding a single variable takes its value as is, so that numbers stay numbers, while
+//! variables within text are replaced by their value as text. Variable names are identifiers,
+//! leaving `${0,3,d}` and the like to $generate, and an undefined one is an error. `$${` stands
+//! for a literal `${`. Variables are not passed on to included files.
+
+use serde::de::DeserializeOwned;
+use serde_yaml::{Mapping, Value};
+
+use crate::load::Format;
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn lookup<'a>(vars: &'a Mapping, name: &str) -> anyhow::Result<&'a Value> {
+    vars.get(&Value::from(name))
+        .ok_or_else(|| anyhow::anyhow!("undefined variable ${{{}}}", name))
+}
+
+fn as_text(name: &str, value: &Value) -> anyhow::Result<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => Err(anyhow::anyhow!("variable {} is not a scalar", name)),
+    }
+}
+
+fn substitute(text: &str, vars: &Mapping) -> anyhow::Result<Value> {
+    let whole = text
+        .strip_prefix("${")
+        .and_then(|rest| rest.strip_suffix('}'))
+        .filter(|name| is_identifier(name));
+    if let Some(name) = whole {
+        return lookup(vars, name).cloned();
+    }
+
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        let after = &rest[start + 2..];
+        if let Some(before) = rest[..start].strip_suffix('$') {
+            out.push_str(before);
+            out.push_str("${");
+            rest = after;
+            continue;
+        }
+        out.push_str(&rest[..start]);
+        match after.find('}') {
+            Some(end) if is_identifier(&after[..end]) => {
+                let name = &after[..end];
+                out.push_str(&as_text(name, lookup(vars, name)?)?);
+                rest = &after[end + 1..];
+            }
+            _ => {
+                out.push_str("${");
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    Ok(Value::String(out))
+}
+
+fn expand(value: &mut Value, vars: &Mapping) -> anyhow::Result<()> {
+    match value {
+        Value::String(text) => *value = substitute(text, vars)?,
+        Value::Sequence(items) => {
+            for item in items.iter_mut() {
+                expand(item, vars)?;
+            }
+        }
+        Value::Mapping(map) => {
+            let mut expanded = Mapping::new();
+            for (mut key, mut value) in std::mem::take(map) {
+                if let Value::String(text) = &key {
+                    key = Value::String(as_text(text, &substitute(text, vars)?)?);
+                }
+                expand(&mut value, vars)?;
+                expanded.insert(key, value);
+            }
+            *map = expanded;
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+fn parse_plain<T: DeserializeOwned>(content: &str, format: Format) -> anyhow::Result<T> {
+    Ok(match format {
+        Format::Toml => toml::from_str(content)?,
+        Format::Json => serde_json::from_str(content)?,
+        _ => serde_yaml::from_str(content)?,
+    })
+}
+
+/// Parses a YAML, JSON or TOML zone file, substituting its variables if it declares any
+pub fn parse<T: DeserializeOwned>(content: &str, format: Format) -> anyhow::Result<T> {
+    let mut doc: Value = match format {
+        Format::Toml => serde_yaml::to_value(toml::from_str::<toml::Value>(content)?)?,
+        Format::Json => serde_yaml::to_value(serde_json::from_str::<serde_json::Value>(content)?)?,
+        _ => serde_yaml::from_str(content)?,
+    };
+    let declared = doc.as_mapping_mut().and_then(|top| {
+        top.remove(&Value::from("$vars"))
+            .or_else(|| top.remove(&Value::from("vars")))
+    });
+    let vars = match declared {
+        Some(Value::Mapping(vars)) => vars,
+        Some(_) => return Err(anyhow::anyhow!("$vars must map names to values")),
+        // Parsed from the text again, for errors to tell where they are
+        None => return parse_plain(content, format),
+    };
+    for (name, _) in vars.iter() {
+        match name.as_str() {
+            Some(name) if is_identifier(name) => (),
+            _ => return Err(anyhow::anyhow!("invalid variable name {:?}", name)),
+        }
+    }
+    expand(&mut doc, &vars)?;
+    Ok(serde_yaml::from_value(doc)?)
+}
@@ -0,0 +1,158 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use crate::{reload, Args, BaseStorage, SharedStorage, ZoneArgs};
+
+/// Files the zone data is read from, with their modification times, those they include, as of
+/// the last time they were read, among them
+fn snapshot(args: &ZoneArgs, included: Vec<PathBuf>) -> BTreeMap<PathBuf, Option<SystemTime>> {
+    let mut files = BTreeMap::new();
+    let mtime = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    for path in args.base_files().into_iter().chain(included) {
+        let mtime = mtime(&path);
+        files.insert(path, mtime);
+    }
+
+    if let Some(dir) = &args.zones_dir {
+        if let Ok(entries) = std::fs::read_dir(dir) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                files.insert(path.clone(), mtime(&path));
+            }
+        }
+    }
+    files
+}
+
+/// Notifications of changes in the directories of the zone files, rather than of the files
+/// themselves, as editors often replace files by renaming others over them
+struct Notifier {
+    watcher: RecommendedWatcher,
+    watched: BTreeSet<PathBuf>,
+    events: mpsc::UnboundedReceiver<()>,
+}
+
+impl Notifier {
+    fn new() -> notify::Result<Self> {
+        let (tx, events) = mpsc::unbounded_channel();
+        // Errors too, such as events lost, the files being compared to what is served anyway
+        let watcher = notify::recommended_watcher(move |_| {
+            let _ = tx.send(());
+        })?;
+        Ok(Self {
+            watcher,
+            watched: BTreeSet::new(),
+            events,
+        })
+    }
+
+    /// Also watches the directories of `files` not watched yet
+    fn watch(&mut self, files: &BTreeMap<PathBuf, Option<SystemTime>>) -> notify::Result<()> {
+        for file in files.keys() {
+            let dir = match file.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            if !self.watched.contains(dir) {
+                self.watcher.watch(dir, RecursiveMode::NonRecursive)?;
+                self.watched.insert(dir.to_owned());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Waits for the zone files to change, notified by the operating system or, where it cannot,
+/// polling them every `interval`, and reloads once they stopped changing for a whole interval, so
+/// that editors writing in several steps do not trigger half-baked reloads.
+pub async fn watch(args: Arc<Args>, storage: SharedStorage, interval: Duration) {
+    let mut served = snapshot(&args.zones, storage.included());
+    let mut pending: Option<BTreeMap<PathBuf, Option<SystemTime>>> = None;
+    let mut notifier = Notifier::new()
+        .map_err(|e| {
+            log::warn!(
+                "Polling zone files for changes, failed to watch them: {}",
+                e
+            )
+        })
+        .ok();
+
+    loop {
+        if let Some(Err(e)) = notifier.as_mut().map(|notifier| notifier.watch(&served)) {
+            log::warn!(
+                "Polling zone files for changes, failed to watch them: {}",
+                e
+            );
+            notifier = None;
+        }
+        match &mut notifier {
+            // Polling then until the files stop changing
+            Some(notifier) if pending.is_none() => {
+                notifier.events.recv().await;
+                while notifier.events.try_recv().is_ok() {}
+            }
+            _ => tokio::time::sleep(interval).await,
+        }
+        let current = snapshot(&args.zones, storage.included());
+
+        match pending.take() {
+            _ if current == served => {}
+            Some(prev) if prev == current => {
+                log::info!("Zone files changed, reloading");
+                let (zones, reloaded) = (args.clone(), storage.clone());
+                match tokio::task::spawn_blocking(move || reload(&zones.zones, &reloaded)).await {
+                    Ok(Ok(())) => log::info!("Zone data reloaded"),
+                    Ok(Err(e)) => log::error!("Reload failed, keeping current zone data: {}", e),
+                    Err(e) => log::error!("Reload task failed: {}", e),
+                }
+                // Do not retry a broken file until it changes again, with the files now included
+                served = snapshot(&args.zones, storage.included());
+            }
+            _ => pending = Some(current),
+        }
+    }
+}
+
+/// Logs the records added and removed between two versions of the zone data
+pub fn log_diff(old: &BaseStorage, new: &BaseStorage) {
+    let mut lines = Vec::new();
+    for (name, records) in new.iter() {
+        let prev = old.get(name.as_ref() as &[String]);
+        for record in records {
+            if !prev.map(|p| p.contains(record)).unwrap_or(false) {
+                lines.push(format!(
+                    "+ {} {} {:?}",
+                    name.as_ref().join("."),
+                    record.ttl,
+                    record.inner
+                ));
+            }
+        }
+    }
+    for (name, records) in old.iter() {
+        let next = new.get(name.as_ref() as &[String]);
+        for record in records {
+            if !next.map(|n| n.contains(record)).unwrap_or(false) {
+                lines.push(format!(
+                    "- {} {} {:?}",
+                    name.as_ref().join("."),
+                    record.ttl,
+                    record.inner
+                ));
+            }
+        }
+    }
+
+    if lines.is_empty() {
+        log::info!("No record changed");
+    }
+    for line in lines {
+        log::info!("{}", line);
+    }
+}
@@ -0,0 +1,50 @@
+//! Weighted pools of records, for traffic to be shifted gradually between endpoints: of an RRset
+//! whose records have a `weight`, e.g.
+//!
+//! ```yaml
+//! www:
+//!   - {type: A, ttl: 60, addr: 192.0.2.1, weight: 90}
+//!   - {type: A, ttl: 60, addr: 192.0.2.2, weight: 10}
+//! ```
+//!
+//! each response carries only --pool-size records, 1 by default, drawn at random without
+//! replacement with probabilities in proportion to their weights, in the order drawn. Records of
+//! the RRset without a weight have weight 1, and those of weight 0 are only served when no other
+//! is, e.g. to drain an endpoint. Weights apply to the records left by geo constraints, see the
+//! geo module, and the records drawn are then ordered by proximity, see the proximity module.
+//!
+//! Resolvers cache the records served for their TTL, which had better be short. As with geo
+//! constraints, weights are not part of master files, nor of zone transfers, and zones signed
+//! offline cannot have any.
+
+use crate::record::Record;
+
+/// At most `size` records of `records`, drawn by weight if any has one
+pub fn pick(records: Vec<&Record>, size: usize) -> Vec<&Record> {
+    if records.iter().all(|record| record.weight.is_none()) {
+        return records;
+    }
+    let weight = |record: &Record| record.weight.unwrap_or(1);
+    let mut pool: Vec<&Record> = records
+        .iter()
+        .copied()
+        .filter(|record| weight(record) > 0)
+        .collect();
+    if pool.is_empty() {
+        pool = records;
+    }
+    // Drawn without replacement by sorting on u^(1/w), Efraimidis and Spirakis
+    let mut keyed: Vec<(f64, &Record)> = pool
+        .into_iter()
+        .map(|record| {
+            let u: f64 = rand::random();
+            (u.powf(1.0 / weight(record).max(1) as f64), record)
+        })
+        .collect();
+    keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+    keyed
+        .into_iter()
+        .take(size)
+        .map(|(_, record)| record)
+        .collect()
+}
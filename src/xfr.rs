@@ -0,0 +1,141 @@
+//! Outgoing zone transfers over TCP. AXFR (RFC 5936) sends the zone as its SOA, every other
+//! record of the zone, glue included, and the SOA again. IXFR (RFC 1995) sends the deltas from
+//! the serial of the client, when the journal still has them. Either is split into as many
+//! messages as needed. Whole transfers of the zones signed online carry their ZONEMD record
+//! after the first SOA, see the zonemd module.
+
+use std::net::SocketAddr;
+
+use crate::message::{MessageWriter, Section};
+use crate::parser::{Class, Type};
+use crate::record::{Name, Record, TtlBounds};
+use crate::zonemd::Rr;
+use crate::{Conn, Options, RecordStorage};
+
+/// Records of `zone` in transfer order, SOA first and last, or None if `zone` has no SOA. Names
+/// at or below the apex of another zone belong to that zone.
+pub fn zone_records<'a>(
+    storage: &'a RecordStorage,
+    zone: &'a [String],
+) -> Option<Vec<(&'a [String], &'a Record)>> {
+    let is_apex = |name: &[String]| storage.query_all(name).any(|r| r.inner.ty() == Type::SOA);
+    let soa = storage
+        .query_all(zone)
+        .find(|r| r.inner.ty() == Type::SOA)?;
+
+    let mut names: Vec<&Name> = storage
+        .base
+        .keys()
+        .filter(|name| {
+            let name = name.as_ref();
+            name.len() >= zone.len()
+                && name[name.len() - zone.len()..]
+                    .iter()
+                    .zip(zone.iter())
+                    .all(|(a, b)| a.eq_ignore_ascii_case(b))
+                && !(0..name.len() - zone.len()).any(|i| is_apex(&name[i..]))
+        })
+        .collect();
+    names.sort_by_key(|name| {
+        let mut labels: Vec<String> = name
+            .as_ref()
+            .iter()
+            .map(|l| l.to_ascii_lowercase())
+            .collect();
+        labels.reverse();
+        labels
+    });
+
+    let mut records = vec![(zone, soa)];
+    for name in names {
+        for record in storage.base[name].iter() {
+            if record.inner.ty() != Type::SOA {
+                records.push((name.as_ref(), record));
+            }
+        }
+    }
+    records.push((zone, soa));
+    Some(records)
+}
+
+/// The ZONEMD record of `records`, a transfer of the whole of `zone` with `ttl_bounds`, from
+/// which the ZONEMD records it had are removed
+pub fn zonemd(
+    zone: &[String],
+    records: &mut Vec<(&[String], &Record)>,
+    ttl_bounds: &TtlBounds,
+) -> Record {
+    records.retain(|(name, record)| {
+        record.inner.ty() != Type::ZONEMD || !crate::acl::names_eq(name, zone)
+    });
+    let rrs: Vec<Rr> = records
+        .iter()
+        .map(|(name, record)| Rr::new(name, record, ttl_bounds))
+        .collect();
+    crate::zonemd::record(zone, &rrs, records[0].1)
+}
+
+/// Records of an incremental transfer of `zone`, currently at `soa`, to a client at `serial`. None
+/// if the journal does not go back to `serial`.
+pub fn ixfr_records<'a>(
+    storage: &'a RecordStorage,
+    zone: &'a [String],
+    soa: &'a Record,
+    serial: u32,
+) -> Option<Vec<(&'a [String], &'a Record)>> {
+    let current = crate::journal::serial(soa)?;
+    // Clients already up to date only get the SOA
+    if !crate::serial::serial_gt(current, serial) {
+        return Some(vec![(zone, soa)]);
+    }
+
+    let deltas = storage.journal.since(zone, serial);
+    let deltas = match deltas {
+        Some(deltas) if deltas.last().map(|d| &d.new_soa) == Some(soa) => deltas,
+        _ => {
+            log::debug!(
+                "No IXFR history of {:?} from serial {}, sending the whole zone",
+                zone,
+                serial
+            );
+            return None;
+        }
+    };
+    let mut records = vec![(zone, soa)];
+    for delta in deltas {
+        records.push((zone, &delta.old_soa));
+        records.extend(delta.removed.iter().map(|(name, r)| (name.as_ref(), r)));
+        records.push((zone, &delta.new_soa));
+        records.extend(delta.added.iter().map(|(name, r)| (name.as_ref(), r)));
+    }
+    records.push((zone, soa));
+    Some(records)
+}
+
+/// Streams `records` on `conn`, starting with `msg`, which holds the question
+pub async fn send(
+    conn: &Conn,
+    remote: &SocketAddr,
+    mut msg: MessageWriter<'_>,
+    records: Vec<(&[String], &Record)>,
+    opts: &Options,
+) -> anyhow::Result<()> {
+    let mut messages = 1;
+    for (name, record) in records {
+        if !msg.push(Section::Answer, name, record, Class::IN, &opts.ttl_bounds)? {
+            let (out, next) = msg.finish_continued();
+            conn.send(remote, &out).await?;
+            msg = next;
+            messages += 1;
+            if !msg.push(Section::Answer, name, record, Class::IN, &opts.ttl_bounds)? {
+                return Err(anyhow::anyhow!(
+                    "Record of {:?} too large to transfer",
+                    name
+                ));
+            }
+        }
+    }
+    conn.send(remote, &msg.finish()).await?;
+    log::debug!("Transfer to {} sent in {} message(s)", remote, messages);
+    Ok(())
+}
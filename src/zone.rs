@@ -0,0 +1,586 @@
+//! Zones served, each given by its origin, the name holding its SOA record. Every name served
+//! belongs to the zone of the closest origin at or above it: answers are authoritative within
+//! a zone, except below its delegations, and negative answers carry its SOA (RFC 2308).
+//!
+//! Names outside of every zone are local overrides, shadowing what upstream resolvers would
+//! answer for them, see the forward and resolver modules: they are answered from their records
+//! alone, without authority, and so are the names below them, which do not exist unless they
+//! hold records of their own. The zones served take precedence over overrides above them, and
+//! overrides over zones of type forward and the cache.
+//!
+//! The names of signed zones are indexed in canonical order as well, the chain of their NSEC
+//! records proving negative answers, or by their hash for zones denying with NSEC3 records, see
+//! the sign module.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::config::Nsec3Config;
+use crate::label::unescape;
+use crate::parser::Type;
+use crate::record::{Name, Record, RecordInner};
+use crate::BaseStorage;
+
+pub struct Zone {
+    pub origin: Name,
+    pub soa: Record,
+}
+
+impl Zone {
+    /// The SOA record to put in the authority section of negative answers, whose TTL is the
+    /// lower of its own and the minimum field, RFC 2308 Section 3
+    pub fn negative_soa(&self) -> Record {
+        let mut soa = self.soa.clone();
+        if let RecordInner::SOA { minimum, .. } = &soa.inner {
+            soa.ttl = soa.ttl.min(*minimum);
+        }
+        soa
+    }
+}
+
+/// The chain of a signed zone, proving negative answers
+enum Chain {
+    /// Its names in canonical order (RFC 4034 Section 6.1) with their sort keys, the owners of
+    /// its NSEC records: those holding records, but below its delegations, RFC 4034 Section
+    /// 4.1.1
+    Nsec(Vec<(Vec<Vec<u8>>, Name)>),
+    /// The hashes of its names in order with the names hashed, RFC 5155 Section 7.1, or with
+    /// the owners of its NSEC3 records when signed offline
+    Nsec3 {
+        params: Nsec3Config,
+        hashes: Vec<(Vec<u8>, Name)>,
+    },
+}
+
+impl Chain {
+    /// The chain of `names`, those of the zone at `origin`, signed online with NSEC3 records
+    /// when `signed` holds their parameters, or as the records it holds tell when not given
+    fn new(
+        base: &BaseStorage,
+        origin: &Name,
+        names: Vec<&Name>,
+        signed: Option<&Option<Nsec3Config>>,
+    ) -> Self {
+        let holds = |name: &Name, ty: Type| base[name].iter().any(|r| r.inner.ty() == ty);
+        let params = match signed {
+            Some(params) => params.clone(),
+            None => base[origin].iter().find_map(|r| match &r.inner {
+                RecordInner::NSEC3PARAM {
+                    iterations, salt, ..
+                } => Some(Nsec3Config {
+                    salt: salt.clone(),
+                    iterations: *iterations,
+                    opt_out: false,
+                }),
+                _ => None,
+            }),
+        };
+        let Some(params) = params else {
+            let mut chain: Vec<(Vec<Vec<u8>>, Name)> = authoritative(base, origin, &names, false)
+                .into_iter()
+                .map(|name| (canonical_key(name.as_ref()), name))
+                .collect();
+            chain.sort_by(|a, b| a.0.cmp(&b.0));
+            return Self::Nsec(chain);
+        };
+
+        let mut hashes: Vec<(Vec<u8>, Name)> = match signed {
+            // The owners of the NSEC3 records, named by their hash
+            None => names
+                .into_iter()
+                .filter(|name| holds(name, Type::NSEC3))
+                .filter_map(|name| {
+                    let hash = crate::dnssec::base32hex(name.as_ref().first()?)?;
+                    Some((hash, name.clone()))
+                })
+                .collect(),
+            // Empty non-terminals have NSEC3 records too, RFC 5155 Section 7.1
+            Some(_) => {
+                let mut all = HashSet::new();
+                for name in authoritative(base, origin, &names, params.opt_out) {
+                    let segs: &[String] = name.as_ref();
+                    for i in 0..=segs.len() - origin.as_ref().len() {
+                        all.insert(Name::from(segs[i..].to_vec()));
+                    }
+                }
+                all.into_iter()
+                    .map(|name| (params.hash(name.as_ref()), name))
+                    .collect()
+            }
+        };
+        hashes.sort_by(|a, b| a.0.cmp(&b.0));
+        Self::Nsec3 { params, hashes }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Self::Nsec(chain) => chain.len(),
+            Self::Nsec3 { hashes, .. } => hashes.len(),
+        }
+    }
+
+    /// The `i`th record of the chain, the first following the last
+    fn link(&self, i: usize) -> Link<'_> {
+        let next = (i + 1) % self.len();
+        match self {
+            Self::Nsec(chain) => Link::Nsec(&chain[i].1, &chain[next].1),
+            Self::Nsec3 { params, hashes } => Link::Nsec3 {
+                name: &hashes[i].1,
+                hash: &hashes[i].0,
+                next: &hashes[next].0,
+                params,
+            },
+        }
+    }
+}
+
+/// Of `names`, those of the zone at `origin`, those with authoritative data or delegations,
+/// not below them. With `opt_out`, the delegations without DS records are left out too.
+fn authoritative(base: &BaseStorage, origin: &Name, names: &[&Name], opt_out: bool) -> Vec<Name> {
+    let holds = |name: &Name, ty: Type| base[name].iter().any(|r| r.inner.ty() == ty);
+    let cuts: Vec<&Name> = names
+        .iter()
+        .filter(|name| **name != origin && holds(name, Type::NS))
+        .copied()
+        .collect();
+    names
+        .iter()
+        .filter(|name| {
+            let segs: &[String] = name.as_ref();
+            !cuts.iter().any(|cut| {
+                let cut: &[String] = cut.as_ref();
+                segs.len() > cut.len() && segs.ends_with(cut)
+            })
+        })
+        .filter(|name| !(opt_out && cuts.contains(name) && !holds(name, Type::DS)))
+        .map(|name| (*name).clone())
+        .collect()
+}
+
+/// A record of the chain of a signed zone, see `Zones::denial`
+pub enum Link<'a> {
+    /// The NSEC record of a name, with the next name
+    Nsec(&'a Name, &'a Name),
+    /// The NSEC3 record of a name, or of its owner when signed offline, with its hash and the
+    /// next hash
+    Nsec3 {
+        name: &'a Name,
+        hash: &'a [u8],
+        next: &'a [u8],
+        params: &'a Nsec3Config,
+    },
+}
+
+impl Link<'_> {
+    /// The name whose types the record lists, holding it when signed offline
+    pub fn name(&self) -> &Name {
+        match self {
+            Self::Nsec(name, _) | Self::Nsec3 { name, .. } => name,
+        }
+    }
+}
+
+impl PartialEq for Link<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.name() == other.name()
+    }
+}
+
+/// Sort key of names in canonical order, RFC 4034 Section 6.1
+pub fn canonical_key(name: &[String]) -> Vec<Vec<u8>> {
+    name.iter()
+        .rev()
+        .map(|label| unescape(label).to_ascii_lowercase())
+        .collect()
+}
+
+/// The wildcard at `encloser`
+fn wildcard(encloser: &[String]) -> Vec<String> {
+    std::iter::once("*".to_string())
+        .chain(encloser.iter().cloned())
+        .collect()
+}
+
+#[derive(Default)]
+pub struct Zones {
+    by_origin: HashMap<Name, Zone>,
+    /// Names holding records, and those between them and their origin, which exist as well
+    /// (RFC 8020)
+    existing: HashSet<Name>,
+    /// Names outside of every zone
+    overrides: HashSet<Name>,
+    /// Of the signed zones, signed online or holding NSEC or NSEC3PARAM records, by origin
+    chains: HashMap<Name, Chain>,
+}
+
+impl Zones {
+    /// Indexes the zones of `base`, and the overrides outside of them. `signed` are the zones
+    /// signed online, with their NSEC3 parameters if denying so, see the sign module.
+    pub fn index(base: &BaseStorage, signed: &[(Name, Option<Nsec3Config>)]) -> Self {
+        let mut by_origin = HashMap::new();
+        for (name, records) in base.iter() {
+            if let Some(soa) = records.iter().find(|r| r.inner.ty() == Type::SOA) {
+                let zone = Zone {
+                    origin: name.clone(),
+                    soa: soa.clone(),
+                };
+                by_origin.insert(name.clone(), zone);
+            }
+        }
+        let mut zones = Self {
+            by_origin,
+            existing: HashSet::new(),
+            overrides: HashSet::new(),
+            chains: HashMap::new(),
+        };
+        let chained: HashSet<&Name> = zones
+            .by_origin
+            .keys()
+            .filter(|origin| {
+                signed.iter().any(|(zone, _)| zone == *origin)
+                    || base[*origin]
+                        .iter()
+                        .any(|r| [Type::NSEC, Type::NSEC3PARAM].contains(&r.inner.ty()))
+            })
+            .collect();
+
+        let (mut existing, mut overrides) = (HashSet::new(), HashSet::new());
+        let mut members: HashMap<&Name, Vec<&Name>> = HashMap::new();
+        for name in base.keys() {
+            let segs: &[String] = name.as_ref();
+            let zone = match zones.find(segs) {
+                Some(zone) => zone,
+                None => {
+                    overrides.insert(name.clone());
+                    continue;
+                }
+            };
+            for i in 0..=segs.len() - zone.origin.as_ref().len() {
+                existing.insert(Name::from(segs[i..].to_vec()));
+            }
+            if chained.contains(&zone.origin) {
+                members.entry(&zone.origin).or_default().push(name);
+            }
+        }
+        let chains = members
+            .into_iter()
+            .map(|(origin, names)| {
+                let signed = signed
+                    .iter()
+                    .find(|(zone, _)| zone == origin)
+                    .map(|(_, nsec3)| nsec3);
+                (origin.clone(), Chain::new(base, origin, names, signed))
+            })
+            .collect();
+        zones.existing = existing;
+        zones.overrides = overrides;
+        zones.chains = chains;
+        zones
+    }
+
+    /// The zone whose origin is `name`
+    pub fn get(&self, name: &[String]) -> Option<&Zone> {
+        self.by_origin.get(name)
+    }
+
+    /// The zone `name` belongs to, with the closest origin at or above it
+    pub fn find(&self, name: &[String]) -> Option<&Zone> {
+        (0..=name.len()).find_map(|i| self.by_origin.get(&name[i..]))
+    }
+
+    /// Whether `name` holds records, or is above a name which does within its zone
+    pub fn exists(&self, name: &[String]) -> bool {
+        self.existing.contains(name)
+    }
+
+    /// Whether `name` is a local override, or below one
+    pub fn is_overridden(&self, name: &[String]) -> bool {
+        (0..name.len()).any(|i| self.overrides.contains(&name[i..]))
+    }
+
+    /// The records of the chain proving that `name`, of the signed zone at `origin`, does not
+    /// exist with `nxdomain`, or has no records of the type queried otherwise: NSEC records
+    /// (RFC 4035 Section 3.1.3), or NSEC3 ones proving its closest encloser unless matching it
+    /// (RFC 5155 Section 7.2)
+    pub fn denial(&self, origin: &[String], name: &[String], nxdomain: bool) -> Vec<Link<'_>> {
+        let mut links = Vec::new();
+        let Some(chain) = self.chains.get(origin) else {
+            return links;
+        };
+        match chain {
+            Chain::Nsec(names) => {
+                let find = |name: &[String]| {
+                    let key = canonical_key(name);
+                    match names.binary_search_by(|(other, _)| other.cmp(&key)) {
+                        Ok(i) => chain.link(i),
+                        Err(i) => chain.link(i.max(1) - 1),
+                    }
+                };
+                links.push(find(name));
+                if nxdomain {
+                    // No wildcard at the closest encloser either
+                    let encloser = (1..=name.len())
+                        .map(|i| &name[i..])
+                        .find(|encloser| encloser.len() <= origin.len() || self.exists(encloser))
+                        .unwrap_or(origin);
+                    links.push(find(&wildcard(encloser)));
+                }
+            }
+            Chain::Nsec3 { params, hashes } => {
+                let search = |name: &[String]| {
+                    let hash = params.hash(name);
+                    hashes.binary_search_by(|(other, _)| other.cmp(&hash))
+                };
+                // The last hash before, wrapping around
+                let cover = |name: &[String]| match search(name) {
+                    Ok(i) => chain.link(i),
+                    Err(i) => chain.link((i + hashes.len() - 1) % hashes.len()),
+                };
+                match search(name) {
+                    Ok(i) if !nxdomain => links.push(chain.link(i)),
+                    _ => {
+                        // Its closest encloser, the origin at worst, and the next closer name,
+                        // covered by an opt-out record below delegations left out
+                        let Some((i, encloser)) = (1..=name.len() - origin.len())
+                            .find_map(|i| search(&name[i..]).ok().map(|found| (i, found)))
+                        else {
+                            return links;
+                        };
+                        links.push(chain.link(encloser));
+                        links.push(cover(&name[i - 1..]));
+                        if nxdomain {
+                            links.push(cover(&wildcard(&name[i..])));
+                        }
+                    }
+                }
+            }
+        }
+        let mut unique: Vec<Link> = Vec::new();
+        for link in links {
+            if !unique.contains(&link) {
+                unique.push(link);
+            }
+        }
+        unique
+    }
+
+    /// Every record of the chain of the signed zone at `origin`
+    pub fn chain(&self, origin: &[String]) -> Vec<Link<'_>> {
+        match self.chains.get(origin) {
+            Some(chain) => (0..chain.len()).map(|i| chain.link(i)).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Zone> {
+        self.by_origin.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> Name {
+        Name::from(crate::label::split_name(s))
+    }
+
+    fn record(inner: RecordInner) -> Record {
+        Record::new(inner, 300)
+    }
+
+    /// Zone example with a.example, the empty non-terminal c.example above b.c.example and the
+    /// delegation d.example with glue, along with the override www.other
+    fn base() -> BaseStorage {
+        let soa = RecordInner::SOA {
+            serial: 1,
+            mname: name("ns.example"),
+            rname: name("admin.example"),
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 60,
+        };
+        let a = |last| {
+            record(RecordInner::A {
+                addr: [192, 0, 2, last],
+            })
+        };
+        let ns = |ns| record(RecordInner::NS { ns: name(ns) });
+        let mut base = BaseStorage::new();
+        base.insert(name("example"), vec![record(soa), ns("ns.example")]);
+        base.insert(name("a.example"), vec![a(1)]);
+        base.insert(name("b.c.example"), vec![a(2)]);
+        base.insert(name("d.example"), vec![ns("ns.d.example")]);
+        base.insert(name("ns.d.example"), vec![a(3)]);
+        base.insert(name("www.other"), vec![a(4)]);
+        base
+    }
+
+    fn signed(nsec3: Option<Nsec3Config>) -> Zones {
+        Zones::index(&base(), &[(name("example"), nsec3)])
+    }
+
+    fn names(links: &[Link<'_>]) -> Vec<String> {
+        links.iter().map(|link| link.name().to_string()).collect()
+    }
+
+    #[test]
+    fn index() {
+        let zones = signed(None);
+        let segs = |s: &str| crate::label::split_name(s);
+        assert_eq!(
+            zones.find(&segs("x.a.example")).unwrap().origin,
+            name("example")
+        );
+        assert!(zones.find(&segs("www.other")).is_none());
+        assert!(zones.exists(&segs("c.example")));
+        assert!(!zones.exists(&segs("x.a.example")));
+        assert!(zones.is_overridden(&segs("x.www.other")));
+        assert!(!zones.is_overridden(&segs("a.example")));
+        assert_eq!(zones.get(&segs("example")).unwrap().negative_soa().ttl, 60);
+    }
+
+    #[test]
+    fn nsec_chain() {
+        let zones = signed(None);
+        let chain = zones.chain(name("example").as_ref());
+        // Canonical order, without the glue below the delegation nor the empty non-terminal
+        assert_eq!(
+            names(&chain),
+            ["example", "a.example", "b.c.example", "d.example",]
+        );
+        match chain.last().unwrap() {
+            Link::Nsec(_, next) => assert_eq!(**next, name("example")),
+            _ => panic!("expected NSEC"),
+        }
+    }
+
+    #[test]
+    fn nsec_denial() {
+        let zones = signed(None);
+        let origin = name("example");
+        let deny =
+            |s: &str, nxdomain| names(&zones.denial(origin.as_ref(), name(s).as_ref(), nxdomain));
+        // Covered by a.example, and no wildcard, covered by example
+        assert_eq!(deny("aa.example", true), ["a.example", "example"]);
+        assert_eq!(deny("a.example", false), ["a.example"]);
+        // Below an empty non-terminal, the closest encloser, whose wildcard sorts before
+        // b.c.example
+        assert_eq!(deny("x.c.example", true), ["b.c.example", "a.example"]);
+        assert!(zones
+            .denial(name("other").as_ref(), name("x.other").as_ref(), true)
+            .is_empty());
+    }
+
+    fn nsec3_hashes(zones: &Zones) -> Vec<String> {
+        let mut hashes: Vec<String> = zones
+            .chain(name("example").as_ref())
+            .iter()
+            .map(|link| match link {
+                Link::Nsec3 { hash, .. } => crate::dnssec::to_base32hex(hash),
+                _ => panic!("expected NSEC3"),
+            })
+            .collect();
+        hashes.sort();
+        hashes
+    }
+
+    fn hashes_of(params: &Nsec3Config, names: &[&str]) -> Vec<String> {
+        let mut hashes: Vec<String> = names
+            .iter()
+            .map(|s| crate::dnssec::to_base32hex(&params.hash(name(s).as_ref())))
+            .collect();
+        hashes.sort();
+        hashes
+    }
+
+    #[test]
+    fn nsec3_chain() {
+        let params = Nsec3Config::default();
+        let zones = signed(Some(params.clone()));
+        // With the empty non-terminal, without the glue
+        let expected = [
+            "example",
+            "a.example",
+            "b.c.example",
+            "c.example",
+            "d.example",
+        ];
+        assert_eq!(nsec3_hashes(&zones), hashes_of(&params, &expected));
+        // In order, each linked to the next, the last to the first
+        let chain = zones.chain(name("example").as_ref());
+        for (i, link) in chain.iter().enumerate() {
+            let Link::Nsec3 { hash, next, .. } = link else {
+                panic!("expected NSEC3");
+            };
+            let Link::Nsec3 {
+                hash: following, ..
+            } = &chain[(i + 1) % chain.len()]
+            else {
+                panic!("expected NSEC3");
+            };
+            assert_eq!(next, following);
+            assert!(i + 1 == chain.len() || hash < next);
+        }
+    }
+
+    #[test]
+    fn nsec3_opt_out() {
+        let params = Nsec3Config {
+            opt_out: true,
+            ..Default::default()
+        };
+        let zones = signed(Some(params.clone()));
+        // The delegation, without DS records, is left out
+        let expected = ["example", "a.example", "b.c.example", "c.example"];
+        assert_eq!(nsec3_hashes(&zones), hashes_of(&params, &expected));
+    }
+
+    /// Whether `link` covers the hash of `name`, strictly between its own and the next
+    fn covers(link: &Link<'_>, params: &Nsec3Config, s: &str) -> bool {
+        let hash = params.hash(name(s).as_ref());
+        match link {
+            Link::Nsec3 {
+                hash: own, next, ..
+            } => match own < next {
+                true => **own < *hash && *hash < **next,
+                false => **own < *hash || *hash < **next,
+            },
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn nsec3_denial() {
+        let params = Nsec3Config {
+            salt: vec![0xaa, 0xbb],
+            iterations: 1,
+            opt_out: false,
+        };
+        let zones = signed(Some(params.clone()));
+        let origin = name("example");
+
+        // The closest encloser, and the records covering the next closer name and the wildcard
+        let links = zones.denial(origin.as_ref(), name("x.y.a.example").as_ref(), true);
+        assert_eq!(links[0].name(), &name("a.example"));
+        assert!(covers(&links[1], &params, "y.a.example"));
+        assert!(covers(&links[2], &params, "*.a.example"));
+
+        // No data: the record of the name itself
+        let links = zones.denial(origin.as_ref(), name("a.example").as_ref(), false);
+        assert_eq!(names(&links), ["a.example"]);
+        let links = zones.denial(origin.as_ref(), name("c.example").as_ref(), false);
+        assert_eq!(names(&links), ["c.example"]);
+
+        // The same record may cover both, and is then only given once
+        let links = zones.denial(origin.as_ref(), name("z.example").as_ref(), true);
+        assert_eq!(links[0].name(), &origin);
+        assert!(links[1..]
+            .iter()
+            .any(|link| covers(link, &params, "z.example")));
+        assert!(links[1..]
+            .iter()
+            .any(|link| covers(link, &params, "*.example")));
+        assert!(links.len() <= 3);
+    }
+}
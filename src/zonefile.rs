@@ -0,0 +1,774 @@
+//! RFC 1035 Section 5 master file parser, producing the same storage as the YAML format
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use base64ct::{Base64, Encoding};
+
+use crate::generate::{substitute, Range};
+use crate::label::split_name;
+use crate::parser::Type;
+use crate::record::{parse_salt, parse_signature_time, Name, Record, RecordInner};
+use crate::BaseStorage;
+
+#[derive(Debug)]
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+/// One logical entry, parentheses already joined
+#[derive(Debug)]
+struct Entry {
+    line: usize,
+    /// The owner is left out, meaning the previous one is reused
+    blank_owner: bool,
+    tokens: Vec<Token>,
+}
+
+fn tokenize(content: &str) -> anyhow::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut cur: Option<Entry> = None;
+    let mut depth = 0;
+
+    for (idx, line) in content.lines().enumerate() {
+        let lineno = idx + 1;
+        if cur.is_none() {
+            cur = Some(Entry {
+                line: lineno,
+                blank_owner: line.starts_with([' ', '\t']),
+                tokens: Vec::new(),
+            });
+        }
+        let entry = cur.as_mut().unwrap();
+
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                ';' => break,
+                ' ' | '\t' => {}
+                '(' => depth += 1,
+                ')' => {
+                    if depth == 0 {
+                        return Err(anyhow::anyhow!("line {}: unbalanced ')'", lineno));
+                    }
+                    depth -= 1;
+                }
+                '"' => {
+                    let mut text = String::new();
+                    let mut closed = false;
+                    while let Some(c) = chars.next() {
+                        match c {
+                            '"' => {
+                                closed = true;
+                                break;
+                            }
+                            '\\' => {
+                                if let Some(next) = chars.next() {
+                                    text.push(next);
+                                }
+                            }
+                            c => text.push(c),
+                        }
+                    }
+                    if !closed {
+                        return Err(anyhow::anyhow!("line {}: unterminated string", lineno));
+                    }
+                    entry.tokens.push(Token { text, quoted: true });
+                }
+                c => {
+                    let mut text = String::from(c);
+                    let mut escaped = c == '\\';
+                    while let Some(&next) = chars.peek() {
+                        if !escaped && matches!(next, ' ' | '\t' | ';' | '(' | ')' | '"') {
+                            break;
+                        }
+                        escaped = !escaped && next == '\\';
+                        text.push(next);
+                        chars.next();
+                    }
+                    entry.tokens.push(Token {
+                        text,
+                        quoted: false,
+                    });
+                }
+            }
+        }
+
+        if depth == 0 {
+            let entry = cur.take().unwrap();
+            if !entry.tokens.is_empty() {
+                entries.push(entry);
+            }
+        }
+    }
+
+    if depth != 0 {
+        return Err(anyhow::anyhow!("unbalanced '(' at end of file"));
+    }
+    Ok(entries)
+}
+
+/// Parses a TTL, either plain seconds or BIND-style units such as 1h30m
+pub fn parse_ttl(s: &str) -> Option<u32> {
+    if let Ok(secs) = s.parse::<u32>() {
+        return Some(secs);
+    }
+
+    let mut total: u64 = 0;
+    let mut num: Option<u64> = None;
+    for c in s.chars() {
+        if let Some(d) = c.to_digit(10) {
+            num = Some(num.unwrap_or(0) * 10 + d as u64);
+            if num > Some(u32::MAX as u64) {
+                return None;
+            }
+            continue;
+        }
+        let mult = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return None,
+        };
+        total += num.take()? * mult;
+    }
+    if num.is_some() {
+        return None;
+    }
+    u32::try_from(total).ok()
+}
+
+struct Parser<'a> {
+    file: &'a str,
+    origin: Option<Vec<String>>,
+    default_ttl: Option<u32>,
+    last_owner: Option<Vec<String>>,
+    last_ttl: Option<u32>,
+}
+
+impl<'a> Parser<'a> {
+    fn err(&self, line: usize, msg: impl std::fmt::Display) -> anyhow::Error {
+        anyhow::anyhow!("{}:{}: {}", self.file, line, msg)
+    }
+
+    fn name(&self, line: usize, s: &str) -> anyhow::Result<Vec<String>> {
+        if s == "@" {
+            return self
+                .origin
+                .clone()
+                .ok_or_else(|| self.err(line, "@ used without $ORIGIN"));
+        }
+        if s == "." {
+            return Ok(Vec::new());
+        }
+
+        let absolute = s.ends_with('.') && !s.ends_with("\\.");
+        let mut segs = split_name(if absolute { &s[..s.len() - 1] } else { s });
+        if segs.iter().any(String::is_empty) {
+            return Err(self.err(line, format!("empty label in {}", s)));
+        }
+        if !absolute {
+            match &self.origin {
+                Some(origin) => segs.extend(origin.iter().cloned()),
+                None => return Err(self.err(line, format!("relative name {} without $ORIGIN", s))),
+            }
+        }
+        Ok(segs)
+    }
+
+    fn ty(&self, line: usize, s: &str) -> anyhow::Result<Type> {
+        s.parse()
+            .map_err(|_| self.err(line, format!("unsupported record type {}", s)))
+    }
+
+    /// Base64 data, which may be split in several fields
+    fn base64(&self, line: usize, tokens: &[&Token]) -> anyhow::Result<Vec<u8>> {
+        let text: String = tokens.iter().map(|t| t.text.as_str()).collect();
+        Base64::decode_vec(&text).map_err(|_| self.err(line, format!("invalid base64 {}", text)))
+    }
+
+    fn record(&mut self, entry: &Entry) -> anyhow::Result<(Vec<String>, Record)> {
+        let line = entry.line;
+        let mut tokens = entry.tokens.iter().map(|t| t.text.as_str()).peekable();
+
+        let owner = if entry.blank_owner {
+            self.last_owner
+                .clone()
+                .ok_or_else(|| self.err(line, "no previous owner name"))?
+        } else {
+            let owner = tokens.next().unwrap();
+            self.name(line, owner)?
+        };
+
+        // [TTL] [CLASS] or [CLASS] [TTL], in any order
+        let mut ttl = None;
+        for _ in 0..2 {
+            match tokens.peek() {
+                Some(t) if t.eq_ignore_ascii_case("IN") => {
+                    tokens.next();
+                }
+                Some(t) if ["CH", "HS", "CS"].iter().any(|c| t.eq_ignore_ascii_case(c)) => {
+                    return Err(self.err(line, format!("unsupported class {}", t)));
+                }
+                Some(t) if t.starts_with(|c: char| c.is_ascii_digit()) => {
+                    ttl = Some(
+                        parse_ttl(t).ok_or_else(|| self.err(line, format!("invalid TTL {}", t)))?,
+                    );
+                    tokens.next();
+                }
+                _ => break,
+            }
+        }
+
+        let ty = tokens
+            .next()
+            .ok_or_else(|| self.err(line, "missing record type"))?
+            .to_ascii_uppercase();
+        let rdata: Vec<&Token> = entry
+            .tokens
+            .iter()
+            .skip(entry.tokens.len() - tokens.len())
+            .collect();
+
+        let expect = |n: usize| -> anyhow::Result<()> {
+            if rdata.len() != n {
+                Err(self.err(
+                    line,
+                    format!("{} expects {} field(s), got {}", ty, n, rdata.len()),
+                ))
+            } else {
+                Ok(())
+            }
+        };
+        let num = |t: &Token| -> anyhow::Result<u32> {
+            parse_ttl(&t.text).ok_or_else(|| self.err(line, format!("invalid number {}", t.text)))
+        };
+        let num8 = |t: &Token| -> anyhow::Result<u8> {
+            t.text
+                .parse()
+                .map_err(|_| self.err(line, format!("invalid number {}", t.text)))
+        };
+        let num16 = |t: &Token| -> anyhow::Result<u16> {
+            t.text
+                .parse()
+                .map_err(|_| self.err(line, format!("invalid number {}", t.text)))
+        };
+
+        let inner =
+            match ty.as_str() {
+                "A" => {
+                    expect(1)?;
+                    let addr: Ipv4Addr = rdata[0]
+                        .text
+                        .parse()
+                        .map_err(|e| self.err(line, format!("{}: {}", rdata[0].text, e)))?;
+                    RecordInner::A {
+                        addr: addr.octets(),
+                    }
+                }
+                "AAAA" => {
+                    expect(1)?;
+                    let addr: Ipv6Addr = rdata[0]
+                        .text
+                        .parse()
+                        .map_err(|e| self.err(line, format!("{}: {}", rdata[0].text, e)))?;
+                    RecordInner::AAAA {
+                        addr: addr.octets(),
+                    }
+                }
+                "NS" => {
+                    expect(1)?;
+                    RecordInner::NS {
+                        ns: Name::from(self.name(line, &rdata[0].text)?),
+                    }
+                }
+                "CNAME" => {
+                    expect(1)?;
+                    RecordInner::CNAME {
+                        to: Name::from(self.name(line, &rdata[0].text)?),
+                    }
+                }
+                "PTR" => {
+                    expect(1)?;
+                    RecordInner::PTR {
+                        ptr: Name::from(self.name(line, &rdata[0].text)?),
+                    }
+                }
+                "SRV" => {
+                    expect(4)?;
+                    RecordInner::SRV {
+                        priority: num16(rdata[0])?,
+                        weight: num16(rdata[1])?,
+                        port: num16(rdata[2])?,
+                        target: Name::from(self.name(line, &rdata[3].text)?),
+                    }
+                }
+                "TXT" => {
+                    if rdata.is_empty() {
+                        return Err(self.err(line, "TXT expects at least one string"));
+                    }
+                    RecordInner::TXT {
+                        content: rdata
+                            .iter()
+                            .map(|t| t.text.as_str())
+                            .collect::<Vec<_>>()
+                            .join(if rdata.iter().all(|t| t.quoted) {
+                                ""
+                            } else {
+                                " "
+                            }),
+                    }
+                }
+                "SOA" => {
+                    expect(7)?;
+                    RecordInner::SOA {
+                        mname: Name::from(self.name(line, &rdata[0].text)?),
+                        rname: Name::from(self.name(line, &rdata[1].text)?),
+                        serial: rdata[2].text.parse().map_err(|_| {
+                            self.err(line, format!("invalid serial {}", rdata[2].text))
+                        })?,
+                        refresh: num(rdata[3])?,
+                        retry: num(rdata[4])?,
+                        expire: num(rdata[5])?,
+                        minimum: num(rdata[6])?,
+                    }
+                }
+                "DNSKEY" | "CDNSKEY" => {
+                    if rdata.len() < 4 {
+                        return Err(self.err(line, format!("{} expects at least 4 fields", ty)));
+                    }
+                    let flags = rdata[0]
+                        .text
+                        .parse()
+                        .map_err(|_| self.err(line, format!("invalid flags {}", rdata[0].text)))?;
+                    let (protocol, algorithm) = (num8(rdata[1])?, num8(rdata[2])?);
+                    let public_key = self.base64(line, &rdata[3..])?;
+                    if ty == "DNSKEY" {
+                        RecordInner::DNSKEY {
+                            flags,
+                            protocol,
+                            algorithm,
+                            public_key,
+                        }
+                    } else {
+                        RecordInner::CDNSKEY {
+                            flags,
+                            protocol,
+                            algorithm,
+                            public_key,
+                        }
+                    }
+                }
+                "CDS" => {
+                    if rdata.len() < 4 {
+                        return Err(self.err(line, "CDS expects at least 4 fields"));
+                    }
+                    let digest: String = rdata[3..].iter().map(|t| t.text.as_str()).collect();
+                    RecordInner::CDS {
+                        key_tag: num16(rdata[0])?,
+                        algorithm: num8(rdata[1])?,
+                        digest_type: num8(rdata[2])?,
+                        digest: crate::dnssec::hex(&digest)
+                            .ok_or_else(|| self.err(line, format!("invalid digest {}", digest)))?,
+                    }
+                }
+                "ZONEMD" => {
+                    if rdata.len() < 4 {
+                        return Err(self.err(line, "ZONEMD expects at least 4 fields"));
+                    }
+                    let digest: String = rdata[3..].iter().map(|t| t.text.as_str()).collect();
+                    RecordInner::ZONEMD {
+                        serial: rdata[0].text.parse().map_err(|_| {
+                            self.err(line, format!("invalid serial {}", rdata[0].text))
+                        })?,
+                        scheme: num8(rdata[1])?,
+                        hash_algorithm: num8(rdata[2])?,
+                        digest: crate::dnssec::hex(&digest)
+                            .ok_or_else(|| self.err(line, format!("invalid digest {}", digest)))?,
+                    }
+                }
+                "RRSIG" => {
+                    if rdata.len() < 9 {
+                        return Err(self.err(line, "RRSIG expects at least 9 fields"));
+                    }
+                    let time = |t: &Token| -> anyhow::Result<u32> {
+                        parse_signature_time(&t.text)
+                            .ok_or_else(|| self.err(line, format!("invalid time {}", t.text)))
+                    };
+                    RecordInner::RRSIG {
+                        type_covered: self.ty(line, &rdata[0].text)?,
+                        algorithm: num8(rdata[1])?,
+                        labels: num8(rdata[2])?,
+                        original_ttl: num(rdata[3])?,
+                        expiration: time(rdata[4])?,
+                        inception: time(rdata[5])?,
+                        key_tag: rdata[6].text.parse().map_err(|_| {
+                            self.err(line, format!("invalid key tag {}", rdata[6].text))
+                        })?,
+                        signer: Name::from(self.name(line, &rdata[7].text)?),
+                        signature: self.base64(line, &rdata[8..])?,
+                    }
+                }
+                "NSEC" => {
+                    if rdata.is_empty() {
+                        return Err(self.err(line, "NSEC expects a next name"));
+                    }
+                    RecordInner::NSEC {
+                        next: Name::from(self.name(line, &rdata[0].text)?),
+                        types: rdata[1..]
+                            .iter()
+                            .map(|t| self.ty(line, &t.text))
+                            .collect::<anyhow::Result<_>>()?,
+                    }
+                }
+                "NSEC3" | "NSEC3PARAM" => {
+                    let fields = if ty == "NSEC3" { 5 } else { 4 };
+                    if rdata.len() < fields {
+                        return Err(self.err(line, format!("{} expects {} fields", ty, fields)));
+                    }
+                    let salt = parse_salt(&rdata[3].text)
+                        .ok_or_else(|| self.err(line, format!("invalid salt {}", rdata[3].text)))?;
+                    if ty == "NSEC3PARAM" {
+                        RecordInner::NSEC3PARAM {
+                            hash_algorithm: num8(rdata[0])?,
+                            flags: num8(rdata[1])?,
+                            iterations: num16(rdata[2])?,
+                            salt,
+                        }
+                    } else {
+                        RecordInner::NSEC3 {
+                            hash_algorithm: num8(rdata[0])?,
+                            flags: num8(rdata[1])?,
+                            iterations: num16(rdata[2])?,
+                            salt,
+                            next: crate::dnssec::base32hex(&rdata[4].text).ok_or_else(|| {
+                                self.err(line, format!("invalid hash {}", rdata[4].text))
+                            })?,
+                            types: rdata[5..]
+                                .iter()
+                                .map(|t| self.ty(line, &t.text))
+                                .collect::<anyhow::Result<_>>()?,
+                        }
+                    }
+                }
+                _ => return Err(self.err(line, format!("unsupported record type {}", ty))),
+            };
+
+        let ttl = ttl
+            .or(self.default_ttl)
+            .or(self.last_ttl)
+            .ok_or_else(|| self.err(line, "no TTL given and no $TTL in effect"))?;
+
+        self.last_owner = Some(owner.clone());
+        self.last_ttl = Some(ttl);
+        Ok((owner, Record::new(inner, ttl)))
+    }
+}
+
+/// Called on $INCLUDE with the file name as written, and the origin to start it with
+pub type IncludeFn<'a> = dyn FnMut(&str, Option<Vec<String>>) -> anyhow::Result<BaseStorage> + 'a;
+
+/// Parses a master file. `file` is only used in error messages, `origin` is the initial $ORIGIN.
+pub fn parse(
+    content: &str,
+    file: &str,
+    origin: Option<Vec<String>>,
+    include: &mut IncludeFn<'_>,
+) -> anyhow::Result<BaseStorage> {
+    let mut parser = Parser {
+        file,
+        origin,
+        default_ttl: None,
+        last_owner: None,
+        last_ttl: None,
+    };
+    let mut base = BaseStorage::new();
+
+    for entry in tokenize(content).map_err(|e| anyhow::anyhow!("{}:{}", file, e))? {
+        let directive = entry.tokens[0].text.to_ascii_uppercase();
+        match directive.as_str() {
+            "$ORIGIN" if !entry.blank_owner => {
+                if entry.tokens.len() != 2 {
+                    return Err(parser.err(entry.line, "$ORIGIN expects one name"));
+                }
+                parser.origin = Some(parser.name(entry.line, &entry.tokens[1].text)?);
+            }
+            "$TTL" if !entry.blank_owner => {
+                if entry.tokens.len() != 2 {
+                    return Err(parser.err(entry.line, "$TTL expects one value"));
+                }
+                parser.default_ttl = Some(
+                    parse_ttl(&entry.tokens[1].text)
+                        .ok_or_else(|| parser.err(entry.line, "invalid $TTL"))?,
+                );
+            }
+            "$INCLUDE" if !entry.blank_owner => {
+                // $INCLUDE <file> [origin], the current origin is left untouched afterwards
+                let origin = match entry.tokens.len() {
+                    2 => parser.origin.clone(),
+                    3 => Some(parser.name(entry.line, &entry.tokens[2].text)?),
+                    _ => {
+                        return Err(parser
+                            .err(entry.line, "$INCLUDE expects a file and an optional origin"))
+                    }
+                };
+                let included = include(&entry.tokens[1].text, origin)
+                    .map_err(|e| parser.err(entry.line, e))?;
+                for (name, records) in included {
+                    base.entry(name).or_default().extend(records);
+                }
+            }
+            "$GENERATE" if !entry.blank_owner => {
+                // $GENERATE <range> <lhs> [ttl] [class] <type> <rhs>
+                if entry.tokens.len() < 4 {
+                    return Err(parser.err(entry.line, "$GENERATE expects a range and a record"));
+                }
+                let range: Range = entry.tokens[1]
+                    .text
+                    .parse()
+                    .map_err(|e| parser.err(entry.line, e))?;
+                for i in range.iter() {
+                    let generated = Entry {
+                        line: entry.line,
+                        blank_owner: false,
+                        tokens: entry.tokens[2..]
+                            .iter()
+                            .map(|t| {
+                                Ok(Token {
+                                    text: substitute(&t.text, i)?,
+                                    quoted: t.quoted,
+                                })
+                            })
+                            .collect::<anyhow::Result<_>>()
+                            .map_err(|e| parser.err(entry.line, e))?,
+                    };
+                    let (owner, record) = parser.record(&generated)?;
+                    base.entry(Name::from(owner)).or_default().push(record);
+                }
+            }
+            d if d.starts_with('$') && !entry.blank_owner => {
+                return Err(parser.err(entry.line, format!("unsupported directive {}", d)));
+            }
+            _ => {
+                let (owner, record) = parser.record(&entry)?;
+                base.entry(Name::from(owner)).or_default().push(record);
+            }
+        }
+    }
+
+    Ok(base)
+}
+
+/// Parses standalone records as provided by dynamic sources: absolute names only, no $INCLUDE
+pub fn parse_standalone(content: &str, source: &str) -> anyhow::Result<BaseStorage> {
+    parse(content, source, None, &mut |_, _| {
+        Err(anyhow::anyhow!("$INCLUDE is not allowed here"))
+    })
+}
+
+/// Builds storage from (name, type, ttl, content) rows as kept by database sources, `content`
+/// being the RDATA in master file syntax
+pub fn parse_rows<'a>(
+    rows: impl IntoIterator<Item = (&'a str, &'a str, u32, &'a str)>,
+    source: &str,
+) -> anyhow::Result<BaseStorage> {
+    let mut zone = String::new();
+    for (name, ty, ttl, content) in rows {
+        zone.push_str(&format!(
+            "{}. {} IN {} {}\n",
+            name.trim_end_matches('.'),
+            ttl,
+            ty,
+            content
+        ));
+    }
+    parse_standalone(&zone, source)
+}
+
+/// Splits an RRset as stored by key-value sources, one "TTL RDATA" line per record
+pub fn split_rrset(value: &str) -> anyhow::Result<Vec<(u32, &str)>> {
+    let mut records = Vec::new();
+    for line in value.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        let (ttl, content) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| anyhow::anyhow!("expected \"TTL RDATA\", got {:?}", line))?;
+        let ttl = ttl
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid TTL {}: {}", ttl, e))?;
+        records.push((ttl, content.trim()));
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_include(_: &str, _: Option<Vec<String>>) -> anyhow::Result<BaseStorage> {
+        Err(anyhow::anyhow!("no includes"))
+    }
+
+    fn parse_zone(content: &str) -> anyhow::Result<BaseStorage> {
+        parse(content, "test.zone", None, &mut no_include)
+    }
+
+    fn records<'a>(base: &'a BaseStorage, name: &str) -> &'a [Record] {
+        base.get(split_name(name).as_slice())
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    const ZONE: &str = "\
+$ORIGIN example.com.
+$TTL 1h
+@   IN  SOA ns admin (
+            2024010100 ; serial
+            3600 600 86400 60 )
+    IN  NS  ns
+ns      A   192.0.2.1
+www 300 A   192.0.2.2
+        AAAA 2001:db8::2 ; same owner
+mail    CNAME www.example.net.
+txt     TXT \"hello \\\"world\\\"\" \"and more\"
+";
+
+    #[test]
+    fn master_file() {
+        let base = parse_zone(ZONE).unwrap();
+        let apex = records(&base, "example.com");
+        assert_eq!(apex.len(), 2);
+        assert_eq!(apex[0].ttl, 3600);
+        match &apex[0].inner {
+            RecordInner::SOA {
+                serial,
+                mname,
+                rname,
+                minimum,
+                ..
+            } => {
+                assert_eq!(*serial, 2024010100);
+                assert_eq!(mname.to_string(), "ns.example.com");
+                assert_eq!(rname.to_string(), "admin.example.com");
+                assert_eq!(*minimum, 60);
+            }
+            other => panic!("expected SOA, got {:?}", other),
+        }
+        assert_eq!(apex[1].inner.ty(), Type::NS);
+
+        let www = records(&base, "www.example.com");
+        assert_eq!(www.len(), 2);
+        assert_eq!(
+            www[0].inner,
+            RecordInner::A {
+                addr: [192, 0, 2, 2]
+            }
+        );
+        assert_eq!(www[0].ttl, 300);
+        // $TTL applies to the records without one, rather than the last TTL
+        assert_eq!(www[1].inner.ty(), Type::AAAA);
+        assert_eq!(www[1].ttl, 3600);
+
+        let mail = records(&base, "mail.example.com");
+        assert_eq!(mail[0].inner.rdata_text(), "www.example.net.");
+        let txt = records(&base, "txt.example.com");
+        assert_eq!(
+            txt[0].inner,
+            RecordInner::TXT {
+                content: "hello \"world\"and more".into()
+            }
+        );
+    }
+
+    #[test]
+    fn ttl_units() {
+        assert_eq!(parse_ttl("3600"), Some(3600));
+        assert_eq!(parse_ttl("1h30m"), Some(5400));
+        assert_eq!(parse_ttl("1w"), Some(604800));
+        assert_eq!(parse_ttl("1x"), None);
+    }
+
+    #[test]
+    fn errors() {
+        let err = |content: &str| parse_zone(content).unwrap_err().to_string();
+        assert!(err("www.example.com. A 192.0.2.1\n").contains("no TTL"));
+        // Without $TTL, the last TTL carries over
+        let base = parse_zone("a.example.com. 300 A 192.0.2.1\nb.example.com. A 192.0.2.2\n");
+        assert_eq!(records(&base.unwrap(), "b.example.com")[0].ttl, 300);
+        assert!(err("$TTL 60\nwww.example.com. CH A 192.0.2.1\n").contains("unsupported class"));
+        assert!(err("$TTL 60\nwww.example.com. A 192.0.2.256\n").contains(":2"));
+        assert!(err("$BOGUS\n").contains("unsupported directive"));
+        assert!(err("$TTL 60\nwww.example.com. A (192.0.2.1\n").contains("unbalanced '('"));
+        assert!(err("$TTL 60\nwww.example.com. TXT \"open\n").contains("unterminated string"));
+        assert!(err("$TTL 60\nwww A 192.0.2.1\n").contains("without $ORIGIN"));
+        assert!(err("$TTL 60\n$INCLUDE other.zone\n").contains("no includes"));
+        assert!(err("$TTL 60\nwww.example.com. TXT\n").contains("at least one string"));
+    }
+
+    #[test]
+    fn generate() {
+        let base =
+            parse_zone("$ORIGIN example.com.\n$TTL 60\n$GENERATE 1-3 host$ A 192.0.2.$\n").unwrap();
+        for i in 1..=3 {
+            let host = records(&base, &format!("host{}.example.com", i));
+            assert_eq!(
+                host[0].inner,
+                RecordInner::A {
+                    addr: [192, 0, 2, i]
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn include() {
+        let content = "\
+$ORIGIN example.com.
+$TTL 60
+$INCLUDE hosts.zone
+$INCLUDE sub.zone sub.example.com.
+after A 192.0.2.9
+";
+        let mut calls = Vec::new();
+        let base = parse(content, "test.zone", None, &mut |file, origin| {
+            let origin = origin.map(|o| o.join("."));
+            calls.push((file.to_owned(), origin.clone()));
+            parse(
+                &format!("$TTL 60\n$ORIGIN {}.\nhost A 192.0.2.1\n", origin.unwrap()),
+                file,
+                None,
+                &mut no_include,
+            )
+        })
+        .unwrap();
+        assert_eq!(
+            calls,
+            [
+                ("hosts.zone".to_owned(), Some("example.com".to_owned())),
+                ("sub.zone".to_owned(), Some("sub.example.com".to_owned())),
+            ]
+        );
+        assert_eq!(records(&base, "host.example.com").len(), 1);
+        assert_eq!(records(&base, "host.sub.example.com").len(), 1);
+        // The origin is left untouched by the included files
+        assert_eq!(records(&base, "after.example.com").len(), 1);
+    }
+
+    #[test]
+    fn standalone() {
+        assert!(parse_standalone("www.example.com. 60 A 192.0.2.1\n", "test").is_ok());
+        assert!(parse_standalone("$INCLUDE /etc/passwd\n", "test").is_err());
+        let rows = [
+            ("www.example.com", "A", 60, "192.0.2.1"),
+            ("www.example.com", "A", 60, "192.0.2.2"),
+        ];
+        let base = parse_rows(rows, "test").unwrap();
+        assert_eq!(records(&base, "www.example.com").len(), 2);
+        assert_eq!(
+            split_rrset("60 192.0.2.1\n\n 300 192.0.2.2 ").unwrap(),
+            [(60, "192.0.2.1"), (300, "192.0.2.2")]
+        );
+        assert!(split_rrset("192.0.2.1").is_err());
+    }
+}
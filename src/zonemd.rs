@@ -0,0 +1,147 @@
+//! Message digests of whole zones, ZONEMD records (RFC 8976), so that secondaries can tell the
+//! zone they transferred is the one published. Zones signed with the `sign` subcommand get one at
+//! their apex, signed along with the rest, and so do the AXFR transfers of the zones signed
+//! online. Either is of the SIMPLE scheme with SHA-384, over the records of the zone in canonical
+//! order and form, the ZONEMD RRset of the apex and its signatures left out.
+//!
+//! Secondary zones are verified as they are transferred, see the secondary module: a transfer
+//! holding ZONEMD records is refused unless one of those of the SIMPLE scheme with SHA-384 or
+//! SHA-512, for the serial of the SOA, matches. Transfers without any, or only with schemes or
+//! algorithms we do not know, are taken as they are.
+
+use sha2::{Digest, Sha384, Sha512};
+
+use crate::parser::{RawRR, Type};
+use crate::record::{Record, RecordInner, TtlBounds};
+
+/// The SIMPLE scheme, RFC 8976 Section 5.2
+const SIMPLE: u8 = 1;
+/// Hash algorithms, RFC 8976 Section 5.3
+const SHA384: u8 = 1;
+const SHA512: u8 = 2;
+
+/// A RR of a zone, in canonical form
+pub struct Rr {
+    /// Owner, see `zone::canonical_key`
+    key: Vec<Vec<u8>>,
+    ty: u16,
+    ttl: u32,
+    rdata: Vec<u8>,
+}
+
+impl Rr {
+    /// `record` at `owner`, as transferred with `ttl_bounds`
+    pub fn new(owner: &[String], record: &Record, ttl_bounds: &TtlBounds) -> Self {
+        let rdata = crate::sign::canonical(&record.inner)
+            .serialize()
+            .expect("writing to a Vec");
+        Self {
+            key: crate::zone::canonical_key(owner),
+            ty: record.inner.ty() as u16,
+            ttl: ttl_bounds.clamp(record.ttl),
+            rdata,
+        }
+    }
+
+    /// `rr`, a RR of `msg`, or None if its RDATA is malformed
+    pub fn from_raw(msg: &[u8], rr: &RawRR<'_>) -> Option<Self> {
+        Some(Self {
+            key: rr
+                .name
+                .labels
+                .iter()
+                .rev()
+                .map(|label| label.to_ascii_lowercase())
+                .collect(),
+            ty: rr.ty,
+            ttl: rr.ttl,
+            rdata: crate::dnssec::rdata(msg, rr, true)?,
+        })
+    }
+
+    /// Is this the ZONEMD RRset at the apex of `zone`, or one of its signatures?
+    fn excluded(&self, zone: &[Vec<u8>]) -> bool {
+        self.key == zone
+            && (self.ty == Type::ZONEMD as u16
+                || (self.ty == Type::RRSIG as u16
+                    && self.rdata.get(..2) == Some(&(Type::ZONEMD as u16).to_be_bytes()[..])))
+    }
+}
+
+/// The digest of the SIMPLE scheme of `rrs`, the records of `zone`, with `hash_algorithm`, or None
+/// if it is not one we know
+pub fn digest(zone: &[String], rrs: &[Rr], hash_algorithm: u8) -> Option<Vec<u8>> {
+    let zone = crate::zone::canonical_key(zone);
+    let id = |rr: &Rr| (rr.key.clone(), rr.ty, rr.rdata.clone());
+    let mut rrs: Vec<&Rr> = rrs.iter().filter(|rr| !rr.excluded(&zone)).collect();
+    rrs.sort_by_key(|rr| id(rr));
+    // Duplicates, such as the SOA closing a transfer, are only digested once
+    rrs.dedup_by_key(|rr| id(rr));
+
+    let mut data = Vec::new();
+    for rr in rrs {
+        for label in rr.key.iter().rev() {
+            data.push(label.len() as u8);
+            data.extend(label);
+        }
+        data.push(0);
+        data.extend(rr.ty.to_be_bytes());
+        data.extend(1u16.to_be_bytes()); // IN
+        data.extend(rr.ttl.to_be_bytes());
+        data.extend((rr.rdata.len() as u16).to_be_bytes());
+        data.extend(&rr.rdata);
+    }
+    match hash_algorithm {
+        SHA384 => Some(Sha384::digest(&data).to_vec()),
+        SHA512 => Some(Sha512::digest(&data).to_vec()),
+        _ => None,
+    }
+}
+
+/// The ZONEMD record of `rrs`, the records of `zone` at `soa`, with the TTL of the latter
+pub fn record(zone: &[String], rrs: &[Rr], soa: &Record) -> Record {
+    let serial = match soa.inner {
+        RecordInner::SOA { serial, .. } => serial,
+        _ => 0,
+    };
+    let zonemd = RecordInner::ZONEMD {
+        serial,
+        scheme: SIMPLE,
+        hash_algorithm: SHA384,
+        digest: digest(zone, rrs, SHA384).expect("SHA-384 is supported"),
+    };
+    Record::new(zonemd, soa.ttl)
+}
+
+/// Checks `rrs`, the records of `zone` whose SOA has `serial`, against the ZONEMD records at its
+/// apex, RFC 8976 Section 4. Returns whether one was verified, false when there are none of a
+/// scheme and algorithm we know.
+pub fn verify(zone: &[String], rrs: &[Rr], serial: u32) -> anyhow::Result<bool> {
+    let key = crate::zone::canonical_key(zone);
+    let zonemds: Vec<(u32, u8, &[u8])> = rrs
+        .iter()
+        .filter(|rr| rr.key == key && rr.ty == Type::ZONEMD as u16 && rr.rdata.len() > 6)
+        .filter(|rr| rr.rdata[4] == SIMPLE && [SHA384, SHA512].contains(&rr.rdata[5]))
+        .map(|rr| {
+            let serial = u32::from_be_bytes(rr.rdata[..4].try_into().unwrap());
+            (serial, rr.rdata[5], &rr.rdata[6..])
+        })
+        .collect();
+    if zonemds.is_empty() {
+        return Ok(false);
+    }
+    let mut algorithms: Vec<u8> = zonemds.iter().map(|(_, algorithm, _)| *algorithm).collect();
+    algorithms.sort_unstable();
+    algorithms.dedup();
+    if algorithms.len() != zonemds.len() {
+        return Err(anyhow::anyhow!(
+            "several ZONEMD records of the same scheme and hash algorithm"
+        ));
+    }
+    for (zonemd_serial, algorithm, expected) in zonemds {
+        if zonemd_serial == serial && digest(zone, rrs, algorithm).as_deref() == Some(expected) {
+            return Ok(true);
+        }
+    }
+    Err(anyhow::anyhow!("ZONEMD mismatch for serial {}", serial))
+}